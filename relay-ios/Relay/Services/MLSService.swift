@@ -51,8 +51,8 @@ final class MLSService: @unchecked Sendable {
         }
 
         do {
-            let keyPackageBytes = try client.createKeyPackage()
-            return Data(keyPackageBytes)
+            let keyPackage = try client.createKeyPackage()
+            return Data(keyPackage.keyPackageBytes)
         } catch {
             throw MLSServiceError.keyPackageCreationFailed(
                 error.localizedDescription
@@ -121,7 +121,7 @@ final class MLSService: @unchecked Sendable {
     func hasGroup(groupId: String) -> Bool {
         guard let client = client else { return false }
         do {
-            _ = try client.members(groupId: groupId)
+            _ = try client.memberCount(groupId: groupId)
             return true
         } catch {
             return false
@@ -195,7 +195,7 @@ final class MLSService: @unchecked Sendable {
         }
 
         do {
-            return try client.members(groupId: groupId)
+            return try client.members(groupId: groupId).map { $0.clientId }
         } catch {
             throw MLSServiceError.groupNotFound(groupId)
         }
@@ -0,0 +1,153 @@
+//! Bots that answer messages
+
+use super::*;
+
+/// How long a bot command may take to answer before it is killed
+pub(crate) const BOT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Least time between two bot replies in a conversation, so two bots
+/// answering each other don't flood it
+pub(crate) const BOT_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A message that arrived, as handed to a Responder
+pub(crate) struct Incoming<'a> {
+    pub(crate) chat: &'a str, // the session's peer or the group's name, as commands take it
+    pub(crate) group_id: &'a str,
+    pub(crate) sender: &'a str,
+    pub(crate) text: &'a str,
+}
+
+/// The reply of a Responder to a message, worked out off the message handler
+pub(crate) type Reply = BoxFuture<'static, Result<Option<String>>>;
+
+/// Hook for bots and integrations built on the client: called with each
+/// text message from someone else, in order, it may return a reply, which
+/// is sent to the same conversation
+pub(crate) trait Responder: Send {
+    fn respond(&mut self, message: &Incoming) -> Reply;
+}
+
+/// Reply printed on stdout by a shell command, which gets the message on
+/// stdin and its sender and conversation in RELAY_SENDER, RELAY_CHAT and
+/// RELAY_GROUP_ID. Printing nothing sends no reply.
+pub(crate) struct CommandResponder(pub(crate) String);
+
+impl Responder for CommandResponder {
+    fn respond(&mut self, message: &Incoming) -> Reply {
+        let mut command = tokio::process::Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.0)
+            .env("RELAY_SENDER", message.sender)
+            .env("RELAY_CHAT", message.chat)
+            .env("RELAY_GROUP_ID", message.group_id)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // A stuck command is killed when the wait for it is given up
+            .kill_on_drop(true);
+        let text = message.text.to_string();
+        async move {
+            let mut child = command
+                .spawn()
+                .map_err(|e| anyhow!("Cannot run bot command: {}", e))?;
+            // Written while the output is read, so a command that answers
+            // before reading all of its input doesn't fill the pipe and wait
+            if let Some(mut stdin) = child.stdin.take() {
+                tokio::spawn(async move {
+                    // A command that doesn't read its input closes the pipe early
+                    let _ = stdin.write_all(text.as_bytes()).await;
+                });
+            }
+            let output = tokio::time::timeout(BOT_TIMEOUT, child.wait_with_output())
+                .await
+                .map_err(|_| anyhow!("Bot command took longer than {:?}", BOT_TIMEOUT))??;
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "Bot command failed ({}): {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            let reply = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok((!reply.is_empty()).then_some(reply))
+        }
+        .boxed()
+    }
+}
+
+/// A Responder and the task awaiting its replies one at a time, in the
+/// order of the messages, so the message handler doesn't wait for them
+pub(crate) struct Bot {
+    responder: Box<dyn Responder>,
+    jobs: UnboundedSender<(String, Reply)>, // group_id to answer in, and the reply
+    replies: UnboundedReceiver<(String, Result<Option<String>>)>,
+}
+
+impl Bot {
+    pub(crate) fn spawn(responder: Box<dyn Responder>) -> Self {
+        let (jobs, mut pending) = unbounded_channel::<(String, Reply)>();
+        let (done, replies) = unbounded_channel();
+        tokio::spawn(async move {
+            while let Some((group_id, reply)) = pending.recv().await {
+                if done.send((group_id, reply.await)).is_err() {
+                    break;
+                }
+            }
+        });
+        Bot {
+            responder,
+            jobs,
+            replies,
+        }
+    }
+
+    /// Hand a message of the group `group_id` to the responder
+    pub(crate) fn answer(&mut self, group_id: &str, message: &Incoming) {
+        let reply = self.responder.respond(message);
+        let _ = self.jobs.send((group_id.to_string(), reply));
+    }
+
+    /// The next reply that is ready, with the group_id to send it to
+    pub(crate) fn reply(&mut self) -> Option<(String, Result<Option<String>>)> {
+        self.replies.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incoming(text: &str) -> Incoming<'_> {
+        Incoming {
+            chat: "bob",
+            group_id: "g",
+            sender: "bob-id",
+            text,
+        }
+    }
+
+    #[tokio::test]
+    async fn command_reply_is_its_trimmed_output() {
+        let mut responder = CommandResponder("echo \"$RELAY_SENDER: $(cat)\"".into());
+        let reply = responder.respond(&incoming("hi\n")).await.unwrap();
+        assert_eq!(reply.as_deref(), Some("bob-id: hi"));
+        let mut silent = CommandResponder("cat >/dev/null".into());
+        assert_eq!(silent.respond(&incoming("hi")).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn command_may_answer_before_reading_a_long_message() {
+        // Both far beyond what a pipe holds
+        let mut responder = CommandResponder("head -c 300000 /dev/zero; wc -c".into());
+        let text = "x".repeat(300_000);
+        let reply = responder.respond(&incoming(&text)).await.unwrap().unwrap();
+        assert!(reply.ends_with("300000"));
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_an_error() {
+        let mut responder = CommandResponder("echo oops >&2; exit 3".into());
+        let error = responder.respond(&incoming("hi")).await.unwrap_err();
+        assert!(error.to_string().contains("oops"));
+    }
+}
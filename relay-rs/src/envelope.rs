@@ -0,0 +1,582 @@
+//! Application messages
+//!
+//! The plaintext of every application message is a CBOR envelope, so new
+//! kinds of messages can be added without breaking older clients:
+//!   AppMessage = {
+//!     "v": uint,            ; envelope version, currently 1
+//!     "type": tstr,         ; "text", ...
+//!     "id": bstr,           ; 8 random bytes naming the message
+//!     "ts": int,            ; sender's clock, Unix seconds
+//!     "body": any,          ; per type, see below
+//!     ? "zbody": bstr,      ; instead of "body": its CBOR, compressed with zstd
+//!     ? "reply_to": bstr,   ; id of the message this one answers
+//!     ? "caps": [* tstr],   ; features the sender reads: "zstd", "cbor", "protobuf"
+//!   }
+//! Bodies of the types this client sends:
+//!   "text":   tstr
+//!   "edit":   { "target": bstr, "text": tstr }    ; new text of the sender's message
+//!   "delete": { "target": bstr }                  ; the sender's message
+//!   "react":  { "target": bstr, "emoji": tstr }   ; replaces the sender's reaction
+//!   "typing": null                                ; the sender is composing a message
+//!   "delivered": { "targets": [* bstr] }          ; the sender received these messages
+//!   "read":   { "targets": [* bstr] }             ; the sender's user has seen them
+//!   "broadcast": null                             ; only the group's creator writes
+//!   "heartbeat": null                             ; the sender is still around
+//!   "attachment": { "url": tstr, "key": bstr, "name": tstr, "size": uint }
+//! "target" is the id of the message the edit, delete or reaction is for;
+//! "targets" are the ids of the text messages a receipt is for.
+//! An attachment is a file stored elsewhere, such as in an HTTP object
+//! store, encrypted with AES-256-GCM under "key" with a zero nonce; "size"
+//! is that of the file. The key is exported from the group's MLS key
+//! schedule (ATTACHMENT_EXPORTER_LABEL, with the message's id as context),
+//! so it is fresh for each attachment and conversation and only the
+//! members of the epoch could derive it.
+//! Later versions only add types and keys, so receivers read any version,
+//! ignore unknown keys and skip types they don't know. The envelope may be
+//! followed by zero bytes of padding, which receivers ignore. A plaintext
+//! that isn't a CBOR map is the UTF-8 text of a client that predates the
+//! envelope; valid UTF-8 never starts with a CBOR map's initial byte.
+//!
+//! A body whose CBOR is longer than COMPRESS_THRESHOLD is sent as "zbody"
+//! when every other member of the group announced "zstd" in the "caps" of
+//! its last message; older clients would take the envelope for text.
+//!
+//! The envelope may instead be encoded with protobuf, for clients whose
+//! ecosystem has protobuf tooling (see envelope.proto): the byte
+//! PROTOBUF_MARKER, which starts neither a CBOR item nor UTF-8 text, then a
+//! length-delimited Envelope. A member reads protobuf if it announced
+//! "protobuf", and CBOR if its last envelope was CBOR, it announced "cbor"
+//! or it sent nothing yet. Protobuf is sent when every other member reads
+//! it and either `envelope = "protobuf"` is set or a member doesn't read
+//! CBOR.
+
+use super::*;
+
+pub(crate) const APP_VERSION: u64 = 1;
+pub(crate) const MESSAGE_ID_LEN: usize = 8;
+/// Optional envelope features this client reads, announced in "caps"
+pub(crate) const CAPABILITIES: [&str; 3] = ["zstd", "cbor", "protobuf"];
+/// Bodies up to this size (encoded) are sent uncompressed
+pub(crate) const COMPRESS_THRESHOLD: usize = 512;
+/// Largest body a "zbody" may decompress to
+pub(crate) const MAX_BODY_SIZE: usize = 1 << 20;
+/// First byte of a protobuf envelope
+pub(crate) const PROTOBUF_MARKER: u8 = 0xff;
+/// MLS exporter label of attachment keys
+pub(crate) const ATTACHMENT_EXPORTER_LABEL: &str = "relay attachment";
+/// Largest file `attach` sends
+pub(crate) const MAX_ATTACHMENT_SIZE: u64 = 64 << 20;
+
+pub(crate) struct AppMessage {
+    pub(crate) kind: String,
+    pub(crate) id: Vec<u8>, // empty for messages without an envelope
+    pub(crate) ts: i64,
+    pub(crate) body: Value,
+    pub(crate) reply_to: Option<Vec<u8>>,
+    pub(crate) caps: Vec<String>, // envelope features the sender reads
+}
+
+impl AppMessage {
+    /// A new message with a random ID
+    pub(crate) fn new(kind: &str, body: Value) -> Self {
+        AppMessage {
+            kind: kind.to_string(),
+            id: rand::thread_rng().gen::<[u8; MESSAGE_ID_LEN]>().to_vec(),
+            ts: Local::now().timestamp(),
+            body,
+            reply_to: None,
+            caps: Vec::new(),
+        }
+    }
+
+    pub(crate) fn text(text: &str) -> Self {
+        Self::new("text", Value::Text(text.into()))
+    }
+
+    /// A "delivered" or "read" receipt for the messages `targets`
+    pub(crate) fn receipt(kind: &str, targets: &[Vec<u8>]) -> Self {
+        let targets = targets.iter().cloned().map(Value::Bytes).collect();
+        let body = vec![(Value::Text("targets".into()), Value::Array(targets))];
+        Self::new(kind, Value::Map(body))
+    }
+
+    /// An "edit", "delete" or "react" message for the message `target`;
+    /// `text` is the new text or the emoji
+    pub(crate) fn update(kind: &str, target: &[u8], text: &str) -> Self {
+        let mut body = vec![(Value::Text("target".into()), Value::Bytes(target.to_vec()))];
+        match kind {
+            "edit" => body.push((Value::Text("text".into()), Value::Text(text.into()))),
+            "react" => body.push((Value::Text("emoji".into()), Value::Text(text.into()))),
+            _ => {}
+        }
+        Self::new(kind, Value::Map(body))
+    }
+
+    /// The envelope, with a long body compressed if `compress`
+    pub(crate) fn encode(&self, compress: bool) -> Vec<u8> {
+        let body = match compress.then(|| compress_body(&self.body)).flatten() {
+            Some(compressed) => ("zbody", Value::Bytes(compressed)),
+            None => ("body", self.body.clone()),
+        };
+        let caps = CAPABILITIES.iter().map(|c| Value::Text(c.to_string()));
+        let mut map = vec![
+            (Value::Text("v".into()), Value::Integer(APP_VERSION.into())),
+            (Value::Text("type".into()), Value::Text(self.kind.clone())),
+            (Value::Text("id".into()), Value::Bytes(self.id.clone())),
+            (Value::Text("ts".into()), Value::Integer(self.ts.into())),
+            (Value::Text(body.0.into()), body.1),
+            (Value::Text("caps".into()), Value::Array(caps.collect())),
+        ];
+        if let Some(reply_to) = &self.reply_to {
+            map.push((
+                Value::Text("reply_to".into()),
+                Value::Bytes(reply_to.clone()),
+            ));
+        }
+        let mut bytes = Vec::new();
+        // Writing to a Vec cannot fail
+        ciborium::into_writer(&Value::Map(map), &mut bytes).expect("CBOR encoding into Vec");
+        bytes
+    }
+
+    /// The envelope in protobuf, with a long body compressed if `compress`
+    pub(crate) fn encode_protobuf(&self, compress: bool) -> Vec<u8> {
+        let body = ProtoBody::from_value(&self.body);
+        let zbody = compress
+            .then(|| zstd_compress(&body.encode_to_vec()))
+            .flatten();
+        let envelope = ProtoEnvelope {
+            v: APP_VERSION,
+            kind: self.kind.clone(),
+            id: self.id.clone(),
+            ts: self.ts,
+            body: zbody.is_none().then_some(body),
+            zbody,
+            reply_to: self.reply_to.clone(),
+            caps: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        };
+        let mut bytes = vec![PROTOBUF_MARKER];
+        // Writing to a Vec cannot fail
+        envelope
+            .encode_length_delimited(&mut bytes)
+            .expect("protobuf encoding into Vec");
+        bytes
+    }
+
+    /// Decode a plaintext, taking one without an envelope as text
+    pub(crate) fn decode(plaintext: &[u8]) -> Self {
+        if let Some(envelope) = plaintext.strip_prefix(&[PROTOBUF_MARKER]) {
+            // Not text either; a message of no type is skipped
+            return Self::from_protobuf(envelope).unwrap_or_else(|| {
+                warn!("Dropped a protobuf envelope that didn't decode");
+                AppMessage {
+                    kind: String::new(),
+                    id: Vec::new(),
+                    ts: Local::now().timestamp(),
+                    body: Value::Null,
+                    reply_to: None,
+                    caps: Vec::new(),
+                }
+            });
+        }
+        Self::from_envelope(plaintext).unwrap_or_else(|| AppMessage {
+            kind: "text".to_string(),
+            id: Vec::new(),
+            ts: Local::now().timestamp(),
+            body: Value::Text(String::from_utf8_lossy(plaintext).to_string()),
+            reply_to: None,
+            caps: Vec::new(),
+        })
+    }
+
+    pub(crate) fn from_envelope(plaintext: &[u8]) -> Option<Self> {
+        let envelope: Value = ciborium::from_reader(plaintext).ok()?;
+        envelope.as_map()?;
+        let body = match map_entry(&envelope, "zbody") {
+            // A body that doesn't decompress leaves the message without one,
+            // which no type takes
+            Some(compressed) => decompress_body(compressed.as_bytes()?).unwrap_or_else(|| {
+                warn!("Dropped the body of a message that didn't decompress");
+                Value::Null
+            }),
+            None => map_entry(&envelope, "body")?.clone(),
+        };
+        let caps = map_entry(&envelope, "caps").and_then(Value::as_array);
+        Some(AppMessage {
+            kind: map_entry(&envelope, "type")?.as_text()?.to_string(),
+            id: map_entry(&envelope, "id")?.as_bytes()?.clone(),
+            ts: i64::try_from(map_entry(&envelope, "ts")?.as_integer()?).ok()?,
+            body,
+            reply_to: map_entry(&envelope, "reply_to")
+                .and_then(Value::as_bytes)
+                .cloned(),
+            // Its sender evidently reads CBOR
+            caps: caps
+                .into_iter()
+                .flatten()
+                .filter_map(|c| c.as_text().map(str::to_string))
+                .chain(std::iter::once("cbor".to_string()))
+                .collect(),
+        })
+    }
+
+    pub(crate) fn from_protobuf(bytes: &[u8]) -> Option<Self> {
+        let envelope = ProtoEnvelope::decode_length_delimited(bytes).ok()?;
+        let body = match envelope.zbody {
+            Some(compressed) => zstd_decompress(&compressed)
+                .and_then(|body| ProtoBody::decode(body.as_slice()).ok())
+                .and_then(|body| body.to_value())
+                .unwrap_or_else(|| {
+                    warn!("Dropped the body of a message that didn't decompress");
+                    Value::Null
+                }),
+            None => envelope.body.unwrap_or_default().to_value()?,
+        };
+        Some(AppMessage {
+            kind: envelope.kind,
+            id: envelope.id,
+            ts: envelope.ts,
+            body,
+            reply_to: envelope.reply_to,
+            caps: envelope.caps,
+        })
+    }
+
+    /// The text of a "text" message
+    pub(crate) fn as_text(&self) -> Option<&str> {
+        match self.kind.as_str() {
+            "text" => self.body.as_text(),
+            _ => None,
+        }
+    }
+
+    /// The messages a receipt is for, and whether they were read rather
+    /// than only delivered
+    pub(crate) fn as_receipt(&self) -> Option<(Vec<&[u8]>, bool)> {
+        let read = match self.kind.as_str() {
+            "delivered" => false,
+            "read" => true,
+            _ => return None,
+        };
+        let targets = map_entry(&self.body, "targets")?.as_array()?;
+        let targets = targets
+            .iter()
+            .filter_map(|t| t.as_bytes().map(Vec::as_slice))
+            .collect();
+        Some((targets, read))
+    }
+
+    pub(crate) fn as_attachment(&self) -> Option<Attachment> {
+        match self.kind.as_str() {
+            "attachment" => Attachment::from_value(&self.body),
+            _ => None,
+        }
+    }
+
+    /// The target of an edit, delete or reaction, and its new text or emoji
+    /// ("" for a delete)
+    pub(crate) fn as_update(&self) -> Option<(&[u8], &str)> {
+        let target = map_entry(&self.body, "target")?.as_bytes()?;
+        let text = match self.kind.as_str() {
+            "edit" => map_entry(&self.body, "text")?.as_text()?,
+            "react" => map_entry(&self.body, "emoji")?.as_text()?,
+            "delete" => "",
+            _ => return None,
+        };
+        Some((target, text))
+    }
+}
+
+/// A file stored out of band, as an "attachment" message refers to it
+pub(crate) struct Attachment {
+    pub(crate) url: String,
+    pub(crate) key: Vec<u8>, // AES-256-GCM
+    pub(crate) name: String, // file name, without a directory
+    pub(crate) size: u64,
+}
+
+impl Attachment {
+    pub(crate) fn to_value(&self) -> Value {
+        Value::Map(vec![
+            (Value::Text("url".into()), Value::Text(self.url.clone())),
+            (Value::Text("key".into()), Value::Bytes(self.key.clone())),
+            (Value::Text("name".into()), Value::Text(self.name.clone())),
+            (Value::Text("size".into()), Value::Integer(self.size.into())),
+        ])
+    }
+
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        Some(Attachment {
+            url: map_entry(value, "url")?.as_text()?.to_string(),
+            key: map_entry(value, "key")?.as_bytes()?.clone(),
+            name: map_entry(value, "name")?.as_text()?.to_string(),
+            size: u64::try_from(map_entry(value, "size")?.as_integer()?).ok()?,
+        })
+    }
+
+    /// How the attachment is shown in place of a message's text
+    pub(crate) fn describe(&self) -> String {
+        format!("[attachment] {} ({})", self.name, format_size(self.size))
+    }
+
+    pub(crate) fn cipher(&self) -> Result<Aes256Gcm> {
+        Aes256Gcm::new_from_slice(&self.key).map_err(|_| anyhow!("Invalid attachment key"))
+    }
+}
+
+/// A size in bytes, as "512 B", "3.2 KiB" or "1.5 MiB"
+pub(crate) fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / (1 << 10) as f64),
+        b => format!("{} B", b),
+    }
+}
+
+/// Run the upload or download command of attachments, with the encrypted
+/// file at $RELAY_FILE and, for downloads, its URL in $RELAY_URL; its
+/// output, trimmed
+pub(crate) fn run_transfer_command(
+    command: &str,
+    file: &Path,
+    url: Option<&str>,
+) -> Result<String> {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command).env("RELAY_FILE", file);
+    if let Some(url) = url {
+        shell.env("RELAY_URL", url);
+    }
+    let output = shell
+        .output()
+        .map_err(|e| anyhow!("Cannot run '{}': {}", command, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "'{}' failed ({}): {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Pad an encoded message with zero bytes to the smallest of the sizes it
+/// fits in, or to a multiple of the largest, so the broker can't tell short
+/// messages from long ones by their size
+pub(crate) fn pad(mut plaintext: Vec<u8>, sizes: &[usize]) -> Vec<u8> {
+    let Some(&largest) = sizes.last() else {
+        return plaintext;
+    };
+    let len = plaintext.len();
+    let size = sizes
+        .iter()
+        .copied()
+        .find(|&size| size >= len)
+        .unwrap_or_else(|| len.div_ceil(largest) * largest);
+    plaintext.resize(size, 0);
+    plaintext
+}
+
+/// The CBOR of a body compressed with zstd, if it is long enough for that
+/// to pay off
+pub(crate) fn compress_body(body: &Value) -> Option<Vec<u8>> {
+    let mut cbor = Vec::new();
+    ciborium::into_writer(body, &mut cbor).ok()?;
+    zstd_compress(&cbor)
+}
+
+/// The body of a "zbody"; None if it isn't zstd of CBOR or would grow
+/// beyond MAX_BODY_SIZE
+pub(crate) fn decompress_body(compressed: &[u8]) -> Option<Value> {
+    let cbor = zstd_decompress(compressed)?;
+    ciborium::from_reader(cbor.as_slice()).ok()
+}
+
+/// An encoded body compressed with zstd, if it is longer than
+/// COMPRESS_THRESHOLD and compressing makes it shorter
+pub(crate) fn zstd_compress(body: &[u8]) -> Option<Vec<u8>> {
+    if body.len() <= COMPRESS_THRESHOLD {
+        return None;
+    }
+    let compressed = zstd::bulk::compress(body, zstd::DEFAULT_COMPRESSION_LEVEL).ok()?;
+    (compressed.len() < body.len()).then_some(compressed)
+}
+
+pub(crate) fn zstd_decompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    zstd::bulk::decompress(compressed, MAX_BODY_SIZE).ok()
+}
+
+/// The protobuf envelope: AppMessage's entries, with the body in a
+/// ProtoBody (envelope.proto)
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ProtoEnvelope {
+    #[prost(uint64, tag = "1")]
+    pub(crate) v: u64,
+    #[prost(string, tag = "2")]
+    pub(crate) kind: String,
+    #[prost(bytes = "vec", tag = "3")]
+    pub(crate) id: Vec<u8>,
+    #[prost(int64, tag = "4")]
+    pub(crate) ts: i64,
+    #[prost(message, optional, tag = "5")]
+    pub(crate) body: Option<ProtoBody>,
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub(crate) zbody: Option<Vec<u8>>, // the ProtoBody, compressed with zstd
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub(crate) reply_to: Option<Vec<u8>>,
+    #[prost(string, repeated, tag = "8")]
+    pub(crate) caps: Vec<String>,
+}
+
+/// A body: text, or the keys of the types this client sends, each in its
+/// own field. Other bodies go as CBOR.
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct ProtoBody {
+    #[prost(string, optional, tag = "1")]
+    pub(crate) text: Option<String>,
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub(crate) target: Option<Vec<u8>>,
+    #[prost(string, optional, tag = "3")]
+    pub(crate) emoji: Option<String>,
+    #[prost(bytes = "vec", repeated, tag = "4")]
+    pub(crate) targets: Vec<Vec<u8>>,
+    #[prost(bytes = "vec", optional, tag = "5")]
+    pub(crate) cbor: Option<Vec<u8>>,
+}
+
+impl ProtoBody {
+    pub(crate) fn from_value(body: &Value) -> Self {
+        let mut proto = ProtoBody::default();
+        let fits = match body {
+            Value::Null => true,
+            Value::Text(text) => {
+                proto.text = Some(text.clone());
+                true
+            }
+            Value::Map(entries) => entries.iter().all(|(key, value)| proto.set(key, value)),
+            _ => false,
+        };
+        // Fields can't tell the order of keys, or a map of only "text" from
+        // text
+        if fits && proto.to_value().as_ref() == Some(body) {
+            return proto;
+        }
+        let mut cbor = Vec::new();
+        // Writing to a Vec cannot fail
+        ciborium::into_writer(body, &mut cbor).expect("CBOR encoding into Vec");
+        ProtoBody {
+            cbor: Some(cbor),
+            ..ProtoBody::default()
+        }
+    }
+
+    /// Take a key of a body map into its field; false for other keys
+    pub(crate) fn set(&mut self, key: &Value, value: &Value) -> bool {
+        match (key.as_text(), value) {
+            (Some("text"), Value::Text(text)) => self.text = Some(text.clone()),
+            (Some("target"), Value::Bytes(target)) => self.target = Some(target.clone()),
+            (Some("emoji"), Value::Text(emoji)) => self.emoji = Some(emoji.clone()),
+            (Some("targets"), Value::Array(targets)) => {
+                let Some(targets) = targets
+                    .iter()
+                    .map(|t| t.as_bytes().cloned())
+                    .collect::<Option<_>>()
+                else {
+                    return false;
+                };
+                self.targets = targets;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The body as AppMessage holds it; None if its CBOR is invalid
+    pub(crate) fn to_value(&self) -> Option<Value> {
+        if let Some(cbor) = &self.cbor {
+            return ciborium::from_reader(cbor.as_slice()).ok();
+        }
+        let text = self.text.clone().map(Value::Text);
+        if self.target.is_none() && self.emoji.is_none() && self.targets.is_empty() {
+            return Some(text.unwrap_or(Value::Null));
+        }
+        let key = |k: &str| Value::Text(k.into());
+        let mut entries = Vec::new();
+        if let Some(target) = &self.target {
+            entries.push((key("target"), Value::Bytes(target.clone())));
+        }
+        if let Some(text) = text {
+            entries.push((key("text"), text));
+        }
+        if let Some(emoji) = &self.emoji {
+            entries.push((key("emoji"), Value::Text(emoji.clone())));
+        }
+        if !self.targets.is_empty() {
+            let targets = self.targets.iter().cloned().map(Value::Bytes).collect();
+            entries.push((key("targets"), Value::Array(targets)));
+        }
+        Some(Value::Map(entries))
+    }
+}
+
+/// A message ID for JSON output, null if the message had none
+pub(crate) fn message_id(id: &[u8]) -> Option<String> {
+    (!id.is_empty()).then(|| hex::encode(id))
+}
+
+/// The start of a message ID, as shown by `history` and taken by commands
+pub(crate) fn short_id(id: &[u8]) -> String {
+    hex::encode(&id[..id.len().min(4)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_rounds_up_to_a_size() {
+        let sizes = [64, 256, 1024];
+        assert_eq!(pad(vec![1; 10], &sizes).len(), 64);
+        assert_eq!(pad(vec![1; 64], &sizes).len(), 64);
+        assert_eq!(pad(vec![1; 65], &sizes).len(), 256);
+        assert_eq!(pad(vec![1; 0], &sizes).len(), 64);
+    }
+
+    #[test]
+    fn pad_beyond_the_largest_size_rounds_to_its_multiple() {
+        let sizes = [64, 256, 1024];
+        assert_eq!(pad(vec![1; 1025], &sizes).len(), 2048);
+        assert_eq!(pad(vec![1; 3072], &sizes).len(), 3072);
+    }
+
+    #[test]
+    fn pad_keeps_the_message_and_appends_zeros() {
+        let padded = pad(vec![1, 2, 3], &[8]);
+        assert_eq!(padded, vec![1, 2, 3, 0, 0, 0, 0, 0]);
+        assert_eq!(pad(vec![1, 2, 3], &[]), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn padded_cbor_envelope_decodes() {
+        let mut message = AppMessage::text("hello");
+        message.reply_to = Some(vec![7; MESSAGE_ID_LEN]);
+        let padded = pad(message.encode(false), &[256]);
+        assert_eq!(padded.len(), 256);
+        let decoded = AppMessage::decode(&padded);
+        assert_eq!(decoded.as_text(), Some("hello"));
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.reply_to, message.reply_to);
+    }
+
+    #[test]
+    fn padded_protobuf_envelope_decodes() {
+        let mut message = AppMessage::text("hello");
+        message.reply_to = Some(vec![7; MESSAGE_ID_LEN]);
+        let padded = pad(message.encode_protobuf(false), &[256]);
+        assert_eq!(padded.len(), 256);
+        let decoded = AppMessage::decode(&padded);
+        assert_eq!(decoded.as_text(), Some("hello"));
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.reply_to, message.reply_to);
+    }
+}
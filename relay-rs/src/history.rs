@@ -0,0 +1,339 @@
+//! Message history
+//!
+//! Each group's messages are appended to history/{group_id}.cbor as a CBOR
+//! sequence of entries:
+//!   Entry = {
+//!     "ts": int,              ; Unix seconds
+//!     "sender": tstr,
+//!     "text": tstr,           ; the message, new text of an edit, or emoji
+//!     ? "id": bstr,           ; the AppMessage's ID
+//!     ? "type": tstr,         ; "edit", "delete" or "react"; "joined", "left"
+//!                             ; or "member_removed" for a membership change,
+//!                             ; "key_changed" for a member's new key;
+//!                             ; a message if absent
+//!     ? "target": bstr,       ; ID of the message edited, deleted or reacted to
+//!     ? "attachment": any,    ; body of an "attachment" message, for `fetch`
+//!   }
+//! Edits, deletes and reactions are applied to their message when reading;
+//! edits and deletes only by the message's sender. For a membership change,
+//! "sender" is the member who committed it and "text" the member who joined,
+//! left, was removed or changed their key.
+//!
+//! With a history passphrase, entries are written encrypted instead:
+//!   bstr = nonce (12 bytes) || AES-256-GCM(Entry), AAD = group_id (hex)
+//! The key is derived with Argon2id from the passphrase and history/salt;
+//! history/check holds an encrypted CBOR null to detect a wrong
+//! passphrase on start. Plain and encrypted entries may be mixed in a file.
+
+use super::*;
+
+pub(crate) const HISTORY_NONCE_LEN: usize = 12;
+pub(crate) const DEFAULT_HISTORY_COUNT: usize = 20;
+/// History entries written by `debug dump`
+pub(crate) const DEBUG_DUMP_ENTRIES: usize = 50;
+pub(crate) const MEMBERSHIP_EVENTS: [&str; 4] = ["joined", "left", "member_removed", "key_changed"];
+/// Messages shown before and after each match of `search`
+pub(crate) const SEARCH_CONTEXT: usize = 2;
+
+pub(crate) struct HistoryEntry {
+    pub(crate) ts: i64,
+    pub(crate) sender: String,
+    pub(crate) text: String,
+    pub(crate) id: Vec<u8>, // empty for messages without an ID
+    pub(crate) edited: bool,
+    pub(crate) reactions: Vec<(String, String)>, // (sender, emoji), one per sender
+    pub(crate) event: Option<String>,            // a membership change instead of a message
+    pub(crate) attachment: Option<Attachment>,   // what `fetch` downloads
+}
+
+pub(crate) struct History {
+    pub(crate) dir: PathBuf,
+    pub(crate) cipher: Option<Aes256Gcm>,
+}
+
+impl History {
+    pub(crate) fn open(dir: PathBuf, passphrase: Option<&str>) -> Result<Self> {
+        create_private_dir(&dir)?;
+        let cipher = match passphrase {
+            Some(passphrase) => {
+                let salt_path = dir.join("salt");
+                let salt = match fs::read(&salt_path) {
+                    Ok(salt) => salt,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        let salt = rand::thread_rng().gen::<[u8; 16]>().to_vec();
+                        write_private(&salt_path, &salt)?;
+                        salt
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                Some(passphrase_cipher(passphrase, &salt)?)
+            }
+            None => None,
+        };
+        let history = Self { dir, cipher };
+
+        // Refuse a wrong passphrase before anything is written with it
+        if history.cipher.is_some() {
+            let check_path = history.dir.join("check");
+            match fs::read(&check_path) {
+                Ok(record) => {
+                    history
+                        .decrypt("check", &record)
+                        .map_err(|_| anyhow!("Wrong history passphrase"))?;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let mut null = Vec::new();
+                    ciborium::into_writer(&Value::Null, &mut null)?;
+                    write_private(&check_path, &history.encrypt("check", &null)?)?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(history)
+    }
+
+    pub(crate) fn path(&self, group_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.cbor", group_id))
+    }
+
+    /// The groups with a history, including those we left
+    pub(crate) fn group_ids(&self) -> Result<Vec<String>> {
+        let mut group_ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "cbor") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    group_ids.push(stem.to_string());
+                }
+            }
+        }
+        group_ids.sort_unstable();
+        Ok(group_ids)
+    }
+
+    /// Append a text message, edit, delete or reaction; other kinds of
+    /// messages are not kept
+    pub(crate) fn append(&self, group_id: &str, sender: &str, message: &AppMessage) -> Result<()> {
+        let mut entry = vec![
+            (
+                Value::Text("ts".into()),
+                Value::Integer(Local::now().timestamp().into()),
+            ),
+            (Value::Text("sender".into()), Value::Text(sender.into())),
+        ];
+        if !message.id.is_empty() {
+            entry.push((Value::Text("id".into()), Value::Bytes(message.id.clone())));
+        }
+        if let Some(text) = message.as_text() {
+            entry.push((Value::Text("text".into()), Value::Text(text.into())));
+        } else if let Some(attachment) = message.as_attachment() {
+            entry.extend([
+                (
+                    Value::Text("text".into()),
+                    Value::Text(attachment.describe()),
+                ),
+                (Value::Text("attachment".into()), message.body.clone()),
+            ]);
+        } else if let Some((target, text)) = message.as_update() {
+            entry.extend([
+                (Value::Text("text".into()), Value::Text(text.into())),
+                (
+                    Value::Text("type".into()),
+                    Value::Text(message.kind.clone()),
+                ),
+                (Value::Text("target".into()), Value::Bytes(target.to_vec())),
+            ]);
+        } else {
+            return Ok(());
+        }
+        self.write_entry(group_id, Value::Map(entry))
+    }
+
+    /// Append an entry to a group's file, encrypted if the history is
+    pub(crate) fn write_entry(&self, group_id: &str, entry: Value) -> Result<()> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&entry, &mut bytes)?;
+
+        if self.cipher.is_some() {
+            let record = self.encrypt(group_id, &bytes)?;
+            bytes.clear();
+            ciborium::into_writer(&Value::Bytes(record), &mut bytes)?;
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(group_id))?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    /// Note that `member` joined, left or was removed, in a Commit by
+    /// `sender`
+    pub(crate) fn append_event(
+        &self,
+        group_id: &str,
+        sender: &str,
+        kind: &str,
+        member: &str,
+    ) -> Result<()> {
+        let entry = vec![
+            (
+                Value::Text("ts".into()),
+                Value::Integer(Local::now().timestamp().into()),
+            ),
+            (Value::Text("sender".into()), Value::Text(sender.into())),
+            (Value::Text("text".into()), Value::Text(member.into())),
+            (Value::Text("type".into()), Value::Text(kind.into())),
+        ];
+        self.write_entry(group_id, Value::Map(entry))
+    }
+
+    /// The last `count` messages of a group, oldest first, with their edits,
+    /// deletes and reactions applied
+    pub(crate) fn recent(&self, group_id: &str, count: usize) -> Result<Vec<HistoryEntry>> {
+        let mut entries = self.transcript(group_id)?;
+        entries.retain(|e| e.event.is_none());
+        Ok(entries.split_off(entries.len().saturating_sub(count)))
+    }
+
+    /// All messages and membership changes of a group, oldest first, with
+    /// edits, deletes and reactions applied
+    pub(crate) fn transcript(&self, group_id: &str) -> Result<Vec<HistoryEntry>> {
+        let bytes = match fs::read(self.path(group_id)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            let value = match ciborium::from_reader(&mut rest)? {
+                Value::Bytes(record) => self.decrypt(group_id, &record)?,
+                value => value,
+            };
+            let corrupt = || anyhow!("Corrupt history entry");
+            let entry = history_entry(&value).ok_or_else(corrupt)?;
+            let target = map_entry(&value, "target").and_then(Value::as_bytes);
+            match (map_entry(&value, "type").and_then(Value::as_text), target) {
+                (None, _) => entries.push(entry),
+                (Some(kind), _) if MEMBERSHIP_EVENTS.contains(&kind) => {
+                    entries.push(HistoryEntry {
+                        event: Some(kind.to_string()),
+                        ..entry
+                    });
+                }
+                (Some(kind), Some(target)) => apply_update(&mut entries, kind, target, entry),
+                (Some(_), None) => return Err(corrupt()),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// nonce || AES-256-GCM(entry), for an encrypted history
+    pub(crate) fn encrypt(&self, group_id: &str, entry: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is not encrypted"))?;
+        let nonce: [u8; HISTORY_NONCE_LEN] = rand::thread_rng().gen();
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: entry,
+                    aad: group_id.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Cannot encrypt history entry"))?;
+        let mut record = nonce.to_vec();
+        record.extend(ciphertext);
+        Ok(record)
+    }
+
+    pub(crate) fn decrypt(&self, group_id: &str, record: &[u8]) -> Result<Value> {
+        let cipher = self
+            .cipher
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is encrypted; set the history passphrase"))?;
+        if record.len() < HISTORY_NONCE_LEN {
+            return Err(anyhow!("Corrupt history entry"));
+        }
+        let (nonce, ciphertext) = record.split_at(HISTORY_NONCE_LEN);
+        let nonce: [u8; HISTORY_NONCE_LEN] = nonce.try_into()?;
+        let plaintext = cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: group_id.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Cannot decrypt history (wrong passphrase?)"))?;
+        Ok(ciborium::from_reader(plaintext.as_slice())?)
+    }
+}
+
+pub(crate) fn history_entry(value: &Value) -> Option<HistoryEntry> {
+    Some(HistoryEntry {
+        ts: i64::try_from(map_entry(value, "ts")?.as_integer()?).ok()?,
+        sender: map_entry(value, "sender")?.as_text()?.to_string(),
+        text: map_entry(value, "text")?.as_text()?.to_string(),
+        id: map_entry(value, "id")
+            .and_then(Value::as_bytes)
+            .cloned()
+            .unwrap_or_default(),
+        edited: false,
+        reactions: Vec::new(),
+        event: None,
+        attachment: map_entry(value, "attachment").and_then(Attachment::from_value),
+    })
+}
+
+/// Apply an edit, delete or reaction entry to the message it targets; those
+/// of messages we don't have are dropped
+pub(crate) fn apply_update(
+    entries: &mut Vec<HistoryEntry>,
+    kind: &str,
+    target: &[u8],
+    update: HistoryEntry,
+) {
+    let Some(pos) = entries
+        .iter()
+        .rposition(|e| !e.id.is_empty() && e.id == target)
+    else {
+        return;
+    };
+    let by_sender = entries[pos].sender == update.sender;
+    match kind {
+        "edit" if by_sender => {
+            entries[pos].text = update.text;
+            entries[pos].edited = true;
+        }
+        "delete" if by_sender => {
+            entries.remove(pos);
+        }
+        "react" => {
+            let reactions = &mut entries[pos].reactions;
+            reactions.retain(|(sender, _)| *sender != update.sender);
+            reactions.push((update.sender, update.text));
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_reopens_with_its_passphrase_only() {
+        let dir = std::env::temp_dir().join(format!("relay-history-{}", std::process::id()));
+        History::open(dir.clone(), Some("passphrase")).unwrap();
+        History::open(dir.clone(), Some("passphrase")).unwrap();
+        assert!(History::open(dir.clone(), Some("other")).is_err());
+        assert!(!dir.join("salt.tmp").exists() && !dir.join("check.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,551 +3,8909 @@
 //! A minimal implementation of the Relay protocol (MLS over MQTT).
 //! Designed for clarity and ease of translation to other languages.
 
-use std::collections::HashMap;
-use std::io::{self, BufRead, Write};
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
 use anyhow::{anyhow, Result};
-use chrono::Local;
+use argon2::Argon2;
+use chrono::{DateTime, Local, TimeZone};
+use ciborium::value::Value;
+use clap::Parser;
+use futures_util::future::{BoxFuture, FutureExt};
+use futures_util::StreamExt;
+use prost::Message as _;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
 use rand::Rng;
-use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use ratatui::crossterm::event::{self as term, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use regex::{Regex, RegexBuilder};
+use rumqttc::v5::mqttbytes::v5::{
+    ConnectProperties, ConnectReturnCode, Filter, LastWill, LastWillProperties, Packet,
+    PubAckReason, Publish, PublishProperties,
+};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{AsyncClient, ClientError, ConnectionError, Event, EventLoop, MqttOptions};
+use rumqttc::{Outgoing, Proxy, ProxyAuth, ProxyType, Transport};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
 use tls_codec::{Deserialize as TlsDeserialize, Serialize as TlsSerialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_socks::tcp::Socks5Stream;
+use tracing::{debug, info, info_span, instrument, warn};
+use tracing_subscriber::EnvFilter;
 
+use openmls::framing::errors::{MessageDecryptionError, SecretTreeError};
 use openmls::prelude::*;
 use openmls_basic_credential::SignatureKeyPair;
 use openmls_rust_crypto::OpenMlsRustCrypto;
+use openmls_traits::signatures::Signer;
+use openmls_traits::storage::StorageProvider;
+
+mod bot;
+mod envelope;
+#[cfg(feature = "fuzz")]
+mod fuzz;
+mod history;
+mod order;
+mod proxy;
+
+use bot::*;
+use envelope::*;
+use history::*;
+use order::*;
+use proxy::*;
 
 // ============================================================================
 // Logging
 // ============================================================================
+//
+// All output goes through these functions. They print text to the terminal,
+// queue it for the TUI while that runs, or in JSON mode print each event as
+// a JSON object on its own line.
 
-fn log(msg: &str) {
-    let ts = Local::now().format("%H:%M:%S");
-    println!("\r[{}] {}", ts, msg);
+enum Sink {
+    Plain,
+    /// Lines waiting for the TUI, with the key of their pane and the ID of
+    /// the message of ours they show (empty for other lines)
+    Tui(Vec<(String, Vec<u8>, Line<'static>)>),
+    Json,
+    /// JSON events waiting to be sent to the daemon's connections
+    Daemon(Vec<JsonValue>),
 }
 
-fn log_msg(sender: &str, text: &str, is_self: bool) {
-    let ts = Local::now().format("%H:%M:%S");
-    let color = if is_self { "34" } else { "32" }; // blue for self, green for peer
-    let name = if is_self { "you" } else { sender };
-    println!("\r[{}] \x1b[{}m<{}>\x1b[0m {}", ts, color, name, text);
-}
+static SINK: Mutex<Sink> = Mutex::new(Sink::Plain);
 
-// ============================================================================
-// Configuration
-// ============================================================================
+/// While rustyline reads the input line, plain output goes through
+/// `printer`, which prints it above the line being edited rather than into
+/// it, and `prompt` is shown for the next line
+struct LineEditor {
+    printer: Box<dyn ExternalPrinter + Send>,
+    prompt: String,
+    done: std::sync::mpsc::Sender<()>, // see line_done
+}
 
-const BROKER_HOST: &str = "broker.emqx.io";
-const BROKER_PORT: u16 = 1883;
-const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+static LINE_EDITOR: Mutex<Option<LineEditor>> = Mutex::new(None);
 
-// ============================================================================
-// Application State
-// ============================================================================
+/// Name of the profile whose events are handled, while it is not the one
+/// commands go to; its plain output is marked with the name
+static OUTPUT_PROFILE: Mutex<Option<String>> = Mutex::new(None);
 
-struct RelayClient {
-    // MLS
-    backend: OpenMlsRustCrypto,
-    client_id: String,
-    signer: SignatureKeyPair,
-    credential: CredentialWithKey,
+fn set_output_profile(profile: Option<String>) {
+    *OUTPUT_PROFILE.lock().unwrap() = profile;
+}
 
-    // MQTT
-    mqtt: Client,
+/// Print a line of plain output, to stderr if `stderr` unless the line
+/// editor prints it
+fn print_plain(line: String, stderr: bool) {
+    let line = match &*OUTPUT_PROFILE.lock().unwrap() {
+        Some(profile) => format!("\r[{}] {}", profile, line.trim_start_matches('\r')),
+        None => line,
+    };
+    if let Some(editor) = &mut *LINE_EDITOR.lock().unwrap() {
+        let _ = editor.printer.print(line);
+    } else if stderr {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
 
-    // State
-    key_packages: HashMap<String, KeyPackage>, // peer_id -> KeyPackage
-    groups: HashMap<String, MlsGroup>,         // peer_id -> MlsGroup
-    group_peers: HashMap<String, String>,      // group_id (hex) -> peer_id
-    pending_connects: Vec<String>,             // peer_ids waiting for KeyPackage
+/// Write diagnostics to `log_file`, or else to stderr if RUST_LOG is set and
+/// `stderr` allows it (the TUI owns the terminal)
+fn init_tracing(log_file: Option<&Path>, stderr: bool) -> Result<()> {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match log_file {
+        Some(path) => {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow!("Cannot open log file {}: {}", path.display(), e))?;
+            subscriber
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .init();
+        }
+        None if stderr && std::env::var_os("RUST_LOG").is_some() => {
+            subscriber.with_writer(io::stderr).init();
+        }
+        None => {}
+    }
+    Ok(())
 }
 
-// ============================================================================
-// Initialization
-// ============================================================================
+fn set_sink(sink: Sink) {
+    *SINK.lock().unwrap() = sink;
+}
 
-impl RelayClient {
-    fn new() -> Result<(Self, rumqttc::Connection)> {
-        let backend = OpenMlsRustCrypto::default();
+fn plain_output() -> bool {
+    matches!(*SINK.lock().unwrap(), Sink::Plain)
+}
 
-        // Generate client identity
-        let client_id = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
-        let credential = BasicCredential::new(client_id.clone().into_bytes());
-        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
-            .map_err(|e| anyhow!("KeyGen error: {:?}", e))?;
-        signer
-            .store(backend.storage())
-            .map_err(|e| anyhow!("Storage error: {:?}", e))?;
+/// The conversation a chat message belongs to
+struct Chat<'a> {
+    group_id: &'a str,
+    name: Option<&'a str>, // group name, None for 1:1 sessions
+}
 
-        let credential = CredentialWithKey {
-            credential: credential.into(),
-            signature_key: signer.public().into(),
-        };
+/// Print an event. In JSON mode `event`, an object naming the kind of event,
+/// is printed instead, with the time and `msg` added.
+fn log_event(event: JsonValue, msg: &str) {
+    info!("{}", msg);
+    let now = Local::now();
+    let ts = now.format("%H:%M:%S");
+    match &mut *SINK.lock().unwrap() {
+        Sink::Plain => print_plain(format!("\r[{}] {}", ts, msg), false),
+        Sink::Tui(queue) => queue.push((
+            STATUS_PANE.to_string(),
+            Vec::new(),
+            Line::raw(format!("[{}] {}", ts, msg)),
+        )),
+        sink => emit_json(sink, event, now, msg),
+    }
+}
 
-        // Connect to MQTT broker
-        let mut options = MqttOptions::new(&client_id, BROKER_HOST, BROKER_PORT);
-        options.set_keep_alive(Duration::from_secs(60));
-        let (mqtt, connection) = Client::new(options, 100);
+fn log(msg: &str) {
+    log_event(json!({ "event": "log" }), msg);
+}
 
-        Ok((
-            Self {
-                backend,
-                client_id,
-                signer,
-                credential,
-                mqtt,
-                key_packages: HashMap::new(),
-                groups: HashMap::new(),
-                group_peers: HashMap::new(),
-                pending_connects: Vec::new(),
-            },
-            connection,
-        ))
+/// Print command output; `event` as for `log_event`
+fn say_event(event: JsonValue, msg: &str) {
+    match &mut *SINK.lock().unwrap() {
+        Sink::Plain => print_plain(msg.to_string(), false),
+        Sink::Tui(queue) => {
+            for line in msg.lines() {
+                queue.push((
+                    STATUS_PANE.to_string(),
+                    Vec::new(),
+                    Line::raw(line.to_string()),
+                ));
+            }
+        }
+        sink => emit_json(sink, event, Local::now(), msg),
     }
+}
 
-    fn publish_key_package(&self) -> Result<()> {
-        let key_package = KeyPackage::builder()
-            .build(
-                CIPHERSUITE,
-                &self.backend,
-                &self.signer,
-                self.credential.clone(),
-            )?
-            .key_package()
-            .clone();
+fn say(msg: &str) {
+    say_event(json!({ "event": "output" }), msg);
+}
 
-        // Serialize as MLSMessage, wrap in CBOR array per protocol spec
-        let kp_bytes = MlsMessageOut::from(key_package).tls_serialize_detached()?;
-        let mut cbor = Vec::new();
-        ciborium::into_writer(&vec![kp_bytes], &mut cbor)?;
+/// A kind of failure the user can do something about. Errors are tagged
+/// with one where it is known, with `failure`, or it is recognised from the
+/// error's causes.
+#[derive(Debug, Clone, Copy)]
+enum Failure {
+    BrokerUnreachable,
+    KeyPackageExpired,
+    EpochDesync,
+    Malformed,
+}
 
-        self.mqtt.publish(
-            format!("relay/k/{}", self.client_id),
-            QoS::AtLeastOnce,
-            true, // retained
-            cbor,
-        )?;
-        Ok(())
+impl Failure {
+    /// Name in the `kind` of JSON error events
+    fn kind(self) -> &'static str {
+        match self {
+            Failure::BrokerUnreachable => "broker_unreachable",
+            Failure::KeyPackageExpired => "key_package_expired",
+            Failure::EpochDesync => "epoch_desync",
+            Failure::Malformed => "malformed",
+        }
     }
 
-    fn subscribe_welcome(&self) -> Result<()> {
-        self.mqtt
-            .subscribe(format!("relay/w/{}", self.client_id), QoS::AtLeastOnce)?;
-        Ok(())
+    fn hint(self) -> &'static str {
+        match self {
+            Failure::BrokerUnreachable => {
+                "Check host, port and tls in the config and your network; \
+                 messages are kept and sent once the broker is back"
+            }
+            Failure::KeyPackageExpired => {
+                "Ask them to start their client, which publishes new ones; \
+                 pending connects and invites go on then"
+            }
+            Failure::EpochDesync => {
+                "A Commit of the group may have been lost; if this keeps happening, \
+                 ask a member to 'resend-welcome' you"
+            }
+            Failure::Malformed => {
+                "Someone published data this client can't read, \
+                 maybe a peer running an incompatible version; it was ignored"
+            }
+        }
+    }
+
+    /// The failure of an error: its tag, or else what its causes show
+    fn of(e: &anyhow::Error) -> Option<Failure> {
+        if let Some(failure) = e.downcast_ref::<Failure>() {
+            return Some(*failure);
+        }
+        e.chain().find_map(|cause| {
+            if cause.is::<ConnectionError>() || cause.is::<ClientError>() {
+                Some(Failure::BrokerUnreachable)
+            } else if cause.is::<tls_codec::Error>() || cause.is::<ciborium::de::Error<io::Error>>()
+            {
+                Some(Failure::Malformed)
+            } else {
+                None
+            }
+        })
     }
 }
 
-// ============================================================================
-// MQTT Message Handlers
-// ============================================================================
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::BrokerUnreachable => "the broker is unreachable",
+            Failure::KeyPackageExpired => "the peer's KeyPackages have expired",
+            Failure::EpochDesync => "out of step with the group's epoch",
+            Failure::Malformed => "malformed payload",
+        })
+    }
+}
 
-impl RelayClient {
-    fn handle_key_package(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
-        // Parse peer_id from topic: relay/k/{peer_id}
-        let peer_id = topic
-            .strip_prefix("relay/k/")
-            .ok_or_else(|| anyhow!("Invalid topic"))?;
+impl std::error::Error for Failure {}
 
-        if peer_id == self.client_id {
-            return Ok(()); // Ignore our own KeyPackage
+/// An error with message `msg`, tagged with `failure`
+fn failure(failure: Failure, msg: String) -> anyhow::Error {
+    anyhow::Error::new(failure).context(msg)
+}
+
+/// Print an error. The user gets its message and, for a known failure, what
+/// to do about it; the diagnostics get the whole chain of causes.
+fn log_error(e: &anyhow::Error) {
+    tracing::error!("{:#}", e);
+    let failure = Failure::of(e);
+    let msg = match failure {
+        Some(_) => e.to_string(),
+        None => format!("{:#}", e),
+    };
+    let shown = match failure {
+        Some(failure) => format!("Error: {}\n  {}", msg, failure.hint()),
+        None => format!("Error: {}", msg),
+    };
+    match &mut *SINK.lock().unwrap() {
+        Sink::Plain => print_plain(format!("\r{}", shown), true),
+        Sink::Tui(queue) => {
+            for line in shown.lines() {
+                queue.push((
+                    STATUS_PANE.to_string(),
+                    Vec::new(),
+                    Line::raw(line.to_string()),
+                ));
+            }
+        }
+        sink => {
+            let event = match failure {
+                Some(failure) => json!({
+                    "event": "error",
+                    "kind": failure.kind(),
+                    "hint": failure.hint(),
+                }),
+                None => json!({ "event": "error" }),
+            };
+            emit_json(sink, event, Local::now(), &msg)
         }
+    }
+}
 
-        // Decode CBOR array of KeyPackages
-        let kp_array: Vec<Vec<u8>> = ciborium::from_reader(payload)?;
-        let kp_bytes = kp_array.first().ok_or_else(|| anyhow!("Empty array"))?;
+/// Print a chat message; `name` is the name to show for `sender`
+fn log_msg(chat: &Chat, id: &[u8], sender: &str, name: &str, text: &str, is_self: bool) {
+    // The text stays out of the diagnostics
+    debug!(group_id = chat.group_id, sender, "Message");
+    let event = json!({ "event": "message", "id": message_id(id) });
+    print_msg(event, chat, Local::now(), sender, name, text, is_self);
+}
 
-        // Deserialize and validate KeyPackage
-        let msg = MlsMessageIn::tls_deserialize(&mut kp_bytes.as_slice())?;
-        let kp = match msg.extract() {
-            MlsMessageBodyIn::KeyPackage(kp) => {
-                kp.validate(self.backend.crypto(), ProtocolVersion::Mls10)?
-            }
-            _ => return Err(anyhow!("Expected KeyPackage")),
-        };
+/// Print a message from the history, with its date; `text` is the entry's
+/// text as shown, with its edits and reactions
+fn log_history(chat: &Chat, entry: &HistoryEntry, name: &str, text: &str, is_self: bool) {
+    let time = Local
+        .timestamp_opt(entry.ts, 0)
+        .single()
+        .unwrap_or_default();
+    let reactions: Vec<JsonValue> = entry
+        .reactions
+        .iter()
+        .map(|(sender, emoji)| json!({ "sender": sender, "emoji": emoji }))
+        .collect();
+    let event = json!({
+        "event": "history",
+        "id": message_id(&entry.id),
+        "edited": entry.edited,
+        "reactions": reactions,
+    });
+    print_msg(event, chat, time, &entry.sender, name, text, is_self);
+}
+
+/// Print a chat message; `event` as for `log_event`, with the chat and
+/// sender added
+fn print_msg(
+    mut event: JsonValue,
+    chat: &Chat,
+    time: DateTime<Local>,
+    sender: &str,
+    name: &str,
+    text: &str,
+    is_self: bool,
+) {
+    let ts = match event["event"].as_str() {
+        Some("history") => time.format("%Y-%m-%d %H:%M:%S"),
+        _ => time.format("%H:%M:%S"),
+    };
+    let name = if is_self { "you" } else { name };
+    match &mut *SINK.lock().unwrap() {
+        Sink::Plain => {
+            let color = if is_self { "34" } else { "32" }; // blue for self, green for peer
+            let group = chat.name.map(|g| format!("#{} ", g)).unwrap_or_default();
+            print_plain(
+                format!(
+                    "\r[{}] {}\x1b[{}m<{}>\x1b[0m {}",
+                    ts, group, color, name, text
+                ),
+                false,
+            );
+        }
+        Sink::Tui(queue) => {
+            let color = if is_self { Color::Blue } else { Color::Green };
+            let line = Line::from(vec![
+                Span::raw(format!("[{}] ", ts)),
+                Span::styled(format!("<{}>", name), Style::default().fg(color)),
+                Span::raw(format!(" {}", text)),
+            ]);
+            // Our own messages get receipt marks
+            let id = if is_self {
+                event["id"].as_str().and_then(|id| hex::decode(id).ok())
+            } else {
+                None
+            };
+            queue.push((chat.group_id.to_string(), id.unwrap_or_default(), line));
+        }
+        sink => {
+            event["group_id"] = json!(chat.group_id);
+            event["group"] = json!(chat.name);
+            event["sender"] = json!(sender);
+            event["self"] = json!(is_self);
+            emit_json(sink, event, time, text);
+        }
+    }
+}
 
-        self.key_packages.insert(peer_id.to_string(), kp);
+/// Report a change in what a peer is doing in a chat, such as typing or
+/// reading. Only JSON output gets `event`, with the chat and sender added:
+/// the TUI shows these from the client's state, and in plain output a line
+/// would be noise.
+fn log_chat_state(mut event: JsonValue, chat: &Chat, sender: &str) {
+    if let sink @ (Sink::Json | Sink::Daemon(_)) = &mut *SINK.lock().unwrap() {
+        event["group_id"] = json!(chat.group_id);
+        event["group"] = json!(chat.name);
+        event["sender"] = json!(sender);
+        emit_json(sink, event, Local::now(), "");
+    }
+}
 
-        // If this peer had a pending connect, establish session now
-        if let Some(pos) = self.pending_connects.iter().position(|p| p == peer_id) {
-            self.pending_connects.remove(pos);
-            self.create_group(peer_id)?;
-            log(&format!("Session established with {}", peer_id));
-        } else {
-            log(&format!("Received KeyPackage for {}", peer_id));
+/// Print a line about a conversation itself, such as a member joining, in
+/// that conversation; `event` as for `log_event`, with the chat added
+fn log_system(mut event: JsonValue, chat: &Chat, msg: &str) {
+    info!(group_id = chat.group_id, "{}", msg);
+    let now = Local::now();
+    let ts = now.format("%H:%M:%S");
+    match &mut *SINK.lock().unwrap() {
+        Sink::Plain => {
+            let group = chat.name.map(|g| format!("#{} ", g)).unwrap_or_default();
+            print_plain(
+                format!("\r[{}] {}\x1b[2m* {}\x1b[0m", ts, group, msg),
+                false,
+            );
+        }
+        Sink::Tui(queue) => {
+            let line = Line::from(vec![
+                Span::raw(format!("[{}] ", ts)),
+                Span::styled(format!("* {}", msg), Style::default().fg(Color::DarkGray)),
+            ]);
+            queue.push((chat.group_id.to_string(), Vec::new(), line));
+        }
+        sink => {
+            event["group_id"] = json!(chat.group_id);
+            event["group"] = json!(chat.name);
+            emit_json(sink, event, now, msg);
         }
-        Ok(())
     }
+}
 
-    fn handle_welcome(&mut self, payload: &[u8]) -> Result<()> {
-        // Deserialize Welcome
-        let msg = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice())?;
-        let welcome = match msg.extract() {
-            MlsMessageBodyIn::Welcome(w) => w,
-            _ => return Err(anyhow!("Expected Welcome")),
-        };
+/// Print an event as a JSON line, or queue it for the daemon
+fn emit_json(sink: &mut Sink, mut event: JsonValue, time: DateTime<Local>, text: &str) {
+    event["ts"] = json!(time.to_rfc3339());
+    event["text"] = json!(text);
+    match sink {
+        Sink::Daemon(queue) => queue.push(event),
+        _ => println!("{}", event),
+    }
+}
 
-        // Join group
-        let config = MlsGroupJoinConfig::builder().build();
-        let group = StagedWelcome::new_from_welcome(&self.backend, &config, welcome, None)?
-            .into_group(&self.backend)?;
+// ============================================================================
+// Configuration
+// ============================================================================
 
-        let group_id = hex::encode(group.group_id().as_slice());
+const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+const DEFAULT_STATE_DIR: &str = "relay-state";
+/// Messages held per group while waiting for the Commit they follow, and
+/// for how long
+const MAX_FUTURE_MESSAGES: usize = 100;
+const FUTURE_MESSAGE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Earlier epochs whose secrets are kept, to decrypt application messages
+/// delivered after a later Commit. Keeping more weakens forward secrecy.
+const MAX_PAST_EPOCHS: usize = 3;
+/// Delay before reconnecting to the broker, doubled after each failure
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Delay before publishing a message again after its publish failed,
+/// doubled after each failure; a reconnect retries at once
+const RETRY_MIN_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
+/// Messages kept for retry at most; sending more fails
+const MAX_UNSENT: usize = 1000;
+/// Interval of keepalive pings to the broker, unless keep_alive is set
+const DEFAULT_KEEP_ALIVE: u64 = 30;
+/// A connection whose ping isn't answered within this is taken to be dead
+/// and replaced, rather than waiting for TCP to notice
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests (publishes, subscriptions, acks) queued for the MQTT task, unless
+/// request_queue is set; more at once fail rather than wait
+const DEFAULT_REQUEST_QUEUE: usize = 1000;
+/// How often the main loop checks its timers: reordering gaps, held
+/// messages and the age of our KeyPackages and retained messages
+const TIMER_INTERVAL: Duration = Duration::from_millis(250);
+/// Days between our automatic key updates in a group, unless rekey_days is
+/// set (protocol Section 8.8)
+const DEFAULT_REKEY_DAYS: u64 = 7;
+/// Minutes between heartbeats in a group we sent nothing else to, unless
+/// heartbeat_minutes is set
+const DEFAULT_HEARTBEAT_MINUTES: u64 = 60;
+/// Days without a message from a peer after which `prune` ends the
+/// session, unless prune_days is set
+const DEFAULT_PRUNE_DAYS: u64 = 30;
+/// How long exiting waits for the broker to take our last publishes
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a channel's directory entry, then its GroupInfo, is waited
+/// for; a name without an entry by then is free
+const CHANNEL_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+const CHANNEL_NAME_MAX: usize = 32;
+/// How long `find` collects the retained records of the peer directory
+const DIRECTORY_LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest display name listed in the peer directory, in characters
+const DIRECTORY_NAME_MAX: usize = 64;
+/// Prefix of what a peer directory record's signature covers
+const DIRECTORY_LABEL: &[u8] = b"relay directory record";
+/// Invitations from strangers kept waiting for `accept` or `decline`
+const MAX_INVITATIONS: usize = 20;
+/// How long the Welcome to a session that duplicates ours is held for the
+/// peer to join ours instead; past it, they evidently lost our Welcome
+const SESSION_CONTEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// One-time KeyPackages kept published, besides the last resort one
+const KEY_PACKAGE_POOL: usize = 10;
+/// Validity of the KeyPackages we publish
+const KEY_PACKAGE_LIFETIME: Duration = Duration::from_secs(28 * 24 * 60 * 60);
+/// Age at which our KeyPackages are replaced (protocol Section 6.2), long
+/// before peers would find them expired
+const KEY_PACKAGE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How long the broker keeps a retained GroupInfo or directory entry once
+/// nobody refreshes it, so those of abandoned groups and clients go away
+const RETAINED_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// How often we publish our retained GroupInfo and directory entry again
+const RETAINED_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+/// Control socket of --daemon, in the state directory
+const DEFAULT_SOCKET: &str = "relay.sock";
+/// How long the daemon waits for a slow connection to take a message
+const CONTROL_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a client of the metrics port gets to send its request
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Diagnostics logged when RUST_LOG is not set
+const DEFAULT_LOG_FILTER: &str = "warn,relay=info";
+/// Retained status on {prefix}/p/{client_id}; exiting, or else the Last
+/// Will, publishes PRESENCE_OFFLINE
+const PRESENCE_ONLINE: &str = "online";
+const PRESENCE_OFFLINE: &str = "offline";
+/// Sizes application messages are padded to, so their length doesn't show
+const DEFAULT_PADDING: [usize; 3] = [256, 1024, 4096];
+/// How long the broker keeps our session, and the messages queued in it,
+/// while we are offline (protocol Section 9.1)
+const SESSION_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Topic aliases the broker may use for the publishes it sends us
+const TOPIC_ALIAS_MAX: u16 = 64;
+/// User property naming the kind of payload of a publish
+const KIND_PROPERTY: &str = "relay-kind";
+/// Largest payload published in one piece, unless max_payload is set;
+/// public brokers often refuse packets over 256 KiB
+const DEFAULT_MAX_PAYLOAD: usize = 128 * 1024;
+const MIN_MAX_PAYLOAD: usize = 1024;
+/// Start of a chunk of a larger payload: neither an MLSMessage, CBOR nor
+/// presence text starts with 0xff
+const CHUNK_MAGIC: &[u8] = b"\xffRC1";
+/// Magic, payload ID (8 bytes), chunk index and count (u16 each)
+const CHUNK_HEADER_LEN: usize = 16;
+/// Chunked payloads being reassembled at once, and for how long one waits
+/// for its missing chunks
+const MAX_REASSEMBLIES: usize = 32;
+/// Largest payload put back together from chunks
+const MAX_REASSEMBLED_SIZE: usize = 16 * 1024 * 1024;
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+/// User property a bridge adds to what it forwards, with its MQTT client ID
+const BRIDGE_PROPERTY: &str = "relay-bridge";
+/// Bridges a publish may pass through; it is dropped after that many
+const MAX_BRIDGE_HOPS: usize = 8;
+/// Scheme of the pairing URIs `qr` shows and `connect` takes:
+/// relay:{client_id}[?kp={topic}&broker={broker}], where the optional part
+/// says where the KeyPackages of the client are published
+const PAIRING_SCHEME: &str = "relay:";
+/// Typing indicators are sent at most this often while composing
+const TYPING_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a peer is shown as typing without a newer indicator
+const TYPING_DISPLAY: Duration = Duration::from_secs(7);
 
-        // Find peer (the other member)
-        let peer_id = group
-            .members()
-            .find_map(|m| {
-                let id = String::from_utf8(m.credential.serialized_content().to_vec()).ok()?;
-                if id != self.client_id {
-                    Some(id)
-                } else {
-                    None
-                }
-            })
-            .unwrap_or_else(|| "unknown".to_string());
+/// Command-line arguments; each broker flag overrides the config file
+#[derive(Parser)]
+#[command(about = "Relay reference client (MLS over MQTT)")]
+struct Args {
+    /// State directory holding identity and sessions
+    #[arg(default_value = DEFAULT_STATE_DIR)]
+    state_dir: PathBuf,
 
-        // Subscribe to group messages
-        self.mqtt
-            .subscribe(format!("relay/g/{}/m", group_id), QoS::AtLeastOnce)?;
+    /// Identity to run, with its own state in <state_dir>/profiles/<name>
+    /// (repeatable; several run in the plain interface)
+    #[arg(long = "profile")]
+    profiles: Vec<String>,
 
-        self.group_peers.insert(group_id, peer_id.clone());
-        self.groups.insert(peer_id.clone(), group);
+    /// TOML config file [default: <state_dir>/config.toml, if present]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-        // Publish a fresh KeyPackage (our old one was consumed)
-        self.publish_key_package()?;
+    /// Broker host name
+    #[arg(long)]
+    host: Option<String>,
 
-        log(&format!("Session established with {}", peer_id));
-        log(&format!("Use 'chat {} <message>' to reply", peer_id));
-        Ok(())
-    }
+    /// Broker port [default: 1883, or 8883 with TLS; 80 or 443 over WebSocket]
+    #[arg(long)]
+    port: Option<u16>,
 
-    fn handle_group_message(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
-        // Parse group_id from topic: relay/g/{group_id}/m
-        let group_id = topic
-            .strip_prefix("relay/g/")
-            .and_then(|s| s.strip_suffix("/m"))
-            .ok_or_else(|| anyhow!("Invalid topic"))?;
+    /// Connect over TLS (mqtts)
+    #[arg(long)]
+    tls: bool,
 
-        let peer_id = self
-            .group_peers
-            .get(group_id)
-            .ok_or_else(|| anyhow!("Unknown group"))?
-            .clone();
+    /// Connect over WebSocket (ws://, or wss:// with --tls)
+    #[arg(long)]
+    websocket: bool,
 
-        let group = self
-            .groups
-            .get_mut(&peer_id)
-            .ok_or_else(|| anyhow!("No group"))?;
+    /// Path of the broker's WebSocket endpoint [default: /mqtt]
+    #[arg(long)]
+    ws_path: Option<String>,
 
-        // Deserialize MLS message
-        let msg = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice())?;
-        let protocol_msg = match msg.extract() {
-            MlsMessageBodyIn::PrivateMessage(m) => ProtocolMessage::from(m),
-            MlsMessageBodyIn::PublicMessage(m) => ProtocolMessage::from(m),
-            _ => return Err(anyhow!("Expected PrivateMessage or PublicMessage")),
-        };
+    /// SOCKS5 proxy for the broker connection, as
+    /// socks5://[user:password@]host:port (e.g. Tor's socks5://127.0.0.1:9050)
+    #[arg(long)]
+    proxy: Option<String>,
 
-        // Process message
-        let processed = match group.process_message(&self.backend, protocol_msg) {
-            Ok(p) => p,
-            Err(ProcessMessageError::ValidationError(ValidationError::CannotDecryptOwnMessage)) => {
-                return Ok(())
-            } // Skip own messages
-            Err(e) => return Err(anyhow!("MLS error: {:?}", e)),
-        };
+    /// PEM file with the CA certificates to trust instead of the system ones
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
 
-        match processed.into_content() {
-            ProcessedMessageContent::ApplicationMessage(app_msg) => {
-                let bytes = app_msg.into_bytes();
-                let text = String::from_utf8_lossy(&bytes);
-                log_msg(&peer_id, &text, false);
-            }
-            ProcessedMessageContent::StagedCommitMessage(staged) => {
-                group.merge_staged_commit(&self.backend, *staged)?;
-            }
-            _ => {}
-        }
-        Ok(())
-    }
-}
+    /// PEM file with a client certificate for the broker (needs --client-key)
+    #[arg(long)]
+    client_cert: Option<PathBuf>,
 
-// ============================================================================
-// Commands
-// ============================================================================
+    /// PEM file with the client certificate's private key
+    #[arg(long)]
+    client_key: Option<PathBuf>,
 
-impl RelayClient {
-    fn connect(&mut self, peer_id: &str) -> Result<()> {
-        if self.groups.contains_key(peer_id) {
-            log(&format!("Already connected to {}", peer_id));
-            return Ok(());
-        }
+    /// Broker username
+    #[arg(long)]
+    username: Option<String>,
 
-        // If we already have their KeyPackage, establish session immediately
-        if self.key_packages.contains_key(peer_id) {
-            self.create_group(peer_id)?;
-            log(&format!("Session established with {}", peer_id));
-            return Ok(());
-        }
+    /// Broker password
+    #[arg(long, env = "RELAY_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
 
-        // Otherwise, fetch KeyPackage and mark as pending
-        self.pending_connects.push(peer_id.to_string());
-        self.mqtt
-            .subscribe(format!("relay/k/{}", peer_id), QoS::AtLeastOnce)?;
-        log(&format!("Connecting to {}...", peer_id));
-        Ok(())
-    }
+    /// Shell command printing a broker token (e.g. a JWT), sent as the password
+    #[arg(long)]
+    token_command: Option<String>,
 
-    fn send(&mut self, peer_id: &str, text: &str) -> Result<()> {
-        // Try to find group by peer_id or partial match
-        let peer = self.find_peer(peer_id)?;
+    /// File holding a broker token, sent as the password
+    #[arg(long)]
+    token_file: Option<PathBuf>,
 
-        // Must have an active session
-        if !self.groups.contains_key(&peer) {
-            return Err(anyhow!(
-                "No session with {}. Use 'connect {}' first.",
-                peer,
-                peer
-            ));
-        }
+    /// Prefix of all MQTT topics
+    #[arg(long)]
+    topic_prefix: Option<String>,
 
-        let group = self.groups.get_mut(&peer).unwrap();
-        let group_id = hex::encode(group.group_id().as_slice());
+    /// Another topic prefix to take part in, with its own peers (repeatable)
+    #[arg(long = "namespace")]
+    namespaces: Vec<String>,
 
-        // Create and send message
-        let mls_msg = group.create_message(&self.backend, &self.signer, text.as_bytes())?;
-        let msg_bytes = mls_msg.tls_serialize_detached()?;
+    /// Client ID to use when creating a new identity
+    #[arg(long)]
+    client_id: Option<String>,
 
-        self.mqtt.publish(
-            format!("relay/g/{}/m", group_id),
-            QoS::AtLeastOnce,
-            false,
-            msg_bytes,
-        )?;
+    /// Don't keep a message history
+    #[arg(long)]
+    no_history: bool,
 
-        // Show sent message locally
-        log_msg("", text, true);
-        Ok(())
-    }
+    /// Passphrase to encrypt the message history with
+    #[arg(long, env = "RELAY_HISTORY_PASSPHRASE", hide_env_values = true)]
+    history_passphrase: Option<String>,
 
-    fn find_peer(&self, query: &str) -> Result<String> {
-        // Exact match in groups
-        if self.groups.contains_key(query) {
-            return Ok(query.to_string());
-        }
+    /// Sizes in bytes to pad messages to, comma-separated [default: 256,1024,4096]
+    #[arg(long, value_delimiter = ',')]
+    padding: Option<Vec<usize>>,
 
-        // Exact match in key_packages
-        if self.key_packages.contains_key(query) {
-            return Ok(query.to_string());
-        }
+    /// Don't pad messages
+    #[arg(long)]
+    no_padding: bool,
 
-        // Partial match in groups (prefix)
-        let group_matches: Vec<_> = self
-            .groups
-            .keys()
-            .filter(|k| k.starts_with(query))
-            .collect();
-        if group_matches.len() == 1 {
-            return Ok(group_matches[0].clone());
-        }
+    /// Don't tell peers when you are typing
+    #[arg(long)]
+    no_typing: bool,
 
-        // Partial match in key_packages (prefix)
-        let kp_matches: Vec<_> = self
-            .key_packages
-            .keys()
-            .filter(|k| k.starts_with(query))
-            .collect();
-        if kp_matches.len() == 1 {
-            return Ok(kp_matches[0].clone());
-        }
+    /// Ask before joining on a Welcome from a known contact too
+    #[arg(long)]
+    no_auto_accept: bool,
 
-        // Show available peers
-        let mut available = vec![];
-        for peer in self.groups.keys() {
-            available.push(format!("{} (session)", peer));
-        }
-        for peer in self.key_packages.keys() {
-            if !self.groups.contains_key(peer) {
-                available.push(format!("{} (keypackage)", peer));
-            }
-        }
+    /// Don't raise desktop notifications for new messages
+    #[arg(long)]
+    no_notifications: bool,
 
-        if available.is_empty() {
-            Err(anyhow!(
-                "No peers available. Use 'connect <peer_id>' first."
-            ))
-        } else {
-            Err(anyhow!(
-                "Unknown peer '{}'. Available:\n  {}",
-                query,
-                available.join("\n  ")
-            ))
-        }
-    }
+    /// Withdraw our retained KeyPackages from the broker on exit
+    #[arg(long)]
+    cleanup_on_exit: bool,
 
-    fn create_group(&mut self, peer_id: &str) -> Result<()> {
-        let peer_kp = self
-            .key_packages
-            .get(peer_id)
-            .ok_or_else(|| anyhow!("No KeyPackage for peer (use 'connect' first)"))?
-            .clone();
+    /// Subscribe to the messages of all groups and drop those of others
+    #[arg(long)]
+    wildcard_groups: bool,
 
-        // Generate random group_id
-        let group_id_bytes: [u8; 16] = rand::thread_rng().gen();
-        let group_id = hex::encode(group_id_bytes);
+    /// File to append diagnostics to, at the levels given by RUST_LOG
+    #[arg(long)]
+    log_file: Option<PathBuf>,
 
-        // Create group
-        let config = MlsGroupCreateConfig::builder()
-            .ciphersuite(CIPHERSUITE)
-            .use_ratchet_tree_extension(true)
-            .build();
+    /// Use the line-based interface instead of the TUI
+    #[arg(long)]
+    plain: bool,
 
-        let mut group = MlsGroup::new_with_group_id(
-            &self.backend,
-            &self.signer,
-            &config,
+    /// Read commands from stdin and print events as JSON lines, for scripts
+    #[arg(long)]
+    json: bool,
+
+    /// Run headless, serving JSON-RPC on a local socket
+    #[arg(long)]
+    daemon: bool,
+
+    /// Socket for --daemon [default: <state_dir>/relay.sock]
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Loopback port to serve Prometheus metrics on, with --daemon
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Forward relay traffic between the broker and the one of this TOML
+    /// config file, instead of running a client
+    #[arg(long)]
+    bridge: Option<PathBuf>,
+}
+
+/// Broker settings, read from the TOML config file:
+///
+/// ```toml
+/// host = "broker.example.com"
+/// port = 8883
+/// tls = true
+/// websocket = false
+/// ws_path = "/mqtt"
+/// proxy = "socks5://127.0.0.1:9050"
+/// ca_cert = "ca.pem"
+/// client_cert = "client.pem"
+/// client_key = "client.key"
+/// username = "alice"
+/// password = "secret"          # or token_command / token_file
+/// topic_prefix = "relay"
+/// namespaces = ["public"]      # further topic prefixes
+/// client_id = "alice"
+/// history = true
+/// history_passphrase = "..."
+/// padding = [256, 1024, 4096] # message sizes to pad to; [] for none
+/// typing_indicators = true
+/// auto_accept = true            # join on Welcomes from known contacts
+/// notifications = true
+/// muted = ["bob"]               # peers to raise no notifications for
+/// blocked = ["mallory"]         # peers whose traffic is dropped
+/// silenced = { eve = 1767225600 } # dropped until then (Unix time)
+/// cleanup_on_exit = false       # withdraw our KeyPackages on exit
+/// rekey_days = 7                # days between automatic key updates; 0: off
+/// heartbeat_minutes = 60        # heartbeats in quiet groups; 0: off
+/// prune_days = 30               # idle days before `prune` ends a session
+/// envelope = "cbor"             # or "protobuf", when every member reads it
+/// directory_name = "Alice Smith" # list us in the peer directory
+/// upload_command = "store-put"  # uploads $RELAY_FILE, prints its URL
+/// download_command = "store-get" # downloads $RELAY_URL to $RELAY_FILE
+/// bot_command = "./echo-bot"    # replies to each message, see Responder
+/// keep_alive = 30               # seconds between keepalive pings
+/// max_inflight = 100            # unacknowledged publishes at once
+/// request_queue = 1000          # requests queued for the MQTT task
+/// max_payload = 131072          # larger publishes are sent in chunks
+/// qos = { application = 1, presence = 1, typing = 0 }
+/// wildcard_groups = false
+/// metrics_port = 9464           # Prometheus metrics of the daemon
+/// log_file = "relay.log"
+/// ```
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    host: String,
+    port: Option<u16>,
+    tls: bool,
+    websocket: bool,
+    ws_path: String,
+    proxy: Option<String>,
+    ca_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    username: Option<String>,
+    password: Option<String>,
+    token_command: Option<String>,
+    token_file: Option<PathBuf>,
+    topic_prefix: String,
+    namespaces: Vec<String>,
+    client_id: Option<String>,
+    history: bool,
+    history_passphrase: Option<String>,
+    padding: Vec<usize>,
+    typing_indicators: bool,
+    auto_accept: bool,
+    notifications: bool,
+    muted: Vec<String>,
+    blocked: Vec<String>,
+    silenced: BTreeMap<String, i64>,
+    cleanup_on_exit: bool,
+    rekey_days: u64,
+    heartbeat_minutes: u64,
+    prune_days: u64,
+    envelope: String,
+    directory_name: Option<String>,
+    upload_command: Option<String>,
+    download_command: Option<String>,
+    bot_command: Option<String>,
+    keep_alive: u64,
+    max_inflight: Option<u16>,
+    request_queue: usize,
+    max_payload: usize,
+    qos: QosLevels,
+    wildcard_groups: bool,
+    metrics_port: Option<u16>,
+    log_file: Option<PathBuf>,
+    /// The file this was read from, or would have been
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: "broker.emqx.io".to_string(),
+            port: None,
+            tls: false,
+            websocket: false,
+            ws_path: "/mqtt".to_string(),
+            proxy: None,
+            ca_cert: None,
+            client_cert: None,
+            client_key: None,
+            username: None,
+            password: None,
+            token_command: None,
+            token_file: None,
+            topic_prefix: "relay".to_string(),
+            namespaces: Vec::new(),
+            client_id: None,
+            history: true,
+            history_passphrase: None,
+            padding: DEFAULT_PADDING.to_vec(),
+            typing_indicators: true,
+            auto_accept: true,
+            notifications: true,
+            muted: Vec::new(),
+            blocked: Vec::new(),
+            silenced: BTreeMap::new(),
+            cleanup_on_exit: false,
+            rekey_days: DEFAULT_REKEY_DAYS,
+            heartbeat_minutes: DEFAULT_HEARTBEAT_MINUTES,
+            prune_days: DEFAULT_PRUNE_DAYS,
+            envelope: "cbor".into(),
+            directory_name: None,
+            upload_command: None,
+            download_command: None,
+            bot_command: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            max_inflight: None,
+            request_queue: DEFAULT_REQUEST_QUEUE,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            qos: QosLevels::default(),
+            wildcard_groups: false,
+            metrics_port: None,
+            log_file: None,
+            path: PathBuf::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The config of the identity in `state_dir`, with `args` applied
+    fn load(args: &Args, state_dir: &Path) -> Result<Self> {
+        let path = args
+            .config
+            .clone()
+            .unwrap_or_else(|| state_dir.join("config.toml"));
+        let mut config: Config = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))?,
+            // Only an explicitly named config file has to exist
+            Err(e) if e.kind() == io::ErrorKind::NotFound && args.config.is_none() => {
+                Config::default()
+            }
+            Err(e) => return Err(anyhow!("Cannot read config {}: {}", path.display(), e)),
+        };
+
+        if let Some(host) = &args.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = args.port {
+            config.port = Some(port);
+        }
+        config.tls |= args.tls;
+        config.websocket |= args.websocket;
+        if let Some(ws_path) = &args.ws_path {
+            config.ws_path = ws_path.clone();
+        }
+        if let Some(proxy) = &args.proxy {
+            config.proxy = Some(proxy.clone());
+        }
+        if let Some(ca_cert) = &args.ca_cert {
+            config.ca_cert = Some(ca_cert.clone());
+        }
+        if let Some(client_cert) = &args.client_cert {
+            config.client_cert = Some(client_cert.clone());
+        }
+        if let Some(client_key) = &args.client_key {
+            config.client_key = Some(client_key.clone());
+        }
+        if let Some(username) = &args.username {
+            config.username = Some(username.clone());
+        }
+        if let Some(password) = &args.password {
+            config.password = Some(password.clone());
+        }
+        if let Some(token_command) = &args.token_command {
+            config.token_command = Some(token_command.clone());
+        }
+        if let Some(token_file) = &args.token_file {
+            config.token_file = Some(token_file.clone());
+        }
+        if let Some(topic_prefix) = &args.topic_prefix {
+            config.topic_prefix = topic_prefix.clone();
+        }
+        config.namespaces.extend(args.namespaces.iter().cloned());
+        if let Some(client_id) = &args.client_id {
+            config.client_id = Some(client_id.clone());
+        }
+        config.history &= !args.no_history;
+        if let Some(passphrase) = &args.history_passphrase {
+            config.history_passphrase = Some(passphrase.clone());
+        }
+        if let Some(padding) = &args.padding {
+            config.padding = padding.clone();
+        }
+        if args.no_padding {
+            config.padding.clear();
+        }
+        config.typing_indicators &= !args.no_typing;
+        config.auto_accept &= !args.no_auto_accept;
+        config.notifications &= !args.no_notifications;
+        config.cleanup_on_exit |= args.cleanup_on_exit;
+        config.path = path;
+        config.wildcard_groups |= args.wildcard_groups;
+        if let Some(port) = args.metrics_port {
+            config.metrics_port = Some(port);
+        }
+        if let Some(log_file) = &args.log_file {
+            config.log_file = Some(log_file.clone());
+        }
+
+        let valid_prefix = |prefix: &str| {
+            let trimmed = prefix.trim_end_matches('/');
+            if trimmed.is_empty() || trimmed.contains(['+', '#']) {
+                return Err(anyhow!("Invalid topic prefix '{}'", prefix));
+            }
+            Ok(trimmed.to_string())
+        };
+        config.topic_prefix = valid_prefix(&config.topic_prefix)?;
+        let mut namespaces: Vec<String> = Vec::new();
+        for namespace in &config.namespaces {
+            let namespace = valid_prefix(namespace)?;
+            if namespace != config.topic_prefix && !namespaces.contains(&namespace) {
+                namespaces.push(namespace);
+            }
+        }
+        config.namespaces = namespaces;
+        if config.padding.contains(&0) {
+            return Err(anyhow!("Padding sizes must be above 0"));
+        }
+        config.padding.sort_unstable();
+        config.padding.dedup();
+        if !config.ws_path.starts_with('/') {
+            return Err(anyhow!("ws_path must start with '/'"));
+        }
+        // rumqttc refuses shorter intervals
+        if config.keep_alive < 5 {
+            return Err(anyhow!("keep_alive must be at least 5 seconds"));
+        }
+        if config.max_inflight == Some(0) || config.request_queue == 0 {
+            return Err(anyhow!("max_inflight and request_queue must be above 0"));
+        }
+        config.qos.check()?;
+        if config.prune_days == 0 {
+            return Err(anyhow!("prune_days must be above 0"));
+        }
+        if let Some(name) = &config.directory_name {
+            let length = name.chars().count();
+            if length == 0 || length > DIRECTORY_NAME_MAX || name.chars().any(char::is_control) {
+                return Err(anyhow!(
+                    "directory_name must have 1 to {} characters, none of them control characters",
+                    DIRECTORY_NAME_MAX
+                ));
+            }
+        }
+        if config.max_payload < MIN_MAX_PAYLOAD {
+            return Err(anyhow!("max_payload must be at least {}", MIN_MAX_PAYLOAD));
+        }
+        if !["cbor", "protobuf"].contains(&config.envelope.as_str()) {
+            return Err(anyhow!("envelope must be \"cbor\" or \"protobuf\""));
+        }
+        config.socks_proxy()?;
+
+        let secrets = [
+            config.password.is_some(),
+            config.token_command.is_some(),
+            config.token_file.is_some(),
+        ];
+        if secrets.into_iter().filter(|set| *set).count() > 1 {
+            return Err(anyhow!(
+                "Set only one of password, token_command and token_file"
+            ));
+        }
+
+        // Certificate settings only make sense over TLS
+        if !config.tls
+            && (config.ca_cert.is_some()
+                || config.client_cert.is_some()
+                || config.client_key.is_some())
+        {
+            return Err(anyhow!("Certificates are set but TLS is off; enable tls"));
+        }
+        Ok(config)
+    }
+
+    /// The SOCKS5 proxy to reach the broker through, if set
+    fn socks_proxy(&self) -> Result<Option<SocksProxy>> {
+        self.proxy.as_deref().map(SocksProxy::parse).transpose()
+    }
+
+    /// Token source for the broker password, if tokens are configured
+    fn token_provider(&self) -> Option<Box<dyn TokenProvider>> {
+        if let Some(command) = &self.token_command {
+            return Some(Box::new(CommandToken(command.clone())));
+        }
+        self.token_file
+            .as_ref()
+            .map(|path| Box::new(FileToken(path.clone())) as Box<dyn TokenProvider>)
+    }
+
+    /// Bot that answers incoming messages, if one is configured
+    fn responder(&self) -> Option<Box<dyn Responder>> {
+        self.bot_command
+            .as_ref()
+            .map(|command| Box::new(CommandResponder(command.clone())) as Box<dyn Responder>)
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(match (self.websocket, self.tls) {
+            (false, false) => 1883,
+            (false, true) => 8883,
+            (true, false) => 80,
+            (true, true) => 443,
+        })
+    }
+
+    /// Broker address for MqttOptions: the host name, or over WebSocket the
+    /// URL of the endpoint, which rumqttc takes the host and port from
+    fn broker_addr(&self) -> String {
+        if !self.websocket {
+            return self.host.clone();
+        }
+        let scheme = if self.tls { "wss" } else { "ws" };
+        // An IPv6 address needs brackets in a URL
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        format!("{}://{}:{}{}", scheme, host, self.port(), self.ws_path)
+    }
+
+    /// The broker as shown to users: host:port, or the WebSocket URL
+    fn broker_name(&self) -> String {
+        if self.websocket {
+            self.broker_addr()
+        } else {
+            format!("{}:{}", self.host, self.port())
+        }
+    }
+
+    /// Transport for the broker connection: TCP or WebSocket, with TLS
+    /// trusting `ca_cert` if set and the system's root certificates otherwise
+    fn transport(&self) -> Result<Transport> {
+        if !self.tls {
+            return Ok(if self.websocket {
+                Transport::ws()
+            } else {
+                Transport::tcp()
+            });
+        }
+        let read = |path: &PathBuf| {
+            fs::read(path).map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))
+        };
+        let client_auth = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((read(cert)?, read(key)?)),
+            (None, None) => None,
+            _ => return Err(anyhow!("client_cert and client_key must be set together")),
+        };
+
+        match (&self.ca_cert, client_auth, self.websocket) {
+            (Some(ca), client_auth, false) => Ok(Transport::tls(read(ca)?, client_auth, None)),
+            (Some(ca), client_auth, true) => Ok(Transport::wss(read(ca)?, client_auth, None)),
+            (None, None, false) => Ok(Transport::tls_with_default_config()),
+            (None, None, true) => Ok(Transport::wss_with_default_config()),
+            (None, Some(_), _) => Err(anyhow!("A client certificate needs ca_cert as well")),
+        }
+    }
+}
+
+/// QoS of the publishes whose loss the client gets over, by config. The
+/// rest (KeyPackages, Welcomes, Commits, proposals, GroupInfo, channel
+/// entries) always use QoS 1: losing one breaks an invitation or a group.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QosLevels {
+    application: u8, // messages, reactions, edits and receipts
+    presence: u8,
+    typing: u8,
+}
+
+impl Default for QosLevels {
+    fn default() -> Self {
+        QosLevels {
+            application: 1,
+            presence: 1,
+            typing: 1,
+        }
+    }
+}
+
+impl QosLevels {
+    fn check(&self) -> Result<()> {
+        if [self.application, self.presence, self.typing]
+            .iter()
+            .any(|level| *level > 2)
+        {
+            return Err(anyhow!("QoS levels are 0, 1 or 2"));
+        }
+        Ok(())
+    }
+
+    /// QoS of a payload; application messages by their envelope type
+    fn of(&self, kind: PayloadKind, message_kind: Option<&str>) -> QoS {
+        let level = match (kind, message_kind) {
+            (PayloadKind::Application, Some("typing")) => self.typing,
+            (PayloadKind::Application, _) => self.application,
+            (PayloadKind::Presence, _) => self.presence,
+            _ => 1,
+        };
+        match level {
+            0 => QoS::AtMostOnce,
+            2 => QoS::ExactlyOnce,
+            _ => QoS::AtLeastOnce,
+        }
+    }
+}
+
+/// Write one setting, such as the muted peers, into the config file,
+/// keeping the rest of it (and its comments) as it is
+fn save_config_entry(path: &Path, key: &str, value: toml_edit::Value) -> Result<()> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(anyhow!("Cannot read config {}: {}", path.display(), e)),
+    };
+    let mut doc: toml_edit::DocumentMut = text
+        .parse()
+        .map_err(|e| anyhow!("Invalid config {}: {}", path.display(), e))?;
+    doc[key] = toml_edit::value(value);
+    fs::write(path, doc.to_string())
+        .map_err(|e| anyhow!("Cannot write config {}: {}", path.display(), e))
+}
+
+/// A duration such as "90s", "30m", "2h" or "7d"
+fn parse_duration(text: &str) -> Result<chrono::Duration> {
+    let invalid = || anyhow!("Invalid duration '{}'; use e.g. 30m, 2h or 7d", text);
+    let unit = text.chars().last().ok_or_else(invalid)?;
+    let count: i64 = text[..text.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    let duration = match unit {
+        's' => chrono::Duration::try_seconds(count),
+        'm' => chrono::Duration::try_minutes(count),
+        'h' => chrono::Duration::try_hours(count),
+        'd' => chrono::Duration::try_days(count),
+        _ => None,
+    };
+    duration
+        .filter(|d| *d > chrono::Duration::zero())
+        .ok_or_else(invalid)
+}
+
+/// A number of seconds in the largest whole unit, such as "3d" or "5m"
+fn format_age(secs: u64) -> String {
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+// ============================================================================
+// Broker Authentication
+// ============================================================================
+
+/// Source of broker tokens for brokers with token (e.g. JWT) auth. The token
+/// is sent as the MQTT password, fetched on start and again whenever the
+/// broker refuses the credentials, so expired tokens get replaced.
+trait TokenProvider: Send {
+    fn token(&mut self) -> Result<String>;
+}
+
+/// Token printed on stdout by a shell command
+struct CommandToken(String);
+
+impl TokenProvider for CommandToken {
+    fn token(&mut self) -> Result<String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&self.0)
+            .output()
+            .map_err(|e| anyhow!("Cannot run token command: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Token command failed ({}): {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        non_empty_token(String::from_utf8(output.stdout)?)
+    }
+}
+
+/// Token read from a file, e.g. one kept fresh by another process
+struct FileToken(PathBuf);
+
+impl TokenProvider for FileToken {
+    fn token(&mut self) -> Result<String> {
+        let token = fs::read_to_string(&self.0)
+            .map_err(|e| anyhow!("Cannot read token file {}: {}", self.0.display(), e))?;
+        non_empty_token(token)
+    }
+}
+
+fn non_empty_token(token: String) -> Result<String> {
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(anyhow!("Token provider returned an empty token"));
+    }
+    Ok(token.to_string())
+}
+
+/// Put a fresh token into the credentials used for the next (re)connect
+fn refresh_token(
+    options: &mut MqttOptions,
+    username: &str,
+    provider: &mut dyn TokenProvider,
+) -> Result<()> {
+    options.set_credentials(username, provider.token()?);
+    Ok(())
+}
+
+// ============================================================================
+// Persistence
+// ============================================================================
+//
+// The state directory holds these CBOR files:
+//   identity.cbor  { "client_id": tstr, "signature_key": bstr }
+//   storage.cbor   { * bstr => bstr }  entries of the OpenMLS storage provider
+//   groups.cbor    { * tstr => tstr }  peer_id -> group_id (hex), 1:1 sessions
+//   names.cbor     { * tstr => tstr }  name -> group_id (hex), named groups
+//   namespaces.cbor { * tstr => tstr } group_id (hex) -> topic prefix, for
+//                                      groups outside the main one
+//   seq.cbor       { * tstr => uint }  group_id -> our next message sequence number
+//   order.cbor     { * tstr => uint }  "group_id/sender" -> next sequence number
+//                                      expected from that sender
+//   aliases.cbor   { * tstr => tstr }  peer_id -> alias
+//   verified.cbor  { * tstr => tstr }  peer_id -> signature key (hex) the
+//                                      user verified
+//   keypackages.cbor [* bstr]          our published KeyPackages (MLSMessage)
+//   broadcast.cbor [* tstr]            group_ids of broadcast groups
+//   updates.cbor   { * tstr => uint }  group_id -> Unix time we last updated
+//                                      our keys in it (0: due)
+//   seen.cbor      { * tstr => uint }  peer_id -> Unix time of their last
+//                                      group message
+//
+// The signer's private key and all group secrets live in storage.cbor, so
+// the directory must be kept private.
+//
+// `identity export` puts these files into one CBOR file, encrypted with a
+// passphrase:
+//   { "salt": bstr, "nonce": bstr (12 bytes), "files": bstr }
+//   files = AES-256-GCM({ * tstr => bstr }), file name -> contents,
+//           AAD = "relay identity"
+// The key is derived with Argon2id from the passphrase and salt.
+
+const STATE_FILES: [&str; 13] = [
+    "identity.cbor",
+    "storage.cbor",
+    "groups.cbor",
+    "names.cbor",
+    "namespaces.cbor",
+    "seq.cbor",
+    "order.cbor",
+    "aliases.cbor",
+    "verified.cbor",
+    "keypackages.cbor",
+    "broadcast.cbor",
+    "updates.cbor",
+    "seen.cbor",
+];
+const EXPORT_AAD: &[u8] = b"relay identity";
+
+fn read_cbor(path: &Path) -> Result<Option<Value>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(ciborium::from_reader(bytes.as_slice())?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_cbor(path: &Path, value: &Value) -> Result<()> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes)?;
+    write_private(path, &bytes)
+}
+
+/// Replace a file with `bytes`, readable by our user only, as state files
+/// hold secrets. It is written to a temporary file first so a crash never
+/// leaves a torn file.
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    // One left by a crash may have been created with other permissions
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?
+        .write_all(bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Create a directory for state or history, or restrict an existing one
+/// from older versions, so only our user can list and read it
+fn create_private_dir(dir: &Path) -> Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+/// Read a `{ * tstr => tstr }` index file
+fn read_index(path: &Path) -> Result<HashMap<String, String>> {
+    let Some(index) = read_cbor(path)? else {
+        return Ok(HashMap::new());
+    };
+    let corrupt = || anyhow!("Corrupt {}", path.display());
+    index
+        .as_map()
+        .ok_or_else(corrupt)?
+        .iter()
+        .map(|(key, value)| match (key.as_text(), value.as_text()) {
+            (Some(key), Some(value)) => Ok((key.to_string(), value.to_string())),
+            _ => Err(corrupt()),
+        })
+        .collect()
+}
+
+fn write_index(path: &Path, index: &HashMap<String, String>) -> Result<()> {
+    let map = Value::Map(
+        index
+            .iter()
+            .map(|(k, v)| (Value::Text(k.clone()), Value::Text(v.clone())))
+            .collect(),
+    );
+    write_cbor(path, &map)
+}
+
+/// Read a `{ * tstr => uint }` counter file
+fn read_counters(path: &Path) -> Result<HashMap<String, u64>> {
+    let Some(counters) = read_cbor(path)? else {
+        return Ok(HashMap::new());
+    };
+    let corrupt = || anyhow!("Corrupt {}", path.display());
+    counters
+        .as_map()
+        .ok_or_else(corrupt)?
+        .iter()
+        .map(|(key, value)| {
+            let count = value.as_integer().and_then(|n| u64::try_from(n).ok());
+            match (key.as_text(), count) {
+                (Some(key), Some(count)) => Ok((key.to_string(), count)),
+                _ => Err(corrupt()),
+            }
+        })
+        .collect()
+}
+
+fn write_counters(path: &Path, counters: &HashMap<String, u64>) -> Result<()> {
+    let map = Value::Map(
+        counters
+            .iter()
+            .map(|(k, v)| (Value::Text(k.clone()), Value::Integer((*v).into())))
+            .collect(),
+    );
+    write_cbor(path, &map)
+}
+
+/// AES-256-GCM keyed with Argon2id from a passphrase and salt
+fn passphrase_cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Cannot derive key: {}", e))?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+/// Write the state files of a state directory, encrypted, to one file
+fn export_state(state_dir: &Path, path: &Path, passphrase: &str) -> Result<()> {
+    let mut files = Vec::new();
+    for name in STATE_FILES {
+        match fs::read(state_dir.join(name)) {
+            Ok(bytes) => files.push((Value::Text(name.into()), Value::Bytes(bytes))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let mut plaintext = Vec::new();
+    ciborium::into_writer(&Value::Map(files), &mut plaintext)?;
+
+    let salt = rand::thread_rng().gen::<[u8; 16]>();
+    let nonce = rand::thread_rng().gen::<[u8; HISTORY_NONCE_LEN]>();
+    let ciphertext = passphrase_cipher(passphrase, &salt)?
+        .encrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: &plaintext,
+                aad: EXPORT_AAD,
+            },
+        )
+        .map_err(|_| anyhow!("Cannot encrypt the identity"))?;
+    let export = Value::Map(vec![
+        (Value::Text("salt".into()), Value::Bytes(salt.to_vec())),
+        (Value::Text("nonce".into()), Value::Bytes(nonce.to_vec())),
+        (Value::Text("files".into()), Value::Bytes(ciphertext)),
+    ]);
+    let mut bytes = Vec::new();
+    ciborium::into_writer(&export, &mut bytes)?;
+
+    // Create it private, as it holds the same secrets as the directory
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| anyhow!("Cannot create {}: {}", path.display(), e))?
+        .write_all(&bytes)?;
+    Ok(())
+}
+
+/// Decrypt an identity export and replace the state files of a state
+/// directory with its files; returns the Client ID it holds
+fn import_state(state_dir: &Path, path: &Path, passphrase: &str) -> Result<String> {
+    let corrupt = || anyhow!("{} is not an identity export", path.display());
+    let export = read_cbor(path)?.ok_or_else(|| anyhow!("{} not found", path.display()))?;
+    let field = |key| {
+        map_entry(&export, key)
+            .and_then(Value::as_bytes)
+            .ok_or_else(corrupt)
+    };
+    let (salt, nonce, ciphertext) = (field("salt")?, field("nonce")?, field("files")?);
+    let nonce: [u8; HISTORY_NONCE_LEN] = nonce.as_slice().try_into().map_err(|_| corrupt())?;
+    let plaintext = passphrase_cipher(passphrase, salt)?
+        .decrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: EXPORT_AAD,
+            },
+        )
+        .map_err(|_| anyhow!("Cannot decrypt {} (wrong passphrase?)", path.display()))?;
+    let files: Value = ciborium::from_reader(plaintext.as_slice())?;
+
+    let mut contents = HashMap::new();
+    for (name, bytes) in files.as_map().ok_or_else(corrupt)? {
+        match (name.as_text(), bytes.as_bytes()) {
+            (Some(name), Some(bytes)) if STATE_FILES.contains(&name) => {
+                contents.insert(name, bytes);
+            }
+            _ => return Err(corrupt()),
+        }
+    }
+    let identity: Value = ciborium::from_reader(
+        contents
+            .get("identity.cbor")
+            .ok_or_else(corrupt)?
+            .as_slice(),
+    )?;
+    let client_id = map_entry(&identity, "client_id")
+        .and_then(Value::as_text)
+        .ok_or_else(corrupt)?
+        .to_string();
+
+    // Files the export lacks would otherwise be left from the old identity
+    for name in STATE_FILES {
+        let path = state_dir.join(name);
+        match contents.get(name) {
+            Some(bytes) => write_private(&path, bytes)?,
+            None => match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            },
+        }
+    }
+    Ok(client_id)
+}
+
+fn map_entry<'a>(map: &'a Value, key: &str) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(k, _)| k.as_text() == Some(key))
+        .map(|(_, v)| v)
+}
+
+// ============================================================================
+// Aliases
+// ============================================================================
+//
+// Local nicknames for peers. An alias is accepted wherever a peer ID is and
+// shown instead of the ID in output.
+
+#[derive(Default)]
+struct Aliases(HashMap<String, String>); // peer_id -> alias
+
+impl Aliases {
+    /// Peer ID an alias stands for, or the query itself
+    fn resolve<'a>(&'a self, query: &'a str) -> &'a str {
+        self.0
+            .iter()
+            .find(|(_, alias)| *alias == query)
+            .map(|(peer_id, _)| peer_id.as_str())
+            .unwrap_or(query)
+    }
+
+    /// A peer's alias, or its ID if it has none
+    fn name<'a>(&'a self, peer_id: &'a str) -> &'a str {
+        self.0.get(peer_id).map(String::as_str).unwrap_or(peer_id)
+    }
+}
+
+// ============================================================================
+// Payload Kinds
+// ============================================================================
+//
+// Every publish is labelled with MQTT v5 properties, so brokers and tools can
+// tell payloads apart without parsing them:
+//   content type   "message/mls" (RFC 9420 Section 17.10) for MLS messages,
+//                  "application/cbor" for KeyPackage arrays, "text/plain"
+//                  for presence
+//   user property  ("relay-kind", kind()), e.g. "welcome" or "commit"
+// Welcomes and KeyPackages carry a message expiry, so a broker drops them
+// once they can't be used anyway.
+
+#[derive(Clone, Copy)]
+enum PayloadKind {
+    KeyPackages,
+    Welcome,
+    Commit,
+    Proposal,
+    Application,
+    GroupInfo,
+    Presence,
+    Channel,
+    Directory,
+}
+
+impl PayloadKind {
+    fn kind(self) -> &'static str {
+        match self {
+            PayloadKind::KeyPackages => "key-packages",
+            PayloadKind::Welcome => "welcome",
+            PayloadKind::Commit => "commit",
+            PayloadKind::Proposal => "proposal",
+            PayloadKind::Application => "application",
+            PayloadKind::GroupInfo => "group-info",
+            PayloadKind::Presence => "presence",
+            PayloadKind::Channel => "channel",
+            PayloadKind::Directory => "directory",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            PayloadKind::KeyPackages | PayloadKind::Channel | PayloadKind::Directory => {
+                "application/cbor"
+            }
+            PayloadKind::Presence => "text/plain",
+            _ => "message/mls",
+        }
+    }
+
+    /// KeyPackages, GroupInfo, presence and directory entries are kept by
+    /// the broker for new subscribers
+    fn retained(self) -> bool {
+        matches!(
+            self,
+            PayloadKind::KeyPackages
+                | PayloadKind::GroupInfo
+                | PayloadKind::Presence
+                | PayloadKind::Channel
+                | PayloadKind::Directory
+        )
+    }
+
+    fn properties(self) -> PublishProperties {
+        let expiry = match self {
+            PayloadKind::KeyPackages | PayloadKind::Welcome => Some(KEY_PACKAGE_LIFETIME),
+            PayloadKind::GroupInfo | PayloadKind::Directory => Some(RETAINED_EXPIRY),
+            _ => None,
+        };
+        PublishProperties {
+            payload_format_indicator: matches!(self, PayloadKind::Presence).then_some(1),
+            message_expiry_interval: expiry.map(|e| e.as_secs() as u32),
+            content_type: Some(self.content_type().to_string()),
+            user_properties: vec![(KIND_PROPERTY.to_string(), self.kind().to_string())],
+            ..Default::default()
+        }
+    }
+}
+
+// ============================================================================
+// Application State
+// ============================================================================
+
+struct RelayClient {
+    // MLS
+    backend: OpenMlsRustCrypto,
+    client_id: String,
+    signer: SignatureKeyPair,
+    credential: CredentialWithKey,
+
+    // MQTT
+    mqtt: AsyncClient,
+    broker: String, // as Config::broker_name
+    topic_prefix: String,
+    namespaces: Vec<String>, // further topic prefixes; peers there are named id@prefix
+    padding: Vec<usize>,     // sizes application messages are padded to
+    qos: QosLevels,          // QoS of the publishes that may be lost
+    rekey_secs: Option<u64>, // seconds between our automatic key updates
+    heartbeat: Option<Duration>, // silence in a group after which we send a heartbeat
+    prune_after: u64,        // seconds without a message before `prune` ends a session
+    prefer_protobuf: bool,   // whether we send protobuf envelopes to members reading both
+    typing_indicators: bool, // whether we send typing indicators
+    auto_accept: bool,       // whether Welcomes from known contacts are joined unasked
+    read_receipts: bool,     // whether we report messages as read (TUI and daemon)
+    notifications: bool,     // whether we raise desktop notifications (TUI and daemon)
+    muted: Vec<String>,      // peer_ids whose messages raise no notification
+    blocked: Vec<String>,    // peer_ids whose traffic is dropped
+    silenced: BTreeMap<String, i64>, // peer_id -> Unix time dropping their traffic ends
+    focused: Option<String>, // group_id of the conversation in view
+    config_path: PathBuf,    // where `mute` and `block` save the peers
+    profile: Option<String>, // name given with --profile, shown in the prompt
+    directory_name: Option<String>, // display name we list in the peer directory
+    upload_command: Option<String>, // stores an attachment, see run_transfer_command
+    download_command: Option<String>,
+    bot: Option<Bot>,                      // answers incoming messages
+    bot_replied: HashMap<String, Instant>, // group_id -> when the bot last replied
+    wildcard_groups: bool,                 // subscribed to g/+/m instead of each group
+    unknown_group_messages: u64,           // messages dropped for groups we are not in
+    connected: bool,
+    rtt: Option<Duration>,      // round-trip time of the last keepalive ping
+    last_pong: Option<Instant>, // when the broker last answered a ping
+    rejected_publishes: u64,    // publishes the broker refused to take
+    metrics: Metrics,
+    own_key_packages: Vec<KeyPackage>, // published: one-time ones, then the last resort one
+    withdrawn: bool,                   // our KeyPackages were cleared from the broker
+    retained_refreshed: Instant,       // GroupInfo and directory entry last published
+
+    // Persistence
+    state_dir: PathBuf,
+    history: Option<History>,
+
+    // State
+    key_packages: HashMap<String, Vec<KeyPackage>>, // peer_id -> their unused KeyPackages
+    used_key_packages: HashMap<String, Vec<KeyPackageRef>>, // peer_id -> one-time ones we used
+    groups: HashMap<String, MlsGroup>,              // group_id (hex) -> MlsGroup
+    group_namespaces: HashMap<String, String>,      // group_id -> prefix, if not topic_prefix
+    sessions: HashMap<String, String>,              // peer_id -> group_id (1:1 sessions)
+    group_names: HashMap<String, String>,           // name -> group_id (named groups)
+    broadcast: HashSet<String>,                     // group_ids only the creator writes in
+    pending_connects: Vec<String>,                  // peer_ids waiting for KeyPackage
+    pending_invites: Vec<(String, String)>,         // (group_id, peer_id) waiting for KeyPackage
+    channel_lookups: HashMap<String, ChannelLookup>, // #name -> lookup for channel create/join
+    directory_lookup: Option<DirectoryLookup>,      // the running `find`
+    invitations: Vec<Invitation>,                   // Welcomes waiting for accept or decline
+    contested: Vec<(Invitation, Instant)>, // Welcomes to duplicate sessions, see hold_contested
+    future_messages: HashMap<String, Vec<(u64, Instant, Vec<u8>)>>, // group_id -> (epoch, arrival, message) ahead of us
+    send_seq: HashMap<String, u64>, // group_id -> our next sequence number
+    key_updates: HashMap<String, u64>, // group_id -> Unix time of our last key update
+    receive_order: HashMap<(String, String), SenderOrder>, // (group_id, sender) -> incoming order
+    own_messages: HashMap<String, VecDeque<Vec<u8>>>, // group_id -> hashes of our messages not yet echoed
+    aliases: Aliases,
+    verified: HashMap<String, String>, // peer_id -> verified signature key (hex)
+    presence: HashMap<String, bool>,   // peer_id -> online, as last published
+    last_seen: HashMap<String, u64>,   // peer_id -> Unix time of their last group message
+    last_sent: HashMap<String, Instant>, // group_id -> when we last sent it anything
+    credential_changes: HashMap<(String, String), u64>, // (group_id, member) -> epoch of change
+    typing: HashMap<(String, String), Instant>, // (group_id, sender) -> when they last typed
+    typing_sent: HashMap<String, Instant>, // group_id -> our last typing indicator
+    unread: HashMap<String, Vec<Vec<u8>>>, // group_id -> IDs of messages not yet reported read
+    receipts: HashMap<Vec<u8>, BTreeMap<String, bool>>, // our message ID -> member -> read
+    member_caps: HashMap<(String, String), Vec<String>>, // (group_id, member) -> envelope features
+    unsent: VecDeque<Unsent>, // encrypted messages waiting to be published, see retry_unsent
+    max_payload: usize,       // larger publishes go in chunks, see publish_with_qos
+    reassemblies: HashMap<(String, [u8; 8]), Reassembly>, // (topic, payload ID) -> chunks so far
+}
+
+/// The chunks of a payload that arrived so far
+struct Reassembly {
+    chunks: Vec<Option<Vec<u8>>>,
+    bytes: usize,
+    since: Instant,
+}
+
+/// An application message whose publish failed, kept to be published again
+struct Unsent {
+    group_id: String,
+    id: Vec<u8>, // the message's ID
+    shown: bool, // a message the user sees, rather than a receipt or indicator
+    qos: QoS,
+    payload: Vec<u8>,
+    failures: u32,
+    next_try: Instant,
+}
+
+// ============================================================================
+// Initialization
+// ============================================================================
+
+impl RelayClient {
+    fn new(config: &Config, state_dir: PathBuf) -> Result<(Self, EventLoop)> {
+        create_private_dir(&state_dir)?;
+        let backend = OpenMlsRustCrypto::default();
+
+        // Restore the OpenMLS storage (signer and group secrets)
+        if let Some(storage) = read_cbor(&state_dir.join("storage.cbor"))? {
+            let entries = storage
+                .as_map()
+                .ok_or_else(|| anyhow!("Corrupt storage.cbor"))?;
+            let mut values = backend.storage().values.write().unwrap();
+            for (key, value) in entries {
+                match (key.as_bytes(), value.as_bytes()) {
+                    (Some(key), Some(value)) => values.insert(key.clone(), value.clone()),
+                    _ => return Err(anyhow!("Corrupt storage.cbor")),
+                };
+            }
+        }
+
+        // Restore the client identity, or generate one on first run
+        let (client_id, signer) = match read_cbor(&state_dir.join("identity.cbor"))? {
+            Some(identity) => {
+                let client_id = map_entry(&identity, "client_id")
+                    .and_then(|v| v.as_text())
+                    .ok_or_else(|| anyhow!("Corrupt identity.cbor"))?
+                    .to_string();
+                if config.client_id.as_ref().is_some_and(|id| *id != client_id) {
+                    return Err(anyhow!(
+                        "State directory belongs to client {}; use another directory",
+                        client_id
+                    ));
+                }
+                let signature_key = map_entry(&identity, "signature_key")
+                    .and_then(|v| v.as_bytes())
+                    .ok_or_else(|| anyhow!("Corrupt identity.cbor"))?;
+                let signer = SignatureKeyPair::read(
+                    backend.storage(),
+                    signature_key,
+                    CIPHERSUITE.signature_algorithm(),
+                )
+                .ok_or_else(|| anyhow!("Signer missing from storage"))?;
+                (client_id, signer)
+            }
+            None => {
+                let client_id = config
+                    .client_id
+                    .clone()
+                    .unwrap_or_else(|| hex::encode(rand::thread_rng().gen::<[u8; 16]>()));
+                let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
+                    .map_err(|e| anyhow!("KeyGen error: {:?}", e))?;
+                signer
+                    .store(backend.storage())
+                    .map_err(|e| anyhow!("Storage error: {:?}", e))?;
+                (client_id, signer)
+            }
+        };
+        let credential = BasicCredential::new(client_id.clone().into_bytes());
+
+        // Reload sessions and named groups from storage
+        let mut groups = HashMap::new();
+        let mut sessions = read_index(&state_dir.join("groups.cbor"))?;
+        let mut group_names = read_index(&state_dir.join("names.cbor"))?;
+        for group_id in sessions.values().chain(group_names.values()) {
+            let group_id_bytes = hex::decode(group_id)?;
+            if let Some(group) =
+                MlsGroup::load(backend.storage(), &GroupId::from_slice(&group_id_bytes))?
+            {
+                groups.insert(group_id.clone(), group);
+            }
+        }
+        sessions.retain(|_, group_id| groups.contains_key(group_id));
+        group_names.retain(|_, group_id| groups.contains_key(group_id));
+        let mut group_namespaces = read_index(&state_dir.join("namespaces.cbor"))?;
+        group_namespaces.retain(|group_id, _| groups.contains_key(group_id));
+        let broadcast = match read_cbor(&state_dir.join("broadcast.cbor"))? {
+            Some(Value::Array(array)) => array
+                .iter()
+                .map(|v| v.as_text().map(str::to_string))
+                .collect::<Option<HashSet<String>>>()
+                .ok_or_else(|| anyhow!("Corrupt broadcast.cbor"))?,
+            Some(_) => return Err(anyhow!("Corrupt broadcast.cbor")),
+            None => HashSet::new(),
+        };
+        let broadcast = broadcast
+            .into_iter()
+            .filter(|group_id| groups.contains_key(group_id))
+            .collect();
+        let mut send_seq = read_counters(&state_dir.join("seq.cbor"))?;
+        send_seq.retain(|group_id, _| groups.contains_key(group_id));
+        let mut key_updates = read_counters(&state_dir.join("updates.cbor"))?;
+        key_updates.retain(|group_id, _| groups.contains_key(group_id));
+        let aliases = Aliases(read_index(&state_dir.join("aliases.cbor"))?);
+        let verified = read_index(&state_dir.join("verified.cbor"))?;
+        // Peers of sessions from before last-seen times were kept count from
+        // now
+        let mut last_seen = read_counters(&state_dir.join("seen.cbor"))?;
+        let now = Local::now().timestamp() as u64;
+        for peer_id in sessions.keys() {
+            last_seen.entry(peer_id.clone()).or_insert(now);
+        }
+        let own_key_packages = match read_cbor(&state_dir.join("keypackages.cbor"))? {
+            // Expired ones are dropped, and replaced when publishing
+            Some(Value::Array(array)) => decode_key_packages(&array, backend.crypto())
+                .into_iter()
+                .flatten()
+                .collect(),
+            Some(_) => return Err(anyhow!("Corrupt keypackages.cbor")),
+            None => Vec::new(),
+        };
+        let mut receive_order = HashMap::new();
+        for (key, next) in read_counters(&state_dir.join("order.cbor"))? {
+            // Group IDs are hex, so the first '/' ends the group ID
+            let (group_id, sender) = key
+                .split_once('/')
+                .ok_or_else(|| anyhow!("Corrupt order.cbor"))?;
+            if groups.contains_key(group_id) {
+                let order = SenderOrder {
+                    next: Some(next),
+                    ..Default::default()
+                };
+                receive_order.insert((group_id.to_string(), sender.to_string()), order);
+            }
+        }
+
+        let credential = CredentialWithKey {
+            credential: credential.into(),
+            signature_key: signer.public().into(),
+        };
+
+        let history = config
+            .history
+            .then(|| {
+                History::open(
+                    state_dir.join("history"),
+                    config.history_passphrase.as_deref(),
+                )
+            })
+            .transpose()?;
+
+        // Connect to MQTT broker
+        let mut options = MqttOptions::new(&client_id, config.broker_addr(), config.port());
+        options.set_keep_alive(Duration::from_secs(config.keep_alive));
+        // Without a limit, as many as the broker's Receive Maximum
+        if let Some(max_inflight) = config.max_inflight {
+            options.set_outgoing_inflight_upper_limit(max_inflight);
+        }
+        // Keep the session at the broker so messages published while we are
+        // offline are delivered on the next connect. Messages are only
+        // acknowledged once handled and saved (see main).
+        options.set_clean_start(false);
+        options.set_manual_acks(true);
+        options.set_transport(config.transport()?);
+        // Aliases let the broker send a topic only once per connection. We
+        // don't alias our own publishes: rumqttc resends unacknowledged ones
+        // unchanged after a reconnect, when the alias is no longer known.
+        let mut properties = ConnectProperties::new();
+        properties.session_expiry_interval = Some(SESSION_EXPIRY.as_secs() as u32);
+        properties.topic_alias_max = Some(TOPIC_ALIAS_MAX);
+        options.set_connect_properties(properties);
+        // The broker marks us offline when the connection drops without a
+        // DISCONNECT, e.g. when the client crashes
+        let will = PayloadKind::Presence.properties();
+        options.set_last_will(LastWill::new(
+            format!("{}/p/{}", config.topic_prefix, client_id),
+            PRESENCE_OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+            Some(LastWillProperties {
+                delay_interval: None,
+                payload_format_indicator: will.payload_format_indicator,
+                message_expiry_interval: None,
+                content_type: will.content_type,
+                response_topic: None,
+                correlation_data: None,
+                user_properties: will.user_properties,
+            }),
+        ));
+        // MQTT has no password without a username
+        if config.username.is_some() || config.password.is_some() {
+            let username = config.username.as_deref().unwrap_or(&client_id);
+            options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+        }
+        let (mqtt, eventloop) = AsyncClient::new(options, config.request_queue);
+
+        Ok((
+            Self {
+                backend,
+                client_id,
+                signer,
+                credential,
+                mqtt,
+                broker: config.broker_name(),
+                topic_prefix: config.topic_prefix.clone(),
+                namespaces: config.namespaces.clone(),
+                padding: config.padding.clone(),
+                qos: config.qos,
+                rekey_secs: (config.rekey_days > 0).then(|| config.rekey_days * 24 * 60 * 60),
+                heartbeat: (config.heartbeat_minutes > 0)
+                    .then(|| Duration::from_secs(config.heartbeat_minutes * 60)),
+                prune_after: config.prune_days * 24 * 60 * 60,
+                prefer_protobuf: config.envelope == "protobuf",
+                typing_indicators: config.typing_indicators,
+                auto_accept: config.auto_accept,
+                read_receipts: false,
+                notifications: false,
+                muted: config.muted.clone(),
+                blocked: config.blocked.clone(),
+                silenced: config.silenced.clone(),
+                focused: None,
+                config_path: config.path.clone(),
+                profile: None,
+                directory_name: config.directory_name.clone(),
+                upload_command: config.upload_command.clone(),
+                download_command: config.download_command.clone(),
+                bot: config.responder().map(Bot::spawn),
+                bot_replied: HashMap::new(),
+                wildcard_groups: config.wildcard_groups,
+                unknown_group_messages: 0,
+                connected: false,
+                rtt: None,
+                last_pong: None,
+                rejected_publishes: 0,
+                metrics: Metrics::default(),
+                own_key_packages,
+                withdrawn: false,
+                retained_refreshed: Instant::now(),
+                history,
+                state_dir,
+                key_packages: HashMap::new(),
+                used_key_packages: HashMap::new(),
+                groups,
+                group_namespaces,
+                sessions,
+                group_names,
+                broadcast,
+                pending_connects: Vec::new(),
+                pending_invites: Vec::new(),
+                channel_lookups: HashMap::new(),
+                directory_lookup: None,
+                invitations: Vec::new(),
+                contested: Vec::new(),
+                future_messages: HashMap::new(),
+                own_messages: HashMap::new(),
+                send_seq,
+                key_updates,
+                receive_order,
+                aliases,
+                verified,
+                presence: HashMap::new(),
+                last_seen,
+                last_sent: HashMap::new(),
+                credential_changes: HashMap::new(),
+                typing: HashMap::new(),
+                typing_sent: HashMap::new(),
+                unread: HashMap::new(),
+                receipts: HashMap::new(),
+                member_caps: HashMap::new(),
+                unsent: VecDeque::new(),
+                max_payload: config.max_payload,
+                reassemblies: HashMap::new(),
+            },
+            eventloop,
+        ))
+    }
+
+    /// The main namespace (None), then the others
+    fn all_namespaces(&self) -> impl Iterator<Item = Option<&str>> {
+        std::iter::once(None).chain(self.namespaces.iter().map(|n| Some(n.as_str())))
+    }
+
+    /// Full MQTT topic for a path in a namespace (None: the main prefix)
+    fn topic_in(&self, namespace: Option<&str>, path: &str) -> String {
+        format!("{}/{}", namespace.unwrap_or(&self.topic_prefix), path)
+    }
+
+    /// The namespace of a topic and the path below it; the longest prefix
+    /// wins, as one may extend another
+    fn split_topic<'a>(&self, topic: &'a str) -> Option<(Option<String>, &'a str)> {
+        self.all_namespaces()
+            .filter_map(|namespace| {
+                let prefix = namespace.unwrap_or(&self.topic_prefix);
+                let path = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+                Some((prefix.len(), namespace.map(str::to_string), path))
+            })
+            .max_by_key(|(len, _, _)| *len)
+            .map(|(_, namespace, path)| (namespace, path))
+    }
+
+    /// Split a peer name into its namespace and Client ID
+    fn split_peer<'a>(&self, peer: &'a str) -> (Option<&str>, &'a str) {
+        match peer.rsplit_once('@') {
+            Some((client_id, namespace)) => {
+                match self.namespaces.iter().find(|n| *n == namespace) {
+                    Some(namespace) => (Some(namespace), client_id),
+                    None => (None, peer),
+                }
+            }
+            None => (None, peer),
+        }
+    }
+
+    /// Topic of a peer's KeyPackages ("k"), Welcomes ("w") or presence ("p")
+    fn peer_topic(&self, kind: &str, peer: &str) -> String {
+        let (namespace, client_id) = self.split_peer(peer);
+        self.topic_in(namespace, &format!("{}/{}", kind, client_id))
+    }
+
+    /// Publish with the QoS and properties of its kind of payload
+    fn publish(&self, topic: String, kind: PayloadKind, payload: Vec<u8>) -> Result<()> {
+        self.publish_with_qos(topic, kind, self.qos.of(kind, None), payload)
+    }
+
+    /// Payloads over max_payload, which brokers may refuse, go out in
+    /// chunks that receivers put back together (see handle_chunk). A
+    /// retained message can't be chunked, as the broker keeps only the last
+    /// one of a topic.
+    fn publish_with_qos(
+        &self,
+        topic: String,
+        kind: PayloadKind,
+        qos: QoS,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        if payload.len() <= self.max_payload {
+            self.mqtt.try_publish_with_properties(
+                topic,
+                qos,
+                kind.retained(),
+                payload,
+                kind.properties(),
+            )?;
+            return Ok(());
+        }
+        let size = self.max_payload - CHUNK_HEADER_LEN;
+        let count = payload.len().div_ceil(size);
+        if kind.retained() || count > u16::MAX as usize {
+            return Err(anyhow!(
+                "A {} of {} bytes is too large to publish",
+                kind.kind(),
+                payload.len()
+            ));
+        }
+        debug!(topic, bytes = payload.len(), count, "Publishing in chunks");
+        let id: [u8; 8] = rand::thread_rng().gen();
+        for (index, data) in payload.chunks(size).enumerate() {
+            self.mqtt.try_publish_with_properties(
+                topic.clone(),
+                qos,
+                false,
+                encode_chunk(&id, index as u16, count as u16, data),
+                kind.properties(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Publish an MLS message to a group, remembering it so the copy the
+    /// broker sends back to us is skipped
+    fn publish_to_group(
+        &mut self,
+        group_id: &str,
+        kind: PayloadKind,
+        message: Vec<u8>,
+    ) -> Result<()> {
+        self.publish_to_group_with_qos(group_id, kind, self.qos.of(kind, None), message)
+    }
+
+    fn publish_to_group_with_qos(
+        &mut self,
+        group_id: &str,
+        kind: PayloadKind,
+        qos: QoS,
+        message: Vec<u8>,
+    ) -> Result<()> {
+        self.remember_own(group_id, &message)?;
+        self.publish_with_qos(self.group_topic(group_id, "m"), kind, qos, message)
+    }
+
+    /// Note a message we publish to a group, so the copy the broker sends
+    /// back to us is skipped
+    fn remember_own(&mut self, group_id: &str, message: &[u8]) -> Result<()> {
+        let hash = self.backend.crypto().hash(HashType::Sha2_256, message)?;
+        let sent = self.own_messages.entry(group_id.to_string()).or_default();
+        if sent.len() == DEDUP_WINDOW {
+            sent.pop_front();
+        }
+        sent.push_back(hash);
+        Ok(())
+    }
+
+    /// Topic of a group's messages ("m") or GroupInfo ("i")
+    fn group_topic(&self, group_id: &str, kind: &str) -> String {
+        let namespace = self.group_namespaces.get(group_id).map(String::as_str);
+        self.topic_in(namespace, &format!("g/{}/{}", group_id, kind))
+    }
+
+    /// The name of a member of a group, as peers are named; we are always
+    /// our plain Client ID
+    fn member_name(&self, group_id: &str, credential: &Credential) -> String {
+        let client_id = member_id(credential);
+        if client_id == self.client_id {
+            return client_id;
+        }
+        qualified(
+            client_id,
+            self.group_namespaces.get(group_id).map(String::as_str),
+        )
+    }
+
+    /// Replace our KeyPackages older than KEY_PACKAGE_MAX_AGE, top them up
+    /// to KEY_PACKAGE_POOL one-time ones and a last resort one (protocol
+    /// Section 6.4), and publish them
+    #[instrument(skip_all)]
+    fn publish_key_packages(&mut self) -> Result<()> {
+        let (mut pool, mut last_resort): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.own_key_packages)
+                .into_iter()
+                .filter(|kp| !key_package_due(kp))
+                .partition(|kp| !kp.last_resort());
+        while pool.len() < KEY_PACKAGE_POOL {
+            pool.push(self.new_key_package(false)?);
+        }
+        if last_resort.is_empty() {
+            last_resort.push(self.new_key_package(true)?);
+        }
+        pool.extend(last_resort);
+        self.own_key_packages = pool;
+        self.republish_key_packages()
+    }
+
+    fn new_key_package(&self, last_resort: bool) -> Result<KeyPackage> {
+        let mut builder = KeyPackage::builder()
+            .key_package_lifetime(Lifetime::new(KEY_PACKAGE_LIFETIME.as_secs()));
+        if last_resort {
+            // Peers reject extensions the leaf does not declare support for
+            builder = builder
+                .mark_as_last_resort()
+                .leaf_node_capabilities(Capabilities::new(
+                    None,
+                    None,
+                    Some(&[ExtensionType::LastResort]),
+                    None,
+                    None,
+                ));
+        }
+        let bundle = builder.build(
+            CIPHERSUITE,
+            &self.backend,
+            &self.signer,
+            self.credential.clone(),
+        )?;
+        Ok(bundle.key_package().clone())
+    }
+
+    /// Our KeyPackages as a KeyPackage array (protocol Section 5)
+    fn key_package_array(&self) -> Result<Value> {
+        let entries = self
+            .own_key_packages
+            .iter()
+            .map(|kp| {
+                Ok(Value::Bytes(
+                    MlsMessageOut::from(kp.clone()).tls_serialize_detached()?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Value::Array(entries))
+    }
+
+    /// Publish our current KeyPackages again, e.g. in case the broker lost
+    /// its retained messages while we were disconnected. Every namespace
+    /// gets the same ones.
+    fn republish_key_packages(&self) -> Result<()> {
+        if self.withdrawn {
+            return Ok(());
+        }
+        let mut cbor = Vec::new();
+        ciborium::into_writer(&self.key_package_array()?, &mut cbor)?;
+        for namespace in self.all_namespaces() {
+            self.publish(
+                self.topic_in(namespace, &format!("k/{}", self.client_id)),
+                PayloadKind::KeyPackages,
+                cbor.clone(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Clear the retained messages we own from the broker: our KeyPackages
+    /// in every namespace, and the GroupInfo of the groups we are the only
+    /// member of. Channels keep theirs for joiners. KeyPackages are not
+    /// published again until the client restarts.
+    fn cleanup(&mut self) -> Result<()> {
+        // An empty retained message removes the retained one
+        for namespace in self.all_namespaces() {
+            self.publish(
+                self.topic_in(namespace, &format!("k/{}", self.client_id)),
+                PayloadKind::KeyPackages,
+                Vec::new(),
+            )?;
+        }
+        self.withdrawn = true;
+        self.publish(
+            self.directory_topic(&self.client_id),
+            PayloadKind::Directory,
+            Vec::new(),
+        )?;
+        let alone: Vec<&String> = self
+            .groups
+            .iter()
+            .filter(|(group_id, group)| group.members().count() == 1 && !self.is_channel(group_id))
+            .map(|(group_id, _)| group_id)
+            .collect();
+        for group_id in &alone {
+            self.clear_group_info(group_id)?;
+        }
+        log(&format!(
+            "Withdrew our KeyPackages, directory entry and the GroupInfo of {} group(s); \
+             nobody can invite us until the client restarts",
+            alone.len()
+        ));
+        Ok(())
+    }
+
+    /// Replace our KeyPackages once they are due; checked from the main loop
+    fn refresh_key_packages(&mut self) -> Result<()> {
+        if self.own_key_packages.iter().any(key_package_due) {
+            info!("Refreshing our KeyPackages");
+            self.publish_key_packages()?;
+            self.save_state()?;
+        }
+        Ok(())
+    }
+
+    /// Publish the GroupInfo of our groups and our directory entry again
+    /// before the broker lets them expire; checked from the main loop.
+    /// What `cleanup` withdrew stays withdrawn.
+    fn refresh_retained(&mut self, now: Instant) -> Result<()> {
+        if !self.connected || now.duration_since(self.retained_refreshed) < RETAINED_REFRESH {
+            return Ok(());
+        }
+        self.retained_refreshed = now;
+        let group_ids: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(group_id, group)| {
+                !self.withdrawn || group.members().count() > 1 || self.is_channel(group_id)
+            })
+            .map(|(group_id, _)| group_id.clone())
+            .collect();
+        info!(groups = group_ids.len(), "Refreshing our retained messages");
+        for group_id in &group_ids {
+            self.publish_group_info(group_id)?;
+        }
+        if !self.withdrawn {
+            self.publish_directory_entry()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the KeyPackage a Welcome used and publish a replacement; returns
+    /// whether it was a last resort one. A replaced last resort KeyPackage
+    /// keeps working, for peers that fetched it before.
+    fn consume_key_package(&mut self, used: &[KeyPackageRef]) -> Result<bool> {
+        let crypto = self.backend.crypto();
+        let Some(pos) = self
+            .own_key_packages
+            .iter()
+            .position(|kp| kp.hash_ref(crypto).is_ok_and(|r| used.contains(&r)))
+        else {
+            // One-time KeyPackages only work once, so this was a replaced
+            // last resort one
+            return Ok(true);
+        };
+        let last_resort = self.own_key_packages.remove(pos).last_resort();
+        debug!(
+            last_resort,
+            left = self.own_key_packages.len(),
+            "A Welcome used one of our KeyPackages"
+        );
+        self.publish_key_packages()?;
+        Ok(last_resort)
+    }
+
+    fn subscribe_welcome(&self) -> Result<()> {
+        for namespace in self.all_namespaces() {
+            self.mqtt.try_subscribe(
+                self.topic_in(namespace, &format!("w/{}", self.client_id)),
+                QoS::AtLeastOnce,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Resubscribe to the groups restored from the state directory, or to
+    /// the messages of all groups with --wildcard-groups
+    fn subscribe_groups(&self) -> Result<()> {
+        if self.wildcard_groups {
+            for namespace in self.all_namespaces() {
+                self.mqtt
+                    .try_subscribe(self.topic_in(namespace, "g/+/m"), QoS::AtLeastOnce)?;
+            }
+            return Ok(());
+        }
+        for group_id in self.groups.keys() {
+            self.subscribe_group(group_id)?;
+        }
+        Ok(())
+    }
+
+    /// Subscribe to a group's messages, unless the wildcard covers them
+    fn subscribe_group(&self, group_id: &str) -> Result<()> {
+        if !self.wildcard_groups {
+            self.mqtt
+                .try_subscribe(self.group_topic(group_id, "m"), QoS::AtLeastOnce)?;
+        }
+        Ok(())
+    }
+
+    /// Follow the presence of the peers we have sessions with
+    fn subscribe_presence(&self) -> Result<()> {
+        for peer_id in self.sessions.keys() {
+            self.mqtt
+                .try_subscribe(self.peer_topic("p", peer_id), QoS::AtLeastOnce)?;
+        }
+        Ok(())
+    }
+
+    /// Mark us online or offline in every namespace (retained). The Last
+    /// Will only covers the main one, as MQTT allows one per connection.
+    fn publish_presence(&self, online: bool) -> Result<()> {
+        let status = if online {
+            PRESENCE_ONLINE
+        } else {
+            PRESENCE_OFFLINE
+        };
+        for namespace in self.all_namespaces() {
+            self.publish(
+                self.topic_in(namespace, &format!("p/{}", self.client_id)),
+                PayloadKind::Presence,
+                status.into(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Restore subscriptions and the KeyPackage after a reconnect; the broker
+    /// may have dropped our session in the meantime
+    #[instrument(skip_all)]
+    fn recover_session(&self) -> Result<()> {
+        self.subscribe_welcome()?;
+        self.subscribe_groups()?;
+        self.subscribe_presence()?;
+        let waiting = self
+            .pending_connects
+            .iter()
+            .chain(self.pending_invites.iter().map(|(_, peer_id)| peer_id));
+        for peer_id in waiting {
+            self.mqtt
+                .try_subscribe(self.peer_topic("k", peer_id), QoS::AtLeastOnce)?;
+        }
+        self.republish_key_packages()
+    }
+
+    /// Print the input prompt, marked while the broker is unreachable
+    fn prompt(&self) -> Result<()> {
+        if !plain_output() {
+            return Ok(());
+        }
+        let profile = self.profile.as_deref().unwrap_or_default();
+        let prompt = if self.connected {
+            format!("{}> ", profile)
+        } else {
+            format!("[offline] {}> ", profile)
+        };
+        // The line editor shows it with the next line it reads
+        if let Some(editor) = &mut *LINE_EDITOR.lock().unwrap() {
+            editor.prompt = prompt;
+            return Ok(());
+        }
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        Ok(())
+    }
+
+    /// Peer IDs, aliases and group names, for tab completion
+    fn completion_names(&self) -> Vec<String> {
+        let peers = self.sessions.keys().chain(self.key_packages.keys());
+        let adders = self.invitations.iter().map(|i| &i.adder);
+        let mut names: Vec<String> = peers
+            .chain(adders)
+            .chain(self.aliases.0.values())
+            .chain(self.group_names.keys())
+            .cloned()
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    /// Write identity, OpenMLS storage and group indexes to the state directory
+    fn save_state(&self) -> Result<()> {
+        let identity = Value::Map(vec![
+            (
+                Value::Text("client_id".into()),
+                Value::Text(self.client_id.clone()),
+            ),
+            (
+                Value::Text("signature_key".into()),
+                Value::Bytes(self.signer.public().to_vec()),
+            ),
+        ]);
+        write_cbor(&self.state_dir.join("identity.cbor"), &identity)?;
+
+        let storage = Value::Map(
+            self.backend
+                .storage()
+                .values
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (Value::Bytes(k.clone()), Value::Bytes(v.clone())))
+                .collect(),
+        );
+        write_cbor(&self.state_dir.join("storage.cbor"), &storage)?;
+
+        write_index(&self.state_dir.join("groups.cbor"), &self.sessions)?;
+        write_index(&self.state_dir.join("names.cbor"), &self.group_names)?;
+        write_index(
+            &self.state_dir.join("namespaces.cbor"),
+            &self.group_namespaces,
+        )?;
+        write_counters(&self.state_dir.join("seq.cbor"), &self.send_seq)?;
+        write_counters(&self.state_dir.join("updates.cbor"), &self.key_updates)?;
+        write_counters(&self.state_dir.join("seen.cbor"), &self.last_seen)?;
+        let receive_order = self
+            .receive_order
+            .iter()
+            .filter_map(|((group_id, sender), order)| {
+                Some((format!("{}/{}", group_id, sender), order.next?))
+            })
+            .collect();
+        write_counters(&self.state_dir.join("order.cbor"), &receive_order)?;
+        write_index(&self.state_dir.join("aliases.cbor"), &self.aliases.0)?;
+        write_index(&self.state_dir.join("verified.cbor"), &self.verified)?;
+        let broadcast = self.broadcast.iter().cloned().map(Value::Text).collect();
+        write_cbor(
+            &self.state_dir.join("broadcast.cbor"),
+            &Value::Array(broadcast),
+        )?;
+        write_cbor(
+            &self.state_dir.join("keypackages.cbor"),
+            &self.key_package_array()?,
+        )
+    }
+}
+
+// ============================================================================
+// MQTT Message Handlers
+// ============================================================================
+
+/// Decode and validate the entries of a KeyPackage array (protocol
+/// Section 5), skipping those that are not KeyPackages, such as the
+/// capabilities map
+fn decode_key_packages(
+    array: &[Value],
+    crypto: &impl OpenMlsCrypto,
+) -> Vec<Result<KeyPackage, KeyPackageVerifyError>> {
+    array
+        .iter()
+        .filter_map(key_package_bytes)
+        .filter_map(|bytes| {
+            match MlsMessageIn::tls_deserialize(&mut bytes.as_slice())
+                .ok()?
+                .extract()
+            {
+                MlsMessageBodyIn::KeyPackage(kp) => {
+                    Some(kp.validate(crypto, ProtocolVersion::Mls10))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// The bytes of a KeyPackage array entry, a bstr or, from clients that
+/// predate capabilities, an array of uints
+fn key_package_bytes(entry: &Value) -> Option<Vec<u8>> {
+    match entry {
+        Value::Bytes(bytes) => Some(bytes.clone()),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| u8::try_from(item.as_integer()?).ok())
+            .collect(),
+        _ => None,
+    }
+}
+
+/// A chunk of a payload published in chunks: CHUNK_MAGIC, the payload ID,
+/// the chunk's index and the chunk count, then its part of the payload
+fn encode_chunk(id: &[u8; 8], index: u16, count: u16, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(CHUNK_HEADER_LEN + data.len());
+    chunk.extend_from_slice(CHUNK_MAGIC);
+    chunk.extend_from_slice(id);
+    chunk.extend_from_slice(&index.to_be_bytes());
+    chunk.extend_from_slice(&count.to_be_bytes());
+    chunk.extend_from_slice(data);
+    chunk
+}
+
+/// Add a chunk to the payloads being reassembled, by topic and payload
+/// ID; returns the payload once all of its chunks are in. Payloads that
+/// stay incomplete for REASSEMBLY_TIMEOUT are dropped.
+fn add_chunk(
+    reassemblies: &mut HashMap<(String, [u8; 8]), Reassembly>,
+    topic: &str,
+    chunk: &[u8],
+    now: Instant,
+) -> Result<Option<Vec<u8>>> {
+    if chunk.len() <= CHUNK_HEADER_LEN {
+        return Err(anyhow!("Ignored a truncated chunk"));
+    }
+    let id: [u8; 8] = chunk[4..12].try_into().expect("8 bytes");
+    let index = u16::from_be_bytes([chunk[12], chunk[13]]) as usize;
+    let count = u16::from_be_bytes([chunk[14], chunk[15]]) as usize;
+    if index >= count {
+        return Err(anyhow!("Ignored a chunk numbered past its count"));
+    }
+    reassemblies.retain(|_, r| now.duration_since(r.since) < REASSEMBLY_TIMEOUT);
+    let key = (topic.to_string(), id);
+    if !reassemblies.contains_key(&key) && reassemblies.len() >= MAX_REASSEMBLIES {
+        return Err(anyhow!("Dropped a chunk: too many payloads are incomplete"));
+    }
+    let reassembly = reassemblies
+        .entry(key.clone())
+        .or_insert_with(|| Reassembly {
+            chunks: vec![None; count],
+            bytes: 0,
+            since: now,
+        });
+    if reassembly.chunks.len() != count {
+        return Err(anyhow!(
+            "Ignored a chunk whose count doesn't match the others"
+        ));
+    }
+    let data = &chunk[CHUNK_HEADER_LEN..];
+    // A chunk sent twice replaces the copy that came first
+    let replaced = reassembly.chunks[index].as_ref().map_or(0, Vec::len);
+    reassembly.bytes = reassembly.bytes - replaced + data.len();
+    if reassembly.bytes > MAX_REASSEMBLED_SIZE {
+        reassemblies.remove(&key);
+        return Err(anyhow!(
+            "Dropped a chunked payload of over {} bytes",
+            MAX_REASSEMBLED_SIZE
+        ));
+    }
+    reassembly.chunks[index] = Some(data.to_vec());
+    if reassembly.chunks.iter().any(Option::is_none) {
+        return Ok(None);
+    }
+    let reassembly = reassemblies.remove(&key).expect("found above");
+    debug!(topic, count, "Reassembled a chunked payload");
+    Ok(Some(
+        reassembly.chunks.into_iter().flatten().flatten().collect(),
+    ))
+}
+
+/// Whether one of our KeyPackages is older than KEY_PACKAGE_MAX_AGE
+fn key_package_due(kp: &KeyPackage) -> bool {
+    let renew_at = kp
+        .life_time()
+        .not_after()
+        .saturating_sub((KEY_PACKAGE_LIFETIME - KEY_PACKAGE_MAX_AGE).as_secs());
+    Local::now().timestamp() as u64 >= renew_at
+}
+
+/// Client ID carried in a member's BasicCredential
+fn member_id(credential: &Credential) -> String {
+    String::from_utf8_lossy(credential.serialized_content()).to_string()
+}
+
+/// How a peer is named: by Client ID, followed by @prefix outside the main
+/// namespace
+fn qualified(client_id: String, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => format!("{}@{}", client_id, namespace),
+        None => client_id,
+    }
+}
+
+/// Whether an MLS message is an application message, which its framing
+/// says without decrypting it
+fn is_application_message(payload: &[u8]) -> bool {
+    let Ok(msg) = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice()) else {
+        return false;
+    };
+    let protocol_msg = match msg.extract() {
+        MlsMessageBodyIn::PrivateMessage(m) => ProtocolMessage::from(m),
+        MlsMessageBodyIn::PublicMessage(m) => ProtocolMessage::from(m),
+        _ => return false,
+    };
+    protocol_msg.content_type() == ContentType::Application
+}
+
+/// A Welcome from someone who isn't a known contact, waiting for `accept`
+/// or `decline`. Opening it used up our KeyPackage, so it can only be
+/// kept in memory.
+struct Invitation {
+    adder: String,
+    group_id: String,
+    namespace: Option<String>,
+    members: Vec<String>, // besides us
+    staged: StagedWelcome,
+    last_resort: bool,  // came with our last resort KeyPackage
+    held: Vec<Vec<u8>>, // messages of the group that arrived meanwhile
+}
+
+impl RelayClient {
+    /// Pass a publish to the handler for its topic; other topics are ignored
+    fn handle_publish(&mut self, topic: &str, payload: &[u8]) -> Result<()> {
+        // A payload of chunks is handled as a whole, once complete
+        if payload.starts_with(CHUNK_MAGIC) {
+            return match self.handle_chunk(topic, payload)? {
+                Some(whole) if whole.starts_with(CHUNK_MAGIC) => {
+                    Err(anyhow!("Ignored chunks of chunks"))
+                }
+                Some(whole) => self.handle_publish(topic, &whole),
+                None => Ok(()),
+            };
+        }
+        let Some((namespace, path)) = self.split_topic(topic) else {
+            return Ok(());
+        };
+        let namespace = namespace.as_deref();
+        if self.is_from_blocked(namespace, path, payload) {
+            debug!(topic, "Dropped traffic of a blocked peer");
+            return Ok(());
+        }
+        if let Some(client_id) = path.strip_prefix("k/") {
+            self.handle_key_package(namespace, client_id, payload)
+        } else if path.starts_with("w/") {
+            self.handle_welcome(namespace, payload)
+        } else if let Some(client_id) = path.strip_prefix("p/") {
+            self.handle_presence(namespace, client_id, payload);
+            Ok(())
+        } else if let Some(group_id) = path.strip_prefix("g/").and_then(|p| p.strip_suffix("/m")) {
+            self.handle_group_message(namespace, group_id, payload)
+        } else if let (None, Some(group_id)) = (
+            namespace,
+            path.strip_prefix("g/").and_then(|p| p.strip_suffix("/i")),
+        ) {
+            self.handle_channel_group_info(group_id, payload)
+        } else if let (None, Some(name)) = (namespace, path.strip_prefix("c/")) {
+            self.handle_channel_entry(name, payload)
+        } else if let (None, Some(client_id)) = (namespace, path.strip_prefix("d/")) {
+            self.handle_directory_entry(client_id, payload)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Keep a chunk of a payload published in chunks; returns the payload
+    /// once all of its chunks are in
+    fn handle_chunk(&mut self, topic: &str, chunk: &[u8]) -> Result<Option<Vec<u8>>> {
+        add_chunk(&mut self.reassemblies, topic, chunk, Instant::now())
+    }
+
+    #[instrument(skip_all)]
+    fn handle_key_package(
+        &mut self,
+        namespace: Option<&str>,
+        client_id: &str,
+        payload: &[u8],
+    ) -> Result<()> {
+        if client_id == self.client_id {
+            return Ok(()); // Ignore our own KeyPackage
+        }
+        let peer_id = &qualified(client_id.to_string(), namespace);
+        if payload.is_empty() {
+            // The peer withdrew its KeyPackages; pending connects and
+            // invites go on once it publishes new ones
+            debug!(peer = peer_id, "KeyPackages withdrawn");
+            self.key_packages.remove(peer_id);
+            return Ok(());
+        }
+
+        let kp_array: Vec<Value> = ciborium::from_reader(payload)?;
+        let decoded = decode_key_packages(&kp_array, self.backend.crypto());
+        let expired = decoded
+            .iter()
+            .any(|kp| matches!(kp, Err(KeyPackageVerifyError::InvalidLifetime)));
+        let kps: Vec<KeyPackage> = decoded.into_iter().flatten().collect();
+        if kps.is_empty() && expired {
+            // Pending connects and invites go on once the peer republishes
+            return Err(failure(
+                Failure::KeyPackageExpired,
+                format!(
+                    "The KeyPackages of {} have expired",
+                    self.aliases.name(peer_id)
+                ),
+            ));
+        } else if kps.is_empty() {
+            return Err(anyhow!("No valid KeyPackage for {}", peer_id));
+        }
+
+        // Anyone may publish on a topic; the peer is who the signed
+        // credential names, so it must match the topic
+        let mut owners = kps.iter().map(|kp| member_id(kp.leaf_node().credential()));
+        if let Some(owner) = owners.find(|owner| owner != client_id) {
+            return Err(anyhow!(
+                "KeyPackage published for {} belongs to {}; ignored",
+                peer_id,
+                owner
+            ));
+        }
+
+        // The retained array is delivered again on each subscribe and may
+        // still list one-time KeyPackages we used; skip those, and forget
+        // used ones the peer no longer publishes
+        let crypto = self.backend.crypto();
+        let refs = kps
+            .iter()
+            .map(|kp| kp.hash_ref(crypto))
+            .collect::<Result<Vec<_>, _>>()?;
+        let used = self
+            .used_key_packages
+            .entry(peer_id.to_string())
+            .or_default();
+        used.retain(|r| refs.contains(r));
+        let kps: Vec<KeyPackage> = kps
+            .into_iter()
+            .zip(&refs)
+            .filter(|(_, r)| !used.contains(r))
+            .map(|(kp, _)| kp)
+            .collect();
+        if kps.is_empty() {
+            debug!(
+                peer = peer_id,
+                "Only used KeyPackages; waiting for a republish"
+            );
+            return Ok(());
+        }
+
+        // Peers republish after each join; only the first arrival is news
+        let known = self.key_packages.insert(peer_id.to_string(), kps).is_some();
+
+        // If this peer had a pending connect, establish session now
+        if let Some(pos) = self.pending_connects.iter().position(|p| p == peer_id) {
+            self.pending_connects.remove(pos);
+            self.create_group(peer_id)?;
+            log_event(
+                json!({ "event": "session", "peer": peer_id, "group_id": self.sessions[peer_id] }),
+                &format!("Session established with {}", self.aliases.name(peer_id)),
+            );
+        } else if self.pending_invites.iter().any(|(_, p)| p == peer_id) {
+            let (invites, waiting) = std::mem::take(&mut self.pending_invites)
+                .into_iter()
+                .partition(|(_, p)| p == peer_id);
+            self.pending_invites = waiting;
+            for (group_id, _) in invites {
+                self.add_member(&group_id, peer_id)?;
+                log(&format!(
+                    "Invited {} to {}",
+                    self.aliases.name(peer_id),
+                    self.chat_name(&group_id)
+                ));
+            }
+        } else if !known {
+            log(&format!(
+                "Received KeyPackage for {}",
+                self.aliases.name(peer_id)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Note a peer's status; an empty payload clears the retained one
+    fn handle_presence(&mut self, namespace: Option<&str>, client_id: &str, payload: &[u8]) {
+        if client_id == self.client_id {
+            return;
+        }
+        let peer_id = qualified(client_id.to_string(), namespace);
+        let online = match payload {
+            b"" => None,
+            p if p == PRESENCE_ONLINE.as_bytes() => Some(true),
+            p if p == PRESENCE_OFFLINE.as_bytes() => Some(false),
+            _ => {
+                debug!(peer = peer_id, "Ignored an unknown presence status");
+                return;
+            }
+        };
+        debug!(peer = peer_id, online, "Presence");
+        match online {
+            Some(online) => self.presence.insert(peer_id, online),
+            None => self.presence.remove(&peer_id),
+        };
+    }
+
+    #[instrument(skip_all)]
+    fn handle_welcome(&mut self, namespace: Option<&str>, payload: &[u8]) -> Result<()> {
+        // Deserialize Welcome
+        let msg = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice())?;
+        let welcome = match msg.extract() {
+            MlsMessageBodyIn::Welcome(w) => w,
+            _ => return Err(anyhow!("Expected Welcome")),
+        };
+        let used: Vec<KeyPackageRef> = welcome.secrets().iter().map(|s| s.new_member()).collect();
+
+        // Join group
+        let config = MlsGroupJoinConfig::builder()
+            .max_past_epochs(MAX_PAST_EPOCHS)
+            .build();
+        let staged = StagedWelcome::new_from_welcome(&self.backend, &config, welcome, None)?;
+        // Anyone can fetch our KeyPackages and invite us; a group with the
+        // ID of one of ours would replace it
+        let group_id = hex::encode(staged.group_context().group_id().as_slice());
+        if self.groups.contains_key(&group_id) {
+            return Err(anyhow!("Ignored a Welcome to a group we are already in"));
+        }
+        if self.invitations.iter().any(|i| i.group_id == group_id) {
+            return Err(anyhow!(
+                "Ignored a second Welcome to a group you are invited to"
+            ));
+        }
+        let adder = qualified(member_id(staged.welcome_sender()?.credential()), namespace);
+        // Replace the KeyPackage the Welcome used, whatever becomes of it
+        let last_resort = self.consume_key_package(&used)?;
+        // Welcomes don't name the adder outside the encrypted part
+        if self.is_blocked(&adder) {
+            debug!(adder, "Dropped a Welcome from a blocked peer");
+            return Ok(());
+        }
+        let members: Vec<String> = staged
+            .members()
+            .map(|m| member_id(&m.credential))
+            .filter(|id| *id != self.client_id)
+            .map(|id| qualified(id, namespace))
+            .collect();
+        let invitation = Invitation {
+            adder,
+            group_id,
+            namespace: namespace.map(str::to_string),
+            members,
+            staged,
+            last_resort,
+            held: Vec::new(),
+        };
+        if self.auto_accept && self.is_contact(&invitation.adder) {
+            return self.join_invitation(invitation);
+        }
+
+        // Anyone on the broker can invite us; strangers wait to be accepted.
+        // The group's messages are kept meanwhile.
+        if self.invitations.len() >= MAX_INVITATIONS {
+            return Err(anyhow!(
+                "Dropped an invitation from {}: too many are waiting",
+                invitation.adder
+            ));
+        }
+        if !self.wildcard_groups {
+            let topic = format!("g/{}/m", invitation.group_id);
+            self.mqtt
+                .try_subscribe(self.topic_in(namespace, &topic), QoS::AtLeastOnce)?;
+        }
+        let adder = self.aliases.name(&invitation.adder).to_string();
+        let what = match invitation.members.as_slice() {
+            [peer_id] if *peer_id == invitation.adder => "a session".to_string(),
+            members => {
+                let members: Vec<&str> = members.iter().map(|id| self.aliases.name(id)).collect();
+                format!("a group with {}", members.join(", "))
+            }
+        };
+        let event = json!({
+            "event": "invitation",
+            "from": invitation.adder,
+            "group_id": invitation.group_id,
+            "members": invitation.members,
+        });
+        log_event(event, &format!("Invitation from {} to {}", adder, what));
+        log(&format!("Use 'accept {}' or 'decline {}'", adder, adder));
+        self.invitations.push(invitation);
+        Ok(())
+    }
+
+    /// Whether a peer is someone we know: a session, alias, verified key or
+    /// pending `connect`
+    fn is_contact(&self, peer_id: &str) -> bool {
+        self.sessions.contains_key(peer_id)
+            || self.aliases.0.contains_key(peer_id)
+            || self.verified.contains_key(peer_id)
+            || self.pending_connects.iter().any(|p| p == peer_id)
+    }
+
+    /// Join the group of a Welcome, unless it duplicates the session we
+    /// started with the same peer and ours wins
+    fn join_invitation(&mut self, invitation: Invitation) -> Result<()> {
+        let duplicate = self.duplicate_session(&invitation);
+        if duplicate.is_some() && self.wins_contest(&invitation.adder) {
+            self.hold_contested(invitation);
+            return Ok(());
+        }
+        self.enter_invitation(invitation, duplicate)
+    }
+
+    /// Our session with the peer who sent a Welcome to a session, if we
+    /// started it and they never wrote in it: either they lost its Welcome,
+    /// or we both ran `connect` at once
+    fn duplicate_session(&self, invitation: &Invitation) -> Option<String> {
+        let [peer_id] = invitation.members.as_slice() else {
+            return None;
+        };
+        let old = self.sessions.get(peer_id)?;
+        let ours = self.groups.get(old)?.own_leaf_index().u32() == 0; // the creator's leaf
+        (ours && !self.wrote_in(old, peer_id)).then(|| old.clone())
+    }
+
+    /// Whether a peer sent anything in a group
+    fn wrote_in(&self, group_id: &str, peer_id: &str) -> bool {
+        let key = (group_id.to_string(), peer_id.to_string());
+        self.receive_order.contains_key(&key) || self.member_caps.contains_key(&key)
+    }
+
+    /// Whether our session is kept over a peer's when both of us started
+    /// one at once: that of the lower Client ID is, so both sides agree
+    fn wins_contest(&self, peer_id: &str) -> bool {
+        self.client_id.as_str() < self.split_peer(peer_id).1
+    }
+
+    /// Hold the Welcome to a session that duplicates the one we started,
+    /// for the peer to join ours and drop theirs. If they don't within
+    /// SESSION_CONTEST_TIMEOUT, settle_contested_sessions joins theirs.
+    fn hold_contested(&mut self, invitation: Invitation) {
+        info!(
+            peer = invitation.adder,
+            group_id = invitation.group_id,
+            "Holding the Welcome to a duplicate session"
+        );
+        self.contested.push((invitation, Instant::now()));
+    }
+
+    /// Drop the held Welcomes of peers who joined our session since, and
+    /// join those of peers who didn't within SESSION_CONTEST_TIMEOUT as of
+    /// `now`
+    fn settle_contested_sessions(&mut self, now: Instant) -> Result<()> {
+        for (invitation, since) in std::mem::take(&mut self.contested) {
+            let peer_id = &invitation.adder;
+            let joined = self
+                .sessions
+                .get(peer_id)
+                .is_some_and(|old| self.wrote_in(old, peer_id));
+            if joined {
+                info!(
+                    peer = peer_id,
+                    group_id = invitation.group_id,
+                    "Dropped a duplicate session"
+                );
+            } else if now.duration_since(since) < SESSION_CONTEST_TIMEOUT {
+                self.contested.push((invitation, since));
+            } else {
+                let duplicate = self.duplicate_session(&invitation);
+                if let Err(e) = self.enter_invitation(invitation, duplicate) {
+                    log_error(&e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Join the group of a Welcome in place of `duplicate`, the session
+    /// with the same peer it replaces, then handle its messages that
+    /// arrived while the invitation waited
+    fn enter_invitation(
+        &mut self,
+        invitation: Invitation,
+        duplicate: Option<String>,
+    ) -> Result<()> {
+        let Invitation {
+            adder,
+            group_id,
+            namespace,
+            members: others,
+            staged,
+            last_resort,
+            held,
+        } = invitation;
+        let namespace = namespace.as_deref();
+        let group = staged.into_group(&self.backend)?;
+        info!(
+            group_id,
+            epoch = group.epoch().as_u64(),
+            adder,
+            "Joined a group"
+        );
+
+        // The group lives in the namespace the Welcome came from
+        if let Some(namespace) = namespace {
+            self.group_namespaces
+                .insert(group_id.clone(), namespace.to_string());
+        }
+
+        // Subscribe to group messages
+        self.subscribe_group(&group_id)?;
+
+        // Either the peer lost the Welcome of the session we started, or
+        // we both started one at once and theirs won. Theirs replaces ours.
+        if let Some(old) = &duplicate {
+            info!(
+                peer = adder,
+                old, "Replaced a session the peer never joined"
+            );
+            log(&format!(
+                "{} started a session with you too; using theirs in place of yours",
+                self.aliases.name(&adder)
+            ));
+            self.clear_group_info(old)?;
+            self.forget_group(old)?;
+        }
+
+        // Groups carry no name, so a group of two is taken as a 1:1 session
+        // with the other member (until it grows), and larger ones get a name
+        // from their ID
+        match others.as_slice() {
+            [peer_id] if !self.sessions.contains_key(peer_id) => {
+                self.sessions.insert(peer_id.clone(), group_id.clone());
+                self.seen(peer_id);
+                self.mqtt
+                    .try_subscribe(self.peer_topic("p", peer_id), QoS::AtLeastOnce)?;
+                let peer = self.aliases.name(peer_id);
+                log_event(
+                    json!({ "event": "session", "peer": peer_id, "group_id": group_id }),
+                    &format!("Session established with {}", peer),
+                );
+                log(&format!("Use 'chat {} <message>' to reply", peer));
+            }
+            _ => {
+                // Group IDs are chosen by the adder and may be short
+                let prefix: String = group_id.chars().take(8).collect();
+                let name = self.unused_group_name(&format!("group-{}", prefix));
+                self.group_names.insert(name.clone(), group_id.clone());
+                let event = json!({
+                    "event": "joined",
+                    "group": name,
+                    "group_id": group_id,
+                    "by": adder,
+                    "members": others,
+                });
+                let others: Vec<&str> = others.iter().map(|id| self.aliases.name(id)).collect();
+                let msg = format!(
+                    "{} added you to group {} with {}",
+                    self.aliases.name(&adder),
+                    name,
+                    others.join(", ")
+                );
+                log_event(event, &msg);
+                log(&format!("Use 'group chat {} <message>' to reply", name));
+            }
+        }
+        self.groups.insert(group_id.clone(), group);
+        self.check_verified(&group_id);
+
+        // The last resort KeyPackage may have been used before, so replace
+        // the keys it gave us here
+        if last_resort {
+            let epoch = self.self_update(&group_id)?;
+            log(&format!(
+                "Rekeyed {} (joined with a reusable KeyPackage; epoch {})",
+                self.chat_name(&group_id),
+                epoch
+            ));
+        }
+        for payload in held {
+            if let Err(e) = self.handle_group_message(namespace, &group_id, &payload) {
+                log_error(&e);
+            }
+        }
+
+        // Writing in the session tells a peer holding the Welcome to the
+        // one we dropped that it can drop it too
+        if duplicate.is_some() {
+            self.send_message(&group_id, &AppMessage::new("heartbeat", Value::Null))?;
+        }
+        Ok(())
+    }
+
+    /// Route a message to its group. Messages of groups we are not in, which
+    /// --wildcard-groups brings in numbers, are counted and dropped.
+    fn handle_group_message(
+        &mut self,
+        namespace: Option<&str>,
+        group_id: &str,
+        payload: &[u8],
+    ) -> Result<()> {
+        // A group lives in one namespace; others may reuse its ID
+        let known = self.groups.contains_key(group_id)
+            && self.group_namespaces.get(group_id).map(String::as_str) == namespace;
+        if !known {
+            let invited = self
+                .invitations
+                .iter_mut()
+                .find(|i| i.group_id == group_id && i.namespace.as_deref() == namespace);
+            if let Some(invitation) = invited {
+                if invitation.held.len() < MAX_FUTURE_MESSAGES {
+                    invitation.held.push(payload.to_vec());
+                }
+                return Ok(());
+            }
+            self.unknown_group_messages += 1;
+            debug!(group_id, "Dropped a message of a group we are not in");
+            return Ok(());
+        }
+        // The broker sends our own messages back; we can't decrypt them
+        let hash = self.backend.crypto().hash(HashType::Sha2_256, payload)?;
+        if let Some(sent) = self.own_messages.get_mut(group_id) {
+            if let Some(pos) = sent.iter().position(|h| *h == hash) {
+                sent.remove(pos);
+                debug!(group_id, "Skipped the echo of our own message");
+                return Ok(());
+            }
+        }
+        self.process_group_message(group_id, payload)?;
+        // A Commit may have brought the group to the epoch of held messages
+        while let Some(payload) = self.next_future_message(group_id) {
+            self.process_group_message(group_id, &payload)?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self, payload))]
+    fn process_group_message(&mut self, group_id: &str, payload: &[u8]) -> Result<()> {
+        let name = self.group_name(group_id).map(str::to_string);
+        let chat_name = self.chat_name(group_id);
+        let peer_id = self.session_peer(group_id).map(str::to_string);
+        let namespace = self.group_namespaces.get(group_id).cloned();
+        let namespace = namespace.as_deref();
+        // Messages may still arrive for a group we just left
+        let Some(group) = self.groups.get_mut(group_id) else {
+            return Ok(());
+        };
+
+        // Deserialize MLS message
+        let msg = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice())?;
+        let protocol_msg = match msg.extract() {
+            MlsMessageBodyIn::PrivateMessage(m) => ProtocolMessage::from(m),
+            MlsMessageBodyIn::PublicMessage(m) => ProtocolMessage::from(m),
+            _ => return Err(anyhow!("Expected PrivateMessage or PublicMessage")),
+        };
+
+        // Handshake messages from an earlier epoch are our own Commits
+        // coming back from the broker, or lost to a Commit we already merged.
+        // Application messages can still be decrypted for MAX_PAST_EPOCHS.
+        if protocol_msg.epoch() < group.epoch()
+            && protocol_msg.content_type() != ContentType::Application
+        {
+            debug!(
+                epoch = protocol_msg.epoch().as_u64(),
+                "Ignored a handshake message from an earlier epoch"
+            );
+            return Ok(());
+        }
+
+        // Messages from a later epoch were sent after a Commit we have not
+        // seen yet; the broker may deliver a backlog from several senders out
+        // of order. Hold them until the Commit arrives, or for up to
+        // FUTURE_MESSAGE_TIMEOUT.
+        if protocol_msg.epoch() > group.epoch() {
+            let held = self
+                .future_messages
+                .entry(group_id.to_string())
+                .or_default();
+            if held.len() >= MAX_FUTURE_MESSAGES {
+                return Err(failure(
+                    Failure::EpochDesync,
+                    format!(
+                        "Dropped a message for {}: too many ahead of its epoch",
+                        chat_name
+                    ),
+                ));
+            }
+            held.push((
+                protocol_msg.epoch().as_u64(),
+                Instant::now(),
+                payload.to_vec(),
+            ));
+            debug!(
+                epoch = protocol_msg.epoch().as_u64(),
+                current = group.epoch().as_u64(),
+                "Held a message from a later epoch"
+            );
+            return Ok(());
+        }
+
+        // Process message
+        let processed = match group.process_message(&self.backend, protocol_msg) {
+            Ok(p) => p,
+            // Echoes of what we sent were skipped, so a message from our own
+            // leaf was sent again, by the broker or someone replaying it
+            Err(ProcessMessageError::ValidationError(ValidationError::CannotDecryptOwnMessage)) => {
+                warn!("A message from our own leaf that we did not just send");
+                return Err(anyhow!(
+                    "Ignored a message in {} that claims to be from you but is not one this client sent since it started; it may have been replayed",
+                    chat_name
+                ));
+            }
+            // The key of this message was used up, so it's a duplicate
+            Err(ProcessMessageError::ValidationError(ValidationError::UnableToDecrypt(
+                MessageDecryptionError::SecretTreeError(SecretTreeError::SecretReuseError),
+            ))) => {
+                debug!("Dropped a message whose key was used up");
+                return Ok(());
+            }
+            // Keys of an epoch we are not in, or no longer keep
+            Err(
+                e @ ProcessMessageError::ValidationError(
+                    ValidationError::WrongEpoch | ValidationError::UnableToDecrypt(_),
+                ),
+            ) => {
+                self.metrics.decrypt_failures += 1;
+                let msg = format!("Cannot decrypt a message in {}", chat_name);
+                return Err(failure(Failure::EpochDesync, format!("{:?}", e)).context(msg));
+            }
+            Err(e) => {
+                self.metrics.decrypt_failures += 1;
+                return Err(anyhow!("MLS error: {:?}", e));
+            }
+        };
+        let sender = qualified(member_id(processed.credential()), namespace);
+        let external = matches!(processed.sender(), Sender::NewMemberCommit);
+        let creator = matches!(processed.sender(), Sender::Member(i) if i.u32() == 0);
+        let sender_index = match processed.sender() {
+            Sender::Member(i) => Some(*i),
+            _ => None,
+        };
+        let seq = decode_seq(processed.aad());
+        debug!(
+            sender,
+            seq,
+            epoch = processed.epoch().as_u64(),
+            "Processed a message"
+        );
+        self.seen(&sender);
+        // seen() borrows the whole client, so look the group up again
+        let Some(group) = self.groups.get_mut(group_id) else {
+            return Ok(());
+        };
+        let from = self.aliases.name(&sender).to_string();
+        // "bob rekeyed your session", not "bob rekeyed the session with bob"
+        let chat_name = if peer_id.as_ref() == Some(&sender) {
+            "your session".to_string()
+        } else {
+            chat_name
+        };
+
+        match processed.into_content() {
+            ProcessedMessageContent::ApplicationMessage(app_msg) => {
+                // Groups don't name the sender before decrypting
+                if self.is_blocked(&sender) {
+                    debug!(sender, "Dropped a message from a blocked peer");
+                    return Ok(());
+                }
+                // Other members of a broadcast group only read
+                if !creator && self.broadcast.contains(group_id) {
+                    warn!(
+                        sender,
+                        "Ignored a message from a reader of a broadcast group"
+                    );
+                    return Ok(());
+                }
+                self.metrics.messages_received += 1;
+                let message = AppMessage::decode(&app_msg.into_bytes());
+                self.member_caps
+                    .insert((group_id.to_string(), sender.clone()), message.caps.clone());
+                let ordered = match seq {
+                    Some(seq) => self
+                        .receive_order
+                        .entry((group_id.to_string(), sender.clone()))
+                        .or_default()
+                        .receive(seq, message),
+                    None => vec![Ordered::Message(message)],
+                };
+                self.show_ordered(group_id, &sender, ordered)?;
+            }
+            ProcessedMessageContent::StagedCommitMessage(staged) => {
+                // Anyone can fetch a group's GroupInfo and commit themselves
+                // into it; only channels are open to that
+                if external && !name.as_ref().is_some_and(|n| n.starts_with('#')) {
+                    warn!(sender, "Ignored an external Commit");
+                    return Err(anyhow!(
+                        "Ignored {} joining {} without an invitation",
+                        from,
+                        chat_name
+                    ));
+                }
+                let mut added: Vec<String> = staged
+                    .add_proposals()
+                    .map(|p| member_id(p.add_proposal().key_package().leaf_node().credential()))
+                    .map(|id| qualified(id, namespace))
+                    .collect();
+                if external {
+                    added.push(sender.clone());
+                }
+                // (member, whether they asked to leave)
+                let removed: Vec<(String, bool)> = staged
+                    .remove_proposals()
+                    .filter_map(|p| {
+                        let index = p.remove_proposal().removed();
+                        let left = matches!(p.sender(), Sender::Member(i) if *i == index);
+                        Some((member_id(group.member(index)?), left))
+                    })
+                    .filter(|(id, _)| *id != self.client_id)
+                    .map(|(id, left)| (qualified(id, namespace), left))
+                    .collect();
+                let self_removed = staged.self_removed();
+                // A Commit with a path but no membership change is a rekey
+                let rekeyed = added.is_empty()
+                    && removed.is_empty()
+                    && !self_removed
+                    && staged.update_path_leaf_node().is_some();
+                let before: HashMap<u32, (Credential, Vec<u8>)> = group
+                    .members()
+                    .map(|m| (m.index.u32(), (m.credential, m.signature_key)))
+                    .collect();
+                group.merge_staged_commit(&self.backend, *staged)?;
+                self.metrics.epochs_advanced += 1;
+                // Note members who kept their leaf but changed their
+                // credential or signature key, for the roster
+                let mut key_changes = Vec::new();
+                for member in group.members() {
+                    let Some((credential, key)) = before.get(&member.index.u32()) else {
+                        continue;
+                    };
+                    let same_client = member_id(credential) == member_id(&member.credential);
+                    if same_client
+                        && (*credential != member.credential || *key != member.signature_key)
+                    {
+                        let name = qualified(member_id(&member.credential), namespace);
+                        self.credential_changes
+                            .insert((group_id.to_string(), name.clone()), group.epoch().as_u64());
+                        key_changes.push((name, hex::encode(&member.signature_key)));
+                    }
+                }
+                info!(
+                    epoch = group.epoch().as_u64(),
+                    added = added.len(),
+                    removed = removed.len(),
+                    "Merged a Commit"
+                );
+                if rekeyed {
+                    log(&format!("{} rekeyed {}", from, chat_name));
+                }
+                let chat = Chat {
+                    group_id,
+                    name: name.as_deref(),
+                };
+                for peer_id in &added {
+                    let event = json!({ "event": "joined", "member": peer_id, "by": sender });
+                    log_system(
+                        event,
+                        &chat,
+                        &format!("{} joined", self.aliases.name(peer_id)),
+                    );
+                }
+                for (peer_id, left) in &removed {
+                    let peer = self.aliases.name(peer_id);
+                    if *left {
+                        let event = json!({ "event": "left", "member": peer_id });
+                        log_system(event, &chat, &format!("{} left", peer));
+                    } else {
+                        let event =
+                            json!({ "event": "member_removed", "member": peer_id, "by": sender });
+                        log_system(event, &chat, &format!("{} was removed by {}", peer, from));
+                    }
+                }
+                // A new key may be the peer's rotation or someone else
+                // holding their identity. Verified peers are told about by
+                // check_verified, which also drops their verification.
+                for (peer_id, key) in key_changes.iter().filter(|(id, _)| *id != self.client_id) {
+                    if self
+                        .verified
+                        .get(peer_id)
+                        .is_some_and(|verified| verified != key)
+                    {
+                        continue;
+                    }
+                    let peer = self.aliases.name(peer_id);
+                    let event = json!({ "event": "key_changed", "peer": peer_id });
+                    log_system(event, &chat, &format!("{}'s identity key changed", peer));
+                }
+                // Kept with the messages, for transcripts
+                if let Some(history) = &self.history {
+                    for peer_id in &added {
+                        history.append_event(group_id, &sender, "joined", peer_id)?;
+                    }
+                    for (peer_id, left) in &removed {
+                        let kind = if *left { "left" } else { "member_removed" };
+                        history.append_event(group_id, &sender, kind, peer_id)?;
+                    }
+                    for (peer_id, _) in &key_changes {
+                        history.append_event(group_id, &sender, "key_changed", peer_id)?;
+                    }
+                }
+
+                if self_removed {
+                    log_event(
+                        json!({ "event": "removed", "group_id": group_id, "by": sender }),
+                        &format!("{} removed you from {}", from, chat_name),
+                    );
+                    return self.forget_group(group_id);
+                }
+
+                // After a removal one member besides the committer updates its
+                // keys too, on the next timer tick, after any backlog. Only
+                // one does, so their Commits don't collide.
+                if !removed.is_empty() {
+                    let updater = group
+                        .members()
+                        .map(|m| m.index)
+                        .find(|i| Some(*i) != sender_index);
+                    if updater == Some(group.own_leaf_index()) {
+                        self.key_updates.insert(group_id.to_string(), 0);
+                    }
+                }
+
+                // A session that gains members becomes a named group
+                if name.is_none() && group.members().count() > 2 {
+                    self.promote_session(group_id);
+                }
+                self.check_verified(group_id);
+            }
+            ProcessedMessageContent::ProposalMessage(proposal) => {
+                // Leave requests (a member removing itself) are committed
+                // right away, and announced with the Commit that removes them
+                let leaver = match (proposal.proposal(), proposal.sender()) {
+                    (Proposal::Remove(remove), Sender::Member(index))
+                        if remove.removed() == *index =>
+                    {
+                        Some(*index)
+                    }
+                    _ => None,
+                };
+                if let Some(leaver) = leaver {
+                    group.store_pending_proposal(self.backend.storage(), *proposal)?;
+                    return self.commit_leave(group_id, leaver);
+                }
+                if self.is_blocked(&sender) {
+                    debug!(sender, "Dropped a proposal from a blocked peer");
+                    return Ok(());
+                }
+                let group = self
+                    .groups
+                    .get_mut(group_id)
+                    .ok_or_else(|| anyhow!("Unknown group"))?;
+                // Other proposals wait for a member to `commit` them
+                let (kind, subject) = match proposal.proposal() {
+                    Proposal::Add(add) => {
+                        let id = member_id(add.key_package().leaf_node().credential());
+                        ("add", Some(qualified(id, namespace)))
+                    }
+                    Proposal::Remove(remove) => {
+                        let id = group
+                            .member(remove.removed())
+                            .map(member_id)
+                            .ok_or_else(|| anyhow!("Ignored a proposal to remove a vacant leaf"))?;
+                        ("remove", Some(qualified(id, namespace)))
+                    }
+                    Proposal::Update(_) => ("update", None),
+                    _ => ("other", None),
+                };
+                group.store_pending_proposal(self.backend.storage(), *proposal)?;
+                let what = match (kind, &subject) {
+                    ("add", Some(id)) => format!("adding {}", self.aliases.name(id)),
+                    ("remove", Some(id)) => format!("removing {}", self.aliases.name(id)),
+                    ("update", _) => "updating their keys".to_string(),
+                    _ => "a change to the group".to_string(),
+                };
+                let target = match (&name, &peer_id) {
+                    (Some(name), _) => name.clone(),
+                    (None, Some(peer_id)) => self.aliases.name(peer_id).to_string(),
+                    (None, None) => group_id.to_string(),
+                };
+                let chat = Chat {
+                    group_id,
+                    name: name.as_deref(),
+                };
+                let event = json!({
+                    "event": "proposal",
+                    "kind": kind,
+                    "member": subject,
+                    "by": sender,
+                });
+                log_system(event, &chat, &format!("{} proposes {}", from, what));
+                log(&format!("Use 'commit {}' to commit it", target));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Show messages released in sequence order and add them to the history
+    fn show_ordered(&mut self, group_id: &str, sender: &str, ordered: Vec<Ordered>) -> Result<()> {
+        let name = self.group_name(group_id).map(str::to_string);
+        let name = name.as_deref();
+        let chat = Chat { group_id, name };
+        let from = self.aliases.name(sender).to_string();
+        let typing_key = (group_id.to_string(), sender.to_string());
+        let mut delivered = Vec::new();
+        for item in ordered {
+            let (message, late) = match item {
+                Ordered::Message(message) => (message, false),
+                Ordered::Late(message) => (message, true),
+                Ordered::Missing(count) => {
+                    let place = name.map(|g| format!(" in {}", g)).unwrap_or_default();
+                    let event = json!({
+                        "event": "missing",
+                        "group_id": group_id,
+                        "sender": sender,
+                        "count": count,
+                    });
+                    log_event(
+                        event,
+                        &format!("{} message(s) from {}{} did not arrive", count, from, place),
+                    );
+                    continue;
+                }
+            };
+            if message.as_update().is_some() {
+                self.show_update(&chat, sender, &message)?;
+                continue;
+            }
+            if let Some((targets, read)) = message.as_receipt() {
+                self.apply_receipt(&chat, sender, &targets, read);
+                continue;
+            }
+            if message.kind == "broadcast" {
+                self.mark_broadcast(&chat, sender);
+                continue;
+            }
+            // Only counts for last_seen, which any message updates
+            if message.kind == "heartbeat" {
+                continue;
+            }
+            if message.kind == "typing" {
+                // A late indicator is stale
+                if !late {
+                    self.typing.insert(typing_key.clone(), Instant::now());
+                    log_chat_state(json!({ "event": "typing" }), &chat, sender);
+                }
+                continue;
+            }
+            // Kinds of a later version are for clients that know them
+            let text = match (message.as_text(), message.as_attachment()) {
+                (Some(text), _) => text.to_string(),
+                (None, Some(attachment)) => attachment.describe(),
+                (None, None) => {
+                    debug!(kind = %message.kind, "Skipped a message of an unknown type");
+                    continue;
+                }
+            };
+            let text = text.as_str();
+            self.typing.remove(&typing_key);
+            self.notify(&chat, sender, &from, text);
+            if late {
+                log_msg(
+                    &chat,
+                    &message.id,
+                    sender,
+                    &from,
+                    &format!("(late) {}", text),
+                    false,
+                );
+            } else {
+                log_msg(&chat, &message.id, sender, &from, text, false);
+            }
+            if let Some(history) = &self.history {
+                history.append(group_id, sender, &message)?;
+            }
+            // Messages without an ID can't be acknowledged
+            if !message.id.is_empty() {
+                delivered.push(message.id.clone());
+            }
+            // Its reply is sent once ready, see send_bot_replies
+            if let (Some(bot), Some(text)) = (&mut self.bot, message.as_text()) {
+                let incoming = Incoming {
+                    chat: name.unwrap_or(sender),
+                    group_id,
+                    sender,
+                    text,
+                };
+                bot.answer(group_id, &incoming);
+            }
+        }
+
+        if !delivered.is_empty() {
+            self.send_message(group_id, &AppMessage::receipt("delivered", &delivered))?;
+            if self.read_receipts {
+                self.unread
+                    .entry(group_id.to_string())
+                    .or_default()
+                    .extend(delivered);
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the replies of the bot that are ready; returns whether anything
+    /// was shown
+    fn send_bot_replies(&mut self) -> Result<bool> {
+        let mut shown = false;
+        while let Some((group_id, reply)) = self.bot.as_mut().and_then(Bot::reply) {
+            let text = match reply {
+                Ok(Some(text)) => text,
+                Ok(None) => continue,
+                Err(e) => {
+                    log_error(&e);
+                    shown = true;
+                    continue;
+                }
+            };
+            let name = self.group_name(&group_id).map(str::to_string);
+            let chat = Chat {
+                group_id: &group_id,
+                name: name.as_deref(),
+            };
+            self.send_bot_reply(&chat, &text)?;
+            shown = true;
+        }
+        Ok(shown)
+    }
+
+    /// Send a reply of the bot to a conversation, unless it replied there
+    /// less than BOT_MIN_INTERVAL ago
+    fn send_bot_reply(&mut self, chat: &Chat, text: &str) -> Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.bot_replied.get(chat.group_id) {
+            if now.duration_since(*last) < BOT_MIN_INTERVAL {
+                debug!(
+                    group_id = chat.group_id,
+                    "Dropped a bot reply that came too soon"
+                );
+                return Ok(());
+            }
+        }
+        self.bot_replied.insert(chat.group_id.to_string(), now);
+        let message = AppMessage::text(text);
+        self.send_to(chat.group_id, &message)?;
+        log_msg(chat, &message.id, &self.client_id, "", text, true);
+        Ok(())
+    }
+
+    /// Raise a desktop notification for a message, unless its conversation
+    /// is in view or the sender is muted
+    fn notify(&self, chat: &Chat, sender: &str, from: &str, text: &str) {
+        if !self.notifications
+            || self.focused.as_deref() == Some(chat.group_id)
+            || self.muted.iter().any(|peer| peer == sender)
+        {
+            return;
+        }
+        let summary = match chat.name {
+            Some(group) => format!("{} in #{}", from, group),
+            None => from.to_string(),
+        };
+        let body = text.to_string();
+        // Showing one waits for the desktop's notification service
+        tokio::task::spawn_blocking(move || {
+            let shown = notify_rust::Notification::new()
+                .appname("relay")
+                .summary(&summary)
+                .body(&body)
+                .show();
+            if let Err(e) = shown {
+                warn!(error = %e, "Cannot show a notification");
+            }
+        });
+    }
+
+    /// Note a receipt for messages of ours, and report it as JSON
+    fn apply_receipt(&mut self, chat: &Chat, sender: &str, targets: &[&[u8]], read: bool) {
+        let mut ids = Vec::new();
+        for target in targets {
+            // Only messages we sent since starting are tracked
+            if let Some(members) = self.receipts.get_mut(*target) {
+                let was_read = members.insert(sender.to_string(), read).unwrap_or(false);
+                // A late "delivered" doesn't undo "read"
+                if was_read {
+                    members.insert(sender.to_string(), true);
+                }
+                ids.push(message_id(target));
+            }
+        }
+        if !ids.is_empty() {
+            let event = json!({ "event": "receipt", "ids": ids, "read": read });
+            log_chat_state(event, chat, sender);
+        }
+    }
+
+    /// Note that a group is a broadcast group, when its creator says so
+    fn mark_broadcast(&mut self, chat: &Chat, sender: &str) {
+        if self.group_creator(chat.group_id).as_deref() != Some(sender)
+            || !self.broadcast.insert(chat.group_id.to_string())
+        {
+            return;
+        }
+        let event = json!({ "event": "broadcast" });
+        let text = format!(
+            "Only {} writes in this group; you can read along",
+            self.aliases.name(sender)
+        );
+        log_system(event, chat, &text);
+    }
+
+    /// Name of the member who created a group, as long as they are in it
+    fn group_creator(&self, group_id: &str) -> Option<String> {
+        let credential = self.groups.get(group_id)?.member(LeafNodeIndex::new(0))?;
+        Some(self.member_name(group_id, credential))
+    }
+
+    /// Whether we are a reader of a broadcast group, who sends nothing to it
+    fn read_only(&self, group_id: &str) -> bool {
+        self.broadcast.contains(group_id)
+            && self
+                .groups
+                .get(group_id)
+                .is_some_and(|g| g.own_leaf_index().u32() != 0)
+    }
+
+    /// Send a read receipt for the messages of a chat received since the
+    /// last one, once the user has seen them
+    fn mark_read(&mut self, group_id: &str) -> Result<()> {
+        let Some(ids) = self.unread.remove(group_id) else {
+            return Ok(());
+        };
+        self.send_message(group_id, &AppMessage::receipt("read", &ids))
+    }
+
+    /// … while a message of ours waits to be published, ✓ once it reached
+    /// a member, ✓✓ once all other members have seen it; None for other
+    /// messages
+    fn receipt_mark(&self, group_id: &str, id: &[u8]) -> Option<&'static str> {
+        if self.is_unsent(id) {
+            return Some("…");
+        }
+        let members = self.receipts.get(id).filter(|m| !m.is_empty())?;
+        let others = self
+            .groups
+            .get(group_id)?
+            .members()
+            .count()
+            .saturating_sub(1);
+        let read = members.values().filter(|read| **read).count();
+        Some(if read >= others { "✓✓" } else { "✓" })
+    }
+
+    /// Show an edit, delete or reaction and add it to the history. Edits and
+    /// deletes of someone else's message are ignored.
+    fn show_update(&self, chat: &Chat, sender: &str, message: &AppMessage) -> Result<()> {
+        let Some((target, text)) = message.as_update() else {
+            return Ok(());
+        };
+        let original = self.history_message(chat.group_id, target)?;
+        if let Some(original) = &original {
+            if message.kind != "react" && original.sender != sender {
+                warn!(kind = %message.kind, sender, "Ignored a change to someone else's message");
+                return Ok(());
+            }
+        }
+        let what = match &original {
+            Some(original) => format!("\"{}\"", original.text),
+            None => short_id(target),
+        };
+        let shown = match message.kind.as_str() {
+            "edit" => format!("(edited {}) {}", what, text),
+            "delete" => format!("(deleted {})", what),
+            _ => format!("(reacted {} to {})", text, what),
+        };
+        let event = json!({
+            "event": message.kind,
+            "id": message_id(&message.id),
+            "target": hex::encode(target),
+        });
+        let is_self = sender == self.client_id;
+        let name = self.aliases.name(sender);
+        print_msg(event, chat, Local::now(), sender, name, &shown, is_self);
+        if let Some(history) = &self.history {
+            history.append(chat.group_id, sender, message)?;
+        }
+        Ok(())
+    }
+
+    /// A message of a group's history by its ID, None without a history
+    fn history_message(&self, group_id: &str, id: &[u8]) -> Result<Option<HistoryEntry>> {
+        let Some(history) = &self.history else {
+            return Ok(None);
+        };
+        Ok(history
+            .recent(group_id, usize::MAX)?
+            .into_iter()
+            .find(|entry| entry.id == id))
+    }
+
+    /// Give up waiting for messages missing for longer than REORDER_WINDOW
+    /// by `now`, and for Commits that messages held for longer than
+    /// FUTURE_MESSAGE_TIMEOUT follow; returns whether anything was shown
+    fn expire_gaps(&mut self, now: Instant) -> Result<bool> {
+        let expired = self.expire_future_messages(now);
+        self.typing
+            .retain(|_, at| now.duration_since(*at) < TYPING_DISPLAY);
+        let mut released = Vec::new();
+        for ((group_id, sender), order) in &mut self.receive_order {
+            let ordered = order.expire(now);
+            if !ordered.is_empty() {
+                released.push((group_id.clone(), sender.clone(), ordered));
+            }
+        }
+        let shown = expired || !released.is_empty();
+        for (group_id, sender, ordered) in released {
+            self.show_ordered(&group_id, &sender, ordered)?;
+        }
+        Ok(shown)
+    }
+
+    /// Drop messages held for a later epoch whose Commit has not come within
+    /// FUTURE_MESSAGE_TIMEOUT, with a warning; returns whether any were
+    fn expire_future_messages(&mut self, now: Instant) -> bool {
+        let mut dropped = Vec::new();
+        for (group_id, held) in &mut self.future_messages {
+            let before = held.len();
+            held.retain(|(_, arrival, _)| now.duration_since(*arrival) < FUTURE_MESSAGE_TIMEOUT);
+            if held.len() < before {
+                dropped.push((group_id.clone(), before - held.len()));
+            }
+        }
+        self.future_messages.retain(|_, held| !held.is_empty());
+        for (group_id, count) in &dropped {
+            warn!(group_id, count, "Dropped messages from a later epoch");
+            let epoch = self.groups.get(group_id).map(|g| g.epoch().as_u64());
+            log_event(
+                json!({ "event": "expired", "group_id": group_id, "count": count }),
+                &format!(
+                    "Dropped {} message(s) in {}: the Commit they follow did not arrive (still at epoch {})",
+                    count,
+                    self.chat_name(group_id),
+                    epoch.unwrap_or_default()
+                ),
+            );
+        }
+        !dropped.is_empty()
+    }
+
+    /// Take a held message for the group's current epoch, discarding those
+    /// that can no longer be processed
+    fn next_future_message(&mut self, group_id: &str) -> Option<Vec<u8>> {
+        let epoch = self.groups.get(group_id)?.epoch().as_u64();
+        let held = self.future_messages.get_mut(group_id)?;
+        held.retain(|(e, _, _)| *e >= epoch);
+        let index = held.iter().position(|(e, _, _)| *e == epoch)?;
+        Some(held.remove(index).2)
+    }
+
+    /// Commit a member's leave request. The remaining member with the lowest
+    /// leaf index commits it, so members don't race with competing Commits;
+    /// a 1:1 session ends instead.
+    #[instrument(skip(self, leaver))]
+    fn commit_leave(&mut self, group_id: &str, leaver: LeafNodeIndex) -> Result<()> {
+        let is_session = self.session_peer(group_id).is_some();
+        let group = self
+            .groups
+            .get_mut(group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        if is_session {
+            let leaver_id = group.member(leaver).map(member_id).unwrap_or_default();
+            log(&format!(
+                "{} ended the session",
+                self.aliases.name(&leaver_id)
+            ));
+            self.clear_group_info(group_id)?;
+            return self.forget_group(group_id);
+        }
+
+        let committer = group.members().map(|m| m.index).find(|i| *i != leaver);
+        if committer != Some(group.own_leaf_index()) {
+            return Ok(());
+        }
+
+        let leaver_id = group.member(leaver).map(member_id).unwrap_or_default();
+        let (commit, _, _) = group.commit_to_pending_proposals(&self.backend, &self.signer)?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+
+        // Send Commit
+        self.publish_to_group(
+            group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+        if let Some(history) = &self.history {
+            let namespace = self.group_namespaces.get(group_id).map(String::as_str);
+            let leaver_id = qualified(leaver_id, namespace);
+            history.append_event(group_id, &self.client_id, "left", &leaver_id)?;
+        }
+        self.publish_group_info(group_id)
+    }
+
+    /// Drop a group we were removed from: removed clients can't read the
+    /// group any more, so stop listening and delete its state
+    fn forget_group(&mut self, group_id: &str) -> Result<()> {
+        if !self.wildcard_groups {
+            self.mqtt.try_unsubscribe(self.group_topic(group_id, "m"))?;
+        }
+        if let Some(peer_id) = self.session_peer(group_id).map(str::to_string) {
+            self.mqtt.try_unsubscribe(self.peer_topic("p", &peer_id))?;
+            self.presence.remove(&peer_id);
+        }
+        self.sessions.retain(|_, id| id != group_id);
+        self.group_names.retain(|_, id| id != group_id);
+        self.broadcast.remove(group_id);
+        self.future_messages.remove(group_id);
+        self.own_messages.remove(group_id);
+        self.unread.remove(group_id);
+        self.send_seq.remove(group_id);
+        self.key_updates.remove(group_id);
+        self.last_sent.remove(group_id);
+        self.receive_order.retain(|(id, _), _| id != group_id);
+        self.group_namespaces.remove(group_id);
+        self.credential_changes.retain(|(id, _), _| id != group_id);
+        self.member_caps.retain(|(id, _), _| id != group_id);
+        if let Some(mut group) = self.groups.remove(group_id) {
+            group.delete(self.backend.storage())?;
+        }
+        Ok(())
+    }
+
+    /// Warn about verified peers whose signature key in a group is not the
+    /// one the user verified, and forget that they were verified
+    fn check_verified(&mut self, group_id: &str) {
+        let Some(group) = self.groups.get(group_id) else {
+            return;
+        };
+        let changed: Vec<String> = group
+            .members()
+            .filter(|m| {
+                self.verified
+                    .get(&self.member_name(group_id, &m.credential))
+                    .is_some_and(|key| *key != hex::encode(&m.signature_key))
+            })
+            .map(|m| self.member_name(group_id, &m.credential))
+            .collect();
+        for peer_id in changed {
+            self.verified.remove(&peer_id);
+            let peer = self.aliases.name(&peer_id);
+            log_event(
+                json!({ "event": "key_changed", "peer": peer_id, "group_id": group_id }),
+                &format!(
+                    "The key of {} changed, so they are no longer verified; use 'verify {}' to compare safety numbers again",
+                    peer, peer
+                ),
+            );
+        }
+    }
+
+    /// Turn a 1:1 session into a named group, freeing the peer for a new
+    /// session
+    fn promote_session(&mut self, group_id: &str) {
+        let Some(peer_id) = self.session_peer(group_id).map(str::to_string) else {
+            return;
+        };
+        self.sessions.remove(&peer_id);
+        let name = self.unused_group_name(&format!("group-{}", &group_id[..8]));
+        self.group_names.insert(name.clone(), group_id.to_string());
+        log(&format!(
+            "Session with {} is now group {}; use 'group chat {} <message>'",
+            peer_id, name, name
+        ));
+    }
+}
+
+// ============================================================================
+// Commands
+// ============================================================================
+
+impl RelayClient {
+    fn connect(&mut self, peer_id: &str) -> Result<()> {
+        let peer_id = match peer_id.strip_prefix(PAIRING_SCHEME) {
+            Some(uri) => self.parse_pairing_uri(uri)?,
+            None => self.aliases.resolve(peer_id).to_string(),
+        };
+        let peer = self.aliases.name(&peer_id).to_string();
+        if self.sessions.contains_key(&peer_id) {
+            log(&format!("Already connected to {}", peer));
+            return Ok(());
+        }
+
+        // If we already have their KeyPackage, establish session immediately
+        if self.has_key_package(&peer_id) {
+            self.create_group(&peer_id)?;
+            log_event(
+                json!({ "event": "session", "peer": peer_id, "group_id": self.sessions[&peer_id] }),
+                &format!("Session established with {}", peer),
+            );
+            return Ok(());
+        }
+
+        // Otherwise, fetch KeyPackage and mark as pending
+        self.mqtt
+            .try_subscribe(self.peer_topic("k", &peer_id), QoS::AtLeastOnce)?;
+        self.pending_connects.push(peer_id);
+        log(&format!("Connecting to {}...", peer));
+        Ok(())
+    }
+
+    /// Our pairing URI; with `hint`, it names the topic of our KeyPackages
+    /// and the broker too
+    fn pairing_uri(&self, hint: bool) -> String {
+        let mut uri = format!("{}{}", PAIRING_SCHEME, self.client_id);
+        if hint {
+            let topic = self.topic_in(None, &format!("k/{}", self.client_id));
+            uri += &format!("?kp={}&broker={}", topic, self.broker);
+        }
+        uri
+    }
+
+    /// Show our pairing URI as a QR code, for a peer next to us to scan
+    fn show_qr(&self, hint: bool) -> Result<()> {
+        let uri = self.pairing_uri(hint);
+        let code = QrCode::new(uri.as_bytes())?;
+        // Light modules on a dark terminal, which most are
+        let image = code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .quiet_zone(true)
+            .build();
+        say_event(
+            json!({ "event": "qr", "uri": uri }),
+            &format!("{}\n{}", image, uri),
+        );
+        Ok(())
+    }
+
+    /// The peer a pairing URI (after the scheme) names. A KeyPackage topic
+    /// in another namespace names the peer there.
+    fn parse_pairing_uri(&self, uri: &str) -> Result<String> {
+        let (client_id, query) = uri.split_once('?').unwrap_or((uri, ""));
+        if client_id.is_empty() || client_id.contains(['/', '+', '#', '@']) {
+            return Err(anyhow!("Invalid pairing URI '{}{}'", PAIRING_SCHEME, uri));
+        }
+        let mut peer_id = client_id.to_string();
+        for (key, value) in query.split('&').filter_map(|p| p.split_once('=')) {
+            match key {
+                "kp" => {
+                    let Some((namespace, path)) = self.split_topic(value) else {
+                        return Err(anyhow!(
+                            "{} publishes in '{}', which this client doesn't take part in; add it with --namespace",
+                            client_id,
+                            value.rsplit_once("/k/").map_or(value, |(prefix, _)| prefix)
+                        ));
+                    };
+                    if path != format!("k/{}", client_id) {
+                        return Err(anyhow!("Invalid KeyPackage topic '{}'", value));
+                    }
+                    peer_id = qualified(client_id.to_string(), namespace.as_deref());
+                }
+                "broker" if value != self.broker => {
+                    log(&format!(
+                        "{} uses the broker {}, this client {}; they can only be reached on the same broker",
+                        client_id, value, self.broker
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(peer_id)
+    }
+
+    fn send(&mut self, peer_id: &str, text: &str) -> Result<()> {
+        // Try to find group by peer_id or partial match
+        let peer = self.find_peer(peer_id)?;
+
+        // Must have an active session
+        let group_id = self
+            .sessions
+            .get(&peer)
+            .ok_or_else(|| {
+                let peer = self.aliases.name(&peer);
+                anyhow!("No session with {}. Use 'connect {}' first.", peer, peer)
+            })?
+            .clone();
+        let message = AppMessage::text(text);
+        self.send_to(&group_id, &message)?;
+
+        // Show sent message locally
+        let chat = Chat {
+            group_id: &group_id,
+            name: None,
+        };
+        log_msg(&chat, &message.id, &self.client_id, "", text, true);
+        Ok(())
+    }
+
+    /// Send a message to a group and add it to the history
+    fn send_to(&mut self, group_id: &str, message: &AppMessage) -> Result<()> {
+        self.send_message(group_id, message)?;
+        // Typing again after this message is news
+        self.typing_sent.remove(group_id);
+        if message.as_text().is_some() || message.as_attachment().is_some() {
+            self.receipts.insert(message.id.clone(), BTreeMap::new());
+        }
+        if let Some(history) = &self.history {
+            history.append(group_id, &self.client_id, message)?;
+        }
+        Ok(())
+    }
+
+    /// Tell a conversation that we are composing a message, unless we told
+    /// it less than TYPING_INTERVAL ago. Nothing is sent while offline: an
+    /// indicator delivered later would be stale.
+    fn send_typing(&mut self, group_id: &str) -> Result<()> {
+        if !self.typing_indicators || !self.connected {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if let Some(sent) = self.typing_sent.get(group_id) {
+            if now.duration_since(*sent) < TYPING_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.typing_sent.insert(group_id.to_string(), now);
+        self.send_message(group_id, &AppMessage::new("typing", Value::Null))
+    }
+
+    /// Whether every other member of a group reads an envelope feature, as
+    /// of its last message; members yet to send one read only CBOR
+    fn all_read(&self, group_id: &str, capability: &str) -> bool {
+        let Some(group) = self.groups.get(group_id) else {
+            return false;
+        };
+        group.members().all(|m| {
+            let member = self.member_name(group_id, &m.credential);
+            if member == self.client_id {
+                return true;
+            }
+            match self.member_caps.get(&(group_id.to_string(), member)) {
+                Some(caps) => caps.iter().any(|c| c == capability),
+                None => capability == "cbor",
+            }
+        })
+    }
+
+    /// Members typing in a conversation, by name, as of `now`
+    fn typing_in(&self, group_id: &str, now: Instant) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .typing
+            .iter()
+            .filter(|((g, _), at)| g == group_id && now.duration_since(**at) < TYPING_DISPLAY)
+            .map(|((_, sender), _)| self.aliases.name(sender))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// Encrypt an application message for a group and publish it
+    #[instrument(skip(self, message), fields(kind = %message.kind))]
+    fn send_message(&mut self, group_id: &str, message: &AppMessage) -> Result<()> {
+        // The creator would ignore typing and receipts; the rest is refused
+        if self.read_only(group_id) {
+            if ["typing", "delivered", "read", "heartbeat"].contains(&message.kind.as_str()) {
+                return Ok(());
+            }
+            return Err(anyhow!(
+                "Only the creator of {} writes in it",
+                self.chat_name(group_id)
+            ));
+        }
+        let compress = self.all_read(group_id, "zstd");
+        let protobuf = self.all_read(group_id, "protobuf")
+            && (self.prefer_protobuf || !self.all_read(group_id, "cbor"));
+        let group = self
+            .groups
+            .get_mut(group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+
+        // A message that may be lost carries no sequence number, so its loss
+        // doesn't show as a gap
+        let qos = self.qos.of(PayloadKind::Application, Some(&message.kind));
+        let sequenced = !matches!(qos, QoS::AtMostOnce);
+        let seq = self.send_seq.entry(group_id.to_string()).or_default();
+        group.set_aad(if sequenced {
+            encode_aad(*seq)
+        } else {
+            Vec::new()
+        });
+        let envelope = match protobuf {
+            true => message.encode_protobuf(compress),
+            false => message.encode(compress),
+        };
+        let plaintext = pad(envelope, &self.padding);
+        let mls_msg = group.create_message(&self.backend, &self.signer, &plaintext)?;
+        if sequenced {
+            *seq += 1;
+        }
+        let msg_bytes = mls_msg.tls_serialize_detached()?;
+        self.remember_own(group_id, &msg_bytes)?;
+        self.last_sent.insert(group_id.to_string(), Instant::now());
+
+        // The message is encrypted and numbered already, so one that can't
+        // be published now is kept and published later as it is. Messages
+        // after it in the group wait behind it, to keep their order.
+        let mut unsent = Unsent {
+            group_id: group_id.to_string(),
+            id: message.id.clone(),
+            shown: message.as_text().is_some() || message.as_attachment().is_some(),
+            qos,
+            payload: msg_bytes,
+            failures: 0,
+            next_try: Instant::now(),
+        };
+        let waiting = self.unsent.iter().any(|u| u.group_id == group_id);
+        let reason = if !self.connected {
+            "offline".to_string()
+        } else if waiting {
+            "earlier messages are waiting".to_string()
+        } else {
+            match self.publish_unsent(&unsent) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    unsent.failures = 1;
+                    unsent.next_try += RETRY_MIN_DELAY;
+                    e.to_string()
+                }
+            }
+        };
+        if self.unsent.len() >= MAX_UNSENT {
+            return Err(anyhow!(
+                "Couldn't send to {}: {}",
+                self.chat_name(group_id),
+                reason
+            ));
+        }
+        debug!(group_id, reason = %reason, "Kept a message to publish later");
+        if unsent.shown {
+            let chat = Chat {
+                group_id,
+                name: self.group_name(group_id),
+            };
+            let event =
+                json!({ "event": "queued", "id": message_id(&message.id), "reason": reason });
+            log_chat_state(event, &chat, &self.client_id);
+            if !waiting && self.connected {
+                log(&format!(
+                    "Couldn't send to {} ({}); it will be retried",
+                    self.chat_name(group_id),
+                    reason
+                ));
+            }
+        }
+        self.unsent.push_back(unsent);
+        Ok(())
+    }
+
+    /// Publish an encrypted application message
+    fn publish_unsent(&mut self, unsent: &Unsent) -> Result<()> {
+        let topic = self.group_topic(&unsent.group_id, "m");
+        let payload = unsent.payload.clone();
+        self.publish_with_qos(topic, PayloadKind::Application, unsent.qos, payload)?;
+        self.metrics.messages_sent += 1;
+        Ok(())
+    }
+
+    /// Publish the kept messages that are due as of `now`, or all of them
+    /// after a reconnect, in the order they were sent. A failure puts the
+    /// message off by twice the last delay, with the rest of its group.
+    /// Messages of groups left meanwhile are dropped.
+    fn retry_unsent(&mut self, now: Instant, reconnected: bool) -> Result<()> {
+        if !self.connected || self.unsent.is_empty() {
+            return Ok(());
+        }
+        let mut blocked: HashSet<String> = HashSet::new();
+        let mut sent: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for mut unsent in std::mem::take(&mut self.unsent) {
+            if !self.groups.contains_key(&unsent.group_id) {
+                continue;
+            }
+            let due = reconnected || now >= unsent.next_try;
+            if !due || blocked.contains(&unsent.group_id) {
+                blocked.insert(unsent.group_id.clone());
+                self.unsent.push_back(unsent);
+                continue;
+            }
+            if let Err(e) = self.publish_unsent(&unsent) {
+                debug!(
+                    group_id = unsent.group_id,
+                    error = %e,
+                    "Publishing a kept message failed again"
+                );
+                let delay = RETRY_MIN_DELAY * 2u32.saturating_pow(unsent.failures.min(16));
+                unsent.failures += 1;
+                unsent.next_try = now + delay.min(RETRY_MAX_DELAY);
+                blocked.insert(unsent.group_id.clone());
+                self.unsent.push_back(unsent);
+                continue;
+            }
+            if unsent.shown {
+                sent.entry(unsent.group_id)
+                    .or_default()
+                    .extend(message_id(&unsent.id));
+            }
+        }
+        for (group_id, ids) in sent {
+            let chat = Chat {
+                group_id: &group_id,
+                name: self.group_name(&group_id),
+            };
+            let text = format!(
+                "Sent {} kept message(s) to {}",
+                ids.len(),
+                self.chat_name(&group_id)
+            );
+            log_chat_state(
+                json!({ "event": "sent", "ids": ids }),
+                &chat,
+                &self.client_id,
+            );
+            log(&text);
+        }
+        Ok(())
+    }
+
+    /// Whether a message of ours is still waiting to be published
+    fn is_unsent(&self, id: &[u8]) -> bool {
+        !id.is_empty() && self.unsent.iter().any(|u| u.id == id)
+    }
+
+    fn find_peer(&self, query: &str) -> Result<String> {
+        let query = self.aliases.resolve(query);
+
+        // Exact match in sessions
+        if self.sessions.contains_key(query) {
+            return Ok(query.to_string());
+        }
+
+        // Exact match in key_packages
+        if self.key_packages.contains_key(query) {
+            return Ok(query.to_string());
+        }
+
+        // Partial match in sessions (prefix)
+        let session_matches: Vec<_> = self
+            .sessions
+            .keys()
+            .filter(|k| k.starts_with(query))
+            .collect();
+        if session_matches.len() == 1 {
+            return Ok(session_matches[0].clone());
+        }
+
+        // Partial match in key_packages (prefix)
+        let kp_matches: Vec<_> = self
+            .key_packages
+            .keys()
+            .filter(|k| k.starts_with(query))
+            .collect();
+        if kp_matches.len() == 1 {
+            return Ok(kp_matches[0].clone());
+        }
+
+        // Show available peers
+        let mut available = vec![];
+        for peer in self.sessions.keys() {
+            available.push(format!("{} (session)", self.aliases.name(peer)));
+        }
+        for peer in self.key_packages.keys() {
+            if !self.sessions.contains_key(peer) {
+                available.push(format!("{} (keypackage)", self.aliases.name(peer)));
+            }
+        }
+
+        if available.is_empty() {
+            Err(anyhow!(
+                "No peers available. Use 'connect <peer_id>' first."
+            ))
+        } else {
+            Err(anyhow!(
+                "Unknown peer '{}'. Available:\n  {}",
+                query,
+                available.join("\n  ")
+            ))
+        }
+    }
+
+    /// Start a 1:1 session with a peer whose KeyPackage we hold
+    #[instrument(skip(self))]
+    fn create_group(&mut self, peer_id: &str) -> Result<()> {
+        if !self.key_packages.contains_key(peer_id) {
+            return Err(anyhow!("No KeyPackage for peer (use 'connect' first)"));
+        }
+        let namespace = self.split_peer(peer_id).0.map(str::to_string);
+        let group_id = self.new_group(namespace)?;
+        self.add_member(&group_id, peer_id)?;
+        self.sessions.insert(peer_id.to_string(), group_id);
+        self.seen(peer_id);
+        self.mqtt
+            .try_subscribe(self.peer_topic("p", peer_id), QoS::AtLeastOnce)?;
+        Ok(())
+    }
+
+    /// Create a group with only ourselves in it, in a namespace (None: the
+    /// main one), and subscribe to it
+    fn new_group(&mut self, namespace: Option<String>) -> Result<String> {
+        // Generate random group_id
+        let group_id_bytes: [u8; 16] = rand::thread_rng().gen();
+        let group_id = hex::encode(group_id_bytes);
+
+        // Create group
+        let config = MlsGroupCreateConfig::builder()
+            .ciphersuite(CIPHERSUITE)
+            .use_ratchet_tree_extension(true)
+            .max_past_epochs(MAX_PAST_EPOCHS)
+            .build();
+
+        let group = MlsGroup::new_with_group_id(
+            &self.backend,
+            &self.signer,
+            &config,
             GroupId::from_slice(&group_id_bytes),
             self.credential.clone(),
         )?;
+        if let Some(namespace) = namespace {
+            self.group_namespaces.insert(group_id.clone(), namespace);
+        }
+
+        // Subscribe to group messages
+        self.subscribe_group(&group_id)?;
+
+        self.groups.insert(group_id.clone(), group);
+        Ok(group_id)
+    }
+
+    /// Add a peer whose KeyPackage we hold: the Commit goes to the current
+    /// members, the Welcome to the peer, and the GroupInfo is republished
+    #[instrument(skip(self))]
+    fn add_member(&mut self, group_id: &str, peer_id: &str) -> Result<()> {
+        let peer_kp = self.take_key_package(peer_id)?;
+        let welcome_topic = self.peer_topic("w", peer_id);
+        let group = self
+            .groups
+            .get_mut(group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+
+        // Add peer
+        let (commit, welcome, _) = group.add_members(&self.backend, &self.signer, &[peer_kp])?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+
+        // Send Welcome
+        self.publish(
+            welcome_topic,
+            PayloadKind::Welcome,
+            welcome.tls_serialize_detached()?,
+        )?;
+
+        // Send Commit to the existing members
+        self.publish_to_group(
+            group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+
+        self.check_verified(group_id);
+        self.publish_group_info(group_id)?;
+        if let Some(history) = &self.history {
+            history.append_event(group_id, &self.client_id, "joined", peer_id)?;
+        }
+
+        // Members learn that the group is a broadcast one from its creator,
+        // after joining
+        if self.broadcast.contains(group_id) {
+            self.send_message(group_id, &AppMessage::new("broadcast", Value::Null))?;
+        }
+        Ok(())
+    }
+
+    /// Whether we hold a KeyPackage of the peer that hasn't expired since it
+    /// arrived; expired ones are dropped, so the retained ones are fetched
+    /// again
+    fn has_key_package(&mut self, peer_id: &str) -> bool {
+        let Some(pool) = self.key_packages.get_mut(peer_id) else {
+            return false;
+        };
+        pool.retain(|kp| kp.life_time().is_valid());
+        if pool.is_empty() {
+            self.key_packages.remove(peer_id);
+            return false;
+        }
+        true
+    }
+
+    /// Pick one of a peer's KeyPackages at random, so peers adding them at
+    /// the same time likely pick different ones (protocol Section 6.3).
+    /// One-time KeyPackages are used up; the last resort one is used only
+    /// when none are left, and kept.
+    fn take_key_package(&mut self, peer_id: &str) -> Result<KeyPackage> {
+        let no_key_package = || anyhow!("No KeyPackage for {}", peer_id);
+        let pool = self
+            .key_packages
+            .get_mut(peer_id)
+            .ok_or_else(no_key_package)?;
+        let one_time: Vec<usize> = (0..pool.len())
+            .filter(|&i| !pool[i].last_resort())
+            .collect();
+        let key_package = match one_time.len() {
+            0 => pool.first().cloned().ok_or_else(no_key_package)?,
+            n => pool.remove(one_time[rand::thread_rng().gen_range(0..n)]),
+        };
+        if !key_package.last_resort() {
+            let used = key_package.hash_ref(self.backend.crypto())?;
+            self.used_key_packages
+                .entry(peer_id.to_string())
+                .or_default()
+                .push(used);
+        }
+        if pool.is_empty() {
+            self.key_packages.remove(peer_id);
+        }
+        Ok(key_package)
+    }
+
+    /// Delete the retained GroupInfo of a group nobody is left to maintain
+    fn clear_group_info(&self, group_id: &str) -> Result<()> {
+        // An empty retained message removes the retained one
+        self.publish(
+            self.group_topic(group_id, "i"),
+            PayloadKind::GroupInfo,
+            Vec::new(),
+        )?;
+        Ok(())
+    }
+
+    /// Publish the group's current GroupInfo (retained)
+    fn publish_group_info(&self, group_id: &str) -> Result<()> {
+        let group = self
+            .groups
+            .get(group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        let group_info = group.export_group_info(self.backend.crypto(), &self.signer, true)?;
+
+        self.publish(
+            self.group_topic(group_id, "i"),
+            PayloadKind::GroupInfo,
+            group_info.tls_serialize_detached()?,
+        )?;
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Group Commands
+// ============================================================================
+
+impl RelayClient {
+    /// Create a group in the main namespace, or in one given by its prefix.
+    /// In a broadcast group only we write.
+    fn group_create(&mut self, name: &str, namespace: Option<&str>, broadcast: bool) -> Result<()> {
+        if name.starts_with('#') {
+            return Err(anyhow!("Names starting with '#' are for channels"));
+        }
+        if self.group_names.contains_key(name) {
+            return Err(anyhow!("Group '{}' already exists", name));
+        }
+        let namespace = match namespace {
+            Some(prefix) if prefix.trim_end_matches('/') == self.topic_prefix => None,
+            Some(prefix) => Some(
+                self.namespaces
+                    .iter()
+                    .find(|n| *n == prefix.trim_end_matches('/'))
+                    .cloned()
+                    .ok_or_else(|| {
+                        anyhow!("Not in namespace '{}'; add it with --namespace", prefix)
+                    })?,
+            ),
+            None => None,
+        };
+        let group_id = self.new_group(namespace)?;
+        self.publish_group_info(&group_id)?;
+        self.group_names.insert(name.to_string(), group_id.clone());
+        if broadcast {
+            self.broadcast.insert(group_id);
+            log(&format!("Created broadcast group {}", name));
+        } else {
+            log(&format!("Created group {}", name));
+        }
+        log(&format!(
+            "Use 'group invite {} <peer_id>' to add members",
+            name
+        ));
+        Ok(())
+    }
+
+    fn group_invite(&mut self, name: &str, peer_id: &str) -> Result<()> {
+        let (name, group_id) = self.find_group(name)?;
+        // Only the creator tells new members that the group is a broadcast one
+        if self.read_only(&group_id) {
+            return Err(anyhow!("Only the creator of {} adds members", name));
+        }
+        // Only those who joined by name know the group is a channel
+        if self.is_channel(&group_id) {
+            return Err(anyhow!(
+                "Peers join channels themselves, with 'channel join {}'",
+                name
+            ));
+        }
+        // Peers we know may be given by prefix; others need their full ID
+        let mut peer_id = self
+            .find_peer(peer_id)
+            .unwrap_or_else(|_| self.aliases.resolve(peer_id).to_string());
+
+        // Only peers of the group's namespace can join; a plain Client ID
+        // is taken to be in it
+        let namespace = self.group_namespaces.get(&group_id).cloned();
+        match self.split_peer(&peer_id).0 {
+            Some(peer_namespace) if Some(peer_namespace) != namespace.as_deref() => {
+                return Err(anyhow!(
+                    "{} is in namespace {}, but {} is not",
+                    peer_id,
+                    peer_namespace,
+                    name
+                ));
+            }
+            None if namespace.is_some() => peer_id = qualified(peer_id, namespace.as_deref()),
+            _ => {}
+        }
+        let peer = self.aliases.name(&peer_id).to_string();
+
+        let is_member = self.groups[&group_id]
+            .members()
+            .any(|m| self.member_name(&group_id, &m.credential) == peer_id);
+        if is_member {
+            log(&format!("{} is already in {}", peer, name));
+            return Ok(());
+        }
+
+        // If we already have their KeyPackage, add them immediately
+        if self.has_key_package(&peer_id) {
+            self.add_member(&group_id, &peer_id)?;
+            log(&format!("Invited {} to {}", peer, name));
+            return Ok(());
+        }
+
+        // Otherwise, fetch KeyPackage and mark as pending
+        self.mqtt
+            .try_subscribe(self.peer_topic("k", &peer_id), QoS::AtLeastOnce)?;
+        self.pending_invites.push((group_id, peer_id));
+        log(&format!("Inviting {} to {}...", peer, name));
+        Ok(())
+    }
+
+    fn group_chat(&mut self, name: &str, text: &str) -> Result<()> {
+        let (name, group_id) = self.find_group(name)?;
+        let message = AppMessage::text(text);
+        self.send_to(&group_id, &message)?;
+        let chat = Chat {
+            group_id: &group_id,
+            name: Some(&name),
+        };
+        log_msg(&chat, &message.id, &self.client_id, "", text, true);
+        Ok(())
+    }
+
+    /// Leave a session or group: ask the other members to remove us, then
+    /// drop the group. Without other members, the group ends here.
+    #[instrument(skip(self))]
+    fn leave(&mut self, target: &str) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+
+        if group.members().count() > 1 {
+            // A member can't commit its own removal, so send a Remove
+            // proposal for one of the others to commit; they republish the
+            // GroupInfo then
+            let proposal = group.leave_group(&self.backend, &self.signer)?;
+            self.publish_to_group(
+                &group_id,
+                PayloadKind::Proposal,
+                proposal.tls_serialize_detached()?,
+            )?;
+        } else {
+            self.clear_group_info(&group_id)?;
+            if self.is_channel(&group_id) {
+                self.publish_channel_entry(&label, None)?;
+            }
+        }
+
+        self.forget_group(&group_id)?;
+        log(&format!("Left {}", label));
+        Ok(())
+    }
+
+    /// Replace our leaf's keys with a self-update Commit, so keys that leaked
+    /// before no longer decrypt new messages (post-compromise security)
+    #[instrument(skip(self))]
+    fn rekey(&mut self, target: &str) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let epoch = self.self_update(&group_id)?;
+        log(&format!("Rekeyed {} (epoch {})", label, epoch));
+        Ok(())
+    }
+
+    /// Commit a self-update in a group; returns the new epoch
+    fn self_update(&mut self, group_id: &str) -> Result<u64> {
+        let group = self
+            .groups
+            .get_mut(group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+
+        let bundle =
+            group.self_update(&self.backend, &self.signer, LeafNodeParameters::default())?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+        let epoch = group.epoch().as_u64();
+
+        // Send Commit
+        let (commit, _, _) = bundle.into_messages();
+        self.publish_to_group(
+            group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+        self.publish_group_info(group_id)?;
+        self.key_updates
+            .insert(group_id.to_string(), Local::now().timestamp() as u64);
+        Ok(epoch)
+    }
+
+    /// Commit the proposals members sent in a session or group: the Commit
+    /// goes to the members, the Welcome to those it adds
+    #[instrument(skip(self))]
+    fn commit(&mut self, target: &str) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let namespace = self.group_namespaces.get(&group_id).cloned();
+        let namespace = namespace.as_deref();
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        let mut count = 0;
+        for queued in group.pending_proposals() {
+            match queued.proposal() {
+                Proposal::Add(add) => {
+                    let id = member_id(add.key_package().leaf_node().credential());
+                    added.push(qualified(id, namespace));
+                }
+                Proposal::Remove(remove) => {
+                    if let Some(credential) = group.member(remove.removed()) {
+                        removed.push(qualified(member_id(credential), namespace));
+                    }
+                }
+                _ => {}
+            }
+            count += 1;
+        }
+        if count == 0 {
+            return Err(anyhow!("No proposals to commit in {}", label));
+        }
+
+        let (commit, welcome, _) =
+            group.commit_to_pending_proposals(&self.backend, &self.signer)?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+        let epoch = group.epoch().as_u64();
+        let grown = group.members().count() > 2;
+
+        // Send Commit
+        self.publish_to_group(
+            &group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+
+        // Send the Welcome to each member added
+        if let Some(welcome) = welcome {
+            let welcome = welcome.tls_serialize_detached()?;
+            for peer_id in &added {
+                let topic = self.peer_topic("w", peer_id);
+                self.publish(topic, PayloadKind::Welcome, welcome.clone())?;
+            }
+        }
+
+        if let Some(history) = &self.history {
+            for peer_id in &added {
+                history.append_event(&group_id, &self.client_id, "joined", peer_id)?;
+            }
+            for peer_id in &removed {
+                history.append_event(&group_id, &self.client_id, "member_removed", peer_id)?;
+            }
+        }
+        self.check_verified(&group_id);
+        self.publish_group_info(&group_id)?;
+        log(&format!(
+            "Committed {} proposal(s) in {} (epoch {})",
+            count, label, epoch
+        ));
+        if grown {
+            self.promote_session(&group_id);
+        }
+        Ok(())
+    }
+
+    /// Update our keys in the groups we last did so rekey_secs ago, or
+    /// that are marked due; checked from the main loop. Groups without a
+    /// record count from now. A failed update is retried after another
+    /// interval.
+    fn scheduled_rekeys(&mut self) -> Result<()> {
+        let Some(interval) = self.rekey_secs.filter(|_| self.connected) else {
+            return Ok(());
+        };
+        let now = Local::now().timestamp() as u64;
+        let mut due = Vec::new();
+        for group_id in self.groups.keys() {
+            let last = *self.key_updates.entry(group_id.clone()).or_insert(now);
+            if now.saturating_sub(last) >= interval {
+                due.push(group_id.clone());
+            }
+        }
+        for group_id in &due {
+            match self.self_update(group_id) {
+                Ok(epoch) => info!(group_id, epoch, "Updated our keys"),
+                Err(e) => {
+                    self.key_updates.insert(group_id.clone(), now);
+                    log_error(&e.context(format!("Rekeying {}", self.chat_name(group_id))));
+                }
+            }
+        }
+        if !due.is_empty() {
+            self.save_state()?;
+        }
+        Ok(())
+    }
+
+    /// Send a heartbeat to the groups we sent nothing to for the heartbeat
+    /// interval, so their members see we are still around; checked from
+    /// the main loop. Channels, being public rooms, get none.
+    fn send_heartbeats(&mut self, now: Instant) -> Result<()> {
+        let Some(interval) = self.heartbeat.filter(|_| self.connected) else {
+            return Ok(());
+        };
+        let quiet: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| group.members().count() > 1)
+            .map(|(group_id, _)| group_id)
+            .filter(|group_id| !self.is_channel(group_id) && !self.read_only(group_id))
+            .filter(|group_id| match self.last_sent.get(*group_id) {
+                Some(sent) => now.duration_since(*sent) >= interval,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        // A failed heartbeat is not retried before the next one is due
+        for group_id in &quiet {
+            self.last_sent.insert(group_id.clone(), now);
+            if let Err(e) = self.send_message(group_id, &AppMessage::new("heartbeat", Value::Null))
+            {
+                log_error(&e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Note that a peer sent something just now
+    fn seen(&mut self, peer_id: &str) {
+        if peer_id != self.client_id {
+            self.last_seen
+                .insert(peer_id.to_string(), Local::now().timestamp() as u64);
+        }
+    }
+
+    /// How long ago a peer last sent anything, in seconds
+    fn idle_for(&self, peer_id: &str) -> Option<u64> {
+        let seen = self.last_seen.get(peer_id)?;
+        Some((Local::now().timestamp() as u64).saturating_sub(*seen))
+    }
+
+    /// Whether a peer sent nothing for prune_days
+    fn is_stale(&self, peer_id: &str) -> bool {
+        self.idle_for(peer_id)
+            .is_some_and(|idle| idle >= self.prune_after)
+    }
+
+    /// End the sessions whose peer sent nothing for `max_idle`, or the
+    /// configured prune_days
+    fn prune(&mut self, max_idle: Option<&str>) -> Result<()> {
+        let max_idle = match max_idle {
+            Some(text) => parse_duration(text)?.num_seconds() as u64,
+            None => self.prune_after,
+        };
+        let mut stale: Vec<String> = self
+            .sessions
+            .keys()
+            .filter(|peer_id| self.idle_for(peer_id).is_some_and(|idle| idle >= max_idle))
+            .cloned()
+            .collect();
+        if stale.is_empty() {
+            say(&format!(
+                "No sessions idle for {} or more",
+                format_age(max_idle)
+            ));
+            return Ok(());
+        }
+        stale.sort();
+        for peer_id in &stale {
+            self.leave(peer_id)?;
+            self.last_seen.remove(peer_id);
+        }
+        Ok(())
+    }
+
+    /// Show the last messages of a session or group
+    fn show_history(&self, target: &str, count: Option<&str>) -> Result<()> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is turned off"))?;
+        let count = match count {
+            Some(n) => n
+                .parse()
+                .map_err(|_| anyhow!("Invalid message count '{}'", n))?,
+            None => DEFAULT_HISTORY_COUNT,
+        };
+        let (label, group_id) = self.find_chat(target)?;
+
+        let entries = history.recent(&group_id, count)?;
+        if entries.is_empty() {
+            say(&format!("No messages with {}", label));
+        }
+        let chat = Chat {
+            group_id: &group_id,
+            name: self.group_name(&group_id),
+        };
+        for entry in &entries {
+            let is_self = entry.sender == self.client_id;
+            let mut text = entry.text.clone();
+            if entry.edited {
+                text.push_str(" (edited)");
+            }
+            if !entry.reactions.is_empty() {
+                let reactions: Vec<String> = entry
+                    .reactions
+                    .iter()
+                    .map(|(sender, emoji)| {
+                        if *sender == self.client_id {
+                            format!("{} you", emoji)
+                        } else {
+                            format!("{} {}", emoji, self.aliases.name(sender))
+                        }
+                    })
+                    .collect();
+                text.push_str(&format!(" [{}]", reactions.join(", ")));
+            }
+            if !entry.id.is_empty() {
+                text.push_str(&format!(" ({})", short_id(&entry.id)));
+            }
+            log_history(
+                &chat,
+                entry,
+                self.aliases.name(&entry.sender),
+                &text,
+                is_self,
+            );
+        }
+        Ok(())
+    }
+
+    /// Print the messages matching a query, each with the messages around
+    /// it, in one session or group or in all of them. `/pattern/` is a
+    /// regular expression; anything else is matched as a substring,
+    /// ignoring case.
+    fn search(&self, query: &str, target: Option<&str>) -> Result<()> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is turned off"))?;
+        let pattern = match query.strip_prefix('/').and_then(|q| q.strip_suffix('/')) {
+            Some(re) if !re.is_empty() => {
+                Regex::new(re).map_err(|e| anyhow!("Invalid regular expression: {}", e))?
+            }
+            _ => RegexBuilder::new(&regex::escape(query))
+                .case_insensitive(true)
+                .build()?,
+        };
+        // Groups we left keep their history, under their ID
+        let group_ids = match target {
+            Some(target) => vec![self.find_chat(target)?.1],
+            None => history.group_ids()?,
+        };
+
+        let mut found = 0;
+        for group_id in group_ids {
+            let entries = history.recent(&group_id, usize::MAX)?;
+            let hits: Vec<usize> = (0..entries.len())
+                .filter(|i| pattern.is_match(&entries[*i].text))
+                .collect();
+            if hits.is_empty() {
+                continue;
+            }
+            say(&format!("{}:", self.chat_name(&group_id)));
+            // The last entry printed, so overlapping context isn't repeated
+            let mut shown: Option<usize> = None;
+            for hit in &hits {
+                let start = hit.saturating_sub(SEARCH_CONTEXT);
+                let end = (hit + SEARCH_CONTEXT).min(entries.len() - 1);
+                let start = match shown {
+                    Some(last) if start > last + 1 => {
+                        say("  ...");
+                        start
+                    }
+                    Some(last) => start.max(last + 1),
+                    None => start,
+                };
+                for (i, entry) in entries.iter().enumerate().take(end + 1).skip(start) {
+                    let marker = if hits.contains(&i) { '>' } else { ' ' };
+                    say(&format!("{} {}", marker, self.history_line(entry)));
+                }
+                shown = Some(end);
+            }
+            found += hits.len();
+        }
+        if found == 0 {
+            say(&format!("No messages match '{}'", query));
+        }
+        Ok(())
+    }
+
+    /// A message of the history on one line, for search results
+    fn history_line(&self, entry: &HistoryEntry) -> String {
+        let time = Local
+            .timestamp_opt(entry.ts, 0)
+            .single()
+            .unwrap_or_default();
+        let name = if entry.sender == self.client_id {
+            "you"
+        } else {
+            self.aliases.name(&entry.sender)
+        };
+        let mut line = format!(
+            "[{}] {}: {}",
+            time.format("%Y-%m-%d %H:%M:%S"),
+            name,
+            entry.text
+        );
+        if !entry.id.is_empty() {
+            line.push_str(&format!(" ({})", short_id(&entry.id)));
+        }
+        line
+    }
+
+    /// Write the history of a session or group to a file as a transcript:
+    /// Markdown for a .md file, JSON otherwise
+    fn export_transcript(&self, target: &str, file: &str) -> Result<()> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is turned off"))?;
+        let (label, group_id) = self.find_chat(target)?;
+        let entries = history.transcript(&group_id)?;
+        let time = |ts: i64| Local.timestamp_opt(ts, 0).single().unwrap_or_default();
+        let name = |id: &str| {
+            if id == self.client_id {
+                "you".to_string()
+            } else {
+                self.aliases.name(id).to_string()
+            }
+        };
+
+        let path = Path::new(file);
+        let markdown = path.extension().is_some_and(|e| e == "md");
+        let contents = if markdown {
+            let mut md = format!(
+                "# {}\n\nExported by {} on {}\n\n",
+                label,
+                self.client_id,
+                Local::now().format("%Y-%m-%d %H:%M")
+            );
+            for entry in &entries {
+                let ts = time(entry.ts).format("%Y-%m-%d %H:%M:%S");
+                let line = match entry.event.as_deref() {
+                    Some("joined") => format!("*{} {} joined*", ts, name(&entry.text)),
+                    Some("left") => format!("*{} {} left*", ts, name(&entry.text)),
+                    Some("key_changed") => {
+                        format!("*{} {}'s identity key changed*", ts, name(&entry.text))
+                    }
+                    Some(_) => format!(
+                        "*{} {} was removed by {}*",
+                        ts,
+                        name(&entry.text),
+                        name(&entry.sender)
+                    ),
+                    None => {
+                        let mut text = entry.text.replace('\n', "\n  ");
+                        if entry.edited {
+                            text.push_str(" *(edited)*");
+                        }
+                        if !entry.reactions.is_empty() {
+                            let reactions: Vec<String> = entry
+                                .reactions
+                                .iter()
+                                .map(|(sender, emoji)| format!("{} {}", emoji, name(sender)))
+                                .collect();
+                            text.push_str(&format!(" [{}]", reactions.join(", ")));
+                        }
+                        format!("**{} {}:** {}", ts, name(&entry.sender), text)
+                    }
+                };
+                md.push_str(&format!("- {}\n", line));
+            }
+            md
+        } else {
+            let items: Vec<JsonValue> = entries
+                .iter()
+                .map(|entry| {
+                    let ts = time(entry.ts).to_rfc3339();
+                    match &entry.event {
+                        Some(event) => json!({
+                            "ts": ts,
+                            "event": event,
+                            "member": entry.text,
+                            "by": entry.sender,
+                        }),
+                        None => {
+                            let reactions: Vec<JsonValue> = entry
+                                .reactions
+                                .iter()
+                                .map(|(sender, emoji)| json!({ "sender": sender, "emoji": emoji }))
+                                .collect();
+                            json!({
+                                "ts": ts,
+                                "event": "message",
+                                "id": message_id(&entry.id),
+                                "sender": entry.sender,
+                                "text": entry.text,
+                                "edited": entry.edited,
+                                "reactions": reactions,
+                            })
+                        }
+                    }
+                })
+                .collect();
+            let transcript = json!({
+                "chat": label,
+                "group_id": group_id,
+                "exported_by": self.client_id,
+                "exported_at": Local::now().to_rfc3339(),
+                "entries": items,
+            });
+            serde_json::to_string_pretty(&transcript)? + "\n"
+        };
+
+        // Created private, as the history is
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| anyhow!("Cannot create {}: {}", path.display(), e))?
+            .write_all(contents.as_bytes())?;
+        log(&format!(
+            "Exported {} entries of {} to {}",
+            entries.len(),
+            label,
+            file
+        ));
+        Ok(())
+    }
+
+    /// Edit, delete or react to a message of a session or group, given by
+    /// the start of its ID as `history` shows it
+    #[instrument(skip(self, text))]
+    fn update_message(&mut self, kind: &str, target: &str, id: &str, text: &str) -> Result<()> {
+        let (_, group_id) = self.find_chat(target)?;
+        let original = self.find_message(&group_id, id)?;
+        if kind != "react" && original.sender != self.client_id {
+            return Err(anyhow!("You can only {} your own messages", kind));
+        }
+        let message = AppMessage::update(kind, &original.id, text);
+        self.send_message(&group_id, &message)?;
+        let chat = Chat {
+            group_id: &group_id,
+            name: self.group_name(&group_id),
+        };
+        self.show_update(&chat, &self.client_id, &message)
+    }
+
+    /// Encrypt a file, upload it with upload_command and send the
+    /// conversation a reference to it
+    #[instrument(skip(self))]
+    fn attach(&mut self, target: &str, path: &str) -> Result<()> {
+        let command = self
+            .upload_command
+            .clone()
+            .ok_or_else(|| anyhow!("Set upload_command in the config to send attachments"))?;
+        let (_, group_id) = self.find_chat(target)?;
+        let path = Path::new(path);
+        let size = fs::metadata(path)
+            .map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?
+            .len();
+        if size > MAX_ATTACHMENT_SIZE {
+            return Err(anyhow!(
+                "{} is larger than {}",
+                path.display(),
+                format_size(MAX_ATTACHMENT_SIZE)
+            ));
+        }
+        let contents = fs::read(path)?;
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+
+        let mut message = AppMessage::new("attachment", Value::Null);
+        let group = self
+            .groups
+            .get(&group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        let key = group
+            .export_secret(
+                self.backend.crypto(),
+                ATTACHMENT_EXPORTER_LABEL,
+                &message.id,
+                32,
+            )
+            .map_err(|e| anyhow!("Cannot derive an attachment key: {:?}", e))?;
+        let mut attachment = Attachment {
+            url: String::new(),
+            key,
+            name,
+            size,
+        };
+        // Each key encrypts a single file, so the nonce can be fixed
+        let ciphertext = attachment
+            .cipher()?
+            .encrypt(&Nonce::default(), contents.as_slice())
+            .map_err(|_| anyhow!("Cannot encrypt {}", path.display()))?;
+        let file = self.state_dir.join("attachment.tmp");
+        fs::write(&file, ciphertext)?;
+        let uploaded = run_transfer_command(&command, &file, None);
+        let _ = fs::remove_file(&file);
+        attachment.url = uploaded?;
+        if attachment.url.is_empty() {
+            return Err(anyhow!("upload_command printed no URL"));
+        }
+
+        message.body = attachment.to_value();
+        self.send_to(&group_id, &message)?;
+        let chat = Chat {
+            group_id: &group_id,
+            name: self.group_name(&group_id),
+        };
+        let text = attachment.describe();
+        log_msg(&chat, &message.id, &self.client_id, "", &text, true);
+        Ok(())
+    }
+
+    /// Download the attachment of a message with download_command, decrypt
+    /// it and save it to `path`
+    #[instrument(skip(self))]
+    fn fetch(&mut self, target: &str, id: &str, path: &str) -> Result<()> {
+        let command = self
+            .download_command
+            .clone()
+            .ok_or_else(|| anyhow!("Set download_command in the config to fetch attachments"))?;
+        let (_, group_id) = self.find_chat(target)?;
+        let attachment = self
+            .find_message(&group_id, id)?
+            .attachment
+            .ok_or_else(|| anyhow!("Message {} has no attachment", id))?;
+        let file = self.state_dir.join("attachment.tmp");
+        let downloaded = run_transfer_command(&command, &file, Some(&attachment.url))
+            .and_then(|_| Ok(fs::read(&file)?));
+        let _ = fs::remove_file(&file);
+        let ciphertext = downloaded?;
+        // The tag takes 16 bytes
+        if ciphertext.len() as u64 > MAX_ATTACHMENT_SIZE + 16 {
+            return Err(anyhow!("The download of {} is too large", attachment.name));
+        }
+        let contents = attachment
+            .cipher()?
+            .decrypt(&Nonce::default(), ciphertext.as_slice())
+            .map_err(|_| anyhow!("The download of {} is corrupt", attachment.name))?;
+        fs::write(path, contents).map_err(|e| anyhow!("Cannot write {}: {}", path, e))?;
+        log(&format!("Saved {} to {}", attachment.name, path));
+        Ok(())
+    }
+
+    /// A message of a group's history by the start of its ID (hex)
+    fn find_message(&self, group_id: &str, query: &str) -> Result<HistoryEntry> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or_else(|| anyhow!("History is turned off, so messages can't be found"))?;
+        let query = query.to_lowercase();
+        let mut matches: Vec<HistoryEntry> = history
+            .recent(group_id, usize::MAX)?
+            .into_iter()
+            .filter(|entry| !entry.id.is_empty() && hex::encode(&entry.id).starts_with(&query))
+            .collect();
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            0 => Err(anyhow!("No message {}; 'history' shows message IDs", query)),
+            _ => Err(anyhow!(
+                "Several messages start with {}; give more of the ID",
+                query
+            )),
+        }
+    }
+
+    /// Resolve a session's peer or a group name to (label, group_id)
+    fn find_chat(&self, query: &str) -> Result<(String, String)> {
+        if let Ok(peer) = self.find_peer(query) {
+            if let Some(group_id) = self.sessions.get(&peer) {
+                return Ok((self.aliases.name(&peer).to_string(), group_id.clone()));
+            }
+        }
+        self.find_group(query)
+    }
+
+    /// Remove a member with a Commit, which goes to the remaining members
+    /// and the removed one alike
+    #[instrument(skip(self))]
+    fn group_kick(&mut self, name: &str, peer_id: &str) -> Result<()> {
+        let (name, group_id) = self.find_group(name)?;
+        let peer_id = self.aliases.resolve(peer_id);
+
+        // Find the member by client ID or unique prefix
+        let members: Vec<(LeafNodeIndex, String)> = self.groups[&group_id]
+            .members()
+            .map(|m| (m.index, self.member_name(&group_id, &m.credential)))
+            .collect();
+        let prefixed: Vec<_> = members
+            .iter()
+            .filter(|(_, id)| id.starts_with(peer_id))
+            .collect();
+        let (leaf_index, peer_id) =
+            match (members.iter().find(|(_, id)| id == peer_id), &prefixed[..]) {
+                (Some(member), _) | (None, &[member]) => member.clone(),
+                _ => return Err(anyhow!("{} is not a member of {}", peer_id, name)),
+            };
+        if peer_id == self.client_id {
+            return Err(anyhow!("Cannot kick yourself"));
+        }
+
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        let (commit, _, _) = group.remove_members(&self.backend, &self.signer, &[leaf_index])?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+
+        // Send Commit
+        self.publish_to_group(
+            &group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+        self.publish_group_info(&group_id)?;
+        if let Some(history) = &self.history {
+            history.append_event(&group_id, &self.client_id, "member_removed", &peer_id)?;
+        }
+
+        log(&format!(
+            "Removed {} from {}",
+            self.aliases.name(&peer_id),
+            name
+        ));
+        Ok(())
+    }
+
+    /// Send a member whose Welcome was lost a new one: a Commit removes their
+    /// leaf and another adds them again from a fresh KeyPackage. `peer` is
+    /// only needed for groups.
+    #[instrument(skip(self))]
+    fn resend_welcome(&mut self, target: &str, peer: Option<&str>) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let peer_id = match peer {
+            Some(peer) => self.aliases.resolve(peer).to_string(),
+            None => self
+                .session_peer(&group_id)
+                .ok_or_else(|| anyhow!("Name the member of {} to send a Welcome to", label))?
+                .to_string(),
+        };
+        if peer_id == self.client_id {
+            return Err(anyhow!("Cannot resend your own Welcome"));
+        }
+        let leaf_index = self.groups[&group_id]
+            .members()
+            .find(|m| self.member_name(&group_id, &m.credential) == peer_id)
+            .map(|m| m.index)
+            .ok_or_else(|| anyhow!("{} is not a member of {}", peer_id, label))?;
+
+        let group = self
+            .groups
+            .get_mut(&group_id)
+            .ok_or_else(|| anyhow!("Unknown group"))?;
+        let (commit, _, _) = group.remove_members(&self.backend, &self.signer, &[leaf_index])?;
+        group.merge_pending_commit(&self.backend)?;
+        self.metrics.epochs_advanced += 1;
+        self.publish_to_group(
+            &group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
+        )?;
+
+        let peer = self.aliases.name(&peer_id).to_string();
+        if self.has_key_package(&peer_id) {
+            self.add_member(&group_id, &peer_id)?;
+            log(&format!("Sent {} a new Welcome to {}", peer, label));
+        } else {
+            self.publish_group_info(&group_id)?;
+            self.mqtt
+                .try_subscribe(self.peer_topic("k", &peer_id), QoS::AtLeastOnce)?;
+            self.pending_invites.push((group_id, peer_id));
+            log(&format!(
+                "Removed {} from {}; waiting for a KeyPackage to send a new Welcome",
+                peer, label
+            ));
+        }
+        Ok(())
+    }
+
+    /// Show who is in a session or group's ratchet tree: each member's Client
+    /// ID from their credential, leaf index and any credential change seen
+    /// since the client started, with the current epoch
+    fn roster(&self, target: &str) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let group = &self.groups[&group_id];
+        say(&format!(
+            "Roster of {} (epoch {}, {} members):",
+            label,
+            group.epoch().as_u64(),
+            group.members().count()
+        ));
+        for member in group.members() {
+            let name = self.member_name(&group_id, &member.credential);
+            let mut notes = Vec::new();
+            if name == self.client_id {
+                notes.push("you".to_string());
+            } else if self.verified.contains_key(&name) {
+                notes.push("verified".to_string());
+            }
+            if let Some(epoch) = self
+                .credential_changes
+                .get(&(group_id.clone(), name.clone()))
+            {
+                notes.push(format!("credential changed at epoch {}", epoch));
+            }
+            let notes = if notes.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", notes.join(", "))
+            };
+            say(&format!(
+                "  [{}] {}{}",
+                member.index.u32(),
+                self.peer_label(&name),
+                notes
+            ));
+        }
+        Ok(())
+    }
+
+    /// Write what a bug report about a session or group needs to a JSON
+    /// file: the epoch, the public ratchet tree, pending proposals, message
+    /// ordering state and the latest history entries, without their text
+    fn debug_dump(&self, target: &str, file: Option<&str>) -> Result<()> {
+        let (label, group_id) = self.find_chat(target)?;
+        let group = &self.groups[&group_id];
+        let members: Vec<JsonValue> = group
+            .members()
+            .map(|m| {
+                json!({
+                    "index": m.index.u32(),
+                    "client_id": self.member_name(&group_id, &m.credential),
+                    "signature_key": hex::encode(&m.signature_key),
+                    "encryption_key": hex::encode(&m.encryption_key),
+                })
+            })
+            .collect();
+        let sender_name = |sender: &Sender| match sender {
+            Sender::Member(index) => group
+                .member(*index)
+                .map(|c| self.member_name(&group_id, c))
+                .unwrap_or_else(|| format!("leaf {}", index.u32())),
+            other => format!("{:?}", other),
+        };
+        let proposals: Vec<JsonValue> = group
+            .pending_proposals()
+            .map(|queued| {
+                json!({
+                    "type": format!("{:?}", queued.proposal().proposal_type()),
+                    "sender": sender_name(queued.sender()),
+                })
+            })
+            .collect();
+        let ordering: Vec<JsonValue> = self
+            .receive_order
+            .iter()
+            .filter(|((id, _), _)| *id == group_id)
+            .map(|((_, sender), order)| {
+                json!({
+                    "sender": sender,
+                    "next": order.next,
+                    "held": order.held.keys().collect::<Vec<_>>(),
+                    "seen": order.seen,
+                })
+            })
+            .collect();
+        let entries = match &self.history {
+            Some(history) => {
+                let mut entries = history.transcript(&group_id)?;
+                entries.split_off(entries.len().saturating_sub(DEBUG_DUMP_ENTRIES))
+            }
+            None => Vec::new(),
+        };
+        let entries: Vec<JsonValue> = entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "ts": entry.ts,
+                    "id": message_id(&entry.id),
+                    "sender": entry.sender,
+                    "event": entry.event,
+                    "edited": entry.edited,
+                    "attachment": entry.attachment.is_some(),
+                })
+            })
+            .collect();
+        let tree = group.export_ratchet_tree().tls_serialize_detached()?;
+        let dump = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "dumped_by": self.client_id,
+            "dumped_at": Local::now().to_rfc3339(),
+            "chat": label,
+            "group_id": group_id,
+            "namespace": self.group_namespaces.get(&group_id),
+            "ciphersuite": format!("{:?}", group.ciphersuite()),
+            "epoch": group.epoch().as_u64(),
+            "own_leaf": group.own_leaf_index().u32(),
+            "members": members,
+            "ratchet_tree": hex::encode(tree),
+            "pending_proposals": proposals,
+            "pending_commit": group.pending_commit().is_some(),
+            "send_seq": self.send_seq.get(&group_id),
+            "receive_order": ordering,
+            "future_messages": self.future_messages.get(&group_id).map_or(0, |m| m.len()),
+            "unsent": self.unsent.iter().filter(|u| u.group_id == group_id).count(),
+            "history": entries,
+        });
+
+        let file = match file {
+            Some(file) => file.to_string(),
+            None => format!("relay-dump-{}.json", &group_id[..group_id.len().min(8)]),
+        };
+        let path = Path::new(&file);
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .map_err(|e| anyhow!("Cannot create {}: {}", path.display(), e))?
+            .write_all((serde_json::to_string_pretty(&dump)? + "\n").as_bytes())?;
+        log(&format!("Wrote the state of {} to {}", label, file));
+        Ok(())
+    }
+
+    /// List sessions and peers we hold KeyPackages of, per namespace if
+    /// there are several
+    fn list_peers(&self) {
+        if self.sessions.is_empty() && self.key_packages.is_empty() {
+            say("No peers. Use 'connect <peer_id>' to connect.");
+            return;
+        }
+        for namespace in self.all_namespaces() {
+            let in_namespace = |peer: &&String| self.split_peer(peer).0 == namespace;
+            let sessions: Vec<&String> = self.sessions.keys().filter(in_namespace).collect();
+            let key_packages: Vec<&String> = self
+                .key_packages
+                .keys()
+                .filter(|peer| !self.sessions.contains_key(*peer))
+                .filter(in_namespace)
+                .collect();
+            if self.namespaces.is_empty() {
+                say("Active sessions:");
+            } else if sessions.is_empty() && key_packages.is_empty() {
+                continue;
+            } else {
+                say(&format!(
+                    "Peers in {}:",
+                    namespace.unwrap_or(&self.topic_prefix)
+                ));
+            }
+            for peer in sessions {
+                let verified = if self.verified.contains_key(peer) {
+                    ", verified"
+                } else {
+                    ""
+                };
+                let presence = match self.presence.get(peer) {
+                    Some(true) => ", online",
+                    Some(false) => ", offline",
+                    None => "",
+                };
+                let stale = if self.is_stale(peer) { ", stale" } else { "" };
+                let seen = match self.idle_for(peer) {
+                    Some(idle) => format!("{}, last seen {} ago", stale, format_age(idle)),
+                    None => String::new(),
+                };
+                say(&format!(
+                    "  {} (session{}{}{})",
+                    self.peer_label(peer),
+                    verified,
+                    presence,
+                    seen
+                ));
+            }
+            for peer in key_packages {
+                say(&format!("  {} (keypackage only)", self.peer_label(peer)));
+            }
+        }
+    }
+
+    /// Position of the oldest invitation from a peer, given by ID, unique
+    /// prefix or alias
+    fn find_invitation(&self, query: &str) -> Result<usize> {
+        let query = self.aliases.resolve(query);
+        if let Some(pos) = self.invitations.iter().position(|i| i.adder == query) {
+            return Ok(pos);
+        }
+        let mut adders: Vec<&str> = self
+            .invitations
+            .iter()
+            .map(|i| i.adder.as_str())
+            .filter(|adder| adder.starts_with(query))
+            .collect();
+        adders.dedup();
+        match adders.as_slice() {
+            [adder] => Ok(self
+                .invitations
+                .iter()
+                .position(|i| i.adder == *adder)
+                .unwrap_or_default()),
+            [] => Err(anyhow!("No invitation from '{}'", query)),
+            _ => Err(anyhow!(
+                "'{}' matches invitations from several peers",
+                query
+            )),
+        }
+    }
+
+    fn accept(&mut self, query: &str) -> Result<()> {
+        let pos = self.find_invitation(query)?;
+        let invitation = self.invitations.remove(pos);
+        self.join_invitation(invitation)
+    }
+
+    /// Turn an invitation down. The inviter isn't told, and still counts us
+    /// as a member.
+    fn decline(&mut self, query: &str) -> Result<()> {
+        let pos = self.find_invitation(query)?;
+        let invitation = self.invitations.remove(pos);
+        if !self.wildcard_groups {
+            let topic = format!("g/{}/m", invitation.group_id);
+            self.mqtt
+                .try_unsubscribe(self.topic_in(invitation.namespace.as_deref(), &topic))?;
+        }
+        log(&format!(
+            "Declined the invitation from {}",
+            self.aliases.name(&invitation.adder)
+        ));
+        Ok(())
+    }
+
+    fn list_invitations(&self) {
+        if self.invitations.is_empty() {
+            say("No invitations.");
+            return;
+        }
+        say("Invitations:");
+        for invitation in &self.invitations {
+            let members: Vec<&str> = invitation
+                .members
+                .iter()
+                .map(|id| self.aliases.name(id))
+                .collect();
+            say(&format!(
+                "  from {}: {}",
+                self.peer_label(&invitation.adder),
+                members.join(", ")
+            ));
+        }
+    }
+
+    fn group_list(&self) {
+        if self.group_names.is_empty() {
+            say("No groups. Use 'group create <name>' to create one.");
+            return;
+        }
+        say("Groups:");
+        for (name, group_id) in &self.group_names {
+            let members: Vec<String> = self.groups[group_id]
+                .members()
+                .map(|m| self.member_name(group_id, &m.credential))
+                .map(|id| {
+                    if id == self.client_id {
+                        "you".to_string()
+                    } else {
+                        self.aliases.name(&id).to_string()
+                    }
+                })
+                .collect();
+            let mode = if self.broadcast.contains(group_id) {
+                ", broadcast"
+            } else {
+                ""
+            };
+            say(&format!(
+                "  {} ({}{}): {}",
+                name,
+                members.len(),
+                mode,
+                members.join(", ")
+            ));
+        }
+    }
+
+    /// Give a peer a local nickname, replacing any previous one
+    fn set_alias(&mut self, peer_id: &str, alias: &str) -> Result<()> {
+        // Peers we know may be given by prefix or alias; others need their
+        // full ID
+        let peer_id = self
+            .find_peer(peer_id)
+            .unwrap_or_else(|_| self.aliases.resolve(peer_id).to_string());
+        if peer_id == self.client_id {
+            return Err(anyhow!("Cannot alias yourself"));
+        }
+        let taken = self.aliases.resolve(alias) != alias
+            || self.sessions.contains_key(alias)
+            || self.key_packages.contains_key(alias);
+        if taken && self.aliases.resolve(alias) != peer_id {
+            return Err(anyhow!("'{}' already names another peer", alias));
+        }
+        self.aliases.0.insert(peer_id.clone(), alias.to_string());
+        log(&format!("{} is now known as {}", peer_id, alias));
+        Ok(())
+    }
+
+    fn remove_alias(&mut self, alias: &str) -> Result<()> {
+        let peer_id = self.aliases.resolve(alias).to_string();
+        let alias = self
+            .aliases
+            .0
+            .remove(&peer_id)
+            .ok_or_else(|| anyhow!("No alias '{}'", alias))?;
+        log(&format!("Removed alias {} of {}", alias, peer_id));
+        Ok(())
+    }
+
+    /// Turn notifications for a peer off or on, and save that in the config
+    /// file
+    fn set_muted(&mut self, query: &str, muted: bool) -> Result<()> {
+        let peer_id = self
+            .find_peer(query)
+            .unwrap_or_else(|_| self.aliases.resolve(query).to_string());
+        // Unmuting also ends a timed mute
+        if !muted && self.silenced.contains_key(&peer_id) {
+            return self.unblock(&peer_id);
+        }
+        let was_muted = self.muted.contains(&peer_id);
+        if muted == was_muted {
+            let state = if muted { "already muted" } else { "not muted" };
+            return Err(anyhow!("{} is {}", self.aliases.name(&peer_id), state));
+        }
+        if muted {
+            self.muted.push(peer_id.clone());
+        } else {
+            self.muted.retain(|peer| *peer != peer_id);
+        }
+        let list: toml_edit::Array = self.muted.iter().map(String::as_str).collect();
+        save_config_entry(&self.config_path, "muted", list.into())?;
+        let verb = if muted { "Muted" } else { "Unmuted" };
+        log(&format!("{} {}", verb, self.aliases.name(&peer_id)));
+        Ok(())
+    }
+
+    fn list_muted(&self) {
+        if self.muted.is_empty() {
+            say("No muted peers. Use 'mute <peer_id>' to mute one.");
+            return;
+        }
+        say("Muted:");
+        for peer_id in &self.muted {
+            say(&format!("  {}", self.aliases.name(peer_id)));
+        }
+    }
+
+    /// Whether a peer's traffic is dropped: blocked, or muted for a while
+    fn is_blocked(&self, peer_id: &str) -> bool {
+        self.blocked.iter().any(|peer| peer == peer_id)
+            || self
+                .silenced
+                .get(peer_id)
+                .is_some_and(|until| *until > Local::now().timestamp())
+    }
+
+    /// Whether a publish is traffic of a blocked peer that the topic names:
+    /// their KeyPackages or presence, or an application message in our
+    /// session with them. Its Commits still apply, so the session works
+    /// again once the peer is unblocked.
+    fn is_from_blocked(&self, namespace: Option<&str>, path: &str, payload: &[u8]) -> bool {
+        let peer_path = ["k/", "p/", "d/"]
+            .iter()
+            .find_map(|prefix| path.strip_prefix(prefix));
+        if let Some(client_id) = peer_path {
+            return self.is_blocked(&qualified(client_id.to_string(), namespace));
+        }
+        let Some(group_id) = path.strip_prefix("g/").and_then(|p| p.strip_suffix("/m")) else {
+            return false;
+        };
+        self.session_peer(group_id)
+            .is_some_and(|peer_id| self.is_blocked(peer_id))
+            && is_application_message(payload)
+    }
+
+    /// Drop all traffic of a peer until unblocked, and save that in the
+    /// config file
+    fn block(&mut self, query: &str) -> Result<()> {
+        let peer_id = self
+            .find_peer(query)
+            .unwrap_or_else(|_| self.aliases.resolve(query).to_string());
+        if peer_id == self.client_id {
+            return Err(anyhow!("Cannot block yourself"));
+        }
+        if self.blocked.contains(&peer_id) {
+            return Err(anyhow!(
+                "{} is already blocked",
+                self.aliases.name(&peer_id)
+            ));
+        }
+        self.blocked.push(peer_id.clone());
+        self.silenced.remove(&peer_id);
+        self.save_blocks()?;
+        log(&format!("Blocked {}", self.aliases.name(&peer_id)));
+        Ok(())
+    }
+
+    /// Drop a peer's traffic for a while, as `block` does
+    fn silence(&mut self, query: &str, duration: &str) -> Result<()> {
+        let peer_id = self
+            .find_peer(query)
+            .unwrap_or_else(|_| self.aliases.resolve(query).to_string());
+        if peer_id == self.client_id {
+            return Err(anyhow!("Cannot mute yourself"));
+        }
+        if self.blocked.contains(&peer_id) {
+            return Err(anyhow!("{} is blocked", self.aliases.name(&peer_id)));
+        }
+        let until = Local::now() + parse_duration(duration)?;
+        self.silenced.insert(peer_id.clone(), until.timestamp());
+        self.save_blocks()?;
+        log(&format!(
+            "Muted {} until {}",
+            self.aliases.name(&peer_id),
+            until.format("%Y-%m-%d %H:%M")
+        ));
+        Ok(())
+    }
+
+    /// End a block or a timed mute
+    fn unblock(&mut self, query: &str) -> Result<()> {
+        let peer_id = self
+            .find_peer(query)
+            .unwrap_or_else(|_| self.aliases.resolve(query).to_string());
+        let was_blocked = self.blocked.contains(&peer_id);
+        self.blocked.retain(|peer| *peer != peer_id);
+        let was_silenced = self.silenced.remove(&peer_id).is_some();
+        if !was_blocked && !was_silenced {
+            return Err(anyhow!("{} is not blocked", self.aliases.name(&peer_id)));
+        }
+        self.save_blocks()?;
+        log(&format!("Unblocked {}", self.aliases.name(&peer_id)));
+        Ok(())
+    }
+
+    /// Save the blocked peers and timed mutes in the config file, dropping
+    /// mutes that have run out
+    fn save_blocks(&mut self) -> Result<()> {
+        let now = Local::now().timestamp();
+        self.silenced.retain(|_, until| *until > now);
+        let blocked: toml_edit::Array = self.blocked.iter().map(String::as_str).collect();
+        save_config_entry(&self.config_path, "blocked", blocked.into())?;
+        let mut silenced = toml_edit::InlineTable::new();
+        for (peer_id, until) in &self.silenced {
+            silenced.insert(peer_id.as_str(), (*until).into());
+        }
+        save_config_entry(&self.config_path, "silenced", silenced.into())
+    }
+
+    fn list_blocks(&self) {
+        let now = Local::now().timestamp();
+        let silenced: Vec<(&String, &i64)> = self
+            .silenced
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .collect();
+        if self.blocked.is_empty() && silenced.is_empty() {
+            say("No blocked peers. Use 'block <peer_id>' or 'mute <peer_id> <duration>'.");
+            return;
+        }
+        say("Blocked:");
+        for peer_id in &self.blocked {
+            say(&format!("  {}", self.aliases.name(peer_id)));
+        }
+        for (peer_id, until) in silenced {
+            let until = Local.timestamp_opt(*until, 0).single().unwrap_or_default();
+            say(&format!(
+                "  {} (muted until {})",
+                self.aliases.name(peer_id),
+                until.format("%Y-%m-%d %H:%M")
+            ));
+        }
+    }
+
+    fn list_aliases(&self) {
+        if self.aliases.0.is_empty() {
+            say("No aliases. Use 'alias <peer_id> <name>' to add one.");
+            return;
+        }
+        say("Aliases:");
+        for (peer_id, alias) in &self.aliases.0 {
+            say(&format!("  {} = {}", alias, peer_id));
+        }
+    }
+
+    /// Show the safety number of our session with a peer, to compare out of
+    /// band (protocol Section 7.4)
+    fn verify(&self, query: &str) -> Result<()> {
+        let (peer_id, group_id) = self.session_with(query)?;
+        let peer = self.aliases.name(&peer_id);
+        let group = &self.groups[&group_id];
+        say(&format!(
+            "Safety number with {} (epoch {}):",
+            peer,
+            group.epoch().as_u64()
+        ));
+        say(&format!("  {}", self.safety_number(group)?));
+        if self.verified.contains_key(&peer_id) {
+            say(&format!("{} is verified", peer));
+        } else {
+            say(&format!(
+                "Compare it with the one {} sees at the same epoch, in person or on a call; \
+                 if they match, run 'verify {} confirm'",
+                peer, peer
+            ));
+        }
+        Ok(())
+    }
+
+    /// Mark a peer verified: remember the signature key they use in our
+    /// session, so a change is reported
+    fn confirm_verified(&mut self, query: &str) -> Result<()> {
+        let (peer_id, group_id) = self.session_with(query)?;
+        let key = self.groups[&group_id]
+            .members()
+            .find(|m| self.member_name(&group_id, &m.credential) == peer_id)
+            .map(|m| hex::encode(&m.signature_key))
+            .ok_or_else(|| anyhow!("{} is not in the session", peer_id))?;
+        self.verified.insert(peer_id.clone(), key);
+        log_event(
+            json!({ "event": "verified", "peer": peer_id }),
+            &format!("Marked {} as verified", self.aliases.name(&peer_id)),
+        );
+        Ok(())
+    }
+
+    /// Replace our signature key, for when it may have leaked: a Commit in
+    /// every session and group moves our leaf to the new key, our
+    /// KeyPackages and directory entry are replaced, and the old key is
+    /// deleted. The Commits are all created, and the new key stored, before
+    /// we switch to it, so a failure until then leaves everything on the
+    /// old key.
+    #[instrument(skip(self))]
+    fn rotate_identity(&mut self) -> Result<()> {
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
+            .map_err(|e| anyhow!("KeyGen error: {:?}", e))?;
+        let credential = CredentialWithKey {
+            credential: self.credential.credential.clone(),
+            signature_key: signer.public().into(),
+        };
+        let mut group_ids: Vec<String> = self.groups.keys().cloned().collect();
+        group_ids.sort_unstable();
+
+        let mut commits = Vec::new();
+        for group_id in &group_ids {
+            let group = self.groups.get_mut(group_id).expect("listed above");
+            let new_signer = NewSignerBundle {
+                signer: &signer,
+                credential_with_key: credential.clone(),
+            };
+            match group.self_update_with_new_signer(
+                &self.backend,
+                &self.signer,
+                new_signer,
+                LeafNodeParameters::default(),
+            ) {
+                Ok(bundle) => commits.push((group_id, bundle.into_messages().0)),
+                Err(e) => {
+                    self.discard_pending_commits(commits.iter().map(|(group_id, _)| *group_id))?;
+                    return Err(anyhow!(
+                        "Cannot move {} to a new key, so nothing changed: {:?}",
+                        self.chat_name(group_id),
+                        e
+                    ));
+                }
+            }
+        }
+        let prepared = commits
+            .iter()
+            .map(|(group_id, commit)| Ok((*group_id, commit.tls_serialize_detached()?)))
+            .collect::<Result<Vec<_>>>()
+            .and_then(|commits| {
+                signer
+                    .store(self.backend.storage())
+                    .map_err(|e| anyhow!("Storage error: {:?}", e))?;
+                Ok(commits)
+            });
+        let commits = match prepared {
+            Ok(commits) => commits,
+            Err(e) => {
+                self.discard_pending_commits(commits.iter().map(|(group_id, _)| *group_id))?;
+                return Err(anyhow!("Cannot rotate the key, so nothing changed: {}", e));
+            }
+        };
+
+        let old = std::mem::replace(&mut self.signer, signer);
+        self.credential = credential;
+        let now = Local::now().timestamp() as u64;
+        let mut failed = Vec::new();
+        for (group_id, commit) in commits {
+            // One group failing must not keep the others on the old key
+            let group = self.groups.get_mut(group_id).expect("listed above");
+            let moved = group
+                .merge_pending_commit(&self.backend)
+                .map_err(|e| anyhow!("{:?}", e))
+                .and_then(|()| {
+                    self.metrics.epochs_advanced += 1;
+                    self.key_updates.insert(group_id.clone(), now);
+                    self.publish_to_group(group_id, PayloadKind::Commit, commit)?;
+                    self.publish_group_info(group_id)
+                });
+            if let Err(e) = moved {
+                warn!(group_id, "Key rotation failed: {}", e);
+                self.discard_pending_commits([group_id])?;
+                failed.push(self.chat_name(group_id));
+            }
+        }
+
+        // A Welcome to one of the old KeyPackages would join us with the
+        // old key, so they go, private keys included
+        for key_package in std::mem::take(&mut self.own_key_packages) {
+            let hash_ref = key_package.hash_ref(self.backend.crypto())?;
+            self.backend
+                .storage()
+                .delete_key_package(&hash_ref)
+                .map_err(|e| anyhow!("Storage error: {:?}", e))?;
+        }
+        self.publish_key_packages()?;
+        self.publish_directory_entry()?;
+        if !failed.is_empty() {
+            // Their members may still hold our leaf with the old key
+            return Err(anyhow!(
+                "Rotated your signature key, but not in {}; the old key is kept. \
+                 Leave and rejoin them to stop using it.",
+                failed.join(", ")
+            ));
+        }
+        SignatureKeyPair::delete(self.backend.storage(), old.public(), old.signature_scheme())
+            .map_err(|e| anyhow!("Storage error: {:?}", e))?;
+        log(&format!(
+            "Rotated your signature key in {} sessions and groups; the old key is deleted",
+            group_ids.len()
+        ));
+        log("Peers who verified you see a changed key; compare safety numbers with them again");
+        Ok(())
+    }
+
+    /// Take back the Commits of a key rotation that was abandoned
+    fn discard_pending_commits<'a>(
+        &mut self,
+        group_ids: impl IntoIterator<Item = &'a String>,
+    ) -> Result<()> {
+        for group_id in group_ids {
+            if let Some(group) = self.groups.get_mut(group_id) {
+                group.clear_pending_commit(self.backend.storage())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write our identity, sessions and groups to a file encrypted with a
+    /// passphrase, to move them to another machine
+    fn export_identity(&self, file: &str, passphrase: &str) -> Result<()> {
+        self.save_state()?;
+        export_state(&self.state_dir, Path::new(file), passphrase)?;
+        log(&format!(
+            "Exported {} to {}; import it on the other machine and stop using it here",
+            self.client_id, file
+        ));
+        Ok(())
+    }
+
+    /// Replace our state with an identity export. The client must be
+    /// restarted to use it, without saving its own state first.
+    fn import_identity(&mut self, file: &str, passphrase: &str) -> Result<()> {
+        if !self.groups.is_empty() {
+            return Err(anyhow!(
+                "This client has sessions or groups; import into a new state directory"
+            ));
+        }
+        let client_id = import_state(&self.state_dir, Path::new(file), passphrase)?;
+        // Nobody is to invite the identity the import replaced
+        if client_id != self.client_id {
+            self.cleanup()?;
+        }
+        log(&format!(
+            "Imported {}; start the client again to use it",
+            client_id
+        ));
+        Ok(())
+    }
+
+    /// The peer and group of a session, given the peer by alias or prefix
+    fn session_with(&self, query: &str) -> Result<(String, String)> {
+        let peer_id = self.find_peer(query)?;
+        let group_id = self.sessions.get(&peer_id).ok_or_else(|| {
+            let peer = self.aliases.name(&peer_id);
+            anyhow!("No session with {}. Use 'connect {}' first.", peer, peer)
+        })?;
+        Ok((peer_id.clone(), group_id.clone()))
+    }
+
+    /// An MLS-Exporter secret over the members' credentials, shown as six
+    /// groups of five digits; it changes with every epoch
+    fn safety_number(&self, group: &MlsGroup) -> Result<String> {
+        let crypto = self.backend.crypto();
+        let mut hashes = group
+            .members()
+            .map(|m| {
+                let credential = m.credential.tls_serialize_detached()?;
+                Ok(crypto.hash(CIPHERSUITE.hash_algorithm(), &credential)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        hashes.sort();
+        let secret =
+            group.export_secret(crypto, "relay user verification", &hashes.concat(), 32)?;
+        let groups: Vec<String> = secret
+            .chunks(5)
+            .take(6)
+            .map(|chunk| {
+                let n = chunk.iter().fold(0u64, |n, b| (n << 8) | u64::from(*b));
+                format!("{:05}", n % 100_000)
+            })
+            .collect();
+        Ok(groups.join(" "))
+    }
+
+    /// A peer's alias and ID for listings, or just the ID
+    fn peer_label(&self, peer_id: &str) -> String {
+        match self.aliases.0.get(peer_id) {
+            Some(alias) => format!("{} ({})", alias, peer_id),
+            None => peer_id.to_string(),
+        }
+    }
+
+    /// Resolve a group name, exact or by unique prefix, to (name, group_id)
+    fn find_group(&self, query: &str) -> Result<(String, String)> {
+        if let Some(group_id) = self.group_names.get(query) {
+            return Ok((query.to_string(), group_id.clone()));
+        }
+
+        let matches: Vec<_> = self
+            .group_names
+            .iter()
+            .filter(|(name, _)| name.starts_with(query))
+            .collect();
+        if let [(name, group_id)] = matches.as_slice() {
+            return Ok((name.to_string(), group_id.to_string()));
+        }
+
+        if self.group_names.is_empty() {
+            Err(anyhow!("No groups. Use 'group create <name>' first."))
+        } else {
+            let names: Vec<&str> = self.group_names.keys().map(String::as_str).collect();
+            Err(anyhow!(
+                "Unknown group '{}'. Available:\n  {}",
+                query,
+                names.join("\n  ")
+            ))
+        }
+    }
+
+    /// Name of a named group, None for 1:1 sessions
+    fn group_name(&self, group_id: &str) -> Option<&str> {
+        self.group_names
+            .iter()
+            .find(|(_, id)| *id == group_id)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Peer of a 1:1 session
+    fn session_peer(&self, group_id: &str) -> Option<&str> {
+        self.sessions
+            .iter()
+            .find(|(_, id)| *id == group_id)
+            .map(|(peer_id, _)| peer_id.as_str())
+    }
+
+    /// Name of a group for log lines
+    fn chat_name(&self, group_id: &str) -> String {
+        match (self.group_name(group_id), self.session_peer(group_id)) {
+            (Some(name), _) => name.to_string(),
+            (None, Some(peer_id)) => format!("the session with {}", self.aliases.name(peer_id)),
+            (None, None) => group_id.to_string(),
+        }
+    }
+
+    /// `name`, or `name-2`, `name-3`, ... if taken
+    fn unused_group_name(&self, name: &str) -> String {
+        let mut candidate = name.to_string();
+        let mut n = 2;
+        while self.group_names.contains_key(&candidate) {
+            candidate = format!("{}-{}", name, n);
+            n += 1;
+        }
+        candidate
+    }
+}
+
+// ============================================================================
+// Channels
+// ============================================================================
+//
+// A channel is a group anyone can join by name. Its creator publishes the
+// group ID, retained, at {prefix}/c/{name} (without the '#'):
+//   { "group_id": tstr }
+// Joiners look the name up there, fetch the retained GroupInfo of the group
+// and commit themselves into it with an external Commit, which members of
+// other groups refuse. Channels are kept as named groups whose name starts
+// with '#'. The directory is first come, first served and not
+// authenticated: whoever can publish on the broker can replace an entry.
+
+/// A channel name looked up in the directory
+struct ChannelLookup {
+    create: bool,             // create the channel if the name is free
+    group_id: Option<String>, // once found: waiting for the GroupInfo
+    since: Instant,
+}
+
+/// A channel name as `#name`, given with or without the '#'
+fn channel_name(input: &str) -> Result<String> {
+    let name = input.strip_prefix('#').unwrap_or(input).to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= CHANNEL_NAME_MAX
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_');
+    if !valid {
+        return Err(anyhow!(
+            "Invalid channel name '{}': use up to {} letters, digits, '-' and '_'",
+            input,
+            CHANNEL_NAME_MAX
+        ));
+    }
+    Ok(format!("#{}", name))
+}
+
+impl RelayClient {
+    /// Topic of a channel's directory entry
+    fn channel_topic(&self, name: &str) -> String {
+        self.topic_in(None, &format!("c/{}", name.trim_start_matches('#')))
+    }
+
+    fn is_channel(&self, group_id: &str) -> bool {
+        self.group_name(group_id)
+            .is_some_and(|name| name.starts_with('#'))
+    }
+
+    /// Create a channel, once the directory shows the name is free
+    fn channel_create(&mut self, name: &str) -> Result<()> {
+        let name = channel_name(name)?;
+        if self.group_names.contains_key(&name) {
+            return Err(anyhow!("You are already in {}", name));
+        }
+        self.look_up_channel(&name, true)?;
+        log(&format!("Checking that {} is free...", name));
+        Ok(())
+    }
+
+    /// Join a channel by its name
+    fn channel_join(&mut self, name: &str) -> Result<()> {
+        let name = channel_name(name)?;
+        if self.group_names.contains_key(&name) {
+            return Err(anyhow!("You are already in {}", name));
+        }
+        self.look_up_channel(&name, false)?;
+        log(&format!("Looking up {}...", name));
+        Ok(())
+    }
+
+    /// Subscribe to a channel's directory entry; a retained one comes right
+    /// away, none within CHANNEL_LOOKUP_TIMEOUT means the name is free
+    fn look_up_channel(&mut self, name: &str, create: bool) -> Result<()> {
+        if self.channel_lookups.contains_key(name) {
+            return Err(anyhow!("Already looking up {}", name));
+        }
+        self.mqtt
+            .try_subscribe(self.channel_topic(name), QoS::AtLeastOnce)?;
+        let lookup = ChannelLookup {
+            create,
+            group_id: None,
+            since: Instant::now(),
+        };
+        self.channel_lookups.insert(name.to_string(), lookup);
+        Ok(())
+    }
+
+    fn channel_list(&self) {
+        let channels: Vec<(&String, &String)> = self
+            .group_names
+            .iter()
+            .filter(|(name, _)| name.starts_with('#'))
+            .collect();
+        if channels.is_empty() {
+            say("No channels. Use 'channel create <#name>' or 'channel join <#name>'.");
+            return;
+        }
+        say("Channels:");
+        for (name, group_id) in channels {
+            let members = self.groups[group_id].members().count();
+            say(&format!("  {} ({} members)", name, members));
+        }
+    }
+
+    /// A directory entry for a channel we are looking up
+    #[instrument(skip(self, payload))]
+    fn handle_channel_entry(&mut self, name: &str, payload: &[u8]) -> Result<()> {
+        let name = format!("#{}", name);
+        let Some(lookup) = self.channel_lookups.get_mut(&name) else {
+            return Ok(());
+        };
+        if lookup.group_id.is_some() {
+            return Ok(());
+        }
+        // An empty retained message is a cleared entry
+        if payload.is_empty() {
+            return self.channel_not_found(&name);
+        }
+        let create = lookup.create;
+        self.channel_lookups.remove(&name);
+        self.mqtt.try_unsubscribe(self.channel_topic(&name))?;
+
+        let entry: Value = ciborium::from_reader(payload)?;
+        let group_id = map_entry(&entry, "group_id")
+            .and_then(|v| v.as_text())
+            .filter(|id| id.len() == 32 && hex::decode(id).is_ok())
+            .ok_or_else(|| anyhow!("Corrupt directory entry for {}", name))?
+            .to_string();
+        if create {
+            return Err(anyhow!("Channel {} already exists; join it instead", name));
+        }
+        if self.groups.contains_key(&group_id) {
+            return Err(anyhow!("You are already in {}", name));
+        }
+        debug!(group_id, "Found a channel");
+        self.mqtt
+            .try_subscribe(self.group_topic(&group_id, "i"), QoS::AtLeastOnce)?;
+        let lookup = ChannelLookup {
+            create: false,
+            group_id: Some(group_id),
+            since: Instant::now(),
+        };
+        self.channel_lookups.insert(name, lookup);
+        Ok(())
+    }
 
-        // Add peer
-        let (_, welcome, group_info) =
-            group.add_members(&self.backend, &self.signer, &[peer_kp])?;
-        group.merge_pending_commit(&self.backend)?;
+    /// The GroupInfo of a channel we are joining: join with an external
+    /// Commit
+    #[instrument(skip(self, payload))]
+    fn handle_channel_group_info(&mut self, group_id: &str, payload: &[u8]) -> Result<()> {
+        let Some(name) = self
+            .channel_lookups
+            .iter()
+            .find(|(_, lookup)| lookup.group_id.as_deref() == Some(group_id))
+            .map(|(name, _)| name.clone())
+        else {
+            return Ok(());
+        };
+        self.channel_lookups.remove(&name);
+        self.mqtt.try_unsubscribe(self.group_topic(group_id, "i"))?;
+        if payload.is_empty() {
+            return Err(anyhow!("Channel {} has no members left", name));
+        }
 
-        // Publish GroupInfo (retained)
-        self.mqtt.publish(
-            format!("relay/g/{}/i", group_id),
-            QoS::AtLeastOnce,
-            true,
-            group_info.tls_serialize_detached()?,
-        )?;
+        let msg = MlsMessageIn::tls_deserialize(&mut payload.to_vec().as_slice())?;
+        let group_info = match msg.extract() {
+            MlsMessageBodyIn::GroupInfo(g) => g,
+            _ => return Err(anyhow!("Expected GroupInfo")),
+        };
+        if group_info.group_id().as_slice() != hex::decode(group_id)?.as_slice() {
+            return Err(anyhow!("The GroupInfo of {} is for another group", name));
+        }
+        let config = MlsGroupJoinConfig::builder()
+            .max_past_epochs(MAX_PAST_EPOCHS)
+            .build();
+        let (group, bundle) = MlsGroup::external_commit_builder()
+            .with_config(config)
+            .build_group(&self.backend, group_info, self.credential.clone())?
+            .load_psks(self.backend.storage())?
+            .build(
+                self.backend.rand(),
+                self.backend.crypto(),
+                &self.signer,
+                |_| true,
+            )?
+            .finalize(&self.backend)?;
+        info!(group_id, epoch = group.epoch().as_u64(), "Joined a channel");
+        let others: Vec<String> = group
+            .members()
+            .map(|m| member_id(&m.credential))
+            .filter(|id| *id != self.client_id)
+            .collect();
 
-        // Send Welcome
-        self.mqtt.publish(
-            format!("relay/w/{}", peer_id),
-            QoS::AtLeastOnce,
-            false,
-            welcome.tls_serialize_detached()?,
+        self.subscribe_group(group_id)?;
+        self.groups.insert(group_id.to_string(), group);
+        self.group_names.insert(name.clone(), group_id.to_string());
+        let (commit, _, _) = bundle.into_messages();
+        self.publish_to_group(
+            group_id,
+            PayloadKind::Commit,
+            commit.tls_serialize_detached()?,
         )?;
+        self.publish_group_info(group_id)?;
+        self.check_verified(group_id);
 
-        // Subscribe to group messages
+        let event = json!({
+            "event": "joined",
+            "group": name,
+            "group_id": group_id,
+            "channel": true,
+            "members": others,
+        });
+        log_event(
+            event,
+            &format!("Joined {} ({} other members)", name, others.len()),
+        );
+        log(&format!("Use 'group chat {} <message>' to write", name));
+        Ok(())
+    }
+
+    /// Create a channel whose name nobody has taken, or give up on joining
+    /// one nobody has
+    fn channel_not_found(&mut self, name: &str) -> Result<()> {
+        let Some(lookup) = self.channel_lookups.remove(name) else {
+            return Ok(());
+        };
+        self.mqtt.try_unsubscribe(self.channel_topic(name))?;
+        if !lookup.create {
+            return Err(anyhow!("No channel {}", name));
+        }
+        let group_id = self.new_group(None)?;
+        self.publish_group_info(&group_id)?;
+        self.group_names.insert(name.to_string(), group_id.clone());
+        self.publish_channel_entry(name, Some(&group_id))?;
+
+        log_event(
+            json!({ "event": "channel", "group": name, "group_id": group_id }),
+            &format!("Created channel {}", name),
+        );
+        log(&format!("Others join it with 'channel join {}'", name));
+        Ok(())
+    }
+
+    /// Publish a channel's directory entry, or clear it (None)
+    fn publish_channel_entry(&self, name: &str, group_id: Option<&str>) -> Result<()> {
+        let mut payload = Vec::new();
+        if let Some(group_id) = group_id {
+            let entry = Value::Map(vec![(
+                Value::Text("group_id".into()),
+                Value::Text(group_id.to_string()),
+            )]);
+            ciborium::into_writer(&entry, &mut payload)?;
+        }
+        self.publish(self.channel_topic(name), PayloadKind::Channel, payload)
+    }
+
+    /// Settle lookups the directory or the GroupInfo did not answer within
+    /// CHANNEL_LOOKUP_TIMEOUT of `now`
+    fn expire_channel_lookups(&mut self, now: Instant) -> Result<()> {
+        let expired: Vec<(String, Option<String>)> = self
+            .channel_lookups
+            .iter()
+            .filter(|(_, lookup)| now.duration_since(lookup.since) >= CHANNEL_LOOKUP_TIMEOUT)
+            .map(|(name, lookup)| (name.clone(), lookup.group_id.clone()))
+            .collect();
+        for (name, group_id) in expired {
+            match group_id {
+                None => self.channel_not_found(&name)?,
+                Some(group_id) => {
+                    self.channel_lookups.remove(&name);
+                    self.mqtt
+                        .try_unsubscribe(self.group_topic(&group_id, "i"))?;
+                    return Err(anyhow!("Channel {} has no members left", name));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Peer Directory
+// ============================================================================
+//
+// Opt-in: with directory_name set, the client lists its Client ID under
+// that display name, retained, at {prefix}/d/{client_id}:
+//   { "client_id": tstr, "name": tstr, "ts": int, "key": bstr, "sig": bstr }
+// "key" is the signature key of our credential and "sig" its signature of
+// DIRECTORY_LABEL followed by the CBOR array [client_id, name, ts]. `find`
+// subscribes to {prefix}/d/+ for DIRECTORY_LOOKUP_TIMEOUT and lists the
+// records whose name or Client ID contains the query. Records must match
+// their topic and carry a valid signature; for a peer whose key we already
+// know, from its KeyPackages or our session, it must be that key. Anyone
+// can list any display name, so a record found is a lead to check with
+// `verify`, not proof of who the peer is. `cleanup` removes our record.
+
+/// A `find` collecting directory records
+struct DirectoryLookup {
+    query: String,                   // lowercased
+    found: BTreeMap<String, String>, // client_id -> display name
+    since: Instant,
+}
+
+/// What a directory record's signature covers
+fn directory_signed_content(client_id: &str, name: &str, ts: i64) -> Vec<u8> {
+    let fields = Value::Array(vec![
+        Value::Text(client_id.to_string()),
+        Value::Text(name.to_string()),
+        Value::Integer(ts.into()),
+    ]);
+    let mut content = DIRECTORY_LABEL.to_vec();
+    // Writing to a Vec cannot fail
+    ciborium::into_writer(&fields, &mut content).expect("CBOR encoding into Vec");
+    content
+}
+
+impl RelayClient {
+    fn directory_topic(&self, client_id: &str) -> String {
+        self.topic_in(None, &format!("d/{}", client_id))
+    }
+
+    /// List us in the directory under directory_name, if it is set
+    fn publish_directory_entry(&self) -> Result<()> {
+        let Some(name) = &self.directory_name else {
+            return Ok(());
+        };
+        let ts = Local::now().timestamp();
+        let sig = self
+            .signer
+            .sign(&directory_signed_content(&self.client_id, name, ts))
+            .map_err(|e| anyhow!("Cannot sign our directory entry: {:?}", e))?;
+        let record = Value::Map(vec![
+            (
+                Value::Text("client_id".into()),
+                Value::Text(self.client_id.clone()),
+            ),
+            (Value::Text("name".into()), Value::Text(name.clone())),
+            (Value::Text("ts".into()), Value::Integer(ts.into())),
+            (
+                Value::Text("key".into()),
+                Value::Bytes(self.signer.public().to_vec()),
+            ),
+            (Value::Text("sig".into()), Value::Bytes(sig)),
+        ]);
+        let mut payload = Vec::new();
+        ciborium::into_writer(&record, &mut payload)?;
+        self.publish(
+            self.directory_topic(&self.client_id),
+            PayloadKind::Directory,
+            payload,
+        )
+    }
+
+    /// Search the directory for a display name or Client ID; the results
+    /// are shown after DIRECTORY_LOOKUP_TIMEOUT
+    fn find(&mut self, query: &str) -> Result<()> {
+        if self.directory_lookup.is_some() {
+            return Err(anyhow!("Already searching the directory"));
+        }
         self.mqtt
-            .subscribe(format!("relay/g/{}/m", group_id), QoS::AtLeastOnce)?;
+            .try_subscribe(self.directory_topic("+"), QoS::AtLeastOnce)?;
+        self.directory_lookup = Some(DirectoryLookup {
+            query: query.to_lowercase(),
+            found: BTreeMap::new(),
+            since: Instant::now(),
+        });
+        log(&format!("Searching the directory for '{}'...", query));
+        Ok(())
+    }
+
+    /// The signature key of a peer's credential, from our session with them
+    /// or their KeyPackages
+    fn known_signature_key(&self, peer_id: &str) -> Option<Vec<u8>> {
+        if let Some(group_id) = self.sessions.get(peer_id) {
+            return self.groups.get(group_id)?.members().find_map(|m| {
+                (self.member_name(group_id, &m.credential) == peer_id).then_some(m.signature_key)
+            });
+        }
+        let key_package = self.key_packages.get(peer_id)?.first()?;
+        Some(key_package.leaf_node().signature_key().as_slice().to_vec())
+    }
+
+    /// A directory record, while `find` runs
+    #[instrument(skip(self, payload))]
+    fn handle_directory_entry(&mut self, client_id: &str, payload: &[u8]) -> Result<()> {
+        let Some(lookup) = &self.directory_lookup else {
+            return Ok(());
+        };
+        // An empty retained message is a removed record
+        if payload.is_empty() || client_id == self.client_id {
+            return Ok(());
+        }
+        let record: Value = ciborium::from_reader(payload)?;
+        let text = |key: &str| map_entry(&record, key).and_then(|v| v.as_text());
+        let bytes = |key: &str| map_entry(&record, key).and_then(|v| v.as_bytes());
+        let ts = map_entry(&record, "ts")
+            .and_then(|v| v.as_integer())
+            .and_then(|ts| i64::try_from(ts).ok());
+        let (Some(id), Some(name), Some(ts), Some(key), Some(sig)) = (
+            text("client_id"),
+            text("name"),
+            ts,
+            bytes("key"),
+            bytes("sig"),
+        ) else {
+            return Err(anyhow!("Corrupt directory entry for {}", client_id));
+        };
+        if id != client_id || name.chars().count() > DIRECTORY_NAME_MAX {
+            warn!(id, "Ignored a directory entry published for another client");
+            return Ok(());
+        }
+        let matches = name.to_lowercase().contains(&lookup.query)
+            || id.to_lowercase().contains(&lookup.query);
+        if !matches {
+            return Ok(());
+        }
+        let content = directory_signed_content(id, name, ts);
+        let valid = self
+            .backend
+            .crypto()
+            .verify_signature(CIPHERSUITE.signature_algorithm(), &content, key, sig)
+            .is_ok();
+        let known = self.known_signature_key(client_id);
+        if !valid || known.is_some_and(|known| known != *key) {
+            warn!(id, "Ignored a directory entry with a wrong signature");
+            return Ok(());
+        }
+        let name = name.to_string();
+        if let Some(lookup) = &mut self.directory_lookup {
+            lookup.found.insert(client_id.to_string(), name);
+        }
+        Ok(())
+    }
+
+    /// Show the results of a `find` that ran for DIRECTORY_LOOKUP_TIMEOUT
+    /// as of `now`
+    fn expire_directory_lookup(&mut self, now: Instant) -> Result<()> {
+        let running = self
+            .directory_lookup
+            .as_ref()
+            .is_some_and(|lookup| now.duration_since(lookup.since) < DIRECTORY_LOOKUP_TIMEOUT);
+        if running {
+            return Ok(());
+        }
+        let Some(lookup) = self.directory_lookup.take() else {
+            return Ok(());
+        };
+        self.mqtt.try_unsubscribe(self.directory_topic("+"))?;
+        let results: Vec<JsonValue> = lookup
+            .found
+            .iter()
+            .map(|(client_id, name)| json!({ "client_id": client_id, "name": name }))
+            .collect();
+        let text = if lookup.found.is_empty() {
+            format!("Nobody in the directory matches '{}'", lookup.query)
+        } else {
+            let lines: Vec<String> = lookup
+                .found
+                .iter()
+                .map(|(client_id, name)| format!("  {} ({})", client_id, name))
+                .collect();
+            format!(
+                "Found in the directory for '{}':\n{}",
+                lookup.query,
+                lines.join("\n")
+            )
+        };
+        log_event(
+            json!({ "event": "directory", "query": lookup.query, "results": results }),
+            &text,
+        );
+        Ok(())
+    }
+}
+
+// ============================================================================
+// TUI
+// ============================================================================
+//
+// A list of conversations on the left (the status pane, then sessions and
+// groups), the selected conversation on the right and an input line below.
+// In a session or group, input is sent to it unless it starts with '/',
+// which marks a command; in the status pane all input is a command.
+// Tab / Shift-Tab switch conversations, PgUp / PgDn scroll, Ctrl-C quits.
+
+/// Key of the pane for events and command output
+const STATUS_PANE: &str = "";
+/// Lines kept per pane
+const MAX_PANE_LINES: usize = 1000;
+/// Lines scrolled by PgUp / PgDn
+const SCROLL_STEP: usize = 10;
+
+#[derive(Default)]
+struct Pane {
+    lines: VecDeque<(Vec<u8>, Line<'static>)>, // with the ID of our message shown
+    scroll: usize,                             // lines up from the bottom
+    unread: usize,
+}
+
+struct Tui {
+    panes: HashMap<String, Pane>, // group_id -> pane
+    selected: String,
+    input: String,
+    last_info: String,
+}
+
+impl Tui {
+    fn new() -> Self {
+        Tui {
+            panes: HashMap::new(),
+            selected: STATUS_PANE.to_string(),
+            input: String::new(),
+            last_info: String::new(),
+        }
+    }
+
+    /// Pane keys and labels: the status pane, sessions, then groups
+    fn conversations(client: &RelayClient) -> Vec<(String, String)> {
+        let mut sessions: Vec<(String, String)> = client
+            .sessions
+            .iter()
+            .map(|(peer, group_id)| (group_id.clone(), client.aliases.name(peer).to_string()))
+            .collect();
+        sessions.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut groups: Vec<(String, String)> = client
+            .group_names
+            .iter()
+            .map(|(name, group_id)| (group_id.clone(), format!("#{}", name)))
+            .collect();
+        groups.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let mut conversations = vec![(STATUS_PANE.to_string(), "status".to_string())];
+        conversations.extend(sessions);
+        conversations.extend(groups);
+        conversations
+    }
+
+    /// Move the queued output into the panes
+    fn take_output(&mut self) {
+        let output = match &mut *SINK.lock().unwrap() {
+            Sink::Tui(queue) => std::mem::take(queue),
+            _ => Vec::new(),
+        };
+        for (key, id, line) in output {
+            if key == STATUS_PANE {
+                self.last_info = line.to_string();
+            }
+            self.push(&key, id, line);
+        }
+    }
+
+    fn push(&mut self, key: &str, id: Vec<u8>, line: Line<'static>) {
+        let pane = self.panes.entry(key.to_string()).or_default();
+        pane.lines.push_back((id, line));
+        if pane.lines.len() > MAX_PANE_LINES {
+            pane.lines.pop_front();
+        }
+        if key != self.selected {
+            pane.unread += 1;
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, client: &RelayClient) {
+        let conversations = Self::conversations(client);
+        let selected = match conversations
+            .iter()
+            .position(|(key, _)| *key == self.selected)
+        {
+            Some(i) => i,
+            None => {
+                // The session or group was left
+                self.selected = STATUS_PANE.to_string();
+                0
+            }
+        };
+        let [list_area, chat_area] =
+            Layout::horizontal([Constraint::Length(24), Constraint::Min(0)]).areas(frame.area());
+        let [messages_area, input_area] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(3)]).areas(chat_area);
+
+        let items: Vec<ListItem> = conversations
+            .iter()
+            .map(|(key, label)| match self.panes.get(key).map(|p| p.unread) {
+                Some(unread) if unread > 0 => ListItem::new(format!("{} ({})", label, unread))
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+                _ => ListItem::new(label.as_str()),
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title("Conversations"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(selected));
+        frame.render_stateful_widget(list, list_area, &mut state);
+
+        // Events stay visible below the messages of a conversation
+        let mut block = Block::bordered().title(conversations[selected].1.as_str());
+        if self.selected != STATUS_PANE {
+            block = block.title_bottom(self.last_info.as_str());
+            let typing = client.typing_in(&self.selected, Instant::now());
+            if !typing.is_empty() {
+                let verb = if typing.len() == 1 { "is" } else { "are" };
+                let line = Line::from(format!(" {} {} typing… ", typing.join(", "), verb));
+                block = block.title_bottom(line.right_aligned());
+            }
+        }
+        let pane = self.panes.entry(self.selected.clone()).or_default();
+        let lines: Vec<Line> = pane
+            .lines
+            .iter()
+            .map(|(id, line)| match client.receipt_mark(&self.selected, id) {
+                Some(mark) => {
+                    let mut line = line.clone();
+                    line.push_span(Span::styled(
+                        format!(" {}", mark),
+                        Style::default().fg(Color::DarkGray),
+                    ));
+                    line
+                }
+                None => line.clone(),
+            })
+            .collect();
+        let messages = Paragraph::new(lines).wrap(Wrap { trim: false });
+        let inner = block.inner(messages_area);
+        let bottom = messages
+            .line_count(inner.width)
+            .saturating_sub(inner.height as usize);
+        pane.scroll = pane.scroll.min(bottom);
+        let offset = u16::try_from(bottom - pane.scroll).unwrap_or(u16::MAX);
+        frame.render_widget(messages.block(block).scroll((offset, 0)), messages_area);
+
+        let title = match (self.selected == STATUS_PANE, client.connected) {
+            (true, true) => "Command",
+            (true, false) => "Command [offline]",
+            (false, true) => "Message (/ for commands)",
+            (false, false) => "Message (/ for commands) [offline]",
+        };
+        let input = Paragraph::new(self.input.as_str()).block(Block::bordered().title(title));
+        frame.render_widget(input, input_area);
+        let cursor = u16::try_from(self.input.chars().count()).unwrap_or(u16::MAX);
+        frame.set_cursor_position((input_area.x + 1 + cursor, input_area.y + 1));
+    }
+
+    /// Handle a key press; returns false on quit
+    fn handle_key(&mut self, key: KeyEvent, client: &mut RelayClient) -> bool {
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Char(c) => {
+                self.input.push(c);
+                if self.selected != STATUS_PANE && !self.input.starts_with('/') {
+                    if let Err(e) = client.send_typing(&self.selected) {
+                        log_error(&e);
+                    }
+                }
+            }
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Tab => self.select_next(client, 1),
+            KeyCode::BackTab => self.select_next(client, -1),
+            KeyCode::PageUp => self.selected_pane().scroll += SCROLL_STEP,
+            KeyCode::PageDown => {
+                let pane = self.selected_pane();
+                pane.scroll = pane.scroll.saturating_sub(SCROLL_STEP);
+            }
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                return self.submit(&line, client);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn selected_pane(&mut self) -> &mut Pane {
+        self.panes.entry(self.selected.clone()).or_default()
+    }
+
+    fn select_next(&mut self, client: &RelayClient, step: isize) {
+        let conversations = Self::conversations(client);
+        let current = conversations
+            .iter()
+            .position(|(key, _)| *key == self.selected)
+            .unwrap_or(0);
+        let next = (current as isize + step).rem_euclid(conversations.len() as isize);
+        self.selected = conversations[next as usize].0.clone();
+        self.selected_pane().unread = 0;
+    }
+
+    /// Send an input line to the selected conversation or run it as a
+    /// command; returns false on quit
+    fn submit(&mut self, line: &str, client: &mut RelayClient) -> bool {
+        if line.trim().is_empty() {
+            return true;
+        }
+        let command = match line.strip_prefix('/') {
+            Some(command) => command,
+            None if self.selected == STATUS_PANE => line,
+            None => {
+                let command = match client.group_name(&self.selected) {
+                    Some(name) => format!("group chat {} {}", name, line),
+                    None => match client.sessions.iter().find(|(_, g)| **g == self.selected) {
+                        Some((peer, _)) => format!("chat {} {}", peer, line),
+                        None => return true,
+                    },
+                };
+                return run_command(client, &command);
+            }
+        };
+        say(&format!("> {}", command));
+        run_command(client, command)
+    }
+}
+
+async fn run_tui(
+    client: &mut RelayClient,
+    rx: &mut UnboundedReceiver<MqttEvent>,
+    link: &mut LinkState,
+) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = tui_loop(&mut terminal, client, rx, link).await;
+    ratatui::restore();
+
+    // Print what came after the last draw, such as the reply to a command
+    // that quits
+    if let Sink::Tui(lines) = std::mem::replace(&mut *SINK.lock().unwrap(), Sink::Plain) {
+        for (_, _, line) in lines {
+            println!("{}", line);
+        }
+    }
+    result
+}
+
+async fn tui_loop(
+    terminal: &mut DefaultTerminal,
+    client: &mut RelayClient,
+    rx: &mut UnboundedReceiver<MqttEvent>,
+    link: &mut LinkState,
+) -> Result<()> {
+    let mut tui = Tui::new();
+    let mut terminal_events = term::EventStream::new();
+    let mut timer = tokio::time::interval(TIMER_INTERVAL);
+    loop {
+        tui.take_output();
+        client.focused = Some(tui.selected.clone()).filter(|key| key != STATUS_PANE);
+        // The messages of the selected conversation are in view
+        if tui.selected != STATUS_PANE {
+            if let Err(e) = client.mark_read(&tui.selected) {
+                log_error(&e);
+            }
+        }
+        terminal.draw(|frame| tui.draw(frame, client))?;
+
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                handle_mqtt_event(client, link, event)?;
+            }
+            Some(event) = terminal_events.next() => {
+                if let term::Event::Key(key) = event? {
+                    if key.kind == KeyEventKind::Press && !tui.handle_key(key, client) {
+                        return Ok(());
+                    }
+                }
+            }
+            _ = timer.tick() => {
+                if let Err(e) = client.expire_gaps(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.send_bot_replies() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.refresh_key_packages() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.expire_channel_lookups(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.expire_directory_lookup(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.settle_contested_sessions(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.retry_unsent(Instant::now(), false) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.refresh_retained(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.scheduled_rekeys() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.send_heartbeats(Instant::now()) {
+                    log_error(&e);
+                }
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Daemon
+// ============================================================================
+//
+// With --daemon the client runs headless and serves JSON-RPC 2.0 on a Unix
+// socket, one request or response per line. Every connection also gets the
+// client's events, as in JSON mode, in "event" notifications. With
+// metrics_port, the daemon also answers GET /metrics on that loopback port
+// with its counters in the Prometheus text format.
+
+/// What the control socket threads report to the daemon loop
+enum ControlEvent {
+    Opened(usize, OwnedWriteHalf), // for writing to the connection
+    Request(usize, String),
+    Closed(usize),
+    Scrape(TcpStream), // a GET /metrics to answer
+}
+
+/// Counters of the metrics port, since the client started
+#[derive(Default)]
+struct Metrics {
+    messages_sent: u64,     // application messages, of any type
+    messages_received: u64, // application messages decrypted
+    decrypt_failures: u64,  // group messages MLS refused
+    epochs_advanced: u64,   // Commits merged, ours and others'
+    reconnects: u64,
+}
+
+/// A JSON-RPC error
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: &str) -> Self {
+        RpcError {
+            code: -32602,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError {
+            code: -32000,
+            message: format!("{:#}", e),
+        }
+    }
+}
+
+/// Listen on the control socket, and on the metrics port if one is given;
+/// only the owner of the socket file may connect
+fn listen_control(
+    path: &Path,
+    metrics_port: Option<u16>,
+) -> Result<UnboundedReceiver<ControlEvent>> {
+    // A socket left by a daemon that didn't exit cleanly would make bind fail
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(anyhow!("Another daemon is serving {}", path.display()));
+    }
+    let _ = fs::remove_file(path);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+
+    let (tx, rx) = unbounded_channel();
+    if let Some(port) = metrics_port {
+        listen_metrics(port, tx.clone())?;
+    }
+    tokio::spawn(async move {
+        for id in 0.. {
+            let Ok((stream, _)) = listener.accept().await else {
+                break;
+            };
+            let (reader, writer) = stream.into_split();
+            if tx.send(ControlEvent::Opened(id, writer)).is_err() {
+                break;
+            }
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut lines = tokio::io::BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let _ = tx.send(ControlEvent::Request(id, line));
+                }
+                let _ = tx.send(ControlEvent::Closed(id));
+            });
+        }
+    });
+    Ok(rx)
+}
+
+/// Listen for scrapes on a loopback port; the daemon loop answers them
+fn listen_metrics(port: u16, tx: UnboundedSender<ControlEvent>) -> Result<()> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", port))
+        .map_err(|e| anyhow!("Cannot listen for metrics on port {}: {}", port, e))?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match read_scrape(stream).await {
+                    Ok(stream) => {
+                        let _ = tx.send(ControlEvent::Scrape(stream));
+                    }
+                    Err(e) => debug!(error = %e, "Ignored a request on the metrics port"),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Read an HTTP request; the stream if it is a GET /metrics
+async fn read_scrape(mut stream: TcpStream) -> Result<TcpStream> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = tokio::time::timeout(SCRAPE_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 || request.len() + n > 4096 {
+            return Err(anyhow!("Bad HTTP request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    if !request.starts_with(b"GET /metrics ") {
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(anyhow!("Not a GET /metrics"));
+    }
+    Ok(stream)
+}
+
+/// The client's metrics in the Prometheus text format
+fn render_metrics(client: &RelayClient) -> String {
+    let metrics = &client.metrics;
+    let mut samples = vec![
+        (
+            "messages_sent_total",
+            "counter",
+            "Application messages sent",
+            metrics.messages_sent as f64,
+        ),
+        (
+            "messages_received_total",
+            "counter",
+            "Application messages received",
+            metrics.messages_received as f64,
+        ),
+        (
+            "decrypt_failures_total",
+            "counter",
+            "Group messages that failed to decrypt or verify",
+            metrics.decrypt_failures as f64,
+        ),
+        (
+            "epochs_advanced_total",
+            "counter",
+            "Commits merged in groups",
+            metrics.epochs_advanced as f64,
+        ),
+        (
+            "reconnects_total",
+            "counter",
+            "Reconnections to the broker",
+            metrics.reconnects as f64,
+        ),
+        (
+            "rejected_publishes_total",
+            "counter",
+            "Publishes the broker refused",
+            client.rejected_publishes as f64,
+        ),
+        (
+            "unknown_group_messages_total",
+            "counter",
+            "Messages dropped for groups we are not in",
+            client.unknown_group_messages as f64,
+        ),
+        (
+            "connected",
+            "gauge",
+            "Whether the broker connection is up",
+            if client.connected { 1.0 } else { 0.0 },
+        ),
+        (
+            "groups",
+            "gauge",
+            "Sessions and groups we are in",
+            client.groups.len() as f64,
+        ),
+    ];
+    // Unknown until the first keepalive ping is answered
+    if let Some(rtt) = client.rtt {
+        samples.push((
+            "broker_rtt_seconds",
+            "gauge",
+            "Round-trip time of the last keepalive ping",
+            rtt.as_secs_f64(),
+        ));
+    }
+    samples
+        .into_iter()
+        .map(|(name, kind, help, value)| {
+            format!(
+                "# HELP relay_{name} {help}\n# TYPE relay_{name} {kind}\nrelay_{name} {value}\n"
+            )
+        })
+        .collect()
+}
+
+/// Answer a scrape with `body`, unless the client stopped reading
+async fn answer_scrape(mut stream: TcpStream, body: String) {
+    let header = concat!(
+        "HTTP/1.1 200 OK\r\n",
+        "Content-Type: text/plain; version=0.0.4\r\n",
+        "Connection: close\r\n",
+    );
+    let response = format!("{}Content-Length: {}\r\n\r\n{}", header, body.len(), body);
+    let _ = tokio::time::timeout(SCRAPE_TIMEOUT, stream.write_all(response.as_bytes())).await;
+}
+
+/// Answer a JSON-RPC request; None for notifications. Sets `quit` on the
+/// "quit" method.
+fn handle_request(client: &mut RelayClient, line: &str, quit: &mut bool) -> Option<JsonValue> {
+    let request: JsonValue = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            let error = json!({ "code": -32700, "message": format!("Parse error: {}", e) });
+            return Some(json!({ "jsonrpc": "2.0", "id": null, "error": error }));
+        }
+    };
+    let result = match request["method"].as_str() {
+        Some("quit") => {
+            *quit = true;
+            Ok(JsonValue::Null)
+        }
+        Some(method) => {
+            let _span = info_span!("rpc", method).entered();
+            call(client, method, &request["params"])
+        }
+        None => Err(RpcError {
+            code: -32600,
+            message: "Invalid request".to_string(),
+        }),
+    }
+    .and_then(|result| Ok(client.save_state().map(|_| result)?));
+
+    let id = request.get("id")?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    })
+}
+
+fn call(client: &mut RelayClient, method: &str, params: &JsonValue) -> Result<JsonValue, RpcError> {
+    let param = |name: &str| {
+        params[name]
+            .as_str()
+            .ok_or_else(|| RpcError::invalid_params(&format!("Missing '{}'", name)))
+    };
+    match method {
+        "info" => Ok(json!({
+            "client_id": client.client_id,
+            "connected": client.connected,
+            "wildcard_groups": client.wildcard_groups,
+            "unknown_group_messages": client.unknown_group_messages,
+            "rtt_ms": client.rtt.map(|rtt| rtt.as_millis() as u64),
+            "last_keepalive": client.last_pong.map(|t| t.elapsed().as_secs()),
+            "rejected_publishes": client.rejected_publishes,
+            "namespaces": client
+                .all_namespaces()
+                .map(|namespace| namespace.unwrap_or(&client.topic_prefix))
+                .collect::<Vec<_>>(),
+        })),
+        "peers" => {
+            let sessions: Vec<JsonValue> = client
+                .sessions
+                .iter()
+                .map(|(peer, group_id)| {
+                    json!({
+                        "peer": peer,
+                        "alias": client.aliases.0.get(peer),
+                        "group_id": group_id,
+                        "verified": client.verified.contains_key(peer),
+                        "online": client.presence.get(peer),
+                        "last_seen": client.last_seen.get(peer),
+                        "stale": client.is_stale(peer),
+                    })
+                })
+                .collect();
+            let key_packages: Vec<&String> = client
+                .key_packages
+                .keys()
+                .filter(|peer| !client.sessions.contains_key(*peer))
+                .collect();
+            Ok(json!({ "sessions": sessions, "key_packages": key_packages }))
+        }
+        "groups" => {
+            let groups: Vec<JsonValue> = client
+                .group_names
+                .iter()
+                .map(|(name, group_id)| {
+                    let members: Vec<String> = client.groups[group_id]
+                        .members()
+                        .map(|m| client.member_name(group_id, &m.credential))
+                        .collect();
+                    json!({ "name": name, "group_id": group_id, "members": members })
+                })
+                .collect();
+            Ok(json!(groups))
+        }
+        "connect" => {
+            client.connect(param("peer")?)?;
+            Ok(JsonValue::Null)
+        }
+        "accept" => {
+            client.accept(param("peer")?)?;
+            Ok(JsonValue::Null)
+        }
+        "decline" => {
+            client.decline(param("peer")?)?;
+            Ok(JsonValue::Null)
+        }
+        "cleanup" => {
+            client.cleanup()?;
+            Ok(JsonValue::Null)
+        }
+        "prune" => {
+            client.prune(params["idle"].as_str())?;
+            Ok(JsonValue::Null)
+        }
+        "find" => {
+            client.find(param("name")?)?;
+            Ok(JsonValue::Null)
+        }
+        "attach" => {
+            client.attach(param("chat")?, param("file")?)?;
+            Ok(JsonValue::Null)
+        }
+        "fetch" => {
+            client.fetch(param("chat")?, param("id")?, param("file")?)?;
+            Ok(JsonValue::Null)
+        }
+        "send" => {
+            let text = param("text")?;
+            match (params["peer"].as_str(), params["group"].as_str()) {
+                (Some(peer), None) => client.send(peer, text)?,
+                (None, Some(group)) => client.group_chat(group, text)?,
+                _ => return Err(RpcError::invalid_params("Expected 'peer' or 'group'")),
+            }
+            Ok(JsonValue::Null)
+        }
+        "typing" => {
+            let (_, group_id) = client.find_chat(param("chat")?)?;
+            client.send_typing(&group_id)?;
+            Ok(JsonValue::Null)
+        }
+        "mark_read" => {
+            let (_, group_id) = client.find_chat(param("chat")?)?;
+            client.mark_read(&group_id)?;
+            Ok(JsonValue::Null)
+        }
+        "focus" => {
+            client.focused = match params["chat"].as_str() {
+                Some(chat) => Some(client.find_chat(chat)?.1),
+                None => None,
+            };
+            Ok(JsonValue::Null)
+        }
+        "history" => {
+            let history = client
+                .history
+                .as_ref()
+                .ok_or_else(|| anyhow!("History is turned off"))?;
+            let count = match &params["count"] {
+                JsonValue::Null => DEFAULT_HISTORY_COUNT,
+                count => count
+                    .as_u64()
+                    .ok_or_else(|| RpcError::invalid_params("Invalid 'count'"))?
+                    as usize,
+            };
+            let (_, group_id) = client.find_chat(param("chat")?)?;
+            let entries: Vec<JsonValue> = history
+                .recent(&group_id, count)?
+                .into_iter()
+                .map(|entry| {
+                    let time = Local
+                        .timestamp_opt(entry.ts, 0)
+                        .single()
+                        .unwrap_or_default();
+                    let reactions: Vec<JsonValue> = entry
+                        .reactions
+                        .iter()
+                        .map(|(sender, emoji)| json!({ "sender": sender, "emoji": emoji }))
+                        .collect();
+                    json!({
+                        "ts": time.to_rfc3339(),
+                        "id": message_id(&entry.id),
+                        "sender": entry.sender,
+                        "text": entry.text,
+                        "edited": entry.edited,
+                        "reactions": reactions,
+                    })
+                })
+                .collect();
+            Ok(json!(entries))
+        }
+        _ => Err(RpcError {
+            code: -32601,
+            message: format!("Unknown method '{}'", method),
+        }),
+    }
+}
+
+/// Send a line to a control connection; false if it is gone or stuck
+async fn write_line(stream: &mut OwnedWriteHalf, value: &JsonValue) -> bool {
+    let line = format!("{}\n", value);
+    let write = stream.write_all(line.as_bytes());
+    matches!(
+        tokio::time::timeout(CONTROL_WRITE_TIMEOUT, write).await,
+        Ok(Ok(()))
+    )
+}
+
+async fn run_daemon(
+    client: &mut RelayClient,
+    rx: &mut UnboundedReceiver<MqttEvent>,
+    link: &mut LinkState,
+    control: &mut UnboundedReceiver<ControlEvent>,
+    socket: &Path,
+) -> Result<()> {
+    log(&format!("Listening on {}", socket.display()));
+    let mut connections: HashMap<usize, OwnedWriteHalf> = HashMap::new();
+    let mut timer = tokio::time::interval(TIMER_INTERVAL);
+    // Stopping the daemon, e.g. by a service manager, exits as "quit" does
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut quit = false;
 
-        self.group_peers.insert(group_id, peer_id.to_string());
-        self.groups.insert(peer_id.to_string(), group);
+    while !quit {
+        tokio::select! {
+            Some(event) = rx.recv() => {
+                handle_mqtt_event(client, link, event)?;
+            }
+            Some(event) = control.recv() => match event {
+                ControlEvent::Opened(id, stream) => {
+                    connections.insert(id, stream);
+                }
+                ControlEvent::Request(id, line) => {
+                    if let Some(response) = handle_request(client, &line, &mut quit) {
+                        if let Some(stream) = connections.get_mut(&id) {
+                            if !write_line(stream, &response).await {
+                                connections.remove(&id);
+                            }
+                        }
+                    }
+                }
+                ControlEvent::Closed(id) => {
+                    connections.remove(&id);
+                }
+                ControlEvent::Scrape(stream) => {
+                    tokio::spawn(answer_scrape(stream, render_metrics(client)));
+                }
+            },
+            _ = terminate.recv() => quit = true,
+            _ = interrupt.recv() => quit = true,
+            _ = timer.tick() => {
+                if let Err(e) = client.expire_gaps(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.send_bot_replies() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.refresh_key_packages() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.expire_channel_lookups(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.expire_directory_lookup(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.settle_contested_sessions(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.retry_unsent(Instant::now(), false) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.refresh_retained(Instant::now()) {
+                    log_error(&e);
+                }
+                if let Err(e) = client.scheduled_rekeys() {
+                    log_error(&e);
+                }
+                if let Err(e) = client.send_heartbeats(Instant::now()) {
+                    log_error(&e);
+                }
+            }
+        }
 
-        Ok(())
+        // Events are printed as in JSON mode and sent to every connection
+        let events = match &mut *SINK.lock().unwrap() {
+            Sink::Daemon(queue) => std::mem::take(queue),
+            _ => Vec::new(),
+        };
+        for event in events {
+            println!("{}", event);
+            let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+            let mut gone = Vec::new();
+            for (id, stream) in &mut connections {
+                if !write_line(stream, &notification).await {
+                    gone.push(*id);
+                }
+            }
+            for id in gone {
+                connections.remove(&id);
+            }
+        }
     }
+
+    let _ = fs::remove_file(socket);
+    Ok(())
 }
 
 // ============================================================================
-// Main Loop
+// Line Editing
 // ============================================================================
+//
+// On a terminal, the plain interface reads input with rustyline: line
+// editing, history (Up / Down, Ctrl-R) and tab completion of commands, peer
+// IDs, aliases and group names. Ctrl-C and Ctrl-D quit. The input history is
+// saved in the state directory only while the message history is kept
+// unencrypted, since it holds the messages typed; `identity` commands, which
+// carry a passphrase, are never recorded.
 
-fn main() -> Result<()> {
-    let (mut client, mut connection) = RelayClient::new()?;
+/// File in the state directory holding the input history
+const INPUT_HISTORY: &str = "input_history";
+/// Lines kept in the input history
+const INPUT_HISTORY_LEN: usize = 1000;
+/// Commands offered for completion at the start of a line
+const COMMANDS: [&str; 40] = [
+    "info",
+    "peers",
+    "connect",
+    "accept",
+    "decline",
+    "invitations",
+    "qr",
+    "chat",
+    "group",
+    "channel",
+    "history",
+    "export",
+    "search",
+    "react",
+    "edit",
+    "delete",
+    "attach",
+    "fetch",
+    "roster",
+    "resend-welcome",
+    "rekey",
+    "commit",
+    "leave",
+    "alias",
+    "unalias",
+    "mute",
+    "unmute",
+    "block",
+    "unblock",
+    "blocks",
+    "cleanup",
+    "prune",
+    "find",
+    "verify",
+    "identity",
+    "debug",
+    "switch",
+    "help",
+    "quit",
+    "exit",
+];
+const GROUP_COMMANDS: [&str; 6] = ["create", "broadcast", "invite", "kick", "chat", "list"];
+const CHANNEL_COMMANDS: [&str; 3] = ["create", "join", "list"];
+const DEBUG_COMMANDS: [&str; 1] = ["dump"];
 
-    println!("Client ID: {}", client.client_id);
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct InputHelper {
+    names: Arc<Mutex<Vec<String>>>, // see RelayClient::completion_names
+}
 
-    client.publish_key_package()?;
-    client.subscribe_welcome()?;
+impl Completer for InputHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+        let candidates: Vec<String> = match words[..] {
+            [] => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["qr"] => vec!["hint".to_string()],
+            ["group"] => GROUP_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["channel"] => CHANNEL_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            ["debug"] => DEBUG_COMMANDS.iter().map(|c| c.to_string()).collect(),
+            _ => self.names.lock().unwrap().clone(),
+        };
+        let matches = candidates
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect();
+        Ok((start, matches))
+    }
+}
 
-    // Channel for MQTT events
-    let (tx, rx) = std::sync::mpsc::channel();
+/// Read input lines with rustyline on a thread of its own, as it blocks.
+/// `history` is the file to keep the input history in, if any.
+fn spawn_line_editor(
+    names: Arc<Mutex<Vec<String>>>,
+    history: Option<PathBuf>,
+) -> Result<UnboundedReceiver<String>> {
+    let config = rustyline::Config::builder()
+        .max_history_size(INPUT_HISTORY_LEN)?
+        .auto_add_history(false)
+        .build();
+    let mut editor: Editor<InputHelper, DefaultHistory> = Editor::with_config(config)?;
+    editor.set_helper(Some(InputHelper { names }));
+    if let Some(path) = &history {
+        // Created private, as it holds what was typed
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        editor.load_history(path)?;
+    }
+    let printer = Box::new(editor.create_external_printer()?);
+    let (done, done_rx) = std::sync::mpsc::channel();
+    *LINE_EDITOR.lock().unwrap() = Some(LineEditor {
+        printer,
+        prompt: "> ".to_string(),
+        done,
+    });
 
-    // Spawn MQTT event loop in background thread
-    std::thread::spawn(move || {
-        for event in connection.iter() {
-            if let Ok(Event::Incoming(Packet::Publish(p))) = event {
-                let _ = tx.send((p.topic.clone(), p.payload.to_vec()));
+    let (tx, rx) = unbounded_channel();
+    std::thread::spawn(move || loop {
+        let prompt = match &*LINE_EDITOR.lock().unwrap() {
+            Some(editor) => editor.prompt.clone(),
+            None => "> ".to_string(),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => "quit".to_string(),
+            Err(e) => {
+                warn!(error = %e, "Cannot read input");
+                "quit".to_string()
+            }
+        };
+        if !line.trim().is_empty() && !line.trim_start().starts_with("identity") {
+            let _ = editor.add_history_entry(line.as_str());
+            if let Some(path) = &history {
+                if let Err(e) = editor.save_history(path) {
+                    warn!(error = %e, "Cannot save the input history");
+                }
             }
         }
+        // Wait until the line is handled: after "quit" the process exits,
+        // which must not happen while readline has the terminal in raw mode
+        if tx.send(line).is_err() || done_rx.recv().is_err() {
+            break;
+        }
     });
+    Ok(rx)
+}
 
-    // Channel for stdin lines
-    let (stdin_tx, stdin_rx) = std::sync::mpsc::channel();
+/// Let the line editor read the next line, once the last one is handled
+fn line_done() {
+    if let Some(editor) = &*LINE_EDITOR.lock().unwrap() {
+        let _ = editor.done.send(());
+    }
+}
 
-    // Spawn stdin reader in background thread
-    std::thread::spawn(move || {
-        let stdin = io::stdin();
-        for line in stdin.lock().lines().map_while(Result::ok) {
-            let _ = stdin_tx.send(line);
+/// Read input lines from a stdin that isn't a terminal; the channel closes
+/// at the end of input
+fn spawn_line_reader() -> UnboundedReceiver<String> {
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(line).is_err() {
+                break;
+            }
         }
     });
+    rx
+}
+
+// ============================================================================
+// Main Loop
+// ============================================================================
+
+/// What the MQTT event thread reports to the main loop
+enum MqttEvent {
+    Connected,
+    Disconnected(String),
+    Message(Publish),
+    /// A keepalive ping was answered after this long
+    Pong(Duration),
+    /// The broker refused one of our publishes, for this reason
+    Rejected(String),
+}
 
-    print!("> ");
-    io::stdout().flush()?;
+/// Connection history kept by the main loop
+#[derive(Default)]
+struct LinkState {
+    was_connected: bool,
+    // Whether the current outage has been reported, so a broker that stays
+    // unreachable is reported only once
+    outage_reported: bool,
+}
 
-    loop {
-        // Check for MQTT messages (non-blocking)
-        while let Ok((topic, payload)) = rx.try_recv() {
-            let result = if topic.starts_with("relay/k/") {
-                client.handle_key_package(&topic, &payload)
-            } else if topic.starts_with("relay/w/") {
-                client.handle_welcome(&payload)
-            } else if topic.starts_with("relay/g/") && topic.ends_with("/m") {
-                client.handle_group_message(&topic, &payload)
+/// Handle an event from the MQTT thread; returns whether anything was shown
+fn handle_mqtt_event(
+    client: &mut RelayClient,
+    link: &mut LinkState,
+    event: MqttEvent,
+) -> Result<bool> {
+    let publish = match event {
+        MqttEvent::Connected => {
+            client.connected = true;
+            // Replace the offline status a Last Will may have left
+            client.publish_presence(true)?;
+            // The first connect uses the subscriptions made on start
+            if link.was_connected {
+                client.metrics.reconnects += 1;
+                client.recover_session()?;
+            }
+            client.retry_unsent(Instant::now(), true)?;
+            link.was_connected = true;
+            link.outage_reported = false;
+            log_event(json!({ "event": "connected" }), "Connected to broker");
+            return Ok(true);
+        }
+        MqttEvent::Disconnected(reason) => {
+            client.connected = false;
+            if link.outage_reported {
+                return Ok(false);
+            }
+            // Before the first connect, the settings are the likely cause
+            let msg = if link.was_connected {
+                format!("Broker connection lost: {}; reconnecting", reason)
             } else {
-                Ok(())
+                format!(
+                    "Cannot connect to the broker: {}; retrying. {}",
+                    reason,
+                    Failure::BrokerUnreachable.hint()
+                )
             };
+            log_event(json!({ "event": "disconnected", "reason": reason }), &msg);
+            link.outage_reported = true;
+            return Ok(true);
+        }
+        MqttEvent::Pong(rtt) => {
+            client.rtt = Some(rtt);
+            client.last_pong = Some(Instant::now());
+            return Ok(false);
+        }
+        MqttEvent::Rejected(reason) => {
+            client.rejected_publishes += 1;
+            log_event(
+                json!({ "event": "rejected", "reason": reason }),
+                &format!("The broker refused a message: {}", reason),
+            );
+            return Ok(true);
+        }
+        MqttEvent::Message(publish) => publish,
+    };
+    // Topic aliases are already resolved by rumqttc
+    let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+    let _span = info_span!("mqtt", topic, pkid = publish.pkid).entered();
+    let content_type = publish
+        .properties
+        .as_ref()
+        .and_then(|p| p.content_type.as_deref());
+    debug!(
+        bytes = publish.payload.len(),
+        content_type, "Received a publish"
+    );
+    let payload = publish.payload.as_ref();
+    let result = client
+        .handle_publish(topic, payload)
+        .and_then(|_| client.save_state());
+
+    if let Err(e) = result {
+        log_error(&e);
+    }
+    // Acknowledge only now, so the broker redelivers a message that
+    // arrived before a crash
+    client.mqtt.try_ack(&publish)?;
+    Ok(true)
+}
+
+/// Run a command line; returns false on quit
+fn run_command(client: &mut RelayClient, line: &str) -> bool {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.is_empty() {
+        return true;
+    }
 
-            if let Err(e) = result {
-                eprintln!("\rError: {:?}", e);
+    let _span = info_span!("command", name = parts[0]).entered();
+    let result = match parts[0] {
+        "info" => {
+            say(&format!("Client ID: {}", client.client_id));
+            say(&format!(
+                "Broker: {} ({})",
+                client.broker,
+                if client.connected {
+                    "connected"
+                } else {
+                    "offline"
+                }
+            ));
+            if !client.namespaces.is_empty() {
+                say(&format!(
+                    "Namespaces: {}, {}",
+                    client.topic_prefix,
+                    client.namespaces.join(", ")
+                ));
+            }
+            if client.wildcard_groups {
+                say(&format!(
+                    "Subscribed to all groups; dropped {} message(s) of other groups",
+                    client.unknown_group_messages
+                ));
+            }
+            if let (Some(rtt), Some(pong)) = (client.rtt, client.last_pong) {
+                say(&format!(
+                    "Round trip: {} ms (keepalive answered {}s ago)",
+                    rtt.as_millis(),
+                    pong.elapsed().as_secs()
+                ));
+            }
+            if client.rejected_publishes > 0 {
+                say(&format!(
+                    "Messages refused by the broker: {}",
+                    client.rejected_publishes
+                ));
+            }
+            Ok(())
+        }
+        "peers" => {
+            client.list_peers();
+            Ok(())
+        }
+        "connect" if parts.len() >= 2 => client.connect(parts[1]),
+        "qr" => match parts[1..] {
+            [] => client.show_qr(false),
+            ["hint"] => client.show_qr(true),
+            _ => {
+                say("Usage: qr [hint]");
+                Ok(())
+            }
+        },
+        "alias" => match parts[1..] {
+            [peer, alias] => client.set_alias(peer, alias),
+            [] => {
+                client.list_aliases();
+                Ok(())
+            }
+            _ => {
+                say("Usage: alias <peer_id> <name>, or alias to list aliases");
+                Ok(())
+            }
+        },
+        "unalias" if parts.len() == 2 => client.remove_alias(parts[1]),
+        "mute" => match parts[1..] {
+            [peer] => client.set_muted(peer, true),
+            [peer, duration] => client.silence(peer, duration),
+            [] => {
+                client.list_muted();
+                Ok(())
+            }
+            _ => {
+                say("Usage: mute <peer_id> [duration], or mute to list muted peers");
+                Ok(())
+            }
+        },
+        "unmute" if parts.len() == 2 => client.set_muted(parts[1], false),
+        "accept" if parts.len() == 2 => client.accept(parts[1]),
+        "decline" if parts.len() == 2 => client.decline(parts[1]),
+        "invitations" => {
+            client.list_invitations();
+            Ok(())
+        }
+        "block" if parts.len() == 2 => client.block(parts[1]),
+        "unblock" if parts.len() == 2 => client.unblock(parts[1]),
+        "blocks" => {
+            client.list_blocks();
+            Ok(())
+        }
+        "cleanup" => client.cleanup(),
+        "find" if parts.len() >= 2 => client.find(&parts[1..].join(" ")),
+        "prune" if parts.len() <= 2 => client.prune(parts.get(1).copied()),
+        "identity" => match parts[1..] {
+            ["export", file, ref passphrase @ ..] if !passphrase.is_empty() => {
+                client.export_identity(file, &passphrase.join(" "))
+            }
+            ["import", file, ref passphrase @ ..] if !passphrase.is_empty() => {
+                // Quit without saving, which would overwrite the import
+                match client.import_identity(file, &passphrase.join(" ")) {
+                    Ok(()) => return false,
+                    Err(e) => Err(e),
+                }
+            }
+            ["rotate"] => client.rotate_identity(),
+            _ => {
+                say("Usage: identity export <file> <passphrase>, \
+                     identity import <file> <passphrase>, identity rotate");
+                Ok(())
+            }
+        },
+        "verify" => match parts[1..] {
+            [peer] => client.verify(peer),
+            [peer, "confirm"] => client.confirm_verified(peer),
+            _ => {
+                say("Usage: verify <peer>, then verify <peer> confirm if the numbers match");
+                Ok(())
+            }
+        },
+        "chat" if parts.len() >= 3 => client.send(parts[1], &parts[2..].join(" ")),
+        "leave" if parts.len() == 2 => client.leave(parts[1]),
+        "rekey" if parts.len() == 2 => client.rekey(parts[1]),
+        "commit" if parts.len() == 2 => client.commit(parts[1]),
+        "roster" if parts.len() == 2 => client.roster(parts[1]),
+        "resend-welcome" if (2..=3).contains(&parts.len()) => {
+            client.resend_welcome(parts[1], parts.get(2).copied())
+        }
+        "history" if (2..=3).contains(&parts.len()) => {
+            client.show_history(parts[1], parts.get(2).copied())
+        }
+        "export" if parts.len() == 3 => client.export_transcript(parts[1], parts[2]),
+        "search" if (2..=3).contains(&parts.len()) => {
+            client.search(parts[1], parts.get(2).copied())
+        }
+        "react" if parts.len() == 4 => client.update_message("react", parts[1], parts[2], parts[3]),
+        "edit" if parts.len() >= 4 => {
+            client.update_message("edit", parts[1], parts[2], &parts[3..].join(" "))
+        }
+        "delete" if parts.len() == 3 => client.update_message("delete", parts[1], parts[2], ""),
+        "attach" if parts.len() == 3 => client.attach(parts[1], parts[2]),
+        "fetch" if parts.len() == 4 => client.fetch(parts[1], parts[2], parts[3]),
+        "group" => match parts[1..] {
+            ["create", name] => client.group_create(name, None, false),
+            ["create", name, namespace] => client.group_create(name, Some(namespace), false),
+            ["broadcast", name] => client.group_create(name, None, true),
+            ["broadcast", name, namespace] => client.group_create(name, Some(namespace), true),
+            ["invite", name, peer] => client.group_invite(name, peer),
+            ["kick", name, peer] => client.group_kick(name, peer),
+            ["chat", name, ref msg @ ..] if !msg.is_empty() => {
+                client.group_chat(name, &msg.join(" "))
+            }
+            ["list"] => {
+                client.group_list();
+                Ok(())
+            }
+            _ => {
+                say("Group commands: group create <name> [namespace], \
+                     group broadcast <name> [namespace], group invite <group> <peer>, \
+                     group kick <group> <peer>, group chat <group> <msg>, group list");
+                Ok(())
+            }
+        },
+        "channel" => match parts[1..] {
+            ["create", name] => client.channel_create(name),
+            ["join", name] => client.channel_join(name),
+            ["list"] => {
+                client.channel_list();
+                Ok(())
+            }
+            _ => {
+                say("Channel commands: channel create <#name>, channel join <#name>, channel list");
+                Ok(())
+            }
+        },
+        "debug" => match parts[1..] {
+            ["dump", target] => client.debug_dump(target, None),
+            ["dump", target, file] => client.debug_dump(target, Some(file)),
+            _ => {
+                say("Debug commands: debug dump <peer|group> [file]");
+                Ok(())
             }
-            print!("> ");
-            io::stdout().flush()?;
+        },
+        "quit" | "exit" => return false,
+        _ => {
+            say(
+                "Commands: info, peers, connect <peer|pairing URI>, accept <peer>, \
+                 decline <peer>, invitations, qr [hint], chat <peer> <msg>, \
+                 history <peer|group> [n], export <peer|group> <file>, \
+                 search <text|/regex/> [peer|group], \
+                 react <peer|group> <id> <emoji>, edit <peer|group> <id> <msg>, \
+                 delete <peer|group> <id>, attach <peer|group> <file>, \
+                 fetch <peer|group> <id> <file>, \
+                 roster <peer|group>, resend-welcome <peer|group> [peer], \
+                 rekey <peer|group>, commit <peer|group>, leave <peer|group>, \
+                 alias <peer> <name>, unalias <name>, mute [peer] [duration], unmute <peer>, \
+                 block <peer>, unblock <peer>, blocks, cleanup, prune [duration], find <name>, \
+                 verify <peer> [confirm], identity, switch [profile], group, channel, debug, \
+                 quit",
+            );
+            Ok(())
         }
+    }
+    .and_then(|_| client.save_state());
 
-        // Check for stdin input (non-blocking)
-        if let Ok(line) = stdin_rx.try_recv() {
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.is_empty() {
-                print!("> ");
-                io::stdout().flush()?;
-                continue;
+    if let Err(e) = result {
+        log_error(&e);
+    }
+    true
+}
+
+/// An identity run with --profile, or the one in the state directory
+struct Profile {
+    client: RelayClient,
+    config: Config,
+    rx: UnboundedReceiver<MqttEvent>, // from the profile's MQTT task
+    link: LinkState,
+}
+
+/// Start the client of the identity in `state_dir`: restore its state and
+/// connect to its broker
+async fn start_profile(
+    config: Config,
+    state_dir: PathBuf,
+    name: Option<&str>,
+    interactive: bool, // TUI or daemon, where it is known what the user has seen
+) -> Result<Profile> {
+    let (mut client, mut eventloop) = RelayClient::new(&config, state_dir)?;
+    client.profile = name.map(str::to_string);
+    client.read_receipts = interactive;
+    client.notifications = config.notifications && interactive;
+    // Token brokers usually ignore the username; the Client ID stands in
+    let username = config
+        .username
+        .clone()
+        .unwrap_or_else(|| client.client_id.clone());
+    let mut tokens = config.token_provider();
+    if let Some(provider) = tokens.as_mut() {
+        refresh_token(&mut eventloop.options, &username, provider.as_mut())?;
+    }
+    if let Some(proxy) = config.socks_proxy()? {
+        route_through_proxy(&mut eventloop.options, proxy, &config.host, config.port()).await?;
+    }
+
+    say_event(
+        json!({ "event": "identity", "client_id": client.client_id }),
+        &format!("Client ID: {}", client.client_id),
+    );
+    if !client.groups.is_empty() {
+        log(&format!(
+            "Restored {} session(s) and {} group(s)",
+            client.sessions.len(),
+            client.group_names.len()
+        ));
+    }
+
+    client.publish_key_packages()?;
+    client.publish_directory_entry()?;
+    client.subscribe_welcome()?;
+    client.subscribe_groups()?;
+    client.subscribe_presence()?;
+    client.save_state()?;
+
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(run_mqtt(eventloop, tx, username, tokens));
+    Ok(Profile {
+        client,
+        config,
+        rx,
+        link: LinkState::default(),
+    })
+}
+
+/// The next event from the MQTT task of any profile, with the profile's
+/// index
+async fn next_event(profiles: &mut [Profile]) -> (usize, MqttEvent) {
+    std::future::poll_fn(|cx| {
+        for (index, profile) in profiles.iter_mut().enumerate() {
+            if let Poll::Ready(Some(event)) = profile.rx.poll_recv(cx) {
+                return Poll::Ready((index, event));
             }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Send commands to the named profile from now on; list the profiles
+/// without a name
+fn switch_profile(profiles: &[Profile], active: &mut usize, name: Option<&str>) -> Result<()> {
+    if profiles[*active].client.profile.is_none() {
+        return Err(anyhow!(
+            "Start the client with a --profile for each identity to switch between"
+        ));
+    }
+    let Some(name) = name else {
+        for (index, profile) in profiles.iter().enumerate() {
+            say(&format!(
+                "{} {} ({})",
+                if index == *active { "*" } else { " " },
+                profile.client.profile.as_deref().unwrap_or_default(),
+                profile.client.client_id
+            ));
+        }
+        return Ok(());
+    };
+    *active = profiles
+        .iter()
+        .position(|p| p.client.profile.as_deref() == Some(name))
+        .ok_or_else(|| anyhow!("No profile '{}' is running", name))?;
+    say(&format!(
+        "Switched to {} ({})",
+        name, profiles[*active].client.client_id
+    ));
+    Ok(())
+}
+
+/// The output mark of the profile at `index`: its name unless it's `active`
+fn output_profile(profiles: &[Profile], index: usize, active: usize) -> Option<String> {
+    (index != active)
+        .then(|| profiles[index].client.profile.clone())
+        .flatten()
+}
+
+/// The line-based interface: commands on stdin, output printed as it comes.
+/// Commands go to one of the profiles, chosen with `switch`.
+async fn run_plain(profiles: &mut [Profile]) -> Result<()> {
+    let mut active = 0;
+    let names = Arc::new(Mutex::new(profiles[active].client.completion_names()));
+    let mut input = if plain_output() && io::stdin().is_terminal() {
+        let client = &profiles[active].client;
+        // Typed messages are as private as the message history
+        let history = client.history.as_ref().filter(|h| h.cipher.is_none());
+        let history = history.map(|_| client.state_dir.join(INPUT_HISTORY));
+        spawn_line_editor(names.clone(), history)?
+    } else {
+        spawn_line_reader()
+    };
+    // After the end of input (e.g. a pipe) the client keeps running
+    let mut stdin_open = true;
+    let mut timer = tokio::time::interval(TIMER_INTERVAL);
 
-            let result = match parts[0] {
-                "info" => {
-                    println!("Client ID: {}", client.client_id);
-                    Ok(())
+    profiles[active].client.prompt()?;
+    loop {
+        tokio::select! {
+            (index, event) = next_event(profiles) => {
+                set_output_profile(output_profile(profiles, index, active));
+                let profile = &mut profiles[index];
+                let shown = handle_mqtt_event(&mut profile.client, &mut profile.link, event);
+                set_output_profile(None);
+                if shown? {
+                    profiles[active].client.prompt()?;
                 }
-                "peers" => {
-                    if client.groups.is_empty() && client.key_packages.is_empty() {
-                        println!("No peers. Use 'connect <peer_id>' to connect.");
+            }
+            line = input.recv(), if stdin_open => match line {
+                Some(line) => {
+                    let mut words = line.split_whitespace();
+                    if words.next() == Some("switch") {
+                        if let Err(e) = switch_profile(profiles, &mut active, words.next()) {
+                            log_error(&e);
+                        }
                     } else {
-                        println!("Active sessions:");
-                        for peer in client.groups.keys() {
-                            println!("  {} (session)", peer);
+                        if !run_command(&mut profiles[active].client, &line) {
+                            return Ok(());
                         }
-                        for peer in client.key_packages.keys() {
-                            if !client.groups.contains_key(peer) {
-                                println!("  {} (keypackage only)", peer);
-                            }
+                    }
+                    profiles[active].client.prompt()?;
+                    line_done();
+                }
+                None => stdin_open = false,
+            },
+            _ = timer.tick() => {
+                let mut shown = false;
+                for index in 0..profiles.len() {
+                    set_output_profile(output_profile(profiles, index, active));
+                    let client = &mut profiles[index].client;
+                    // Show messages whose missing predecessors are given up on
+                    match client.expire_gaps(Instant::now()) {
+                        Ok(expired) => shown |= expired,
+                        Err(e) => log_error(&e),
+                    }
+                    match client.send_bot_replies() {
+                        Ok(sent) => shown |= sent,
+                        Err(e) => {
+                            log_error(&e);
+                            shown = true;
                         }
                     }
-                    Ok(())
+                    if let Err(e) = client.refresh_key_packages() {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.expire_channel_lookups(Instant::now()) {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.expire_directory_lookup(Instant::now()) {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.settle_contested_sessions(Instant::now()) {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.retry_unsent(Instant::now(), false) {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.refresh_retained(Instant::now()) {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.scheduled_rekeys() {
+                        log_error(&e);
+                        shown = true;
+                    }
+                    if let Err(e) = client.send_heartbeats(Instant::now()) {
+                        log_error(&e);
+                        shown = true;
+                    }
                 }
-                "connect" if parts.len() >= 2 => client.connect(parts[1]),
-                "chat" if parts.len() >= 3 => client.send(parts[1], &parts[2..].join(" ")),
-                "quit" | "exit" => break,
-                _ => {
-                    println!("Commands: info, peers, connect <peer>, chat <peer> <msg>, quit");
-                    Ok(())
+                set_output_profile(None);
+                if shown {
+                    profiles[active].client.prompt()?;
                 }
-            };
+            }
+        }
+        *names.lock().unwrap() = profiles[active].client.completion_names();
+    }
+}
+
+/// Leave the broker cleanly: withdraw our retained messages if asked, mark
+/// us offline (a DISCONNECT drops the Last Will) and wait until what is
+/// still queued is sent
+async fn shut_down(
+    client: &mut RelayClient,
+    rx: &mut UnboundedReceiver<MqttEvent>,
+    cleanup: bool,
+) -> Result<()> {
+    if !client.connected {
+        return Ok(());
+    }
+    if cleanup {
+        client.cleanup()?;
+    }
+    client.publish_presence(false)?;
+    client.mqtt.try_disconnect()?;
+    // Publishes go out ahead of the DISCONNECT, after which run_mqtt ends
+    let closed = async {
+        while let Some(event) = rx.recv().await {
+            if matches!(event, MqttEvent::Disconnected(_)) {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, closed)
+        .await
+        .is_err()
+    {
+        warn!(timeout = ?SHUTDOWN_TIMEOUT, "Exited before the DISCONNECT was sent");
+    }
+    Ok(())
+}
+
+/// Drive the MQTT connection and report its events to the main loop. After
+/// an error the next poll reconnects; wait in between, longer after each
+/// failure.
+async fn run_mqtt(
+    mut eventloop: EventLoop,
+    tx: UnboundedSender<MqttEvent>,
+    username: String,
+    mut tokens: Option<Box<dyn TokenProvider>>,
+) {
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut ping_sent: Option<Instant> = None;
+    loop {
+        let event = match ping_sent {
+            // A ping the broker doesn't answer means a half-dead connection;
+            // drop it, keeping unacknowledged publishes for the next one
+            Some(sent) => {
+                let wait = PING_TIMEOUT.saturating_sub(sent.elapsed());
+                match tokio::time::timeout(wait, eventloop.poll()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        warn!(timeout = ?PING_TIMEOUT, "No answer to a keepalive ping");
+                        ping_sent = None;
+                        eventloop.clean();
+                        let reason = "no answer to a keepalive ping".to_string();
+                        let _ = tx.send(MqttEvent::Disconnected(reason));
+                        continue;
+                    }
+                }
+            }
+            None => eventloop.poll().await,
+        };
+        match event {
+            Ok(Event::Outgoing(Outgoing::PingReq)) => ping_sent = Some(Instant::now()),
+            // The client is exiting; the publishes before the DISCONNECT are
+            // written out
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                let _ = tx.send(MqttEvent::Disconnected("exiting".to_string()));
+                return;
+            }
+            Ok(Event::Incoming(Packet::PingResp(_))) => {
+                if let Some(sent) = ping_sent.take() {
+                    let _ = tx.send(MqttEvent::Pong(sent.elapsed()));
+                }
+            }
+            Ok(Event::Incoming(Packet::PubAck(ack)))
+                if !matches!(
+                    ack.reason,
+                    PubAckReason::Success | PubAckReason::NoMatchingSubscribers
+                ) =>
+            {
+                let reason = ack
+                    .properties
+                    .and_then(|p| p.reason_string)
+                    .unwrap_or_else(|| format!("{:?}", ack.reason));
+                warn!(pkid = ack.pkid, %reason, "Publish refused");
+                let _ = tx.send(MqttEvent::Rejected(reason));
+            }
+            Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                debug!(session_present = ack.session_present, "Connected");
+                delay = RECONNECT_MIN_DELAY;
+                let _ = tx.send(MqttEvent::Connected);
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                let _ = tx.send(MqttEvent::Message(p));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                ping_sent = None;
+                warn!(error = %e, retry_in = ?delay, "MQTT connection failed");
+                // The token may have expired; fetch a new one for the retry
+                if let (
+                    ConnectionError::ConnectionRefused(
+                        ConnectReturnCode::BadUserNamePassword | ConnectReturnCode::NotAuthorized,
+                    ),
+                    Some(provider),
+                ) = (&e, tokens.as_mut())
+                {
+                    let options = &mut eventloop.options;
+                    if let Err(e) = refresh_token(options, &username, provider.as_mut()) {
+                        log_error(&e);
+                    }
+                }
+                if tx.send(MqttEvent::Disconnected(e.to_string())).is_err() {
+                    return;
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+            }
+        }
+    }
+}
+
+// ============================================================================
+// Bridge
+// ============================================================================
+//
+// A bridge runs no client: it connects to two brokers and publishes what
+// arrives under the topic prefix of one under that of the other, so peers on
+// a private broker reach those on the public one. It only sees ciphertext,
+// KeyPackages and retained records, as the brokers do. Its subscriptions are
+// No Local, so its own publishes don't come back to it, and everything it
+// forwards carries its client ID in a user property, so a publish that comes
+// around a cycle of bridges is dropped.
+
+/// One broker of a bridge
+struct BridgeSide {
+    config: Config,
+    mqtt: AsyncClient,
+    rx: UnboundedReceiver<MqttEvent>,
+    link: LinkState,
+}
+
+/// Connect to a broker of a bridge with a clean session under `id`
+async fn bridge_side(config: Config, id: &str) -> Result<BridgeSide> {
+    let mut options = MqttOptions::new(id, config.broker_addr(), config.port());
+    options.set_keep_alive(Duration::from_secs(config.keep_alive));
+    options.set_clean_start(true);
+    options.set_manual_acks(true);
+    options.set_transport(config.transport()?);
+    if config.username.is_some() || config.password.is_some() {
+        let username = config.username.as_deref().unwrap_or(id);
+        options.set_credentials(username, config.password.as_deref().unwrap_or_default());
+    }
+    let (mqtt, mut eventloop) = AsyncClient::new(options, config.request_queue);
+    let username = config.username.clone().unwrap_or_else(|| id.to_string());
+    let mut tokens = config.token_provider();
+    if let Some(provider) = tokens.as_mut() {
+        refresh_token(&mut eventloop.options, &username, provider.as_mut())?;
+    }
+    if let Some(proxy) = config.socks_proxy()? {
+        route_through_proxy(&mut eventloop.options, proxy, &config.host, config.port()).await?;
+    }
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(run_mqtt(eventloop, tx, username, tokens));
+    Ok(BridgeSide {
+        config,
+        mqtt,
+        rx,
+        link: LinkState::default(),
+    })
+}
 
-            if let Err(e) = result {
-                println!("Error: {:?}", e);
+/// Handle an event of one side of a bridge, forwarding publishes to the
+/// other
+fn bridge_event(from: &mut BridgeSide, to: &BridgeSide, id: &str, event: MqttEvent) -> Result<()> {
+    let publish = match event {
+        MqttEvent::Connected => {
+            // The session is clean, so every connection subscribes anew;
+            // retained messages come as they are and are kept retained
+            let mut filter =
+                Filter::new(format!("{}/#", from.config.topic_prefix), QoS::AtLeastOnce);
+            filter.nolocal = true;
+            filter.preserve_retain = true;
+            from.mqtt.try_subscribe_many(vec![filter])?;
+            from.link.outage_reported = false;
+            log_event(
+                json!({ "event": "connected", "broker": from.config.broker_name() }),
+                &format!("Connected to {}", from.config.broker_name()),
+            );
+            return Ok(());
+        }
+        MqttEvent::Disconnected(reason) => {
+            if !from.link.outage_reported {
+                from.link.outage_reported = true;
+                log_event(
+                    json!({
+                        "event": "disconnected",
+                        "broker": from.config.broker_name(),
+                        "reason": reason,
+                    }),
+                    &format!(
+                        "Connection to {} lost: {}; reconnecting",
+                        from.config.broker_name(),
+                        reason
+                    ),
+                );
+            }
+            return Ok(());
+        }
+        MqttEvent::Rejected(reason) => {
+            log(&format!(
+                "{} refused a message: {}",
+                from.config.broker_name(),
+                reason
+            ));
+            return Ok(());
+        }
+        MqttEvent::Pong(_) => return Ok(()),
+        MqttEvent::Message(publish) => publish,
+    };
+    let topic = std::str::from_utf8(&publish.topic).unwrap_or_default();
+    let forwarded = topic
+        .strip_prefix(from.config.topic_prefix.as_str())
+        .filter(|rest| rest.starts_with('/'))
+        .map(|rest| format!("{}{}", to.config.topic_prefix, rest));
+    let mut properties = publish.properties.clone().unwrap_or_default();
+    let hops: Vec<&str> = properties
+        .user_properties
+        .iter()
+        .filter(|(key, _)| key == BRIDGE_PROPERTY)
+        .map(|(_, value)| value.as_str())
+        .collect();
+    match forwarded {
+        Some(_) if hops.contains(&id) => debug!(topic, "Dropped a publish that came around"),
+        Some(_) if hops.len() >= MAX_BRIDGE_HOPS => {
+            debug!(topic, "Dropped a publish with too many hops")
+        }
+        Some(forwarded) => {
+            // Aliases and subscription identifiers belong to the connection
+            // it came on
+            properties.topic_alias = None;
+            properties.subscription_identifiers = Vec::new();
+            properties
+                .user_properties
+                .push((BRIDGE_PROPERTY.to_string(), id.to_string()));
+            debug!(
+                topic,
+                forwarded,
+                bytes = publish.payload.len(),
+                "Forwarded a publish"
+            );
+            if let Err(e) = to.mqtt.try_publish_with_properties(
+                forwarded,
+                publish.qos,
+                publish.retain,
+                publish.payload.clone(),
+                properties,
+            ) {
+                log_error(&anyhow!(
+                    "Cannot forward to {}: {}",
+                    to.config.broker_name(),
+                    e
+                ));
             }
-            print!("> ");
-            io::stdout().flush()?;
         }
+        None => {}
+    }
+    from.mqtt.try_ack(&publish)?;
+    Ok(())
+}
+
+/// Relay between the broker of `near` and that of `far` until interrupted
+async fn run_bridge(near: Config, far: Config) -> Result<()> {
+    // Both brokers see the bridge under the same client ID, which also
+    // marks what it forwards
+    let id = format!(
+        "relay-bridge-{}",
+        hex::encode(rand::thread_rng().gen::<[u8; 4]>())
+    );
+    log(&format!(
+        "Bridging {} ({}) and {} ({}) as {}",
+        near.broker_name(),
+        near.topic_prefix,
+        far.broker_name(),
+        far.topic_prefix,
+        id
+    ));
+    let mut near = bridge_side(near, &id).await?;
+    let mut far = bridge_side(far, &id).await?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            Some(event) = near.rx.recv() => bridge_event(&mut near, &far, &id, event)?,
+            Some(event) = far.rx.recv() => bridge_event(&mut far, &near, &id, event)?,
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+        }
+    }
+    near.mqtt.try_disconnect()?;
+    far.mqtt.try_disconnect()?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    #[cfg(feature = "fuzz")]
+    if let Ok(target) = std::env::var("RELAY_FUZZ") {
+        return fuzz::run(&target);
+    }
+    let args = Args::parse();
+    if let Some(file) = &args.bridge {
+        if args.daemon || !args.profiles.is_empty() {
+            return Err(anyhow!(
+                "--bridge runs no client, so takes no --daemon or --profile"
+            ));
+        }
+        if args.json {
+            set_sink(Sink::Json);
+        }
+        let near = Config::load(&args, &args.state_dir)?;
+        let text = fs::read_to_string(file)
+            .map_err(|e| anyhow!("Cannot read config {}: {}", file.display(), e))?;
+        let far: Config = toml::from_str(&text)
+            .map_err(|e| anyhow!("Invalid config {}: {}", file.display(), e))?;
+        init_tracing(near.log_file.as_deref(), true)?;
+        return run_bridge(near, far).await;
+    }
+    // Several profiles share the plain interface
+    let several = args.profiles.len() > 1;
+    if several && (args.daemon || args.json) {
+        return Err(anyhow!("--daemon and --json run a single profile"));
+    }
+    // The TUI needs a terminal; scripts and pipes get the plain interface
+    let tui = !args.plain
+        && !args.json
+        && !args.daemon
+        && !several
+        && io::stdin().is_terminal()
+        && io::stdout().is_terminal();
+    if args.daemon {
+        set_sink(Sink::Daemon(Vec::new()));
+    } else if args.json {
+        set_sink(Sink::Json);
+    } else if tui {
+        set_sink(Sink::Tui(Vec::new()));
+    }
+
+    // (profile name, state directory)
+    let mut identities: Vec<(Option<&str>, PathBuf)> = Vec::new();
+    for name in &args.profiles {
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if name.is_empty() || !valid {
+            return Err(anyhow!(
+                "Invalid profile name '{}': use letters, digits, '-' and '_'",
+                name
+            ));
+        }
+        if identities.iter().any(|(n, _)| *n == Some(name.as_str())) {
+            return Err(anyhow!("Profile '{}' is given twice", name));
+        }
+        identities.push((Some(name), args.state_dir.join("profiles").join(name)));
+    }
+    if identities.is_empty() {
+        identities.push((None, args.state_dir.clone()));
+    }
+    let configs = identities
+        .iter()
+        .map(|(_, state_dir)| Config::load(&args, state_dir))
+        .collect::<Result<Vec<_>>>()?;
+    init_tracing(configs[0].log_file.as_deref(), !tui)?;
+    let socket = args
+        .socket
+        .clone()
+        .unwrap_or_else(|| identities[0].1.join(DEFAULT_SOCKET));
+    // Listen first, so a second daemon leaves the state of the first alone
+    let control = args
+        .daemon
+        .then(|| listen_control(&socket, configs[0].metrics_port))
+        .transpose()?;
 
-        // Small sleep to avoid busy-waiting
-        std::thread::sleep(Duration::from_millis(10));
+    let mut profiles = Vec::new();
+    for ((name, state_dir), config) in identities.into_iter().zip(configs) {
+        set_output_profile(name.filter(|_| several).map(str::to_string));
+        let profile = start_profile(config, state_dir, name, tui || args.daemon).await;
+        set_output_profile(None);
+        profiles.push(profile?);
     }
 
+    if let Some(mut control) = control {
+        let Profile {
+            client, rx, link, ..
+        } = &mut profiles[0];
+        run_daemon(client, rx, link, &mut control, &socket).await?;
+    } else if tui {
+        let Profile {
+            client, rx, link, ..
+        } = &mut profiles[0];
+        run_tui(client, rx, link).await?;
+    } else {
+        run_plain(&mut profiles).await?;
+    }
+    for profile in &mut profiles {
+        let Profile {
+            client, config, rx, ..
+        } = profile;
+        set_output_profile(client.profile.clone().filter(|_| several));
+        shut_down(client, rx, config.cleanup_on_exit).await?;
+        // Show messages still held for reordering rather than losing them
+        client.expire_gaps(Instant::now() + REORDER_WINDOW)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_package_entry(backend: &OpenMlsRustCrypto) -> Vec<u8> {
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).unwrap();
+        let credential = CredentialWithKey {
+            credential: BasicCredential::new(b"alice".to_vec()).into(),
+            signature_key: signer.public().into(),
+        };
+        let bundle = KeyPackage::builder()
+            .build(CIPHERSUITE, backend, &signer, credential)
+            .unwrap();
+        MlsMessageOut::from(bundle.key_package().clone())
+            .tls_serialize_detached()
+            .unwrap()
+    }
+
+    #[test]
+    fn decode_key_packages_accepts_bstr_and_int_array_entries() {
+        let backend = OpenMlsRustCrypto::default();
+        let bytes = key_package_entry(&backend);
+        let array = vec![
+            Value::Bytes(bytes.clone()),
+            Value::Array(bytes.iter().map(|&b| Value::from(b)).collect()),
+            Value::Map(vec![(Value::from("max_message_size"), Value::from(1024))]),
+        ];
+        let decoded = decode_key_packages(&array, backend.crypto());
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn decode_key_packages_skips_garbage() {
+        let backend = OpenMlsRustCrypto::default();
+        let array = vec![
+            Value::Bytes(vec![0xde, 0xad]),
+            Value::Array(vec![Value::from(256)]),
+            Value::Array(vec![Value::from("x")]),
+            Value::Text("kp".into()),
+        ];
+        assert!(decode_key_packages(&array, backend.crypto()).is_empty());
+    }
+
+    #[test]
+    fn state_files_are_private() {
+        let dir = std::env::temp_dir().join(format!("relay-test-{}", std::process::id()));
+        let state_dir = dir.join("state");
+        create_private_dir(&state_dir).unwrap();
+        let path = state_dir.join("identity.cbor");
+        write_cbor(&path, &Value::Null).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&state_dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_are_reassembled_in_any_order() {
+        let mut reassemblies = HashMap::new();
+        let now = Instant::now();
+        let id = [1; 8];
+        let second = encode_chunk(&id, 1, 2, b"world");
+        let first = encode_chunk(&id, 0, 2, b"hello ");
+        assert_eq!(
+            add_chunk(&mut reassemblies, "t", &second, now).unwrap(),
+            None
+        );
+        assert_eq!(
+            add_chunk(&mut reassemblies, "t", &first, now).unwrap(),
+            Some(b"hello world".to_vec())
+        );
+        assert!(reassemblies.is_empty());
+    }
+
+    #[test]
+    fn missing_chunk_keeps_the_payload_incomplete_until_it_expires() {
+        let mut reassemblies = HashMap::new();
+        let now = Instant::now();
+        let chunk = encode_chunk(&[1; 8], 0, 2, b"hello");
+        assert_eq!(
+            add_chunk(&mut reassemblies, "t", &chunk, now).unwrap(),
+            None
+        );
+        assert_eq!(reassemblies.len(), 1);
+
+        // Any later chunk drops payloads that waited too long
+        let other = encode_chunk(&[2; 8], 0, 2, b"other");
+        add_chunk(&mut reassemblies, "t", &other, now + REASSEMBLY_TIMEOUT).unwrap();
+        assert!(!reassemblies.contains_key(&("t".to_string(), [1; 8])));
+    }
+
+    #[test]
+    fn duplicate_chunk_replaces_the_first_copy() {
+        let mut reassemblies = HashMap::new();
+        let now = Instant::now();
+        let id = [1; 8];
+        let first = encode_chunk(&id, 0, 2, b"hello ");
+        add_chunk(&mut reassemblies, "t", &first, now).unwrap();
+        add_chunk(&mut reassemblies, "t", &first, now).unwrap();
+        assert_eq!(reassemblies[&("t".to_string(), id)].bytes, 6);
+        assert_eq!(
+            add_chunk(
+                &mut reassemblies,
+                "t",
+                &encode_chunk(&id, 1, 2, b"world"),
+                now
+            )
+            .unwrap(),
+            Some(b"hello world".to_vec())
+        );
+    }
+
+    #[test]
+    fn out_of_range_and_mismatched_chunks_are_rejected() {
+        let mut reassemblies = HashMap::new();
+        let now = Instant::now();
+        let id = [1; 8];
+        assert!(add_chunk(&mut reassemblies, "t", &encode_chunk(&id, 2, 2, b"x"), now).is_err());
+        assert!(add_chunk(&mut reassemblies, "t", &encode_chunk(&id, 0, 0, b"x"), now).is_err());
+        assert!(add_chunk(&mut reassemblies, "t", &encode_chunk(&id, 0, 2, b""), now).is_err());
+
+        add_chunk(&mut reassemblies, "t", &encode_chunk(&id, 0, 2, b"x"), now).unwrap();
+        assert!(add_chunk(&mut reassemblies, "t", &encode_chunk(&id, 1, 3, b"y"), now).is_err());
+        // The same ID on another topic is another payload
+        assert_eq!(
+            add_chunk(&mut reassemblies, "u", &encode_chunk(&id, 0, 1, b"z"), now).unwrap(),
+            Some(b"z".to_vec())
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Message ordering
+//!
+//! Each application message carries a per-sender sequence number in its MLS
+//! authenticated data (protocol Section 8.4), which is integrity-protected:
+//!   MessageAad = { ? "seq": uint }
+//!
+//! QoS 1 may deliver a message twice and the broker doesn't order messages
+//! from different senders. Receivers drop a sequence number seen recently and
+//! hold messages that arrive after a gap until the missing ones come in or
+//! REORDER_WINDOW passes; then the gap is reported. Messages without a
+//! sequence number (e.g. from other clients) are shown as they arrive.
+
+use super::*;
+
+/// How long a message after a gap waits for the missing ones
+pub(crate) const REORDER_WINDOW: Duration = Duration::from_secs(5);
+/// Sequence numbers remembered per sender to detect duplicates
+pub(crate) const DEDUP_WINDOW: usize = 256;
+
+pub(crate) fn encode_aad(seq: u64) -> Vec<u8> {
+    let aad = Value::Map(vec![(
+        Value::Text("seq".into()),
+        Value::Integer(seq.into()),
+    )]);
+    let mut bytes = Vec::new();
+    // Writing to a Vec cannot fail
+    ciborium::into_writer(&aad, &mut bytes).expect("CBOR encoding into Vec");
+    bytes
+}
+
+/// Sequence number of a received message; AAD that isn't a MessageAad is
+/// ignored rather than rejected
+pub(crate) fn decode_seq(aad: &[u8]) -> Option<u64> {
+    let aad = ciborium::from_reader::<Value, _>(aad).ok()?;
+    u64::try_from(map_entry(&aad, "seq")?.as_integer()?).ok()
+}
+
+/// Messages of one sender in one group, in sequence order
+#[derive(Default)]
+pub(crate) struct SenderOrder {
+    pub(crate) next: Option<u64>, // next sequence number to show
+    pub(crate) held: BTreeMap<u64, (Instant, AppMessage)>, // arrived after a gap
+    pub(crate) seen: VecDeque<u64>, // recent sequence numbers, oldest first
+}
+
+/// What to show for a message that was released from a SenderOrder
+pub(crate) enum Ordered {
+    Message(AppMessage),
+    Late(AppMessage), // arrived after its gap was given up on
+    Missing(u64),     // number of messages skipped
+}
+
+impl SenderOrder {
+    /// Accept a message; returns what can be shown now
+    pub(crate) fn receive(&mut self, seq: u64, message: AppMessage) -> Vec<Ordered> {
+        if self.seen.contains(&seq) || self.held.contains_key(&seq) {
+            return Vec::new();
+        }
+        let next = *self.next.get_or_insert(seq);
+        if seq < next {
+            self.remember(seq);
+            return vec![Ordered::Late(message)];
+        }
+        self.held.insert(seq, (Instant::now(), message));
+        self.release()
+    }
+
+    /// Give up on gaps whose next message has waited REORDER_WINDOW by `now`
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<Ordered> {
+        let mut out = Vec::new();
+        while let (Some(next), Some((&first, &(arrived, _)))) =
+            (self.next, self.held.first_key_value())
+        {
+            if now.saturating_duration_since(arrived) < REORDER_WINDOW {
+                break;
+            }
+            self.next = Some(first);
+            out.push(Ordered::Missing(first - next));
+            out.extend(self.release());
+        }
+        out
+    }
+
+    /// Show held messages that follow on without a gap
+    pub(crate) fn release(&mut self) -> Vec<Ordered> {
+        let mut out = Vec::new();
+        while let Some(next) = self.next {
+            let Some((_, message)) = self.held.remove(&next) else {
+                break;
+            };
+            self.remember(next);
+            self.next = Some(next + 1);
+            out.push(Ordered::Message(message));
+        }
+        out
+    }
+
+    pub(crate) fn remember(&mut self, seq: u64) {
+        if self.seen.len() == DEDUP_WINDOW {
+            self.seen.pop_front();
+        }
+        self.seen.push_back(seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(order: &mut SenderOrder, seq: u64) -> Vec<Ordered> {
+        order.receive(seq, AppMessage::new("text", Value::from(seq)))
+    }
+
+    /// What was released, as "seq", "late seq" or "missing n"
+    fn shown(out: Vec<Ordered>) -> Vec<String> {
+        let seq = |m: AppMessage| u64::try_from(m.body.as_integer().unwrap()).unwrap();
+        out.into_iter()
+            .map(|o| match o {
+                Ordered::Message(m) => seq(m).to_string(),
+                Ordered::Late(m) => format!("late {}", seq(m)),
+                Ordered::Missing(n) => format!("missing {}", n),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sender_order_drops_duplicates() {
+        let mut order = SenderOrder::default();
+        assert_eq!(shown(text(&mut order, 5)), ["5"]);
+        assert!(text(&mut order, 5).is_empty());
+
+        // A held message sent twice is held once
+        assert!(text(&mut order, 7).is_empty());
+        assert!(text(&mut order, 7).is_empty());
+        assert_eq!(shown(text(&mut order, 6)), ["6", "7"]);
+    }
+
+    #[test]
+    fn sender_order_releases_out_of_order_messages_in_order() {
+        let mut order = SenderOrder::default();
+        assert_eq!(shown(text(&mut order, 1)), ["1"]);
+        assert!(text(&mut order, 4).is_empty());
+        assert!(text(&mut order, 3).is_empty());
+        assert_eq!(shown(text(&mut order, 2)), ["2", "3", "4"]);
+        assert!(order.held.is_empty());
+    }
+
+    #[test]
+    fn sender_order_gives_up_on_a_gap_after_the_window() {
+        let mut order = SenderOrder::default();
+        text(&mut order, 1);
+        assert!(text(&mut order, 4).is_empty());
+        assert!(text(&mut order, 5).is_empty());
+
+        assert!(order.expire(Instant::now()).is_empty());
+        let expired = order.expire(Instant::now() + REORDER_WINDOW);
+        assert_eq!(shown(expired), ["missing 2", "4", "5"]);
+        assert_eq!(order.next, Some(6));
+    }
+
+    #[test]
+    fn sender_order_shows_a_message_from_an_expired_gap_as_late() {
+        let mut order = SenderOrder::default();
+        text(&mut order, 1);
+        text(&mut order, 3);
+        order.expire(Instant::now() + REORDER_WINDOW);
+
+        assert_eq!(shown(text(&mut order, 2)), ["late 2"]);
+        // and only once
+        assert!(text(&mut order, 2).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//! Broker proxy
+//!
+//! rumqttc can only tunnel through HTTP proxies (CONNECT), so the client
+//! serves CONNECT on a loopback port and carries each tunnel on over SOCKS5.
+//! The broker's host name is handed to the SOCKS proxy unresolved, so with Tor
+//! the lookup happens at the exit rather than on the local network. TLS and
+//! WebSocket run inside the tunnel as without a proxy. The bridge tunnels
+//! only to the broker, so other local programs can't use it as an open proxy.
+
+use super::*;
+
+/// A SOCKS5 proxy: `socks5://[user:password@]host:port`
+pub(crate) struct SocksProxy {
+    pub(crate) addr: String,
+    pub(crate) auth: Option<(String, String)>,
+}
+
+impl SocksProxy {
+    pub(crate) fn parse(url: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid proxy '{}'; use socks5://host:port", url);
+        // socks5h:// is the usual way to ask for resolution by the proxy,
+        // which is what happens either way
+        let rest = url
+            .strip_prefix("socks5://")
+            .or_else(|| url.strip_prefix("socks5h://"))
+            .ok_or_else(invalid)?;
+        let (auth, addr) = match rest.rsplit_once('@') {
+            Some((auth, addr)) => {
+                let (user, password) = auth.split_once(':').ok_or_else(invalid)?;
+                (Some((user.to_string(), password.to_string())), addr)
+            }
+            None => (None, rest),
+        };
+        let addr = addr.trim_end_matches('/');
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(invalid()),
+        }
+        Ok(Self {
+            addr: addr.to_string(),
+            auth,
+        })
+    }
+
+    /// Open a connection to host:port through the proxy
+    pub(crate) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let stream = match &self.auth {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(
+                    self.addr.as_str(),
+                    (host, port),
+                    user,
+                    password,
+                )
+                .await?
+            }
+            None => Socks5Stream::connect(self.addr.as_str(), (host, port)).await?,
+        };
+        Ok(stream.into_inner())
+    }
+}
+
+/// Route the broker connection of `options` through a SOCKS5 proxy, by way
+/// of an HTTP CONNECT bridge on a loopback port
+pub(crate) async fn route_through_proxy(
+    options: &mut MqttOptions,
+    proxy: SocksProxy,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let bridge_port = listener.local_addr()?.port();
+    let host = host.to_string();
+    let proxy = Arc::new(proxy);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let (proxy, host) = (proxy.clone(), host.clone());
+            tokio::spawn(async move {
+                if let Err(e) = bridge_tunnel(stream, &proxy, &host, port).await {
+                    warn!(error = %e, proxy = %proxy.addr, "Proxy connection failed");
+                }
+            });
+        }
+    });
+    options.set_proxy(Proxy {
+        ty: ProxyType::Http,
+        auth: ProxyAuth::None,
+        addr: "127.0.0.1".to_string(),
+        port: bridge_port,
+    });
+    Ok(())
+}
+
+/// Answer one CONNECT request for the broker and relay the tunnel
+pub(crate) async fn bridge_tunnel(
+    mut local: TcpStream,
+    proxy: &SocksProxy,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    // The request is a few short header lines
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.ends_with(b"\r\n\r\n") {
+        let n = local.read(&mut buf).await?;
+        if n == 0 || request.len() + n > 4096 {
+            return Err(anyhow!("Bad CONNECT request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    if !request.starts_with("CONNECT ") || target != format!("{}:{}", host, port) {
+        local.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        return Err(anyhow!("Refused a tunnel to {}", target));
+    }
+
+    let mut remote = match proxy.connect(host, port).await {
+        Ok(remote) => remote,
+        Err(e) => {
+            local.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+            return Err(e);
+        }
+    };
+    local
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    tokio::io::copy_bidirectional(&mut local, &mut remote).await?;
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Benchmarks for the add/commit paths of RelayMlsClient at different group sizes.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use swift_openmls::RelayMlsClient;
+
+const GROUP_SIZES: [usize; 3] = [2, 50, 500];
+
+/// Create CBOR-wrapped KeyPackages for `count` fresh clients
+fn key_packages(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| {
+            RelayMlsClient::new(format!("member-{}", i))
+                .unwrap()
+                .create_key_package()
+                .unwrap()
+                .key_package_bytes
+        })
+        .collect()
+}
+
+/// Create a group of `size` members (including the creator)
+fn populated_group(size: usize) -> (RelayMlsClient, String) {
+    let creator = RelayMlsClient::new("creator".to_string()).unwrap();
+    let group_id = creator.create_group().unwrap();
+    if size > 1 {
+        creator
+            .add_members(group_id.clone(), key_packages(size - 1))
+            .unwrap();
+    }
+    (creator, group_id)
+}
+
+/// Build a group of N members with a single batched Commit
+fn bench_add_members_batch(c: &mut Criterion) {
+    let mut bench = c.benchmark_group("add_members_batch");
+    bench.sample_size(10);
+
+    for size in GROUP_SIZES {
+        bench.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched(
+                || {
+                    let creator = RelayMlsClient::new("creator".to_string()).unwrap();
+                    let group_id = creator.create_group().unwrap();
+                    (creator, group_id, key_packages(size - 1))
+                },
+                |(creator, group_id, kps)| creator.add_members(group_id, kps).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    bench.finish();
+}
+
+/// Add one more member to an existing group of N members
+fn bench_add_member(c: &mut Criterion) {
+    let mut bench = c.benchmark_group("add_member");
+    bench.sample_size(10);
+
+    for size in GROUP_SIZES {
+        bench.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_batched(
+                || {
+                    let (creator, group_id) = populated_group(size);
+                    let kp = key_packages(1).remove(0);
+                    (creator, group_id, kp)
+                },
+                |(creator, group_id, kp)| creator.add_member(group_id, kp).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    bench.finish();
+}
+
+/// Encrypt an application message in a group of N members
+fn bench_encrypt(c: &mut Criterion) {
+    let mut bench = c.benchmark_group("encrypt");
+
+    for size in GROUP_SIZES {
+        let (creator, group_id) = populated_group(size);
+        bench.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter(|| {
+                creator
+                    .encrypt(group_id.clone(), b"hello group".to_vec(), None)
+                    .unwrap()
+            })
+        });
+    }
+    bench.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_add_members_batch,
+    bench_add_member,
+    bench_encrypt
+);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Authenticated data attached to application messages.
+//!
+//! MLS carries the `authenticated_data` of a PrivateMessage unencrypted but
+//! integrity-protected. Relay puts a CBOR map there; both keys are optional
+//! and an empty map is sent as no AAD at all:
+//!
+//! ```text
+//! MessageAad = {
+//!     ? "content_type": tstr,   ; e.g. a MIME type
+//!     ? "chunk": bstr,          ; ChunkHeader of a stream chunk
+//! }
+//! ```
+
+use crate::stream::ChunkHeader;
+use ciborium::value::Value;
+
+const CONTENT_TYPE_KEY: &str = "content_type";
+const CHUNK_KEY: &str = "chunk";
+
+#[derive(Default)]
+pub(crate) struct MessageAad {
+    pub content_type: Option<String>,
+    pub chunk: Option<ChunkHeader>,
+}
+
+impl MessageAad {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries = Vec::new();
+        if let Some(content_type) = &self.content_type {
+            entries.push((
+                Value::Text(CONTENT_TYPE_KEY.to_string()),
+                Value::Text(content_type.clone()),
+            ));
+        }
+        if let Some(chunk) = &self.chunk {
+            entries.push((
+                Value::Text(CHUNK_KEY.to_string()),
+                Value::Bytes(chunk.encode()),
+            ));
+        }
+        if entries.is_empty() {
+            return Vec::new();
+        }
+
+        let mut out = Vec::new();
+        // Writing to a Vec cannot fail
+        ciborium::into_writer(&Value::Map(entries), &mut out).expect("CBOR encoding into Vec");
+        out
+    }
+
+    /// Decode the AAD of a received message. AAD that isn't a MessageAad
+    /// (e.g. from another application) is ignored rather than rejected.
+    pub fn decode(bytes: &[u8]) -> Self {
+        let mut aad = Self::default();
+        let Ok(Value::Map(entries)) = ciborium::from_reader::<Value, _>(bytes) else {
+            return aad;
+        };
+
+        for (key, value) in entries {
+            match (key.as_text(), value) {
+                (Some(CONTENT_TYPE_KEY), Value::Text(content_type)) => {
+                    aad.content_type = Some(content_type)
+                }
+                (Some(CHUNK_KEY), Value::Bytes(chunk)) => aad.chunk = ChunkHeader::decode(&chunk),
+                _ => {}
+            }
+        }
+        aad
+    }
+}
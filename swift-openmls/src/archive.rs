@@ -0,0 +1,233 @@
+//! Passphrase-sealed container for exporting a single group's state.
+//!
+//! The archive key is derived from the passphrase with Argon2id and the
+//! contents are sealed with AES-256-GCM, the header being bound to the
+//! ciphertext as associated data:
+//!
+//! ```text
+//! GroupArchive = {
+//!     "version": uint,
+//!     "salt": bstr,           ; 16 bytes, Argon2id salt
+//!     "nonce": bstr,          ; 12 bytes, AES-GCM nonce
+//!     "ciphertext": bstr,     ; sealed ArchiveContents
+//! }
+//! ArchiveContents = {
+//!     "group_id": bstr,
+//!     "entries": [* [bstr, bstr]],   ; storage key, value
+//! }
+//! ```
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::Argon2;
+use ciborium::value::Value;
+
+use crate::storage::RawEntries;
+
+const ARCHIVE_VERSION: u64 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+const VERSION_KEY: &str = "version";
+const SALT_KEY: &str = "salt";
+const NONCE_KEY: &str = "nonce";
+const CIPHERTEXT_KEY: &str = "ciphertext";
+const GROUP_ID_KEY: &str = "group_id";
+const ENTRIES_KEY: &str = "entries";
+
+pub(crate) struct GroupArchive {
+    pub group_id: Vec<u8>,
+    pub entries: RawEntries,
+}
+
+impl GroupArchive {
+    pub fn seal(&self, passphrase: &str) -> Result<Vec<u8>, String> {
+        let salt: [u8; SALT_LEN] = rand::random();
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let cipher = archive_cipher(passphrase, &salt)?;
+
+        let contents = Value::Map(vec![
+            (
+                Value::Text(GROUP_ID_KEY.to_string()),
+                Value::Bytes(self.group_id.clone()),
+            ),
+            (
+                Value::Text(ENTRIES_KEY.to_string()),
+                Value::Array(
+                    self.entries
+                        .iter()
+                        .map(|(key, value)| {
+                            Value::Array(vec![
+                                Value::Bytes(key.clone()),
+                                Value::Bytes(value.clone()),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+        ]);
+        let ciphertext = cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &to_cbor(&contents),
+                    aad: &header_aad(&salt, &nonce),
+                },
+            )
+            .map_err(|_| "Failed to encrypt archive".to_string())?;
+
+        Ok(to_cbor(&Value::Map(vec![
+            (
+                Value::Text(VERSION_KEY.to_string()),
+                Value::Integer(ARCHIVE_VERSION.into()),
+            ),
+            (
+                Value::Text(SALT_KEY.to_string()),
+                Value::Bytes(salt.to_vec()),
+            ),
+            (
+                Value::Text(NONCE_KEY.to_string()),
+                Value::Bytes(nonce.to_vec()),
+            ),
+            (
+                Value::Text(CIPHERTEXT_KEY.to_string()),
+                Value::Bytes(ciphertext),
+            ),
+        ])))
+    }
+
+    pub fn open(archive: &[u8], passphrase: &str) -> Result<Self, String> {
+        let (mut version, mut salt, mut nonce, mut ciphertext) = (None, None, None, None);
+        for (key, value) in cbor_map(archive)? {
+            match (key.as_text(), value) {
+                (Some(VERSION_KEY), Value::Integer(v)) => version = u64::try_from(v).ok(),
+                (Some(SALT_KEY), Value::Bytes(bytes)) => {
+                    salt = <[u8; SALT_LEN]>::try_from(bytes).ok()
+                }
+                (Some(NONCE_KEY), Value::Bytes(bytes)) => {
+                    nonce = <[u8; NONCE_LEN]>::try_from(bytes).ok()
+                }
+                (Some(CIPHERTEXT_KEY), Value::Bytes(bytes)) => ciphertext = Some(bytes),
+                _ => {}
+            }
+        }
+        if version != Some(ARCHIVE_VERSION) {
+            return Err("Unsupported archive version".to_string());
+        }
+        let salt = salt.ok_or("Archive has no valid salt")?;
+        let nonce = nonce.ok_or("Archive has no valid nonce")?;
+        let ciphertext = ciphertext.ok_or("Archive has no ciphertext")?;
+
+        let contents = archive_cipher(passphrase, &salt)?
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &header_aad(&salt, &nonce),
+                },
+            )
+            .map_err(|_| "Wrong passphrase or corrupted archive".to_string())?;
+
+        let (mut group_id, mut entries) = (None, None);
+        for (key, value) in cbor_map(&contents)? {
+            match (key.as_text(), value) {
+                (Some(GROUP_ID_KEY), Value::Bytes(bytes)) => group_id = Some(bytes),
+                (Some(ENTRIES_KEY), Value::Array(items)) => {
+                    entries = Some(items.into_iter().map(entry).collect::<Option<Vec<_>>>())
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            group_id: group_id.ok_or("Archive has no group_id")?,
+            entries: entries
+                .ok_or("Archive has no entries")?
+                .ok_or("Archive has a malformed entry")?,
+        })
+    }
+}
+
+fn archive_cipher(passphrase: &str, salt: &[u8]) -> Result<Aes256Gcm, String> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Failed to derive archive key: {}", e))?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+fn header_aad(salt: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut aad = ARCHIVE_VERSION.to_be_bytes().to_vec();
+    aad.extend_from_slice(salt);
+    aad.extend_from_slice(nonce);
+    aad
+}
+
+fn entry(item: Value) -> Option<(Vec<u8>, Vec<u8>)> {
+    let Value::Array(pair) = item else {
+        return None;
+    };
+    match <[Value; 2]>::try_from(pair).ok()? {
+        [Value::Bytes(key), Value::Bytes(value)] => Some((key, value)),
+        _ => None,
+    }
+}
+
+fn cbor_map(bytes: &[u8]) -> Result<Vec<(Value, Value)>, String> {
+    match ciborium::from_reader::<Value, _>(bytes).map_err(|e| format!("{:?}", e))? {
+        Value::Map(entries) => Ok(entries),
+        _ => Err("Archive is not a CBOR map".to_string()),
+    }
+}
+
+fn to_cbor(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    // Writing to a Vec cannot fail
+    ciborium::into_writer(value, &mut out).expect("CBOR encoding into Vec");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> GroupArchive {
+        GroupArchive {
+            group_id: vec![1, 2, 3],
+            entries: vec![(b"key".to_vec(), b"value".to_vec())],
+        }
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let sealed = archive().seal("passphrase").unwrap();
+        let opened = GroupArchive::open(&sealed, "passphrase").unwrap();
+        assert_eq!(opened.group_id, vec![1, 2, 3]);
+        assert_eq!(opened.entries, archive().entries);
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let sealed = archive().seal("passphrase").unwrap();
+        let err = GroupArchive::open(&sealed, "other").err().unwrap();
+        assert_eq!(err, "Wrong passphrase or corrupted archive");
+    }
+
+    #[test]
+    fn tampered_archive_is_rejected() {
+        let mut sealed = archive().seal("passphrase").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(GroupArchive::open(&sealed, "passphrase").is_err());
+    }
+
+    #[test]
+    fn unknown_version_is_rejected() {
+        let sealed = to_cbor(&Value::Map(vec![(
+            Value::Text(VERSION_KEY.to_string()),
+            Value::Integer((ARCHIVE_VERSION + 1).into()),
+        )]));
+        let err = GroupArchive::open(&sealed, "passphrase").err().unwrap();
+        assert_eq!(err, "Unsupported archive version");
+    }
+}
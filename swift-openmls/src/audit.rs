@@ -0,0 +1,68 @@
+//! Per-epoch record of a group's transcript, for comparing group histories
+//! across members.
+//!
+//! Members that agree on an epoch's entry agree on every Commit that led to
+//! it: the confirmed transcript hash covers all Commits so far, and the
+//! confirmation tag and epoch authenticator are derived from the epoch's
+//! key schedule.
+
+use openmls::prelude::*;
+use openmls_traits::{crypto::OpenMlsCrypto, signatures::Signer};
+use serde::{Deserialize, Serialize};
+use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait, VLBytes};
+
+/// Entries kept per group; older epochs are dropped first
+pub(crate) const MAX_AUDIT_ENTRIES: usize = 1024;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub epoch: u64,
+    pub confirmed_transcript_hash: Vec<u8>,
+    pub interim_transcript_hash: Vec<u8>,
+    /// Confirmation tag of the Commit that started the epoch
+    pub confirmation_tag: Vec<u8>,
+    /// RFC 9420 epoch_authenticator, meant for out-of-band comparison
+    pub epoch_authenticator: Vec<u8>,
+}
+
+impl AuditEntry {
+    /// Entry for the group's current epoch
+    pub fn current(
+        group: &MlsGroup,
+        crypto: &impl OpenMlsCrypto,
+        signer: &impl Signer,
+    ) -> Result<Self, String> {
+        // The GroupContext is only reachable through a GroupInfo
+        let group_info = group
+            .export_group_info(crypto, signer, false)
+            .map_err(|e| format!("{:?}", e))?;
+        let MlsMessageBodyOut::GroupInfo(group_info) = group_info.body() else {
+            return Err("Exported GroupInfo has no GroupInfo body".to_string());
+        };
+        let context = group_info.group_context();
+        let confirmation_tag = group
+            .confirmation_tag()
+            .tls_serialize_detached()
+            .map_err(|e| format!("{:?}", e))?;
+
+        // OpenMLS doesn't expose the interim transcript hash, so recompute it
+        // (RFC 9420 Section 8.2): Hash(confirmed_transcript_hash ||
+        // InterimTranscriptHashInput), the input being the encoded tag
+        let mut input = context.confirmed_transcript_hash().to_vec();
+        input.extend_from_slice(&confirmation_tag);
+        let interim_transcript_hash = crypto
+            .hash(group.ciphersuite().hash_algorithm(), &input)
+            .map_err(|e| format!("{:?}", e))?;
+
+        let confirmation_tag = VLBytes::tls_deserialize_exact(confirmation_tag.as_slice())
+            .map_err(|e| format!("{:?}", e))?;
+
+        Ok(Self {
+            epoch: context.epoch().as_u64(),
+            confirmed_transcript_hash: context.confirmed_transcript_hash().to_vec(),
+            interim_transcript_hash,
+            confirmation_tag: confirmation_tag.as_slice().to_vec(),
+            epoch_authenticator: group.epoch_authenticator().as_slice().to_vec(),
+        })
+    }
+}
@@ -0,0 +1,79 @@
+//! Invite payloads for joining a group by External Commit.
+//!
+//! An invite is a CBOR map, base64url-encoded without padding so it fits in
+//! a link or QR code:
+//!
+//! ```text
+//! Invite = {
+//!     "group_info": bstr,     ; MLSMessage (GroupInfo) with external_pub
+//!     "ratchet_tree": bstr,   ; TLS-serialized ratchet tree
+//!     "broker": tstr,         ; broker the group is reachable on
+//! }
+//! ```
+//!
+//! Anyone holding an invite can join until the group moves past the epoch
+//! it was created in, so it must be shared like a secret.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::value::Value;
+
+const GROUP_INFO_KEY: &str = "group_info";
+const RATCHET_TREE_KEY: &str = "ratchet_tree";
+const BROKER_KEY: &str = "broker";
+
+pub(crate) struct Invite {
+    pub group_info: Vec<u8>,
+    pub ratchet_tree: Vec<u8>,
+    pub broker: String,
+}
+
+impl Invite {
+    pub fn encode(&self) -> String {
+        let map = Value::Map(vec![
+            (
+                Value::Text(GROUP_INFO_KEY.to_string()),
+                Value::Bytes(self.group_info.clone()),
+            ),
+            (
+                Value::Text(RATCHET_TREE_KEY.to_string()),
+                Value::Bytes(self.ratchet_tree.clone()),
+            ),
+            (
+                Value::Text(BROKER_KEY.to_string()),
+                Value::Text(self.broker.clone()),
+            ),
+        ]);
+
+        let mut out = Vec::new();
+        // Writing to a Vec cannot fail
+        ciborium::into_writer(&map, &mut out).expect("CBOR encoding into Vec");
+        URL_SAFE_NO_PAD.encode(out)
+    }
+
+    pub fn decode(invite: &str) -> Result<Self, String> {
+        let bytes = URL_SAFE_NO_PAD
+            .decode(invite.trim())
+            .map_err(|e| format!("Invalid base64url: {}", e))?;
+        let Value::Map(entries) =
+            ciborium::from_reader::<Value, _>(bytes.as_slice()).map_err(|e| format!("{:?}", e))?
+        else {
+            return Err("Invite is not a CBOR map".to_string());
+        };
+
+        let (mut group_info, mut ratchet_tree, mut broker) = (None, None, None);
+        for (key, value) in entries {
+            match (key.as_text(), value) {
+                (Some(GROUP_INFO_KEY), Value::Bytes(bytes)) => group_info = Some(bytes),
+                (Some(RATCHET_TREE_KEY), Value::Bytes(bytes)) => ratchet_tree = Some(bytes),
+                (Some(BROKER_KEY), Value::Text(text)) => broker = Some(text),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            group_info: group_info.ok_or("Invite has no group_info")?,
+            ratchet_tree: ratchet_tree.ok_or("Invite has no ratchet_tree")?,
+            broker: broker.ok_or("Invite has no broker")?,
+        })
+    }
+}
@@ -0,0 +1,138 @@
+//! CBOR wrapper KeyPackages are published in.
+//!
+//! The array holds the KeyPackage MLSMessages, most preferred first, and
+//! optionally one map describing the publishing client, so a peer can check
+//! what it supports before forming a group:
+//!
+//! ```text
+//! KeyPackageArray = [* (bstr / [* uint] / ClientCapabilities)]
+//! ClientCapabilities = {
+//!     "extensions": [* uint],      ; MLS extension types beyond the defaults
+//!     "max_message_size": uint,    ; largest MLSMessage accepted, in bytes
+//!     "protocol_revision": uint,
+//! }
+//! ```
+//!
+//! Clients predating capabilities wrote each KeyPackage as an array of byte
+//! values (serde's encoding of `Vec<u8>`), which is still accepted.
+
+use ciborium::value::Value;
+use serde::{Deserialize, Serialize};
+
+/// Features a client advertises next to its KeyPackages
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCapabilities {
+    pub extensions: Vec<u16>,
+    pub max_message_size: u32,
+    pub protocol_revision: u32,
+}
+
+#[derive(Default)]
+pub(crate) struct KeyPackageArray {
+    pub key_packages: Vec<Vec<u8>>,
+    pub capabilities: Option<ClientCapabilities>,
+}
+
+impl KeyPackageArray {
+    pub fn encode(&self) -> Result<Vec<u8>, ciborium::ser::Error<std::io::Error>> {
+        let mut entries: Vec<Value> = self
+            .key_packages
+            .iter()
+            .map(|kp| Value::Bytes(kp.clone()))
+            .collect();
+        if let Some(capabilities) = &self.capabilities {
+            entries.push(Value::serialized(capabilities).map_err(|e| {
+                ciborium::ser::Error::Value(format!("Failed to encode capabilities: {}", e))
+            })?);
+        }
+
+        let mut out = Vec::new();
+        ciborium::into_writer(&Value::Array(entries), &mut out)?;
+        Ok(out)
+    }
+
+    /// Decode an array, accepting ones without capabilities from older
+    /// clients. Entries of unknown type are skipped.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let entries: Vec<Value> = ciborium::from_reader(bytes).map_err(|e| format!("{:?}", e))?;
+
+        let mut array = Self::default();
+        for entry in entries {
+            match entry {
+                Value::Map(_) if array.capabilities.is_none() => {
+                    array.capabilities =
+                        Some(entry.deserialized().map_err(|e| format!("{:?}", e))?);
+                }
+                entry => array.key_packages.extend(entry_bytes(entry)),
+            }
+        }
+        Ok(array)
+    }
+}
+
+/// The bytes of a KeyPackage entry, written as a bstr or by older clients
+/// as an array of uints
+fn entry_bytes(entry: Value) -> Option<Vec<u8>> {
+    match entry {
+        Value::Bytes(bytes) => Some(bytes),
+        Value::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Value::Integer(byte) => u8::try_from(byte).ok(),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(entries: Vec<Value>) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::into_writer(&Value::Array(entries), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let array = KeyPackageArray {
+            key_packages: vec![vec![1, 2], vec![3]],
+            capabilities: Some(ClientCapabilities {
+                extensions: vec![0xf000],
+                max_message_size: 1024,
+                protocol_revision: 2,
+            }),
+        };
+        let decoded = KeyPackageArray::decode(&array.encode().unwrap()).unwrap();
+        assert_eq!(decoded.key_packages, array.key_packages);
+        assert_eq!(decoded.capabilities, array.capabilities);
+    }
+
+    #[test]
+    fn int_array_entries_are_accepted() {
+        let bytes = encode(vec![
+            Value::Array(vec![Value::from(1), Value::from(255)]),
+            Value::Bytes(vec![7]),
+        ]);
+        let decoded = KeyPackageArray::decode(&bytes).unwrap();
+        assert_eq!(decoded.key_packages, vec![vec![1, 255], vec![7]]);
+        assert!(decoded.capabilities.is_none());
+    }
+
+    #[test]
+    fn unknown_entries_are_skipped() {
+        let bytes = encode(vec![
+            Value::Text("kp".into()),
+            Value::Array(vec![Value::from(256)]),
+            Value::Array(vec![Value::Bool(true)]),
+            Value::Bytes(vec![7]),
+        ]);
+        assert_eq!(
+            KeyPackageArray::decode(&bytes).unwrap().key_packages,
+            vec![vec![7]]
+        );
+    }
+}
@@ -1,10 +1,40 @@
 use openmls::prelude::*;
 use openmls_basic_credential::SignatureKeyPair;
 use openmls_rust_crypto::OpenMlsRustCrypto;
+use openmls_traits::storage::StorageProvider;
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 use tls_codec::{Deserialize as TlsDeserializeTrait, Serialize as TlsSerializeTrait};
 
+mod aad;
+mod archive;
+mod audit;
+mod invite;
+mod key_package_array;
+mod provider;
+mod roles;
+mod storage;
+mod stream;
+
+use aad::MessageAad;
+use archive::GroupArchive;
+pub use audit::AuditEntry;
+use audit::MAX_AUDIT_ENTRIES;
+use invite::Invite;
+pub use key_package_array::ClientCapabilities;
+use key_package_array::KeyPackageArray;
+pub use provider::{RelayProvider, SharedCrypto};
+pub use roles::Role;
+use roles::RoleMap;
+use storage::{GroupUndo, RawEntries};
+pub use storage::{RelayStorage, StorageBackend, StorageError};
+use stream::{ChunkHeader, StreamBuffer, MAX_STREAM_CHUNKS};
+
 // ============================================================================
 // Error Types
 // ============================================================================
@@ -22,6 +52,40 @@ pub enum OpenMlsError {
 
     #[error("Group not found")]
     GroupNotFound,
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    #[error("Credential rejected: {0}")]
+    CredentialRejected(String),
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    /// A bug in this library (a caught panic); the call had no effect the
+    /// app can rely on, but the client remains usable
+    #[error("Internal error: {0}")]
+    InternalError(String),
+}
+
+impl From<StorageError> for OpenMlsError {
+    fn from(e: StorageError) -> Self {
+        OpenMlsError::StorageError(e.to_string())
+    }
+}
+
+/// Run the body of an FFI call, turning a panic into `InternalError` so it
+/// reaches Swift as a thrown error instead of unwinding into the bindings.
+/// Locks are parking_lot locks, which don't poison, so later calls still work.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T, OpenMlsError>) -> Result<T, OpenMlsError> {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Err(OpenMlsError::InternalError(message))
+    })
 }
 
 // ============================================================================
@@ -40,80 +104,672 @@ pub struct KeyPackageBundle {
     pub key_package_hash: Vec<u8>,
 }
 
+pub struct PublishedKeyPackage {
+    pub key_package_bytes: Vec<u8>,
+    pub hash_ref: Vec<u8>,
+    pub not_after: u64,
+}
+
+/// A KeyPackageArray with one KeyPackage per supported ciphersuite
+pub struct PublishedKeyPackageArray {
+    pub key_package_bytes: Vec<u8>,
+    /// hash_ref of each KeyPackage, in array order
+    pub hash_refs: Vec<Vec<u8>>,
+    /// Earliest expiry of the KeyPackages
+    pub not_after: u64,
+}
+
 pub struct AddMemberResult {
     pub welcome_bytes: Vec<u8>,
     pub commit_bytes: Vec<u8>,
 }
 
+/// Outcome of `check_commit_welcome`
+pub struct CommitWelcomeReport {
+    /// Every check passed; the Commit can be published, then the Welcome
+    pub consistent: bool,
+    /// Epoch the Commit was sent in
+    pub commit_epoch: u64,
+    /// Epoch of the last Commit with a Welcome this client made in the group
+    pub expected_epoch: Option<u64>,
+    /// The Commit is exactly that last Commit
+    pub commit_matches: bool,
+    /// KeyPackageRefs (hex) the Commit added that the Welcome lacks
+    pub missing_joiners: Vec<String>,
+    /// KeyPackageRefs (hex) in the Welcome that the Commit didn't add
+    pub unexpected_joiners: Vec<String>,
+}
+
 pub struct DecryptedMessage {
     pub plaintext: Vec<u8>,
     pub sender_client_id: String,
+    /// Epoch the message was sent in
+    pub epoch: u64,
+    /// Sender's leaf index (None for senders outside the group)
+    pub sender_leaf_index: Option<u32>,
+    /// Content type the sender attached to an application message
+    pub content_type: Option<String>,
+    pub wire_format: MessageWireFormat,
+    /// Set instead of `plaintext` when the message was a Commit that was not
+    /// merged: commit review is enabled, or a non-admin changed membership
+    pub pending_commit: Option<PendingCommit>,
+    /// Set instead of `plaintext` when the message was an external join
+    /// proposal ("knock") waiting for `admit_join` or `deny_join`
+    pub join_request: Option<JoinRequest>,
+    /// Set instead of `plaintext` when the message was a Commit for the
+    /// epoch of our last Commit: either ours, confirming it, or one that won
+    /// over it and that `resolve_commit_conflict` was applied to
+    pub conflict_resolution: Option<ConflictResolution>,
+}
+
+/// Framing a message arrived in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageWireFormat {
+    PublicMessage,
+    PrivateMessage,
+}
+
+impl DecryptedMessage {
+    /// Metadata of a processed message, with empty plaintext
+    fn from_processed(processed: &ProcessedMessage, wire_format: WireFormat) -> Self {
+        Self {
+            plaintext: Vec::new(),
+            sender_client_id: String::from_utf8_lossy(processed.credential().serialized_content())
+                .to_string(),
+            epoch: processed.epoch().as_u64(),
+            sender_leaf_index: match processed.sender() {
+                Sender::Member(leaf) => Some(leaf.u32()),
+                _ => None,
+            },
+            content_type: MessageAad::decode(processed.aad()).content_type,
+            wire_format: match wire_format {
+                WireFormat::PublicMessage => MessageWireFormat::PublicMessage,
+                _ => MessageWireFormat::PrivateMessage,
+            },
+            pending_commit: None,
+            join_request: None,
+            conflict_resolution: None,
+        }
+    }
+}
+
+/// An incoming Commit held back for the app to merge or reject
+#[derive(Clone)]
+pub struct PendingCommit {
+    pub handle: u64,
+    /// Epoch the group moves to once merged
+    pub epoch: u64,
+    pub sender_client_id: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub updated: Vec<String>,
+    /// The Commit removes this client from the group
+    pub removes_self: bool,
+}
+
+/// Kind of change in a Commit that lost against a competing Commit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalKind {
+    Add,
+    Remove,
+    RoleChange,
+    SelfUpdate,
+    Other,
+}
+
+/// One change of a lost Commit, as reported by `resolve_commit_conflict`
+#[derive(Clone)]
+pub struct ProposalSummary {
+    pub kind: ProposalKind,
+    /// Member added, removed or given a role
+    pub client_id: Option<String>,
+    /// New role, for role changes
+    pub role: Option<Role>,
+}
+
+/// Outcome of `resolve_commit_conflict`
+#[derive(Default)]
+pub struct ConflictResolution {
+    /// False when the Commit was our own, i.e. ours won and nothing changed
+    pub conflict: bool,
+    /// The winning Commit, if it was held for review rather than merged.
+    /// Nothing is recommitted then and all our changes are listed as dropped.
+    pub pending_commit: Option<PendingCommit>,
+    /// Changes committed again on top of the winning Commit
+    pub reapplied: Vec<ProposalSummary>,
+    /// Changes that no longer apply, e.g. a member the winner already added
+    pub dropped: Vec<ProposalSummary>,
+    /// Commit with the reapplied changes, to publish
+    pub commit_bytes: Option<Vec<u8>>,
+    /// Welcome for the members that Commit adds, to publish after it
+    pub welcome_bytes: Option<Vec<u8>>,
+}
+
+/// A non-member asking to be added to a group
+#[derive(Clone)]
+pub struct JoinRequest {
+    pub handle: u64,
+    pub client_id: String,
+    pub application_id: Option<String>,
 }
 
 pub struct JoinGroupResult {
     pub group_id: String,
 }
 
+pub struct AcceptInviteResult {
+    pub group_id: String,
+    /// Broker named in the invite
+    pub broker: String,
+    /// External Commit to publish to the group
+    pub commit_bytes: Vec<u8>,
+}
+
+/// A group member as listed by `members()`
+pub struct GroupMember {
+    /// Credential identity as UTF-8 (lossy); see `identity` for the raw bytes
+    pub client_id: String,
+    /// Application-level identifier from the member's leaf node, if set
+    pub application_id: Option<String>,
+    pub leaf_index: u32,
+    pub credential_type: CredentialKind,
+    /// Credential identity as sent, for credentials that aren't UTF-8
+    pub identity: Vec<u8>,
+    pub signature_public_key: Vec<u8>,
+    /// The member is this client
+    pub is_self: bool,
+}
+
+/// Type of a member's credential
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CredentialKind {
+    Basic,
+    X509,
+    Other,
+}
+
+impl From<CredentialType> for CredentialKind {
+    fn from(credential_type: CredentialType) -> Self {
+        match credential_type {
+            CredentialType::Basic => Self::Basic,
+            CredentialType::X509 => Self::X509,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Reassembly state after receiving one chunk of a stream.
+/// `plaintext` is set once every chunk has arrived.
+pub struct StreamProgress {
+    pub stream_id: String,
+    pub received: u32,
+    pub total: u32,
+    pub sender_client_id: String,
+    pub content_type: Option<String>,
+    pub plaintext: Option<Vec<u8>>,
+}
+
+/// A message from a group's topic, after `process_incoming`
+enum Incoming {
+    Processed(ProcessedMessage, WireFormat),
+    /// A Commit competing with our last one, already resolved
+    Resolved(Box<DecryptedMessage>),
+}
+
+/// What `decrypt_stream` received
+// Variants are handed to the bindings by value, which cannot box them
+#[allow(clippy::large_enum_variant)]
+pub enum StreamMessage {
+    /// A chunk of a stream
+    Chunk { progress: StreamProgress },
+    /// Any other message, as `decrypt` returns it
+    Message { message: DecryptedMessage },
+}
+
+/// Wire format used for outgoing Commits and Proposals
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandshakeWireFormat {
+    /// PublicMessage: handshakes can be inspected by the Delivery Service
+    Plaintext,
+    /// PrivateMessage: handshakes are encrypted like application messages
+    Ciphertext,
+}
+
+/// Per-group settings chosen when creating or joining a group
+#[derive(Clone, Debug)]
+pub struct GroupConfig {
+    pub handshake_wire_format: HandshakeWireFormat,
+    /// Also accept incoming handshakes in the other wire format, for groups
+    /// whose members made different choices
+    pub accept_mixed_handshakes: bool,
+}
+
+impl Default for GroupConfig {
+    fn default() -> Self {
+        Self {
+            handshake_wire_format: HandshakeWireFormat::Ciphertext,
+            accept_mixed_handshakes: false,
+        }
+    }
+}
+
+impl GroupConfig {
+    /// Outgoing handshakes use the chosen format. Incoming handshakes must
+    /// use it too, unless the group accepts mixed handshakes.
+    fn wire_format_policy(&self) -> WireFormatPolicy {
+        match (self.handshake_wire_format, self.accept_mixed_handshakes) {
+            (HandshakeWireFormat::Plaintext, false) => PURE_PLAINTEXT_WIRE_FORMAT_POLICY,
+            (HandshakeWireFormat::Plaintext, true) => MIXED_PLAINTEXT_WIRE_FORMAT_POLICY,
+            (HandshakeWireFormat::Ciphertext, false) => PURE_CIPHERTEXT_WIRE_FORMAT_POLICY,
+            (HandshakeWireFormat::Ciphertext, true) => MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY,
+        }
+    }
+}
+
+/// What an incoming payload is, so the host can route it without trial decoding.
+///
+/// `group_id` is hex-encoded, matching the ids returned by `create_group`.
+pub enum MessageKind {
+    /// CBOR KeyPackageArray or a bare KeyPackage MLSMessage
+    KeyPackage,
+    /// Welcome (the target group is encrypted to the joiner)
+    Welcome,
+    PublicMessage {
+        group_id: String,
+        epoch: u64,
+    },
+    PrivateMessage {
+        group_id: String,
+        epoch: u64,
+    },
+    GroupInfo {
+        group_id: String,
+        epoch: u64,
+    },
+}
+
+/// Host hook for accepting or rejecting member credentials, e.g. by checking
+/// them against a directory service.
+///
+/// Called with the hex group_id and the member's client_id whenever members
+/// are added, a Welcome is joined, or an incoming Commit introduces or
+/// changes credentials. Returning false fails the operation with
+/// `CredentialRejected` and leaves the group unchanged. Implementations must
+/// not call back into the client.
+pub trait CredentialValidator: Send + Sync {
+    fn validate(&self, group_id: String, client_id: String) -> bool;
+}
+
 const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
 
+/// Ciphersuites we publish KeyPackages for, most preferred first. All use
+/// Ed25519 so one signature key serves every suite.
+const SUPPORTED_CIPHERSUITES: [Ciphersuite; 2] = [
+    CIPHERSUITE,
+    Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+];
+
+/// Relay protocol revision this client implements. KeyPackageArrays from
+/// clients advertising an older revision are not accepted by add_member.
+const PROTOCOL_REVISION: u32 = 1;
+
+/// Largest MLSMessage we accept; bigger payloads go through `encrypt_stream`
+const MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
+
+/// Wrap KeyPackage MLSMessages, followed by our capabilities, in a CBOR
+/// array per protocol spec
+fn encode_key_package_array(key_packages: &[Vec<u8>]) -> Result<Vec<u8>, OpenMlsError> {
+    let array = KeyPackageArray {
+        key_packages: key_packages.to_vec(),
+        capabilities: Some(client_capabilities()),
+    };
+    array
+        .encode()
+        .map_err(|e| OpenMlsError::SerializationError(format!("Failed to encode CBOR: {:?}", e)))
+}
+
+/// Capabilities we advertise in our KeyPackageArrays
+fn client_capabilities() -> ClientCapabilities {
+    ClientCapabilities {
+        extensions: leaf_capabilities()
+            .extensions()
+            .iter()
+            .map(|extension| u16::from(*extension))
+            .collect(),
+        max_message_size: MAX_MESSAGE_SIZE,
+        protocol_revision: PROTOCOL_REVISION,
+    }
+}
+
+/// Capabilities advertised in our leaf nodes (KeyPackages and created groups)
+fn leaf_capabilities() -> Capabilities {
+    let mut extensions = roles::supported_extensions();
+    extensions.push(ExtensionType::LastResort);
+    Capabilities::new(None, None, Some(&extensions), None, None)
+}
+
+// Records RelayMlsClient keeps next to the OpenMLS state
+const IDENTITY_RECORD: &[u8] = b"Identity";
+const GROUPS_RECORD: &[u8] = b"Groups";
+const KEY_PACKAGES_RECORD: &[u8] = b"KeyPackages";
+const SELF_UPDATES_RECORD: &[u8] = b"SelfUpdates";
+
+/// Record holding the audit log of one group
+fn audit_log_record(group_id: &str) -> Vec<u8> {
+    [b"AuditLog/".as_slice(), group_id.as_bytes()].concat()
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    client_id: String,
+    signature_public_key: Vec<u8>,
+}
+
+/// Seconds since the Unix epoch
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// A KeyPackage whose private keys are still in storage
+#[derive(Clone, Serialize, Deserialize)]
+struct IssuedKeyPackage {
+    hash_ref: KeyPackageRef,
+    not_after: u64,
+    last_resort: bool,
+}
+
+/// Our last Commit in a group, kept until the next Commit is merged so it
+/// can be rolled back if a competing Commit for the same epoch wins
+struct OwnCommit {
+    epoch: u64,
+    commit_bytes: Vec<u8>,
+    /// The entries of the group state before the merge that it changed
+    undo: GroupUndo,
+    changes: Vec<OwnChange>,
+}
+
+struct OwnChange {
+    summary: ProposalSummary,
+    /// KeyPackage of an added member, to add it again
+    key_package: Option<KeyPackage>,
+}
+
+/// The last Commit with a Welcome this client made in a group
+struct SentWelcome {
+    epoch: u64,
+    commit_bytes: Vec<u8>,
+    joiners: Vec<String>, // hex KeyPackageRefs
+}
+
 // ============================================================================
 // RelayMlsClient - Stateful client matching relay-rs design
 // ============================================================================
 
 pub struct RelayMlsClient {
-    backend: OpenMlsRustCrypto,
+    backend: RelayProvider,
     client_id: String,
     signer: SignatureKeyPair,
     credential: CredentialWithKey,
     groups: Mutex<HashMap<String, MlsGroup>>, // group_id (hex) -> MlsGroup
+    key_packages: Mutex<Vec<IssuedKeyPackage>>,
+    self_updates: Mutex<HashMap<String, u64>>, // group_id -> time of our last fresh leaf
+    sent_welcomes: Mutex<HashMap<String, SentWelcome>>, // group_id -> last add
+    own_commits: Mutex<HashMap<String, OwnCommit>>, // group_id -> our unconfirmed Commit
+    streams: Mutex<StreamBuffer>,
+    validator: RwLock<Option<Box<dyn CredentialValidator>>>,
+    application_id: RwLock<Option<String>>,
+    review_commits: AtomicBool,
+    pending_commits: Mutex<HashMap<u64, (String, PendingCommit, StagedCommit)>>, // handle -> commit
+    join_requests: Mutex<HashMap<u64, (String, JoinRequest, QueuedProposal)>>, // handle -> request
+    next_handle: AtomicU64,
 }
 
 impl RelayMlsClient {
     pub fn new(client_id: String) -> Result<Self, OpenMlsError> {
-        let backend = OpenMlsRustCrypto::default();
+        Self::with_provider(client_id, RelayProvider::default())
+    }
 
-        // Create credential from client_id
-        let credential = BasicCredential::new(client_id.clone().into_bytes());
+    /// Create a client whose MLS state is encrypted with `storage_key`
+    /// (32 bytes) and persisted through the host's `backend`.
+    ///
+    /// If the backend already holds state for `client_id`, the identity and
+    /// all groups are restored from it.
+    pub fn with_encrypted_storage(
+        client_id: String,
+        storage_key: Vec<u8>,
+        backend: Box<dyn StorageBackend>,
+    ) -> Result<Self, OpenMlsError> {
+        let storage = RelayStorage::encrypted(&storage_key, backend)?;
+        Self::with_provider(client_id, RelayProvider::new(storage))
+    }
+
+    /// Create a client on a provider built by a Rust host, e.g. one using
+    /// `RelayProvider::with_crypto` for hardware-backed crypto or a crypto
+    /// instance shared between clients. Each client needs its own storage.
+    ///
+    /// Not exported to Swift, which can't supply a crypto implementation;
+    /// Swift uses `new` or `with_encrypted_storage`.
+    pub fn with_provider(client_id: String, backend: RelayProvider) -> Result<Self, OpenMlsError> {
+        catch_panic(|| {
+            let stored: Option<StoredIdentity> = backend.storage().read_record(IDENTITY_RECORD)?;
+
+            let signer = match stored {
+                // Restore the signer persisted by a previous session
+                Some(identity) => {
+                    if identity.client_id != client_id {
+                        return Err(OpenMlsError::InvalidInput(format!(
+                            "Storage belongs to client {}",
+                            identity.client_id
+                        )));
+                    }
+                    SignatureKeyPair::read(
+                        backend.storage(),
+                        &identity.signature_public_key,
+                        CIPHERSUITE.signature_algorithm(),
+                    )
+                    .ok_or_else(|| {
+                        OpenMlsError::StorageError("Stored signer not found".to_string())
+                    })?
+                }
+                None => {
+                    // Generate signature keypair (persisted for lifetime of client)
+                    let signer =
+                        SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
+                            OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e))
+                        })?;
+
+                    // Store signer in backend
+                    signer.store(backend.storage())?;
+                    backend.storage().write_record(
+                        IDENTITY_RECORD,
+                        &StoredIdentity {
+                            client_id: client_id.clone(),
+                            signature_public_key: signer.to_public_vec(),
+                        },
+                    )?;
+                    signer
+                }
+            };
+
+            // Create credential from client_id
+            let credential = BasicCredential::new(client_id.clone().into_bytes());
+
+            let credential_with_key = CredentialWithKey {
+                credential: credential.into(),
+                signature_key: signer.public().into(),
+            };
+
+            // Reload groups persisted by a previous session
+            let key_packages: Vec<IssuedKeyPackage> = backend
+                .storage()
+                .read_record(KEY_PACKAGES_RECORD)?
+                .unwrap_or_default();
+            let self_updates: HashMap<String, u64> = backend
+                .storage()
+                .read_record(SELF_UPDATES_RECORD)?
+                .unwrap_or_default();
+
+            let group_ids: Vec<String> = backend
+                .storage()
+                .read_record(GROUPS_RECORD)?
+                .unwrap_or_default();
+            let mut groups = HashMap::new();
+            for group_id in group_ids {
+                let group_id_bytes = hex::decode(&group_id).map_err(|e| {
+                    OpenMlsError::StorageError(format!("Invalid stored group id: {:?}", e))
+                })?;
+                if let Some(group) =
+                    MlsGroup::load(backend.storage(), &GroupId::from_slice(&group_id_bytes))?
+                {
+                    groups.insert(group_id, group);
+                }
+            }
+
+            Ok(Self {
+                backend,
+                client_id,
+                signer,
+                credential: credential_with_key,
+                groups: Mutex::new(groups),
+                key_packages: Mutex::new(key_packages),
+                self_updates: Mutex::new(self_updates),
+                sent_welcomes: Mutex::new(HashMap::new()),
+                own_commits: Mutex::new(HashMap::new()),
+                streams: Mutex::new(StreamBuffer::default()),
+                validator: RwLock::new(None),
+                application_id: RwLock::new(None),
+                review_commits: AtomicBool::new(false),
+                pending_commits: Mutex::new(HashMap::new()),
+                join_requests: Mutex::new(HashMap::new()),
+                next_handle: AtomicU64::new(1),
+            })
+        })
+    }
 
-        // Generate signature keypair (persisted for lifetime of client)
-        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e)))?;
+    /// Install a hook that must accept every new member credential
+    pub fn set_credential_validator(&self, validator: Box<dyn CredentialValidator>) {
+        *self.validator.write() = Some(validator);
+    }
 
-        // Store signer in backend
-        signer
-            .store(backend.storage())
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to store signer: {:?}", e)))?;
+    /// Remove the credential hook; all credentials are accepted again
+    pub fn clear_credential_validator(&self) {
+        *self.validator.write() = None;
+    }
 
-        let credential_with_key = CredentialWithKey {
-            credential: credential.into(),
-            signature_key: signer.public().into(),
-        };
+    /// Set the application_id carried in the leaf node of KeyPackages and
+    /// groups created from now on, e.g. a stable user identifier that is
+    /// independent of the credential. Pass None to stop setting it.
+    pub fn set_application_id(&self, application_id: Option<String>) {
+        *self.application_id.write() = application_id;
+    }
 
-        Ok(Self {
-            backend,
-            client_id,
-            signer,
-            credential: credential_with_key,
-            groups: Mutex::new(HashMap::new()),
-        })
+    /// Leaf node extensions for new KeyPackages and groups
+    fn leaf_extensions(&self) -> Extensions {
+        match self.application_id.read().as_ref() {
+            Some(id) => Extensions::single(Extension::ApplicationId(ApplicationIdExtension::new(
+                id.as_bytes(),
+            ))),
+            None => Extensions::empty(),
+        }
+    }
+
+    /// Record which groups exist so they can be reloaded from storage
+    fn persist_group_index(&self, groups: &HashMap<String, MlsGroup>) -> Result<(), OpenMlsError> {
+        let group_ids: Vec<&String> = groups.keys().collect();
+        Ok(self
+            .backend
+            .storage()
+            .write_record(GROUPS_RECORD, &group_ids)?)
+    }
+
+    /// Record which KeyPackages still have private keys in storage
+    fn persist_key_packages(&self, key_packages: &[IssuedKeyPackage]) -> Result<(), OpenMlsError> {
+        Ok(self
+            .backend
+            .storage()
+            .write_record(KEY_PACKAGES_RECORD, &key_packages)?)
+    }
+
+    /// Remember that our leaf in a group was just replaced
+    fn mark_self_update(&self, group_id: &str) -> Result<(), OpenMlsError> {
+        let mut self_updates = self.self_updates.lock();
+        self_updates.insert(group_id.to_string(), unix_now());
+        Ok(self
+            .backend
+            .storage()
+            .write_record(SELF_UPDATES_RECORD, &*self_updates)?)
+    }
+
+    /// Delete the private keys of a KeyPackage
+    fn delete_key_package(&self, hash_ref: &KeyPackageRef) -> Result<(), OpenMlsError> {
+        self.backend.storage().delete_key_package(hash_ref)?;
+        Ok(())
     }
 
     pub fn client_id(&self) -> String {
         self.client_id.clone()
     }
 
-    /// Create a KeyPackage in CBOR-wrapped MLSMessage format per Relay protocol
-    pub fn create_key_package(&self) -> Result<Vec<u8>, OpenMlsError> {
-        let key_package = KeyPackage::builder()
-            .build(
-                CIPHERSUITE,
-                &self.backend,
-                &self.signer,
-                self.credential.clone(),
-            )
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create KeyPackage: {:?}", e)))?
-            .key_package()
-            .clone();
+    /// Create a KeyPackage in CBOR-wrapped MLSMessage format per Relay protocol.
+    ///
+    /// Also returns the KeyPackage's hash_ref and expiry (seconds since the
+    /// Unix epoch) so the app can index what it has published.
+    pub fn create_key_package(&self) -> Result<PublishedKeyPackage, OpenMlsError> {
+        catch_panic(|| self.issue_key_package(false))
+    }
+
+    /// Create a last-resort KeyPackage (protocol Section 6.4). It may be used
+    /// by several joiners, so its private keys are kept after a Welcome and
+    /// only removed once it expires.
+    pub fn create_last_resort_key_package(&self) -> Result<PublishedKeyPackage, OpenMlsError> {
+        catch_panic(|| self.issue_key_package(true))
+    }
+
+    /// Create a KeyPackageArray with one KeyPackage per supported ciphersuite,
+    /// most preferred first, so peers whose groups use another suite can
+    /// still add this client.
+    pub fn create_key_package_array(&self) -> Result<PublishedKeyPackageArray, OpenMlsError> {
+        catch_panic(|| {
+            let mut key_packages = Vec::with_capacity(SUPPORTED_CIPHERSUITES.len());
+            let mut hash_refs = Vec::with_capacity(SUPPORTED_CIPHERSUITES.len());
+            let mut not_after = u64::MAX;
+            for ciphersuite in SUPPORTED_CIPHERSUITES {
+                let (kp_bytes, hash_ref, expiry) = self.build_key_package(ciphersuite, false)?;
+                key_packages.push(kp_bytes);
+                hash_refs.push(hash_ref);
+                not_after = not_after.min(expiry);
+            }
+
+            Ok(PublishedKeyPackageArray {
+                key_package_bytes: encode_key_package_array(&key_packages)?,
+                hash_refs,
+                not_after,
+            })
+        })
+    }
+
+    fn issue_key_package(&self, last_resort: bool) -> Result<PublishedKeyPackage, OpenMlsError> {
+        let (kp_bytes, hash_ref, not_after) = self.build_key_package(CIPHERSUITE, last_resort)?;
+
+        Ok(PublishedKeyPackage {
+            key_package_bytes: encode_key_package_array(&[kp_bytes])?,
+            hash_ref,
+            not_after,
+        })
+    }
+
+    /// Create and track one KeyPackage, returning its MLSMessage bytes,
+    /// hash_ref and expiry
+    fn build_key_package(
+        &self,
+        ciphersuite: Ciphersuite,
+        last_resort: bool,
+    ) -> Result<(Vec<u8>, Vec<u8>, u64), OpenMlsError> {
+        let (key_package, hash_ref) = self.new_key_package(ciphersuite, last_resort)?;
+        let not_after = key_package.life_time().not_after();
 
         // Serialize as MLSMessage
         let kp_bytes = MlsMessageOut::from(key_package)
@@ -122,41 +778,127 @@ impl RelayMlsClient {
                 OpenMlsError::SerializationError(format!("Failed to serialize KeyPackage: {:?}", e))
             })?;
 
-        // Wrap in CBOR array per protocol spec: KeyPackageArray = [* bstr]
-        let mut cbor = Vec::new();
-        ciborium::into_writer(&vec![kp_bytes], &mut cbor).map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to encode CBOR: {:?}", e))
+        Ok((kp_bytes, hash_ref.as_slice().to_vec(), not_after))
+    }
+
+    /// Create a KeyPackage and track it so its private keys are deleted once
+    /// used or expired
+    fn new_key_package(
+        &self,
+        ciphersuite: Ciphersuite,
+        last_resort: bool,
+    ) -> Result<(KeyPackage, KeyPackageRef), OpenMlsError> {
+        let mut builder = KeyPackage::builder()
+            .leaf_node_capabilities(leaf_capabilities())
+            .leaf_node_extensions(self.leaf_extensions());
+        if last_resort {
+            builder = builder.mark_as_last_resort();
+        }
+        let key_package_bundle = builder
+            .build(
+                ciphersuite,
+                &self.backend,
+                &self.signer,
+                self.credential.clone(),
+            )
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create KeyPackage: {:?}", e)))?;
+
+        let key_package = key_package_bundle.key_package();
+        let hash_ref = key_package.hash_ref(self.backend.crypto()).map_err(|e| {
+            OpenMlsError::MlsError(format!("Failed to compute KeyPackage hash: {:?}", e))
         })?;
+        let not_after = key_package.life_time().not_after();
+
+        let mut key_packages = self.key_packages.lock();
+        key_packages.push(IssuedKeyPackage {
+            hash_ref: hash_ref.clone(),
+            not_after,
+            last_resort,
+        });
+        self.persist_key_packages(&key_packages)?;
+        drop(key_packages);
+
+        Ok((key_package_bundle.key_package().clone(), hash_ref))
+    }
+
+    /// Delete the private keys of expired KeyPackages (including last-resort
+    /// ones) that were never used. Returns the number of KeyPackages removed.
+    pub fn prune_key_packages(&self) -> Result<u32, OpenMlsError> {
+        catch_panic(|| {
+            let now = unix_now();
+
+            let mut key_packages = self.key_packages.lock();
+            let (expired, live): (Vec<_>, Vec<_>) =
+                key_packages.drain(..).partition(|kp| kp.not_after < now);
+            *key_packages = live;
+
+            for kp in &expired {
+                self.delete_key_package(&kp.hash_ref)?;
+            }
+            self.persist_key_packages(&key_packages)?;
+
+            Ok(expired.len() as u32)
+        })
+    }
 
-        Ok(cbor)
+    /// Number of issued KeyPackages whose private keys are still held
+    pub fn pending_key_package_count(&self) -> u32 {
+        self.key_packages.lock().len() as u32
     }
 
     /// Create a new MLS group with random 16-byte group_id
     pub fn create_group(&self) -> Result<String, OpenMlsError> {
-        // Generate random 16-byte group ID
-        let group_id_bytes: [u8; 16] = rand::random();
-        let group_id = hex::encode(group_id_bytes);
-
-        let config = MlsGroupCreateConfig::builder()
-            .ciphersuite(CIPHERSUITE)
-            .use_ratchet_tree_extension(true)
-            .build();
-
-        let group = MlsGroup::new_with_group_id(
-            &self.backend,
-            &self.signer,
-            &config,
-            GroupId::from_slice(&group_id_bytes),
-            self.credential.clone(),
-        )
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to create group: {:?}", e)))?;
-
-        self.groups
-            .lock()
-            .unwrap()
-            .insert(group_id.clone(), group);
+        self.create_group_with_config(GroupConfig::default())
+    }
+
+    /// Create a new MLS group with random 16-byte group_id and explicit settings
+    pub fn create_group_with_config(&self, config: GroupConfig) -> Result<String, OpenMlsError> {
+        catch_panic(|| {
+            // Generate random 16-byte group ID
+            let group_id_bytes: [u8; 16] = rand::random();
+            let group_id = hex::encode(group_id_bytes);
+
+            let config = MlsGroupCreateConfig::builder()
+                .ciphersuite(CIPHERSUITE)
+                .use_ratchet_tree_extension(true)
+                .wire_format_policy(config.wire_format_policy())
+                .capabilities(leaf_capabilities())
+                .build();
+
+            let mut group = MlsGroup::new_with_group_id(
+                &self.backend,
+                &self.signer,
+                &config,
+                GroupId::from_slice(&group_id_bytes),
+                self.credential.clone(),
+            )
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create group: {:?}", e)))?;
+
+            // The create config only accepts unknown leaf extensions, so the
+            // application_id goes in with an update while we are the only member
+            let leaf_extensions = self.leaf_extensions();
+            if leaf_extensions.application_id().is_some() {
+                let parameters = LeafNodeParameters::builder()
+                    .with_extensions(leaf_extensions)
+                    .build();
+                group
+                    .self_update(&self.backend, &self.signer, parameters)
+                    .map_err(|e| {
+                        OpenMlsError::MlsError(format!("Failed to update leaf: {:?}", e))
+                    })?;
+                group.merge_pending_commit(&self.backend).map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e))
+                })?;
+            }
+            self.record_epoch(&group_id, &group)?;
+            self.mark_self_update(&group_id)?;
+
+            let mut groups = self.groups.lock();
+            groups.insert(group_id.clone(), group);
+            self.persist_group_index(&groups)?;
 
-        Ok(group_id)
+            Ok(group_id)
+        })
     }
 
     /// Add a member to a group using their KeyPackage (CBOR-wrapped)
@@ -165,251 +907,2135 @@ impl RelayMlsClient {
         group_id: String,
         key_package_bytes: Vec<u8>,
     ) -> Result<AddMemberResult, OpenMlsError> {
+        self.add_members(group_id, vec![key_package_bytes])
+    }
+
+    /// Add several members to a group in a single Commit.
+    ///
+    /// All KeyPackages are decoded and validated before the groups lock is
+    /// taken, and the group state is merged once for the whole batch.
+    pub fn add_members(
+        &self,
+        group_id: String,
+        key_packages: Vec<Vec<u8>>,
+    ) -> Result<AddMemberResult, OpenMlsError> {
+        catch_panic(|| {
+            let ciphersuite = self
+                .groups
+                .lock()
+                .get(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?
+                .ciphersuite();
+            let key_packages = key_packages
+                .iter()
+                .map(|kp| self.parse_key_package(kp, ciphersuite))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if key_packages.is_empty() {
+                return Err(OpenMlsError::InvalidInput(
+                    "No KeyPackages to add".to_string(),
+                ));
+            }
+
+            for key_package in &key_packages {
+                self.validate_credential(&group_id, key_package.leaf_node().credential())?;
+            }
+            let joiners = key_packages
+                .iter()
+                .map(|kp| self.key_package_ref_hex(kp))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            self.require_admin(group)?;
+            self.expire_join_requests(&group_id, group)?;
+            let epoch = group.epoch().as_u64();
+
+            // Add members
+            let (commit, welcome, _group_info) = group
+                .add_members(&self.backend, &self.signer, &key_packages)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to add member: {:?}", e)))?;
+
+            // Merge pending commit
+            self.merge_own_commit(&group_id, group, &commit)?;
+            drop(groups);
+
+            // Serialize results
+            let welcome_bytes = welcome.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Welcome: {:?}", e))
+            })?;
+
+            let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+            })?;
+            self.record_sent_welcome(&group_id, epoch, &commit_bytes, joiners);
+
+            Ok(AddMemberResult {
+                welcome_bytes,
+                commit_bytes,
+            })
+        })
+    }
+
+    /// Decode a CBOR KeyPackageArray and validate the first KeyPackage for
+    /// `ciphersuite`. Entries for other suites, and ones that fail to decode
+    /// or validate, are skipped.
+    fn parse_key_package(
+        &self,
+        key_package_bytes: &[u8],
+        ciphersuite: Ciphersuite,
+    ) -> Result<KeyPackage, OpenMlsError> {
         // Decode CBOR array
-        let kp_array: Vec<Vec<u8>> = ciborium::from_reader(key_package_bytes.as_slice())
-            .map_err(|e| {
-                OpenMlsError::SerializationError(format!("Failed to decode CBOR: {:?}", e))
+        let kp_array = KeyPackageArray::decode(key_package_bytes).map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to decode CBOR: {}", e))
+        })?;
+
+        if kp_array.key_packages.is_empty() {
+            return Err(OpenMlsError::InvalidInput(
+                "Empty KeyPackage array".to_string(),
+            ));
+        }
+
+        // Arrays without capabilities come from clients predating them
+        if let Some(capabilities) = &kp_array.capabilities {
+            if capabilities.protocol_revision < PROTOCOL_REVISION {
+                return Err(OpenMlsError::InvalidInput(format!(
+                    "Client is on protocol revision {}, need {}",
+                    capabilities.protocol_revision, PROTOCOL_REVISION
+                )));
+            }
+        }
+
+        // An entry that doesn't decode or validate is skipped like one for
+        // another suite; its error is reported if no entry is usable
+        let mut rejected = None;
+        for kp_mls_bytes in &kp_array.key_packages {
+            let key_package = match MlsMessageIn::tls_deserialize(&mut kp_mls_bytes.as_slice()) {
+                Ok(mls_msg) => match mls_msg.extract() {
+                    MlsMessageBodyIn::KeyPackage(kp) => kp
+                        .validate(self.backend.crypto(), ProtocolVersion::Mls10)
+                        .map_err(|e| {
+                            OpenMlsError::MlsError(format!(
+                                "Failed to validate KeyPackage: {:?}",
+                                e
+                            ))
+                        }),
+                    _ => Err(OpenMlsError::InvalidInput(
+                        "Expected KeyPackage message".to_string(),
+                    )),
+                },
+                Err(e) => Err(OpenMlsError::SerializationError(format!(
+                    "Failed to deserialize KeyPackage: {:?}",
+                    e
+                ))),
+            };
+
+            match key_package {
+                Ok(key_package) if key_package.ciphersuite() == ciphersuite => {
+                    return Ok(key_package)
+                }
+                Ok(_) => {}
+                Err(e) => rejected = Some(e),
+            }
+        }
+
+        Err(rejected.unwrap_or_else(|| {
+            OpenMlsError::InvalidInput(format!("No KeyPackage for ciphersuite {:?}", ciphersuite))
+        }))
+    }
+
+    /// Join a group from a Welcome message.
+    ///
+    /// `ratchet_tree_bytes` (a TLS-serialized ratchet tree) is required when
+    /// the group does not use the ratchet_tree extension.
+    pub fn join_from_welcome(
+        &self,
+        welcome_bytes: Vec<u8>,
+        ratchet_tree_bytes: Option<Vec<u8>>,
+        config: Option<GroupConfig>,
+    ) -> Result<JoinGroupResult, OpenMlsError> {
+        catch_panic(|| {
+            // Deserialize Welcome
+            let mls_msg =
+                MlsMessageIn::tls_deserialize(&mut welcome_bytes.as_slice()).map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize Welcome: {:?}",
+                        e
+                    ))
+                })?;
+
+            let welcome = match mls_msg.extract() {
+                MlsMessageBodyIn::Welcome(w) => w,
+                _ => {
+                    return Err(OpenMlsError::InvalidInput(
+                        "Expected Welcome message".to_string(),
+                    ))
+                }
+            };
+
+            // KeyPackages of ours this Welcome may be addressed to
+            let recipients: Vec<KeyPackageRef> = welcome
+                .secrets()
+                .iter()
+                .map(|secrets| secrets.new_member())
+                .collect();
+
+            // Decrypt the Welcome so the tree can be checked against its GroupInfo
+            let config = MlsGroupJoinConfig::builder()
+                .wire_format_policy(config.unwrap_or_default().wire_format_policy())
+                .build();
+            let processed = ProcessedWelcome::new_from_welcome(&self.backend, &config, welcome)
+                .map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to process Welcome: {:?}", e))
+                })?;
+
+            let ratchet_tree = match ratchet_tree_bytes {
+                Some(bytes) => {
+                    let tree =
+                        RatchetTreeIn::tls_deserialize_exact(bytes.as_slice()).map_err(|e| {
+                            OpenMlsError::SerializationError(format!(
+                                "Failed to deserialize ratchet tree: {:?}",
+                                e
+                            ))
+                        })?;
+
+                    // Validate the tree before staging the Welcome
+                    let group_info = processed.unverified_group_info();
+                    tree.clone()
+                        .into_verified(
+                            group_info.ciphersuite(),
+                            self.backend.crypto(),
+                            group_info.group_id(),
+                        )
+                        .map_err(|e| {
+                            OpenMlsError::InvalidInput(format!("Invalid ratchet tree: {:?}", e))
+                        })?;
+                    Some(tree)
+                }
+                None => None,
+            };
+
+            let staged = processed
+                .into_staged_welcome(&self.backend, ratchet_tree)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to stage Welcome: {:?}", e)))?;
+
+            // Check everyone already in the group before joining it
+            let staged_group_id = hex::encode(staged.group_context().group_id().as_slice());
+            let own_key = self.signer.public();
+            for member in staged.members() {
+                if member.signature_key != own_key {
+                    self.validate_credential(&staged_group_id, &member.credential)?;
+                }
+            }
+
+            // Join group
+            let group = staged
+                .into_group(&self.backend)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?;
+
+            let group_id = hex::encode(group.group_id().as_slice());
+            self.record_epoch(&group_id, &group)?;
+            self.mark_self_update(&group_id)?;
+
+            let mut groups = self.groups.lock();
+            groups.insert(group_id.clone(), group);
+            self.persist_group_index(&groups)?;
+            drop(groups);
+
+            // The consumed KeyPackage must not be used again; keep last-resort ones
+            let mut key_packages = self.key_packages.lock();
+            let mut consumed = Vec::new();
+            key_packages.retain(|kp| {
+                let used = !kp.last_resort && recipients.contains(&kp.hash_ref);
+                if used {
+                    consumed.push(kp.hash_ref.clone());
+                }
+                !used
+            });
+            for hash_ref in &consumed {
+                self.delete_key_package(hash_ref)?;
+            }
+            self.persist_key_packages(&key_packages)?;
+
+            Ok(JoinGroupResult { group_id })
+        })
+    }
+
+    /// Export the group's ratchet tree (TLS-serialized) for joiners of groups
+    /// that don't carry the ratchet_tree extension
+    pub fn export_ratchet_tree(&self, group_id: String) -> Result<Vec<u8>, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+
+            group
+                .export_ratchet_tree()
+                .tls_serialize_detached()
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to serialize ratchet tree: {:?}",
+                        e
+                    ))
+                })
+        })
+    }
+
+    /// Create an invite (base64url, for a link or QR code) that lets its
+    /// holder join the group by External Commit. `broker` tells the joiner
+    /// where to publish, e.g. "mqtts://relay.example.com:8883".
+    ///
+    /// The invite stays valid until the group's next Commit and admits
+    /// anyone who has it.
+    pub fn create_invite(&self, group_id: String, broker: String) -> Result<String, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+            self.require_admin(group)?;
+
+            let group_info = group
+                .export_group_info(self.backend.crypto(), &self.signer, false)
+                .map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to export GroupInfo: {:?}", e))
+                })?
+                .tls_serialize_detached()
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to serialize GroupInfo: {:?}",
+                        e
+                    ))
+                })?;
+            let ratchet_tree = group
+                .export_ratchet_tree()
+                .tls_serialize_detached()
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to serialize ratchet tree: {:?}",
+                        e
+                    ))
+                })?;
+
+            Ok(Invite {
+                group_info,
+                ratchet_tree,
+                broker,
+            }
+            .encode())
+        })
+    }
+
+    /// Join a group from an invite created by `create_invite`. Publish the
+    /// returned Commit to the group on the invite's broker; existing members
+    /// add this client when they process it.
+    pub fn accept_invite(
+        &self,
+        invite: String,
+        config: Option<GroupConfig>,
+    ) -> Result<AcceptInviteResult, OpenMlsError> {
+        catch_panic(|| {
+            let invite = Invite::decode(&invite)
+                .map_err(|e| OpenMlsError::InvalidInput(format!("Invalid invite: {}", e)))?;
+
+            let mls_msg = MlsMessageIn::tls_deserialize_exact(invite.group_info.as_slice())
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize GroupInfo: {:?}",
+                        e
+                    ))
+                })?;
+            let group_info = match mls_msg.extract() {
+                MlsMessageBodyIn::GroupInfo(gi) => gi,
+                _ => {
+                    return Err(OpenMlsError::InvalidInput(
+                        "Expected GroupInfo message".to_string(),
+                    ))
+                }
+            };
+            let ratchet_tree = RatchetTreeIn::tls_deserialize_exact(invite.ratchet_tree.as_slice())
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize ratchet tree: {:?}",
+                        e
+                    ))
+                })?;
+
+            let join_config = MlsGroupJoinConfig::builder()
+                .wire_format_policy(config.unwrap_or_default().wire_format_policy())
+                .build();
+            let leaf_node_parameters = LeafNodeParameters::builder()
+                .with_capabilities(leaf_capabilities())
+                .with_extensions(self.leaf_extensions())
+                .build();
+
+            let (mut group, bundle) = MlsGroup::external_commit_builder()
+                .with_ratchet_tree(ratchet_tree)
+                .with_config(join_config)
+                .build_group(&self.backend, group_info, self.credential.clone())
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?
+                .leaf_node_parameters(leaf_node_parameters)
+                .load_psks(self.backend.storage())
+                .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?
+                .build(
+                    self.backend.rand(),
+                    self.backend.crypto(),
+                    &self.signer,
+                    |_| true,
+                )
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to create Commit: {:?}", e)))?
+                .finalize(&self.backend)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?;
+            let (commit, _welcome, _group_info) = bundle.into_contents();
+
+            // Check everyone already in the group; drop the new state if the
+            // host rejects one of them
+            let group_id = hex::encode(group.group_id().as_slice());
+            let own_key = self.signer.public();
+            let rejected = group
+                .members()
+                .filter(|member| member.signature_key != own_key)
+                .find_map(|member| {
+                    self.validate_credential(&group_id, &member.credential)
+                        .err()
+                });
+            if let Some(e) = rejected {
+                group
+                    .delete(self.backend.storage())
+                    .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+                return Err(e);
+            }
+            self.record_epoch(&group_id, &group)?;
+            self.mark_self_update(&group_id)?;
+
+            let mut groups = self.groups.lock();
+            groups.insert(group_id.clone(), group);
+            self.persist_group_index(&groups)?;
+            drop(groups);
+
+            let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+            })?;
+
+            Ok(AcceptInviteResult {
+                group_id,
+                broker: invite.broker,
+                commit_bytes,
+            })
+        })
+    }
+
+    /// Export one group's current state, sealed with `passphrase`, so it can
+    /// be archived or moved to another device of this client.
+    ///
+    /// The group is deleted here once sealed: two copies of the same member
+    /// would reuse its ratchet secrets and fork the group on their next
+    /// Commit. The returned archive is then the only copy of the group, and
+    /// losing it or its passphrase loses the group.
+    pub fn export_group(
+        &self,
+        group_id: String,
+        passphrase: String,
+    ) -> Result<Vec<u8>, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+            let archive = GroupArchive {
+                group_id: group.group_id().as_slice().to_vec(),
+                entries: self.group_state(group)?,
+            }
+            .seal(&passphrase)
+            .map_err(OpenMlsError::SerializationError)?;
+
+            let mut group = groups
+                .remove(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            group
+                .delete(self.backend.storage())
+                .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+            self.persist_group_index(&groups)?;
+            self.own_commits.lock().remove(&group_id);
+            self.pending_commits
+                .lock()
+                .retain(|_, (gid, _, _)| *gid != group_id);
+            self.join_requests
+                .lock()
+                .retain(|_, (gid, _, _)| *gid != group_id);
+            Ok(archive)
+        })
+    }
+
+    /// Import a group exported with `export_group`, returning its group_id.
+    /// The group must have been exported by this same client identity, and
+    /// must not already exist here.
+    pub fn import_group(
+        &self,
+        archive: Vec<u8>,
+        passphrase: String,
+    ) -> Result<String, OpenMlsError> {
+        catch_panic(|| {
+            let archive =
+                GroupArchive::open(&archive, &passphrase).map_err(OpenMlsError::InvalidInput)?;
+            let group_id = hex::encode(&archive.group_id);
+
+            let mut groups = self.groups.lock();
+            if groups.contains_key(&group_id) {
+                return Err(OpenMlsError::InvalidInput(
+                    "Group already exists".to_string(),
+                ));
+            }
+
+            let storage_keys: Vec<Vec<u8>> =
+                archive.entries.iter().map(|(key, _)| key.clone()).collect();
+            self.backend.storage().put_entries(archive.entries)?;
+
+            let group = MlsGroup::load(
+                self.backend.storage(),
+                &GroupId::from_slice(&archive.group_id),
+            )
+            .ok()
+            .flatten()
+            .filter(|group| {
+                group
+                    .own_leaf_node()
+                    .is_some_and(|leaf| leaf.signature_key().as_slice() == self.signer.public())
+            });
+            let Some(group) = group else {
+                self.backend.storage().remove_entries(storage_keys)?;
+                return Err(OpenMlsError::InvalidInput(
+                    "Archive does not hold a group of this client".to_string(),
+                ));
+            };
+
+            self.record_epoch(&group_id, &group)?;
+            groups.insert(group_id.clone(), group);
+            self.persist_group_index(&groups)?;
+
+            Ok(group_id)
+        })
+    }
+
+    /// Encrypt a message for a group
+    ///
+    /// `content_type` (e.g. a MIME type) travels in the message's
+    /// authenticated data and is returned by `decrypt`.
+    pub fn encrypt(
+        &self,
+        group_id: String,
+        plaintext: Vec<u8>,
+        content_type: Option<String>,
+    ) -> Result<Vec<u8>, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+
+            let aad = MessageAad {
+                content_type,
+                chunk: None,
+            };
+            group.set_aad(aad.encode());
+
+            let ciphertext = group
+                .create_message(&self.backend, &self.signer, &plaintext)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to encrypt: {:?}", e)))?;
+
+            ciphertext.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize ciphertext: {:?}", e))
+            })
+        })
+    }
+
+    /// Decrypt a message from a group
+    pub fn decrypt(
+        &self,
+        group_id: String,
+        ciphertext: Vec<u8>,
+    ) -> Result<DecryptedMessage, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+
+            match self.process_incoming(&group_id, group, &ciphertext)? {
+                Incoming::Processed(processed, wire_format) => {
+                    self.decrypted_message(&group_id, group, processed, wire_format)
+                }
+                Incoming::Resolved(message) => Ok(*message),
+            }
+        })
+    }
+
+    /// Deserialize and process a message from a group's topic. A Commit
+    /// competing with our last one is not processed but resolved with
+    /// `resolve_conflict`, and comes back as the message `decrypt` returns
+    /// for it.
+    fn process_incoming(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        ciphertext: &[u8],
+    ) -> Result<Incoming, OpenMlsError> {
+        let mls_msg = MlsMessageIn::tls_deserialize(&mut &*ciphertext).map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to deserialize message: {:?}", e))
+        })?;
+
+        let protocol_msg: ProtocolMessage = match mls_msg.extract() {
+            MlsMessageBodyIn::PrivateMessage(pm) => pm.into(),
+            MlsMessageBodyIn::PublicMessage(pm) => pm.into(),
+            _ => {
+                return Err(OpenMlsError::InvalidInput(
+                    "Invalid message type".to_string(),
+                ))
+            }
+        };
+        let wire_format = protocol_msg.wire_format();
+
+        if self.competes_with_own_commit(group_id, group, &protocol_msg) {
+            let epoch = protocol_msg.epoch().as_u64();
+            let resolution = self.resolve_conflict(group_id, group, protocol_msg, ciphertext)?;
+            return Ok(Incoming::Resolved(Box::new(DecryptedMessage {
+                plaintext: Vec::new(),
+                sender_client_id: "unknown".to_string(),
+                epoch,
+                sender_leaf_index: None,
+                content_type: None,
+                wire_format: match wire_format {
+                    WireFormat::PublicMessage => MessageWireFormat::PublicMessage,
+                    _ => MessageWireFormat::PrivateMessage,
+                },
+                pending_commit: None,
+                join_request: None,
+                conflict_resolution: Some(resolution),
+            })));
+        }
+
+        let processed = group
+            .process_message(&self.backend, protocol_msg)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to process message: {:?}", e)))?;
+        Ok(Incoming::Processed(processed, wire_format))
+    }
+
+    /// What `decrypt` returns for a processed message: an application
+    /// message's plaintext, a held Commit or a join request. A merged Commit
+    /// is reported as an error.
+    fn decrypted_message(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        processed: ProcessedMessage,
+        wire_format: WireFormat,
+    ) -> Result<DecryptedMessage, OpenMlsError> {
+        let sender = processed.sender().clone();
+        let message = DecryptedMessage::from_processed(&processed, wire_format);
+
+        match processed.into_content() {
+            ProcessedMessageContent::ApplicationMessage(app_msg) => Ok(DecryptedMessage {
+                plaintext: app_msg.into_bytes(),
+                ..message
+            }),
+            ProcessedMessageContent::StagedCommitMessage(staged) => {
+                match self.handle_commit(group_id, group, &sender, *staged)? {
+                    Some(pending) => Ok(DecryptedMessage {
+                        pending_commit: Some(pending),
+                        ..message
+                    }),
+                    None => Err(OpenMlsError::InvalidInput(
+                        "Received commit, not application message".to_string(),
+                    )),
+                }
+            }
+            ProcessedMessageContent::ExternalJoinProposalMessage(proposal) => {
+                let request = self.queue_join_request(group_id, group, *proposal)?;
+                Ok(DecryptedMessage {
+                    join_request: Some(request),
+                    ..message
+                })
+            }
+            _ => Err(OpenMlsError::InvalidInput(
+                "Received proposal, not application message".to_string(),
+            )),
+        }
+    }
+
+    /// Merge an incoming Commit, or hold it for review when review is enabled
+    fn handle_commit(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        sender: &Sender,
+        staged: StagedCommit,
+    ) -> Result<Option<PendingCommit>, OpenMlsError> {
+        // Membership and role changes from non-admins are never auto-merged
+        let permitted = Self::sender_may_commit(group, sender, &staged);
+        if permitted && !self.review_commits.load(Ordering::SeqCst) {
+            self.merge_commit(group_id, group, staged)?;
+            return Ok(None);
+        }
+
+        let client_id_at = |leaf: LeafNodeIndex| {
+            group
+                .member(leaf)
+                .map(|c| String::from_utf8_lossy(c.serialized_content()).to_string())
+                .unwrap_or_else(|| "unknown".to_string())
+        };
+        let sender_client_id = match sender {
+            Sender::Member(leaf) => client_id_at(*leaf),
+            _ => "unknown".to_string(),
+        };
+
+        let pending = PendingCommit {
+            handle: self.next_handle.fetch_add(1, Ordering::SeqCst),
+            epoch: staged.group_context().epoch().as_u64(),
+            sender_client_id,
+            added: staged
+                .add_proposals()
+                .map(|p| {
+                    let credential = p.add_proposal().key_package().leaf_node().credential();
+                    String::from_utf8_lossy(credential.serialized_content()).to_string()
+                })
+                .collect(),
+            removed: staged
+                .remove_proposals()
+                .map(|p| client_id_at(p.remove_proposal().removed()))
+                .collect(),
+            updated: staged
+                .update_proposals()
+                .map(|p| {
+                    let credential = p.update_proposal().leaf_node().credential();
+                    String::from_utf8_lossy(credential.serialized_content()).to_string()
+                })
+                .collect(),
+            removes_self: staged.self_removed(),
+        };
+
+        self.pending_commits.lock().insert(
+            pending.handle,
+            (group_id.to_string(), pending.clone(), staged),
+        );
+        Ok(Some(pending))
+    }
+
+    /// Whether a Commit may be merged under the group's role map. Groups
+    /// without a role map are unrestricted.
+    fn sender_may_commit(group: &MlsGroup, sender: &Sender, staged: &StagedCommit) -> bool {
+        let Some(roles) = RoleMap::from_extensions(group.extensions()) else {
+            return true;
+        };
+
+        let restricted = staged.queued_proposals().any(|p| {
+            matches!(
+                p.proposal(),
+                Proposal::Add(_) | Proposal::Remove(_) | Proposal::GroupContextExtensions(_)
+            )
+        });
+        if !restricted {
+            return true;
+        }
+
+        match sender {
+            Sender::Member(leaf) => group.member(*leaf).is_some_and(|credential| {
+                let client_id = String::from_utf8_lossy(credential.serialized_content());
+                roles.role(&client_id) == Role::Admin
+            }),
+            _ => false,
+        }
+    }
+
+    /// Fail unless this client may change the group's membership or roles
+    fn require_admin(&self, group: &MlsGroup) -> Result<(), OpenMlsError> {
+        match RoleMap::from_extensions(group.extensions()) {
+            Some(roles) if roles.role(&self.client_id) != Role::Admin => Err(
+                OpenMlsError::PermissionDenied("Only admins may change membership".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Set a member's role with a Commit, returning the Commit to publish.
+    ///
+    /// The first call on a group installs the role map and makes the caller
+    /// an admin; after that only admins may change roles, add or remove
+    /// members. Incoming Commits that do so from a non-admin are held back as
+    /// a `PendingCommit` instead of being merged.
+    pub fn set_role(
+        &self,
+        group_id: String,
+        client_id: String,
+        role: Role,
+    ) -> Result<Vec<u8>, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            self.require_admin(group)?;
+
+            let mut roles = RoleMap::from_extensions(group.extensions()).unwrap_or_else(|| {
+                let mut roles = RoleMap::default();
+                roles.set(self.client_id.clone(), Role::Admin);
+                roles
+            });
+            roles.set(client_id, role);
+            if roles.admins().next().is_none() {
+                return Err(OpenMlsError::InvalidInput(
+                    "A group needs at least one admin".to_string(),
+                ));
+            }
+
+            self.expire_join_requests(&group_id, group)?;
+            let extensions = roles.into_extensions(group.extensions());
+            let (commit, _welcome, _group_info) = group
+                .update_group_context_extensions(&self.backend, extensions, &self.signer)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to update roles: {:?}", e)))?;
+            self.merge_own_commit(&group_id, group, &commit)?;
+            drop(groups);
+
+            commit.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+            })
+        })
+    }
+
+    /// Get a client's role in a group
+    pub fn role(&self, group_id: String, client_id: String) -> Result<Role, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+
+            Ok(match RoleMap::from_extensions(group.extensions()) {
+                Some(roles) => roles.role(&client_id),
+                // Without a role map every member may manage the group
+                None => Role::Admin,
+            })
+        })
+    }
+
+    /// Get the admins of a group (empty if the group has no role map)
+    pub fn admins(&self, group_id: String) -> Result<Vec<String>, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+
+            Ok(RoleMap::from_extensions(group.extensions())
+                .map(|roles| roles.admins().cloned().collect())
+                .unwrap_or_default())
+        })
+    }
+
+    /// Whether our leaf in the group is older than `max_age_secs` and should
+    /// be refreshed for post-compromise security. Our leaf is fresh after
+    /// creating or joining the group and after each self-update; groups
+    /// restored from state predating this tracking always need one.
+    pub fn needs_update(&self, group_id: String, max_age_secs: u64) -> Result<bool, OpenMlsError> {
+        catch_panic(|| {
+            if !self.groups.lock().contains_key(&group_id) {
+                return Err(OpenMlsError::GroupNotFound);
+            }
+            Ok(match self.self_updates.lock().get(&group_id) {
+                Some(updated_at) => unix_now().saturating_sub(*updated_at) >= max_age_secs,
+                None => true,
+            })
+        })
+    }
+
+    /// Replace our leaf with a self-update Commit if `needs_update` says so.
+    /// Returns the Commit to publish, or None when the leaf is still fresh.
+    /// Meant to be called periodically from a background task.
+    pub fn update_if_stale(
+        &self,
+        group_id: String,
+        max_age_secs: u64,
+    ) -> Result<Option<Vec<u8>>, OpenMlsError> {
+        catch_panic(|| {
+            if !self.needs_update(group_id.clone(), max_age_secs)? {
+                return Ok(None);
+            }
+
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            self.expire_join_requests(&group_id, group)?;
+
+            let bundle = group
+                .self_update(&self.backend, &self.signer, LeafNodeParameters::default())
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to update leaf: {:?}", e)))?;
+            self.merge_own_commit(&group_id, group, bundle.commit())?;
+            drop(groups);
+            self.mark_self_update(&group_id)?;
+
+            let commit_bytes = bundle.commit().tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+            })?;
+            Ok(Some(commit_bytes))
+        })
+    }
+
+    /// Hold incoming Commits for review instead of merging them. Each one is
+    /// returned from decrypt as a `PendingCommit`; the group stays in its
+    /// current epoch until `merge_staged` is called.
+    pub fn set_commit_review(&self, enabled: bool) {
+        self.review_commits.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Commits of a group waiting for `merge_staged` or `reject_staged`
+    pub fn pending_commits(&self, group_id: String) -> Vec<PendingCommit> {
+        let pending = self.pending_commits.lock();
+        let mut commits: Vec<PendingCommit> = pending
+            .values()
+            .filter(|(gid, _, _)| *gid == group_id)
+            .map(|(_, info, _)| info.clone())
+            .collect();
+        commits.sort_by_key(|c| c.handle);
+        commits
+    }
+
+    /// Apply a Commit previously returned for review
+    pub fn merge_staged(&self, group_id: String, handle: u64) -> Result<(), OpenMlsError> {
+        catch_panic(|| {
+            let staged = self.take_pending(&group_id, handle)?;
+
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            self.merge_commit(&group_id, group, staged)?;
+            drop(groups);
+
+            // Other Commits for the same epoch can no longer apply
+            self.pending_commits
+                .lock()
+                .retain(|_, (gid, _, _)| *gid != group_id);
+            Ok(())
+        })
+    }
+
+    /// Discard a Commit previously returned for review. The group stays in
+    /// its current epoch, so later messages from members who applied the
+    /// Commit cannot be decrypted.
+    pub fn reject_staged(&self, group_id: String, handle: u64) -> Result<(), OpenMlsError> {
+        catch_panic(|| self.take_pending(&group_id, handle).map(|_| ()))
+    }
+
+    fn take_pending(&self, group_id: &str, handle: u64) -> Result<StagedCommit, OpenMlsError> {
+        let mut pending = self.pending_commits.lock();
+        match pending.get(&handle) {
+            Some((gid, _, _)) if gid == group_id => Ok(pending
+                .remove(&handle)
+                .map(|(_, _, staged)| staged)
+                .unwrap()),
+            _ => Err(OpenMlsError::InvalidInput(format!(
+                "No pending commit {} in group",
+                handle
+            ))),
+        }
+    }
+
+    /// Check the credentials a Commit introduces, then merge it
+    fn merge_commit(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        staged: StagedCommit,
+    ) -> Result<(), OpenMlsError> {
+        for credential in staged.credentials_to_verify() {
+            self.validate_credential(group_id, credential)?;
+        }
+
+        group
+            .merge_staged_commit(&self.backend, staged)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e)))?;
+        // Our last Commit was accepted, or is now out of reach
+        self.own_commits.lock().remove(group_id);
+        self.record_epoch(group_id, group)?;
+        self.expire_join_requests(group_id, group)
+    }
+
+    /// Merge a Commit this client created, keeping the state before it for
+    /// `resolve_commit_conflict`
+    fn merge_own_commit(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        commit: &MlsMessageOut,
+    ) -> Result<(), OpenMlsError> {
+        let staged = group
+            .pending_commit()
+            .ok_or_else(|| OpenMlsError::InternalError("Own Commit is not pending".to_string()))?;
+        let epoch = group.epoch().as_u64();
+        let changes = own_changes(group, staged);
+        let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+        })?;
+        let before = self.group_state(group)?;
+
+        group
+            .merge_pending_commit(&self.backend)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e)))?;
+        let own_commit = OwnCommit {
+            epoch,
+            commit_bytes,
+            undo: GroupUndo::new(before, &self.group_state(group)?),
+            changes,
+        };
+        self.own_commits
+            .lock()
+            .insert(group_id.to_string(), own_commit);
+        self.record_epoch(group_id, group)
+    }
+
+    /// Recover after a competing Commit for the same epoch won over our last
+    /// Commit at the DS: ours is rolled back, the winning Commit is applied,
+    /// and the changes of ours that still apply are committed again on top of
+    /// it. Publish the returned Commit and Welcome, if any. `decrypt` does
+    /// this itself for such a Commit and reports it in `conflict_resolution`,
+    /// so this is only needed for Commits obtained another way.
+    ///
+    /// Only our last Commit in a group can be rolled back, and only until a
+    /// later Commit is merged. If the winning Commit is rejected, the group
+    /// keeps our Commit.
+    pub fn resolve_commit_conflict(
+        &self,
+        group_id: String,
+        commit_bytes: Vec<u8>,
+    ) -> Result<ConflictResolution, OpenMlsError> {
+        catch_panic(|| {
+            let winner = MlsMessageIn::tls_deserialize_exact(commit_bytes.as_slice())
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize Commit: {:?}",
+                        e
+                    ))
+                })?
+                .try_into_protocol_message()
+                .ok()
+                .filter(|message| message.content_type() == ContentType::Commit)
+                .ok_or_else(|| OpenMlsError::InvalidInput("Expected a Commit".to_string()))?;
+
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            if winner.group_id() != group.group_id() {
+                return Err(OpenMlsError::InvalidInput(
+                    "Commit is for another group".to_string(),
+                ));
+            }
+
+            if !self.competes_with_own_commit(&group_id, group, &winner) {
+                return Err(OpenMlsError::InvalidInput(
+                    "Commit does not compete with our last Commit".to_string(),
+                ));
+            }
+            self.resolve_conflict(&group_id, group, winner, &commit_bytes)
+        })
+    }
+
+    /// Whether `message` is a Commit for the epoch our last Commit in the
+    /// group was made in, so that one of the two lost at the DS
+    fn competes_with_own_commit(
+        &self,
+        group_id: &str,
+        group: &MlsGroup,
+        message: &ProtocolMessage,
+    ) -> bool {
+        message.content_type() == ContentType::Commit
+            && message.group_id() == group.group_id()
+            && self.own_commits.lock().get(group_id).is_some_and(|own| {
+                own.epoch == message.epoch().as_u64() && own.epoch + 1 == group.epoch().as_u64()
+            })
+    }
+
+    /// Roll back our last Commit for a competing `winner` and apply it (see
+    /// `resolve_commit_conflict`)
+    fn resolve_conflict(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        winner: ProtocolMessage,
+        commit_bytes: &[u8],
+    ) -> Result<ConflictResolution, OpenMlsError> {
+        let own = self
+            .own_commits
+            .lock()
+            .remove(group_id)
+            .ok_or_else(|| OpenMlsError::InternalError("Own Commit vanished".to_string()))?;
+        if own.commit_bytes == commit_bytes {
+            return Ok(ConflictResolution::default());
+        }
+
+        // Go back to the epoch both Commits were made in and apply the
+        // winner there
+        let current = self.group_state(group)?;
+        let storage = self.backend.storage();
+        storage.undo_group_change(current.clone(), own.undo)?;
+        let mut previous = MlsGroup::load(storage, group.group_id())?
+            .ok_or_else(|| OpenMlsError::StorageError("Group state is incomplete".to_string()))?;
+        self.forget_epochs_after(group_id, own.epoch)?;
+        let pending = match self.apply_winner(group_id, &mut previous, winner) {
+            Ok(pending) => pending,
+            Err(e) => {
+                *group = self.replace_group_state(&previous, current)?;
+                self.record_epoch(group_id, group)?;
+                return Err(e);
+            }
+        };
+        *group = previous;
+
+        if pending.is_some() || !group.is_active() {
+            return Ok(ConflictResolution {
+                conflict: true,
+                pending_commit: pending,
+                dropped: own.changes.into_iter().map(|c| c.summary).collect(),
+                ..Default::default()
+            });
+        }
+        self.rebase_own_changes(group_id, group, own.changes)
+    }
+
+    /// Process a Commit on a rolled-back group, merging it unless it is held
+    /// for review
+    fn apply_winner(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        winner: ProtocolMessage,
+    ) -> Result<Option<PendingCommit>, OpenMlsError> {
+        group
+            .clear_pending_commit(self.backend.storage())
+            .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+        let processed = group
+            .process_message(&self.backend, winner)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to process message: {:?}", e)))?;
+        let sender = processed.sender().clone();
+        match processed.into_content() {
+            ProcessedMessageContent::StagedCommitMessage(staged) => {
+                self.handle_commit(group_id, group, &sender, *staged)
+            }
+            _ => Err(OpenMlsError::InvalidInput("Expected a Commit".to_string())),
+        }
+    }
+
+    /// Commit again the changes of a lost Commit that still apply
+    fn rebase_own_changes(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        changes: Vec<OwnChange>,
+    ) -> Result<ConflictResolution, OpenMlsError> {
+        let may_manage = self.require_admin(group).is_ok();
+        let member_leaf = |client_id: &str| {
+            group
+                .members()
+                .find(|m| m.credential.serialized_content() == client_id.as_bytes())
+                .map(|m| m.index)
+        };
+        let mut roles = RoleMap::from_extensions(group.extensions()).unwrap_or_else(|| {
+            let mut roles = RoleMap::default();
+            roles.set(self.client_id.clone(), Role::Admin);
+            roles
+        });
+
+        let mut resolution = ConflictResolution {
+            conflict: true,
+            ..Default::default()
+        };
+        let (mut adds, mut removals, mut roles_changed, mut self_update) =
+            (Vec::new(), Vec::new(), false, false);
+        for change in changes {
+            let client_id = change.summary.client_id.as_deref().unwrap_or_default();
+            let reapply = match (change.summary.kind, change.key_package) {
+                (ProposalKind::Add, Some(key_package)) if may_manage => {
+                    let absent = member_leaf(client_id).is_none();
+                    if absent {
+                        adds.push(key_package);
+                    }
+                    absent
+                }
+                (ProposalKind::Remove, _) if may_manage => match member_leaf(client_id) {
+                    Some(leaf) => {
+                        removals.push(leaf);
+                        true
+                    }
+                    None => false,
+                },
+                (ProposalKind::RoleChange, _) if may_manage => match change.summary.role {
+                    Some(role) => {
+                        roles.set(client_id.to_string(), role);
+                        roles_changed = true;
+                        true
+                    }
+                    None => false,
+                },
+                (ProposalKind::SelfUpdate, _) => {
+                    self_update = true;
+                    true
+                }
+                _ => false,
+            };
+            if reapply {
+                resolution.reapplied.push(change.summary);
+            } else {
+                resolution.dropped.push(change.summary);
+            }
+        }
+
+        // Role changes that would leave the group without an admin are lost
+        if roles_changed && roles.admins().next().is_none() {
+            roles_changed = false;
+            let (kept, lost): (Vec<_>, Vec<_>) = resolution
+                .reapplied
+                .into_iter()
+                .partition(|s| s.kind != ProposalKind::RoleChange);
+            resolution.reapplied = kept;
+            resolution.dropped.extend(lost);
+        }
+        if resolution.reapplied.is_empty() {
+            return Ok(resolution);
+        }
+
+        let joiners = adds
+            .iter()
+            .map(|kp| self.key_package_ref_hex(kp))
+            .collect::<Result<Vec<_>, _>>()?;
+        let extensions = roles_changed.then(|| roles.into_extensions(group.extensions()));
+        let epoch = group.epoch().as_u64();
+        self.expire_join_requests(group_id, group)?;
+
+        let mut builder = group
+            .commit_builder()
+            .propose_adds(adds)
+            .propose_removals(removals)
+            .force_self_update(self_update);
+        if let Some(extensions) = extensions {
+            builder = builder.propose_group_context_extensions(extensions);
+        }
+        let bundle = builder
+            .load_psks(self.backend.storage())
+            .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?
+            .build(
+                self.backend.rand(),
+                self.backend.crypto(),
+                &self.signer,
+                |_| true,
+            )
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create Commit: {:?}", e)))?
+            .stage_commit(&self.backend)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create Commit: {:?}", e)))?;
+        let (commit, welcome, _group_info) = bundle.into_messages();
+        self.merge_own_commit(group_id, group, &commit)?;
+        if self_update {
+            self.mark_self_update(group_id)?;
+        }
+
+        let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+        })?;
+        if let Some(welcome) = welcome {
+            resolution.welcome_bytes = Some(welcome.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Welcome: {:?}", e))
+            })?);
+            self.record_sent_welcome(group_id, epoch, &commit_bytes, joiners);
+        }
+        resolution.commit_bytes = Some(commit_bytes);
+        Ok(resolution)
+    }
+
+    /// Raw storage entries of a group's current state (see
+    /// `RelayStorage::group_entries`)
+    fn group_state(&self, group: &MlsGroup) -> Result<RawEntries, OpenMlsError> {
+        let own_leaf = group
+            .own_leaf_node()
+            .ok_or_else(|| OpenMlsError::InternalError("Own leaf not in group".to_string()))?;
+        Ok(self.backend.storage().group_entries(
+            group.group_id(),
+            &group.epoch(),
+            group.own_leaf_index().u32(),
+            own_leaf.encryption_key(),
+        )?)
+    }
+
+    /// Swap a group's stored state for `entries` and load the result
+    fn replace_group_state(
+        &self,
+        group: &MlsGroup,
+        entries: RawEntries,
+    ) -> Result<MlsGroup, OpenMlsError> {
+        let stale = self.group_state(group)?;
+        let storage = self.backend.storage();
+        storage.remove_entries(stale.into_iter().map(|(key, _)| key))?;
+        storage.put_entries(entries)?;
+        MlsGroup::load(storage, group.group_id())?
+            .ok_or_else(|| OpenMlsError::StorageError("Group state is incomplete".to_string()))
+    }
+
+    /// Drop audit entries of epochs after `epoch`, which were rolled back
+    fn forget_epochs_after(&self, group_id: &str, epoch: u64) -> Result<(), OpenMlsError> {
+        let record = audit_log_record(group_id);
+        let mut log: Vec<AuditEntry> = self
+            .backend
+            .storage()
+            .read_record(&record)?
+            .unwrap_or_default();
+        log.retain(|entry| entry.epoch <= epoch);
+        Ok(self.backend.storage().write_record(&record, &log)?)
+    }
+
+    /// Transcript hashes and authenticators of the group's epochs, oldest
+    /// first. Members can compare entries (e.g. the epoch_authenticator of
+    /// the current epoch) to confirm they share the same history. Only the
+    /// epochs this client was a member in are listed, and at most the last
+    /// 1024 of them.
+    pub fn audit_log(&self, group_id: String) -> Result<Vec<AuditEntry>, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+            // Groups stored before the audit log existed start at their
+            // current epoch
+            self.record_epoch(&group_id, group)?;
+
+            Ok(self
+                .backend
+                .storage()
+                .read_record(&audit_log_record(&group_id))?
+                .unwrap_or_default())
+        })
+    }
+
+    /// Append the group's current epoch to its audit log
+    fn record_epoch(&self, group_id: &str, group: &MlsGroup) -> Result<(), OpenMlsError> {
+        let record = audit_log_record(group_id);
+        let mut log: Vec<AuditEntry> = self
+            .backend
+            .storage()
+            .read_record(&record)?
+            .unwrap_or_default();
+        if log
+            .last()
+            .is_some_and(|last| last.epoch == group.epoch().as_u64())
+        {
+            return Ok(());
+        }
+
+        let entry = AuditEntry::current(group, self.backend.crypto(), &self.signer)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to read transcript: {}", e)))?;
+        log.push(entry);
+        if log.len() > MAX_AUDIT_ENTRIES {
+            log.drain(..log.len() - MAX_AUDIT_ENTRIES);
+        }
+        Ok(self.backend.storage().write_record(&record, &log)?)
+    }
+
+    /// Ask the host's CredentialValidator, if any, to accept a credential
+    fn validate_credential(
+        &self,
+        group_id: &str,
+        credential: &Credential,
+    ) -> Result<(), OpenMlsError> {
+        let validator = self.validator.read();
+        let Some(validator) = validator.as_ref() else {
+            return Ok(());
+        };
+
+        let client_id = String::from_utf8_lossy(credential.serialized_content()).to_string();
+        if validator.validate(group_id.to_string(), client_id.clone()) {
+            Ok(())
+        } else {
+            Err(OpenMlsError::CredentialRejected(client_id))
+        }
+    }
+
+    /// Ask to be added to a group this client is not a member of, by
+    /// creating an external join proposal for the group's current epoch
+    /// (e.g. taken from `classify_message` on its GroupInfo). Publish it to
+    /// the group; an admin answers with `admit_join` or `deny_join`, and an
+    /// admitted client then joins from the Welcome.
+    pub fn request_join(&self, group_id: String, epoch: u64) -> Result<Vec<u8>, OpenMlsError> {
+        catch_panic(|| {
+            let group_id_bytes = hex::decode(&group_id)
+                .map_err(|e| OpenMlsError::InvalidInput(format!("Invalid group_id: {:?}", e)))?;
+            let (key_package, _) = self.new_key_package(CIPHERSUITE, false)?;
+
+            let proposal = JoinProposal::new::<RelayStorage>(
+                key_package,
+                GroupId::from_slice(&group_id_bytes),
+                GroupEpoch::from(epoch),
+                &self.signer,
+            )
+            .map_err(|e| {
+                OpenMlsError::MlsError(format!("Failed to create join proposal: {:?}", e))
+            })?;
+
+            proposal.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!(
+                    "Failed to serialize join proposal: {:?}",
+                    e
+                ))
+            })
+        })
+    }
+
+    /// Join requests of a group waiting for `admit_join` or `deny_join`.
+    /// Requests expire when the group moves to a new epoch; the client then
+    /// has to ask again.
+    pub fn join_requests(&self, group_id: String) -> Vec<JoinRequest> {
+        let requests = self.join_requests.lock();
+        let mut pending: Vec<JoinRequest> = requests
+            .values()
+            .filter(|(gid, _, _)| *gid == group_id)
+            .map(|(_, request, _)| request.clone())
+            .collect();
+        pending.sort_by_key(|r| r.handle);
+        pending
+    }
+
+    /// Add the client behind a join request with a Commit. Other requests
+    /// of the group are not included and expire with the epoch.
+    pub fn admit_join(
+        &self,
+        group_id: String,
+        handle: u64,
+    ) -> Result<AddMemberResult, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            self.require_admin(group)?;
+
+            let (request, proposal) = self.join_request(&group_id, handle)?;
+            let mut joiners = Vec::new();
+            if let Proposal::Add(add) = proposal.proposal() {
+                self.validate_credential(&group_id, add.key_package().leaf_node().credential())?;
+                joiners.push(self.key_package_ref_hex(add.key_package())?);
+            }
+            let epoch = group.epoch().as_u64();
+
+            // Commit to this request and to members' pending proposals, but
+            // not to the group's other join requests
+            let others: Vec<QueuedProposal> = self
+                .join_requests
+                .lock()
+                .iter()
+                .filter(|(h, (gid, _, _))| *gid == group_id && **h != handle)
+                .map(|(_, (_, _, p))| p.clone())
+                .collect();
+            self.drop_pending_proposals(group, &others)?;
+            if !group.pending_proposals().any(|p| *p == proposal) {
+                group
+                    .store_pending_proposal(self.backend.storage(), proposal)
+                    .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+            }
+            let committed = group.commit_to_pending_proposals(&self.backend, &self.signer);
+            let (commit, welcome, _group_info) = match committed {
+                Ok(committed) => committed,
+                Err(e) => {
+                    // Leave the other requests decidable
+                    for proposal in others {
+                        group
+                            .store_pending_proposal(self.backend.storage(), proposal)
+                            .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+                    }
+                    return Err(OpenMlsError::MlsError(format!(
+                        "Failed to admit {}: {:?}",
+                        request.client_id, e
+                    )));
+                }
+            };
+            let welcome = welcome.ok_or_else(|| {
+                OpenMlsError::MlsError("Commit has no Welcome for the joiner".to_string())
+            })?;
+
+            self.take_join_request(&group_id, handle)?;
+            self.merge_own_commit(&group_id, group, &commit)?;
+            self.expire_join_requests(&group_id, group)?;
+            drop(groups);
+
+            let welcome_bytes = welcome.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Welcome: {:?}", e))
+            })?;
+
+            let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
             })?;
+            self.record_sent_welcome(&group_id, epoch, &commit_bytes, joiners);
 
-        let kp_mls_bytes = kp_array
-            .first()
-            .ok_or_else(|| OpenMlsError::InvalidInput("Empty KeyPackage array".to_string()))?;
+            Ok(AddMemberResult {
+                welcome_bytes,
+                commit_bytes,
+            })
+        })
+    }
 
-        // Deserialize MLSMessage
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut kp_mls_bytes.as_slice()).map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to deserialize KeyPackage: {:?}", e))
-        })?;
+    /// Check that a Commit and Welcome from `add_member(s)` or `admit_join`
+    /// belong together before handing them to the DS: the Commit must be the
+    /// last one this client made with a Welcome in the group, and the Welcome
+    /// must be addressed to exactly the KeyPackages that Commit added.
+    ///
+    /// Only the most recent such Commit per group is remembered, and only
+    /// for the lifetime of this client.
+    pub fn check_commit_welcome(
+        &self,
+        group_id: String,
+        commit_bytes: Vec<u8>,
+        welcome_bytes: Vec<u8>,
+    ) -> Result<CommitWelcomeReport, OpenMlsError> {
+        catch_panic(|| {
+            let group_id_bytes = hex::decode(&group_id)
+                .map_err(|e| OpenMlsError::InvalidInput(format!("Invalid group id: {:?}", e)))?;
 
-        // Extract KeyPackage
-        let key_package = match mls_msg.extract() {
-            MlsMessageBodyIn::KeyPackage(kp) => kp
-                .validate(self.backend.crypto(), ProtocolVersion::Mls10)
+            let commit = MlsMessageIn::tls_deserialize_exact(commit_bytes.as_slice())
                 .map_err(|e| {
-                    OpenMlsError::MlsError(format!("Failed to validate KeyPackage: {:?}", e))
-                })?,
-            _ => {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize Commit: {:?}",
+                        e
+                    ))
+                })?
+                .try_into_protocol_message()
+                .ok()
+                .filter(|message| message.content_type() == ContentType::Commit)
+                .ok_or_else(|| OpenMlsError::InvalidInput("Expected a Commit".to_string()))?;
+            if commit.group_id().as_slice() != group_id_bytes {
                 return Err(OpenMlsError::InvalidInput(
-                    "Expected KeyPackage message".to_string(),
-                ))
+                    "Commit is for another group".to_string(),
+                ));
             }
-        };
-
-        let mut groups = self.groups.lock().unwrap();
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or(OpenMlsError::GroupNotFound)?;
 
-        // Add member
-        let (commit, welcome, _group_info) = group
-            .add_members(&self.backend, &self.signer, &[key_package])
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to add member: {:?}", e)))?;
-
-        // Merge pending commit
-        group
-            .merge_pending_commit(&self.backend)
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e)))?;
+            let welcome =
+                MlsMessageIn::tls_deserialize_exact(welcome_bytes.as_slice()).map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize Welcome: {:?}",
+                        e
+                    ))
+                })?;
+            let MlsMessageBodyIn::Welcome(welcome) = welcome.extract() else {
+                return Err(OpenMlsError::InvalidInput("Expected a Welcome".to_string()));
+            };
+            let welcome_joiners: Vec<String> = welcome
+                .secrets()
+                .iter()
+                .map(|secrets| hex::encode(secrets.new_member().as_slice()))
+                .collect();
+
+            let commit_epoch = commit.epoch().as_u64();
+            let sent_welcomes = self.sent_welcomes.lock();
+            let sent = sent_welcomes.get(&group_id);
+            let expected_joiners = sent.map(|sent| sent.joiners.as_slice()).unwrap_or_default();
+            let missing_joiners: Vec<String> = expected_joiners
+                .iter()
+                .filter(|joiner| !welcome_joiners.contains(joiner))
+                .cloned()
+                .collect();
+            let unexpected_joiners: Vec<String> = welcome_joiners
+                .iter()
+                .filter(|joiner| !expected_joiners.contains(joiner))
+                .cloned()
+                .collect();
+            let expected_epoch = sent.map(|sent| sent.epoch);
+            let commit_matches = sent.is_some_and(|sent| sent.commit_bytes == commit_bytes);
+
+            Ok(CommitWelcomeReport {
+                consistent: commit_matches
+                    && expected_epoch == Some(commit_epoch)
+                    && missing_joiners.is_empty()
+                    && unexpected_joiners.is_empty(),
+                commit_epoch,
+                expected_epoch,
+                commit_matches,
+                missing_joiners,
+                unexpected_joiners,
+            })
+        })
+    }
 
-        // Serialize results
-        let welcome_bytes = welcome.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize Welcome: {:?}", e))
-        })?;
+    /// Remember a Commit with a Welcome for `check_commit_welcome`
+    fn record_sent_welcome(
+        &self,
+        group_id: &str,
+        epoch: u64,
+        commit_bytes: &[u8],
+        joiners: Vec<String>,
+    ) {
+        self.sent_welcomes.lock().insert(
+            group_id.to_string(),
+            SentWelcome {
+                epoch,
+                commit_bytes: commit_bytes.to_vec(),
+                joiners,
+            },
+        );
+    }
 
-        let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize Commit: {:?}", e))
+    fn key_package_ref_hex(&self, key_package: &KeyPackage) -> Result<String, OpenMlsError> {
+        let hash_ref = key_package.hash_ref(self.backend.crypto()).map_err(|e| {
+            OpenMlsError::MlsError(format!("Failed to compute KeyPackage hash: {:?}", e))
         })?;
+        Ok(hex::encode(hash_ref.as_slice()))
+    }
 
-        Ok(AddMemberResult {
-            welcome_bytes,
-            commit_bytes,
+    /// Drop a join request, so no Commit from this client includes it
+    pub fn deny_join(&self, group_id: String, handle: u64) -> Result<(), OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+            let (_, denied) = self.join_request(&group_id, handle)?;
+            self.drop_pending_proposals(group, &[denied])?;
+            self.take_join_request(&group_id, handle)?;
+            Ok(())
         })
     }
 
-    /// Join a group from a Welcome message
-    pub fn join_from_welcome(&self, welcome_bytes: Vec<u8>) -> Result<JoinGroupResult, OpenMlsError> {
-        // Deserialize Welcome
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut welcome_bytes.as_slice()).map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to deserialize Welcome: {:?}", e))
-        })?;
+    /// Remove received proposals from the group's proposal store. OpenMLS
+    /// can't remove a received proposal by itself, so the store is rebuilt
+    /// without them.
+    fn drop_pending_proposals(
+        &self,
+        group: &mut MlsGroup,
+        dropped: &[QueuedProposal],
+    ) -> Result<(), OpenMlsError> {
+        if !group.pending_proposals().any(|p| dropped.contains(p)) {
+            return Ok(());
+        }
+        let remaining: Vec<QueuedProposal> = group
+            .pending_proposals()
+            .filter(|p| !dropped.contains(p))
+            .cloned()
+            .collect();
+        group
+            .clear_pending_proposals(self.backend.storage())
+            .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+        for proposal in remaining {
+            group
+                .store_pending_proposal(self.backend.storage(), proposal)
+                .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+        }
+        Ok(())
+    }
 
-        let welcome = match mls_msg.extract() {
-            MlsMessageBodyIn::Welcome(w) => w,
-            _ => {
-                return Err(OpenMlsError::InvalidInput(
-                    "Expected Welcome message".to_string(),
-                ))
-            }
+    /// Keep an incoming external join proposal so a later Commit can refer
+    /// to it, and hand it to the app as a JoinRequest
+    fn queue_join_request(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+        proposal: QueuedProposal,
+    ) -> Result<JoinRequest, OpenMlsError> {
+        let Proposal::Add(add) = proposal.proposal() else {
+            return Err(OpenMlsError::InvalidInput(
+                "Join proposal is not an Add".to_string(),
+            ));
+        };
+        let leaf_node = add.key_package().leaf_node();
+        let request = JoinRequest {
+            handle: self.next_handle.fetch_add(1, Ordering::SeqCst),
+            client_id: String::from_utf8_lossy(leaf_node.credential().serialized_content())
+                .to_string(),
+            application_id: leaf_node
+                .extensions()
+                .application_id()
+                .map(|id| String::from_utf8_lossy(id.as_slice()).to_string()),
         };
 
-        // Join group
-        let config = MlsGroupJoinConfig::builder().build();
-        let group =
-            StagedWelcome::new_from_welcome(&self.backend, &config, welcome, None)
-                .map_err(|e| OpenMlsError::MlsError(format!("Failed to stage Welcome: {:?}", e)))?
-                .into_group(&self.backend)
-                .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?;
+        group
+            .store_pending_proposal(self.backend.storage(), proposal.clone())
+            .map_err(|e| OpenMlsError::StorageError(format!("{:?}", e)))?;
+        self.join_requests.lock().insert(
+            request.handle,
+            (group_id.to_string(), request.clone(), proposal),
+        );
+        Ok(request)
+    }
 
-        let group_id = hex::encode(group.group_id().as_slice());
+    fn join_request(
+        &self,
+        group_id: &str,
+        handle: u64,
+    ) -> Result<(JoinRequest, QueuedProposal), OpenMlsError> {
+        match self.join_requests.lock().get(&handle) {
+            Some((gid, request, proposal)) if gid == group_id => {
+                Ok((request.clone(), proposal.clone()))
+            }
+            _ => Err(OpenMlsError::InvalidInput(format!(
+                "No join request {} in group",
+                handle
+            ))),
+        }
+    }
 
-        self.groups.lock().unwrap().insert(group_id.clone(), group);
+    fn take_join_request(&self, group_id: &str, handle: u64) -> Result<(), OpenMlsError> {
+        self.join_request(group_id, handle)?;
+        self.join_requests.lock().remove(&handle);
+        Ok(())
+    }
 
-        Ok(JoinGroupResult { group_id })
+    /// Forget the group's undecided join requests, both the handles and the
+    /// queued proposals. Proposals don't outlive an epoch, and dropping them
+    /// keeps them out of Commits this client makes for other reasons. Other
+    /// pending proposals are kept.
+    fn expire_join_requests(
+        &self,
+        group_id: &str,
+        group: &mut MlsGroup,
+    ) -> Result<(), OpenMlsError> {
+        let expired: Vec<QueuedProposal> = self
+            .join_requests
+            .lock()
+            .values()
+            .filter(|(gid, _, _)| gid == group_id)
+            .map(|(_, _, proposal)| proposal.clone())
+            .collect();
+        self.drop_pending_proposals(group, &expired)?;
+        self.join_requests
+            .lock()
+            .retain(|_, (gid, _, _)| gid != group_id);
+        Ok(())
     }
 
-    /// Encrypt a message for a group
-    pub fn encrypt(&self, group_id: String, plaintext: Vec<u8>) -> Result<Vec<u8>, OpenMlsError> {
-        let mut groups = self.groups.lock().unwrap();
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or(OpenMlsError::GroupNotFound)?;
+    /// Encrypt a large payload as a sequence of application messages of at
+    /// most `chunk_size` plaintext bytes each. Publish them in order; the
+    /// receiver reassembles them with `decrypt_stream`.
+    pub fn encrypt_stream(
+        &self,
+        group_id: String,
+        plaintext: Vec<u8>,
+        chunk_size: u32,
+        content_type: Option<String>,
+    ) -> Result<Vec<Vec<u8>>, OpenMlsError> {
+        catch_panic(|| {
+            if chunk_size == 0 {
+                return Err(OpenMlsError::InvalidInput(
+                    "chunk_size must be positive".to_string(),
+                ));
+            }
+            let total = plaintext.len().div_ceil(chunk_size as usize).max(1);
+            if total > MAX_STREAM_CHUNKS as usize {
+                return Err(OpenMlsError::InvalidInput(format!(
+                    "Payload needs {} chunks, limit is {}",
+                    total, MAX_STREAM_CHUNKS
+                )));
+            }
 
-        let ciphertext = group
-            .create_message(&self.backend, &self.signer, &plaintext)
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to encrypt: {:?}", e)))?;
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+
+            let stream_id: [u8; 16] = rand::random();
+            let mut messages = Vec::with_capacity(total);
+            // An empty payload still produces one (empty) chunk
+            let chunks = plaintext
+                .chunks(chunk_size as usize)
+                .chain(plaintext.is_empty().then_some(&[][..]));
+            for (index, chunk) in chunks.enumerate() {
+                let aad = MessageAad {
+                    content_type: content_type.clone(),
+                    chunk: Some(ChunkHeader {
+                        stream_id,
+                        index: index as u32,
+                        total: total as u32,
+                    }),
+                };
+                // The AAD is reset after every message
+                group.set_aad(aad.encode());
+
+                let ciphertext = group
+                    .create_message(&self.backend, &self.signer, chunk)
+                    .map_err(|e| OpenMlsError::MlsError(format!("Failed to encrypt: {:?}", e)))?;
+
+                messages.push(ciphertext.tls_serialize_detached().map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to serialize ciphertext: {:?}",
+                        e
+                    ))
+                })?);
+            }
 
-        ciphertext.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize ciphertext: {:?}", e))
+            Ok(messages)
         })
     }
 
-    /// Decrypt a message from a group
-    pub fn decrypt(
+    /// Decrypt one chunk produced by `encrypt_stream` and buffer it until the
+    /// whole payload has arrived. Chunks may arrive in any order. Other
+    /// messages on the group's topic are returned as `decrypt` returns them.
+    pub fn decrypt_stream(
         &self,
         group_id: String,
         ciphertext: Vec<u8>,
-    ) -> Result<DecryptedMessage, OpenMlsError> {
-        let mut groups = self.groups.lock().unwrap();
-        let group = groups
-            .get_mut(&group_id)
-            .ok_or(OpenMlsError::GroupNotFound)?;
-
-        // Deserialize message
-        let mls_msg = MlsMessageIn::tls_deserialize(&mut ciphertext.as_slice()).map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to deserialize message: {:?}", e))
-        })?;
+    ) -> Result<StreamMessage, OpenMlsError> {
+        catch_panic(|| {
+            let mut groups = self.groups.lock();
+            let group = groups
+                .get_mut(&group_id)
+                .ok_or(OpenMlsError::GroupNotFound)?;
+
+            let (processed, wire_format) =
+                match self.process_incoming(&group_id, group, &ciphertext)? {
+                    Incoming::Processed(processed, wire_format) => (processed, wire_format),
+                    Incoming::Resolved(message) => {
+                        return Ok(StreamMessage::Message { message: *message })
+                    }
+                };
+
+            // Commits and ordinary messages share the topic with chunks
+            let aad = MessageAad::decode(processed.aad());
+            let chunk = match (aad.chunk, processed.sender(), processed.content()) {
+                (
+                    Some(header),
+                    Sender::Member(leaf),
+                    ProcessedMessageContent::ApplicationMessage(_),
+                ) => Some((header, leaf.u32())),
+                _ => None,
+            };
+            let Some((header, sender)) = chunk else {
+                let message = self.decrypted_message(&group_id, group, processed, wire_format)?;
+                return Ok(StreamMessage::Message { message });
+            };
+            let sender_client_id =
+                String::from_utf8_lossy(processed.credential().serialized_content()).to_string();
+            let ProcessedMessageContent::ApplicationMessage(app_msg) = processed.into_content()
+            else {
+                unreachable!("chunks are application messages");
+            };
+            drop(groups);
+
+            let progress = self
+                .streams
+                .lock()
+                .add_chunk(
+                    &group_id,
+                    sender,
+                    &header,
+                    app_msg.into_bytes(),
+                    Instant::now(),
+                )
+                .map_err(OpenMlsError::InvalidInput)?;
+
+            Ok(StreamMessage::Chunk {
+                progress: StreamProgress {
+                    stream_id: hex::encode(header.stream_id),
+                    received: progress.received,
+                    total: progress.total,
+                    sender_client_id,
+                    content_type: aad.content_type,
+                    plaintext: progress.plaintext,
+                },
+            })
+        })
+    }
 
-        // Extract ProtocolMessage
-        let protocol_msg: ProtocolMessage = match mls_msg.extract() {
-            MlsMessageBodyIn::PrivateMessage(pm) => pm.into(),
-            MlsMessageBodyIn::PublicMessage(pm) => pm.into(),
-            _ => {
-                return Err(OpenMlsError::InvalidInput(
-                    "Invalid message type".to_string(),
-                ))
-            }
-        };
+    /// Drop the buffered chunks of an incomplete stream
+    pub fn discard_stream(&self, group_id: String, stream_id: String) {
+        self.streams.lock().discard(group_id, stream_id);
+    }
 
-        // Process message
-        let processed = group
-            .process_message(&self.backend, protocol_msg)
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to process message: {:?}", e)))?;
+    /// Get the members of a group
+    pub fn members(&self, group_id: String) -> Result<Vec<GroupMember>, OpenMlsError> {
+        self.members_range(group_id, 0, u32::MAX)
+    }
 
-        match processed.into_content() {
-            ProcessedMessageContent::ApplicationMessage(app_msg) => {
-                let plaintext = app_msg.into_bytes();
-                Ok(DecryptedMessage {
-                    plaintext,
-                    sender_client_id: "unknown".to_string(),
-                })
-            }
-            ProcessedMessageContent::StagedCommitMessage(staged) => {
+    /// Groups that have `client_id` as a member, sorted by group_id
+    pub fn find_groups_with_member(&self, client_id: String) -> Vec<String> {
+        let groups = self.groups.lock();
+        let mut found: Vec<String> = groups
+            .iter()
+            .filter(|(_, group)| {
                 group
-                    .merge_staged_commit(&self.backend, *staged)
-                    .map_err(|e| {
-                        OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e))
-                    })?;
-                Err(OpenMlsError::InvalidInput(
-                    "Received commit, not application message".to_string(),
-                ))
-            }
-            _ => Err(OpenMlsError::InvalidInput(
-                "Received proposal, not application message".to_string(),
+                    .members()
+                    .any(|m| m.credential.serialized_content() == client_id.as_bytes())
+            })
+            .map(|(group_id, _)| group_id.clone())
+            .collect();
+        found.sort();
+        found
+    }
+
+    /// The 1:1 group with `client_id`: a group whose only members are this
+    /// client and the peer. If there are several, the first by group_id.
+    pub fn direct_group_with(&self, client_id: String) -> Option<String> {
+        let groups = self.groups.lock();
+        groups
+            .iter()
+            .filter(|(_, group)| {
+                let mut peers = group
+                    .members()
+                    .map(|m| m.credential.serialized_content().to_vec())
+                    .filter(|member| *member != self.client_id.as_bytes());
+                peers.next().as_deref() == Some(client_id.as_bytes()) && peers.next().is_none()
+            })
+            .map(|(group_id, _)| group_id.clone())
+            .min()
+    }
+
+    /// Get the number of members in a group
+    pub fn member_count(&self, group_id: String) -> Result<u32, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+
+            Ok(group.members().count() as u32)
+        })
+    }
+
+    /// Get a page of members, in leaf order. The tree is still walked in
+    /// full; paging only bounds how many entries cross the FFI.
+    pub fn members_range(
+        &self,
+        group_id: String,
+        offset: u32,
+        limit: u32,
+    ) -> Result<Vec<GroupMember>, OpenMlsError> {
+        catch_panic(|| {
+            let groups = self.groups.lock();
+            let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+            let leaves = leaf_nodes(group)?;
+            let own_index = group.own_leaf_index();
+
+            Ok(group
+                .members()
+                .skip(offset as usize)
+                .take(limit as usize)
+                .map(|m| group_member(m, &leaves, own_index))
+                .collect())
+        })
+    }
+}
+
+/// What an own pending Commit changes, for rebasing it if it loses
+fn own_changes(group: &MlsGroup, staged: &StagedCommit) -> Vec<OwnChange> {
+    let client_id = |credential: &Credential| {
+        String::from_utf8_lossy(credential.serialized_content()).to_string()
+    };
+    let change = |kind, client_id, role| OwnChange {
+        summary: ProposalSummary {
+            kind,
+            client_id,
+            role,
+        },
+        key_package: None,
+    };
+    let previous_roles = RoleMap::from_extensions(group.extensions()).unwrap_or_default();
+
+    let mut changes = Vec::new();
+    for proposal in staged.queued_proposals() {
+        match proposal.proposal() {
+            Proposal::Add(add) => changes.push(OwnChange {
+                key_package: Some(add.key_package().clone()),
+                ..change(
+                    ProposalKind::Add,
+                    Some(client_id(add.key_package().leaf_node().credential())),
+                    None,
+                )
+            }),
+            Proposal::Remove(remove) => changes.push(change(
+                ProposalKind::Remove,
+                group.member(remove.removed()).map(client_id),
+                None,
             )),
+            Proposal::GroupContextExtensions(gce) => {
+                match RoleMap::from_extensions(gce.extensions()) {
+                    Some(roles) => {
+                        changes.extend(roles.changes_from(&previous_roles).into_iter().map(
+                            |(id, role)| change(ProposalKind::RoleChange, Some(id), Some(role)),
+                        ))
+                    }
+                    None => changes.push(change(ProposalKind::Other, None, None)),
+                }
+            }
+            _ => changes.push(change(ProposalKind::Other, None, None)),
         }
     }
 
-    /// Get list of member client IDs in a group
-    pub fn members(&self, group_id: String) -> Result<Vec<String>, OpenMlsError> {
-        let groups = self.groups.lock().unwrap();
-        let group = groups.get(&group_id).ok_or(OpenMlsError::GroupNotFound)?;
+    // A Commit without proposals only replaces our leaf
+    if changes.is_empty() && staged.update_path_leaf_node().is_some() {
+        changes.push(change(ProposalKind::SelfUpdate, None, None));
+    }
+    changes
+}
 
-        Ok(group
-            .members()
-            .map(|m| String::from_utf8_lossy(m.credential.serialized_content()).to_string())
-            .collect())
+fn group_member(
+    member: Member,
+    leaves: &[Option<LeafNode>],
+    own_index: LeafNodeIndex,
+) -> GroupMember {
+    GroupMember {
+        client_id: String::from_utf8_lossy(member.credential.serialized_content()).to_string(),
+        application_id: leaves
+            .get(member.index.usize())
+            .and_then(|leaf| leaf.as_ref())
+            .and_then(|leaf| leaf.extensions().application_id())
+            .map(|id| String::from_utf8_lossy(id.as_slice()).to_string()),
+        leaf_index: member.index.u32(),
+        credential_type: member.credential.credential_type().into(),
+        identity: member.credential.serialized_content().to_vec(),
+        signature_public_key: member.signature_key,
+        is_self: member.index == own_index,
     }
 }
 
+/// Leaf nodes of a group indexed by leaf index (blank leaves are None).
+///
+/// MlsGroup only exposes members' credentials and keys, so the leaves are
+/// recovered from the exported ratchet tree.
+fn leaf_nodes(group: &MlsGroup) -> Result<Vec<Option<LeafNode>>, OpenMlsError> {
+    let tree = serde_json::to_value(group.export_ratchet_tree())
+        .map_err(|e| OpenMlsError::SerializationError(format!("Failed to export tree: {:?}", e)))?;
+    let nodes: Vec<Option<Node>> = serde_json::from_value(tree)
+        .map_err(|e| OpenMlsError::SerializationError(format!("Failed to decode tree: {:?}", e)))?;
+
+    // Leaves sit at the even node indices
+    Ok(nodes
+        .into_iter()
+        .step_by(2)
+        .map(|node| match node {
+            Some(Node::LeafNode(leaf)) => Some(*leaf),
+            _ => None,
+        })
+        .collect())
+}
+
+// ============================================================================
+// Message Classification
+// ============================================================================
+
+/// Read the capabilities a client advertised next to its KeyPackages, e.g.
+/// to check features before forming a group. None for arrays published
+/// without capabilities.
+pub fn key_package_capabilities(
+    key_package_bytes: Vec<u8>,
+) -> Result<Option<ClientCapabilities>, OpenMlsError> {
+    catch_panic(|| {
+        let kp_array = KeyPackageArray::decode(&key_package_bytes).map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to decode CBOR: {}", e))
+        })?;
+        Ok(kp_array.capabilities)
+    })
+}
+
+/// Classify an incoming payload by its MLSMessage wire format. Only the
+/// framing is decoded; nothing is verified or decrypted.
+pub fn classify_message(message_bytes: Vec<u8>) -> Result<MessageKind, OpenMlsError> {
+    catch_panic(|| {
+        let mls_msg = match MlsMessageIn::tls_deserialize_exact(message_bytes.as_slice()) {
+            Ok(msg) => msg,
+            Err(e) => {
+                // KeyPackages are published wrapped in a CBOR array
+                return match KeyPackageArray::decode(&message_bytes) {
+                    Ok(kp_array) if !kp_array.key_packages.is_empty() => {
+                        Ok(MessageKind::KeyPackage)
+                    }
+                    _ => Err(OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize message: {:?}",
+                        e
+                    ))),
+                };
+            }
+        };
+
+        let kind = match mls_msg.extract() {
+            MlsMessageBodyIn::KeyPackage(_) => MessageKind::KeyPackage,
+            MlsMessageBodyIn::Welcome(_) => MessageKind::Welcome,
+            MlsMessageBodyIn::PublicMessage(pm) => MessageKind::PublicMessage {
+                group_id: hex::encode(pm.group_id().as_slice()),
+                epoch: pm.epoch().as_u64(),
+            },
+            MlsMessageBodyIn::PrivateMessage(pm) => {
+                let pm = ProtocolMessage::from(pm);
+                MessageKind::PrivateMessage {
+                    group_id: hex::encode(pm.group_id().as_slice()),
+                    epoch: pm.epoch().as_u64(),
+                }
+            }
+            MlsMessageBodyIn::GroupInfo(gi) => MessageKind::GroupInfo {
+                group_id: hex::encode(gi.group_id().as_slice()),
+                epoch: gi.epoch().as_u64(),
+            },
+        };
+
+        Ok(kind)
+    })
+}
+
 // ============================================================================
 // Legacy Functions (for backwards compatibility)
 // ============================================================================
 
 /// Generate a new client identity with a random client ID
 pub fn generate_client_identity(client_id: String) -> Result<ClientIdentity, OpenMlsError> {
-    // Create basic credential
-    let credential = BasicCredential::new(client_id.clone().into_bytes());
-
-    // Generate signature keypair
-    let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
-        OpenMlsError::MlsError(format!("Failed to generate signature keypair: {:?}", e))
-    })?;
-
-    // Serialize credential - convert to Credential first
-    let cred: Credential = credential.into();
-    let credential_bytes = cred.tls_serialize_detached().map_err(|e| {
-        OpenMlsError::SerializationError(format!("Failed to serialize credential: {:?}", e))
-    })?;
-
-    // Get public key bytes
-    let public_key_bytes = signer.public().to_vec();
-
-    Ok(ClientIdentity {
-        client_id,
-        credential_bytes,
-        signature_public_key: public_key_bytes,
+    catch_panic(|| {
+        // Create basic credential
+        let credential = BasicCredential::new(client_id.clone().into_bytes());
+
+        // Generate signature keypair
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
+            OpenMlsError::MlsError(format!("Failed to generate signature keypair: {:?}", e))
+        })?;
+
+        // Serialize credential - convert to Credential first
+        let cred: Credential = credential.into();
+        let credential_bytes = cred.tls_serialize_detached().map_err(|e| {
+            OpenMlsError::SerializationError(format!("Failed to serialize credential: {:?}", e))
+        })?;
+
+        // Get public key bytes
+        let public_key_bytes = signer.public().to_vec();
+
+        Ok(ClientIdentity {
+            client_id,
+            credential_bytes,
+            signature_public_key: public_key_bytes,
+        })
     })
 }
 
 /// Create a KeyPackage for the client (legacy - creates new signer each time)
 pub fn create_key_package(client_id: String) -> Result<KeyPackageBundle, OpenMlsError> {
-    let backend = OpenMlsRustCrypto::default();
+    catch_panic(|| {
+        let backend = OpenMlsRustCrypto::default();
 
-    // Create credential
-    let credential = BasicCredential::new(client_id.into_bytes());
+        // Create credential
+        let credential = BasicCredential::new(client_id.into_bytes());
 
-    // Generate signature keypair
-    let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
-        OpenMlsError::MlsError(format!("Failed to generate signature keypair: {:?}", e))
-    })?;
+        // Generate signature keypair
+        let signer = SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
+            OpenMlsError::MlsError(format!("Failed to generate signature keypair: {:?}", e))
+        })?;
 
-    let credential_with_key = CredentialWithKey {
-        credential: credential.into(),
-        signature_key: signer.public().into(),
-    };
+        let credential_with_key = CredentialWithKey {
+            credential: credential.into(),
+            signature_key: signer.public().into(),
+        };
 
-    // Create KeyPackage
-    let key_package_bundle = KeyPackage::builder()
-        .build(CIPHERSUITE, &backend, &signer, credential_with_key)
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to create KeyPackage: {:?}", e)))?;
-
-    // Serialize KeyPackage
-    let key_package_bytes = key_package_bundle
-        .key_package()
-        .tls_serialize_detached()
-        .map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize KeyPackage: {:?}", e))
-        })?;
+        // Create KeyPackage
+        let key_package_bundle = KeyPackage::builder()
+            .build(CIPHERSUITE, &backend, &signer, credential_with_key)
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create KeyPackage: {:?}", e)))?;
+
+        // Serialize KeyPackage
+        let key_package_bytes = key_package_bundle
+            .key_package()
+            .tls_serialize_detached()
+            .map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize KeyPackage: {:?}", e))
+            })?;
 
-    // Get hash
-    let hash_bytes = key_package_bundle
-        .key_package()
-        .hash_ref(backend.crypto())
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to compute KeyPackage hash: {:?}", e)))?
-        .as_slice()
-        .to_vec();
-
-    Ok(KeyPackageBundle {
-        key_package_bytes,
-        key_package_hash: hash_bytes,
+        // Get hash
+        let hash_bytes = key_package_bundle
+            .key_package()
+            .hash_ref(backend.crypto())
+            .map_err(|e| {
+                OpenMlsError::MlsError(format!("Failed to compute KeyPackage hash: {:?}", e))
+            })?
+            .as_slice()
+            .to_vec();
+
+        Ok(KeyPackageBundle {
+            key_package_bytes,
+            key_package_hash: hash_bytes,
+        })
     })
 }
 
@@ -426,40 +3052,43 @@ pub struct OpenMlsGroup {
 impl OpenMlsGroup {
     /// Create a new MLS group
     pub fn new(group_id: String, client_id: String) -> Result<Self, OpenMlsError> {
-        let backend = Arc::new(OpenMlsRustCrypto::default());
-
-        // Create credential
-        let credential = BasicCredential::new(client_id.clone().into_bytes());
-
-        // Create signer
-        let signer = Arc::new(
-            SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
-                .map_err(|e| OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e)))?,
-        );
-
-        let credential_with_key = CredentialWithKey {
-            credential: credential.into(),
-            signature_key: signer.public().into(),
-        };
+        catch_panic(|| {
+            let backend = Arc::new(OpenMlsRustCrypto::default());
 
-        // Parse group ID (try hex first, fallback to raw bytes)
-        let group_id_bytes =
-            hex::decode(&group_id).unwrap_or_else(|_| group_id.as_bytes().to_vec());
-        let openmls_group_id = GroupId::from_slice(&group_id_bytes);
+            // Create credential
+            let credential = BasicCredential::new(client_id.clone().into_bytes());
 
-        let group = MlsGroup::new_with_group_id(
-            backend.as_ref(),
-            signer.as_ref(),
-            &MlsGroupCreateConfig::default(),
-            openmls_group_id,
-            credential_with_key,
-        )
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to create group: {:?}", e)))?;
+            // Create signer
+            let signer = Arc::new(
+                SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e))
+                })?,
+            );
+
+            let credential_with_key = CredentialWithKey {
+                credential: credential.into(),
+                signature_key: signer.public().into(),
+            };
+
+            // Parse group ID (try hex first, fallback to raw bytes)
+            let group_id_bytes =
+                hex::decode(&group_id).unwrap_or_else(|_| group_id.as_bytes().to_vec());
+            let openmls_group_id = GroupId::from_slice(&group_id_bytes);
+
+            let group = MlsGroup::new_with_group_id(
+                backend.as_ref(),
+                signer.as_ref(),
+                &MlsGroupCreateConfig::default(),
+                openmls_group_id,
+                credential_with_key,
+            )
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to create group: {:?}", e)))?;
 
-        Ok(Self {
-            inner: Arc::new(Mutex::new(group)),
-            backend,
-            signer,
+            Ok(Self {
+                inner: Arc::new(Mutex::new(group)),
+                backend,
+                signer,
+            })
         })
     }
 
@@ -468,171 +3097,268 @@ impl OpenMlsGroup {
         welcome_bytes: Vec<u8>,
         _client_id: String,
     ) -> Result<Self, OpenMlsError> {
-        let backend = Arc::new(OpenMlsRustCrypto::default());
-
-        // Deserialize Welcome
-        let mls_message_in =
-            MlsMessageIn::tls_deserialize(&mut welcome_bytes.as_slice()).map_err(|e| {
-                OpenMlsError::SerializationError(format!("Failed to deserialize Welcome: {:?}", e))
-            })?;
-
-        // Extract Welcome from MlsMessageIn
-        let welcome = match mls_message_in.extract() {
-            MlsMessageBodyIn::Welcome(w) => w,
-            _ => {
-                return Err(OpenMlsError::InvalidInput(
-                    "Not a Welcome message".to_string(),
-                ))
-            }
-        };
-
-        // Create signer
-        let signer = Arc::new(
-            SignatureKeyPair::new(CIPHERSUITE.signature_algorithm())
-                .map_err(|e| OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e)))?,
-        );
-
-        // Join group
-        let group_config = MlsGroupJoinConfig::default();
-        let group = StagedWelcome::new_from_welcome(
-            backend.as_ref(),
-            &group_config,
-            welcome,
-            None, // ratchet tree
-        )
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to stage Welcome: {:?}", e)))?
-        .into_group(backend.as_ref())
-        .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?;
+        catch_panic(|| {
+            let backend = Arc::new(OpenMlsRustCrypto::default());
 
-        Ok(Self {
-            inner: Arc::new(Mutex::new(group)),
-            backend,
-            signer,
+            // Deserialize Welcome
+            let mls_message_in = MlsMessageIn::tls_deserialize(&mut welcome_bytes.as_slice())
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize Welcome: {:?}",
+                        e
+                    ))
+                })?;
+
+            // Extract Welcome from MlsMessageIn
+            let welcome = match mls_message_in.extract() {
+                MlsMessageBodyIn::Welcome(w) => w,
+                _ => {
+                    return Err(OpenMlsError::InvalidInput(
+                        "Not a Welcome message".to_string(),
+                    ))
+                }
+            };
+
+            // Create signer
+            let signer = Arc::new(
+                SignatureKeyPair::new(CIPHERSUITE.signature_algorithm()).map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to create signer: {:?}", e))
+                })?,
+            );
+
+            // Join group
+            let group_config = MlsGroupJoinConfig::default();
+            let group = StagedWelcome::new_from_welcome(
+                backend.as_ref(),
+                &group_config,
+                welcome,
+                None, // ratchet tree
+            )
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to stage Welcome: {:?}", e)))?
+            .into_group(backend.as_ref())
+            .map_err(|e| OpenMlsError::MlsError(format!("Failed to join group: {:?}", e)))?;
+
+            Ok(Self {
+                inner: Arc::new(Mutex::new(group)),
+                backend,
+                signer,
+            })
         })
     }
 
     /// Add a member to the group
     pub fn add_member(&self, key_package_bytes: Vec<u8>) -> Result<AddMemberResult, OpenMlsError> {
-        let mut group = self.inner.lock().unwrap();
+        catch_panic(|| {
+            let mut group = self.inner.lock();
 
-        // Deserialize KeyPackage
-        let key_package_in = KeyPackageIn::tls_deserialize(&mut key_package_bytes.as_slice())
-            .map_err(|e| {
+            // Deserialize KeyPackage
+            let key_package_in = KeyPackageIn::tls_deserialize(&mut key_package_bytes.as_slice())
+                .map_err(|e| {
                 OpenMlsError::SerializationError(format!(
                     "Failed to deserialize KeyPackage: {:?}",
                     e
                 ))
             })?;
 
-        // Validate and convert to KeyPackage
-        let key_package = key_package_in
-            .validate(self.backend.crypto(), ProtocolVersion::default())
-            .map_err(|e| {
-                OpenMlsError::MlsError(format!("Failed to validate KeyPackage: {:?}", e))
-            })?;
+            // Validate and convert to KeyPackage
+            let key_package = key_package_in
+                .validate(self.backend.crypto(), ProtocolVersion::default())
+                .map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to validate KeyPackage: {:?}", e))
+                })?;
 
-        // Add member
-        let (commit, welcome, _group_info) = group
-            .add_members(self.backend.as_ref(), self.signer.as_ref(), &[key_package])
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to add member: {:?}", e)))?;
+            // Add member
+            let (commit, welcome, _group_info) = group
+                .add_members(self.backend.as_ref(), self.signer.as_ref(), &[key_package])
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to add member: {:?}", e)))?;
 
-        // Merge pending commit
-        group
-            .merge_pending_commit(self.backend.as_ref())
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e)))?;
+            // Merge pending commit
+            group
+                .merge_pending_commit(self.backend.as_ref())
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to merge commit: {:?}", e)))?;
 
-        // Serialize results
-        let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize commit: {:?}", e))
-        })?;
+            // Serialize results
+            let commit_bytes = commit.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize commit: {:?}", e))
+            })?;
 
-        let welcome_bytes = welcome.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize welcome: {:?}", e))
-        })?;
+            let welcome_bytes = welcome.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize welcome: {:?}", e))
+            })?;
 
-        Ok(AddMemberResult {
-            welcome_bytes,
-            commit_bytes,
+            Ok(AddMemberResult {
+                welcome_bytes,
+                commit_bytes,
+            })
         })
     }
 
     /// Encrypt a message
     pub fn encrypt(&self, plaintext: Vec<u8>) -> Result<Vec<u8>, OpenMlsError> {
-        let mut group = self.inner.lock().unwrap();
+        catch_panic(|| {
+            let mut group = self.inner.lock();
 
-        let ciphertext = group
-            .create_message(self.backend.as_ref(), self.signer.as_ref(), &plaintext)
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to encrypt: {:?}", e)))?;
+            let ciphertext = group
+                .create_message(self.backend.as_ref(), self.signer.as_ref(), &plaintext)
+                .map_err(|e| OpenMlsError::MlsError(format!("Failed to encrypt: {:?}", e)))?;
 
-        ciphertext.tls_serialize_detached().map_err(|e| {
-            OpenMlsError::SerializationError(format!("Failed to serialize ciphertext: {:?}", e))
+            ciphertext.tls_serialize_detached().map_err(|e| {
+                OpenMlsError::SerializationError(format!("Failed to serialize ciphertext: {:?}", e))
+            })
         })
     }
 
     /// Decrypt a message
     pub fn decrypt(&self, ciphertext_bytes: Vec<u8>) -> Result<DecryptedMessage, OpenMlsError> {
-        let mut group = self.inner.lock().unwrap();
-
-        // Deserialize message
-        let mls_message_in = MlsMessageIn::tls_deserialize(&mut ciphertext_bytes.as_slice())
-            .map_err(|e| {
-                OpenMlsError::SerializationError(format!("Failed to deserialize message: {:?}", e))
-            })?;
-
-        // Extract ProtocolMessage
-        let protocol_message: ProtocolMessage = match mls_message_in.extract() {
-            MlsMessageBodyIn::PrivateMessage(pm) => pm.into(),
-            MlsMessageBodyIn::PublicMessage(pm) => pm.into(),
-            _ => {
-                return Err(OpenMlsError::InvalidInput(
-                    "Invalid message type".to_string(),
-                ))
-            }
-        };
-
-        // Process message
-        let processed = group
-            .process_message(self.backend.as_ref(), protocol_message)
-            .map_err(|e| OpenMlsError::MlsError(format!("Failed to process message: {:?}", e)))?;
-
-        match processed.into_content() {
-            ProcessedMessageContent::ApplicationMessage(app_msg) => {
-                let plaintext = app_msg.into_bytes();
-                let sender_id = "unknown".to_string(); // TODO: extract sender from message
+        catch_panic(|| {
+            let mut group = self.inner.lock();
 
-                Ok(DecryptedMessage {
-                    plaintext,
-                    sender_client_id: sender_id,
-                })
+            // Deserialize message
+            let mls_message_in = MlsMessageIn::tls_deserialize(&mut ciphertext_bytes.as_slice())
+                .map_err(|e| {
+                    OpenMlsError::SerializationError(format!(
+                        "Failed to deserialize message: {:?}",
+                        e
+                    ))
+                })?;
+
+            // Extract ProtocolMessage
+            let protocol_message: ProtocolMessage = match mls_message_in.extract() {
+                MlsMessageBodyIn::PrivateMessage(pm) => pm.into(),
+                MlsMessageBodyIn::PublicMessage(pm) => pm.into(),
+                _ => {
+                    return Err(OpenMlsError::InvalidInput(
+                        "Invalid message type".to_string(),
+                    ))
+                }
+            };
+
+            let wire_format = protocol_message.wire_format();
+
+            // Process message
+            let processed = group
+                .process_message(self.backend.as_ref(), protocol_message)
+                .map_err(|e| {
+                    OpenMlsError::MlsError(format!("Failed to process message: {:?}", e))
+                })?;
+            let message = DecryptedMessage::from_processed(&processed, wire_format);
+
+            match processed.into_content() {
+                ProcessedMessageContent::ApplicationMessage(app_msg) => Ok(DecryptedMessage {
+                    plaintext: app_msg.into_bytes(),
+                    ..message
+                }),
+                ProcessedMessageContent::ProposalMessage(_) => Err(OpenMlsError::InvalidInput(
+                    "Received proposal, not application message".to_string(),
+                )),
+                ProcessedMessageContent::ExternalJoinProposalMessage(_) => Err(
+                    OpenMlsError::InvalidInput("Received external join proposal".to_string()),
+                ),
+                ProcessedMessageContent::StagedCommitMessage(_) => Err(OpenMlsError::InvalidInput(
+                    "Received commit, not application message".to_string(),
+                )),
             }
-            ProcessedMessageContent::ProposalMessage(_) => Err(OpenMlsError::InvalidInput(
-                "Received proposal, not application message".to_string(),
-            )),
-            ProcessedMessageContent::ExternalJoinProposalMessage(_) => Err(
-                OpenMlsError::InvalidInput("Received external join proposal".to_string()),
-            ),
-            ProcessedMessageContent::StagedCommitMessage(_) => Err(OpenMlsError::InvalidInput(
-                "Received commit, not application message".to_string(),
-            )),
-        }
+        })
     }
 
     /// Get the group ID as a hex string
     pub fn group_id(&self) -> String {
-        let group = self.inner.lock().unwrap();
+        let group = self.inner.lock();
         hex::encode(group.group_id().as_slice())
     }
 
     /// Get list of member client IDs
-    pub fn members(&self) -> Vec<String> {
-        let group = self.inner.lock().unwrap();
+    pub fn members(&self) -> Vec<GroupMember> {
+        let group = self.inner.lock();
+        // Without the leaves only application_id is missing
+        let leaves = leaf_nodes(&group).unwrap_or_default();
+        let own_index = group.own_leaf_index();
         group
             .members()
-            .map(|member| {
-                String::from_utf8_lossy(member.credential.serialized_content()).to_string()
-            })
+            .map(|member| group_member(member, &leaves, own_index))
             .collect()
     }
 }
 
 uniffi::include_scaffolding!("swift_openmls");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Alice's group with Bob in it, and its group_id
+    fn two_member_group() -> (RelayMlsClient, RelayMlsClient, String) {
+        let alice = RelayMlsClient::new("alice".to_string()).unwrap();
+        let bob = RelayMlsClient::new("bob".to_string()).unwrap();
+        let group_id = alice.create_group().unwrap();
+        let key_package = bob.create_key_package().unwrap().key_package_bytes;
+        let added = alice.add_member(group_id.clone(), key_package).unwrap();
+        bob.join_from_welcome(added.welcome_bytes, None, None)
+            .unwrap();
+        (alice, bob, group_id)
+    }
+
+    /// Self-update Commits from Alice and Bob in the same epoch
+    fn competing_commits(
+        alice: &RelayMlsClient,
+        bob: &RelayMlsClient,
+        group_id: &str,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let ours = alice.update_if_stale(group_id.to_string(), 0).unwrap();
+        let theirs = bob.update_if_stale(group_id.to_string(), 0).unwrap();
+        (ours.unwrap(), theirs.unwrap())
+    }
+
+    fn epoch(client: &RelayMlsClient, group_id: &str) -> u64 {
+        client.groups.lock()[group_id].epoch().as_u64()
+    }
+
+    #[test]
+    fn decrypt_resolves_a_competing_commit() {
+        let (alice, bob, group_id) = two_member_group();
+        let (_, theirs) = competing_commits(&alice, &bob, &group_id);
+
+        let message = alice.decrypt(group_id.clone(), theirs).unwrap();
+        let resolution = message.conflict_resolution.unwrap();
+        assert!(resolution.conflict);
+        assert!(resolution.dropped.is_empty());
+
+        // Our update is committed again on top of Bob's
+        let rebased = resolution.commit_bytes.unwrap();
+        // A merged Commit is reported as an error
+        let merged = bob.decrypt(group_id.clone(), rebased);
+        assert!(matches!(merged, Err(OpenMlsError::InvalidInput(_))));
+        assert_eq!(epoch(&alice, &group_id), epoch(&bob, &group_id));
+    }
+
+    #[test]
+    fn decrypt_stream_resolves_a_competing_commit() {
+        let (alice, bob, group_id) = two_member_group();
+        let (_, theirs) = competing_commits(&alice, &bob, &group_id);
+
+        let StreamMessage::Message { message } =
+            alice.decrypt_stream(group_id.clone(), theirs).unwrap()
+        else {
+            panic!("a Commit is not a chunk");
+        };
+        let resolution = message.conflict_resolution.unwrap();
+        assert!(resolution.conflict);
+
+        let rebased = resolution.commit_bytes.unwrap();
+        let merged = bob.decrypt_stream(group_id.clone(), rebased);
+        assert!(matches!(merged, Err(OpenMlsError::InvalidInput(_))));
+        assert_eq!(epoch(&alice, &group_id), epoch(&bob, &group_id));
+    }
+
+    #[test]
+    fn our_own_commit_confirms_it() {
+        let (alice, bob, group_id) = two_member_group();
+        let (ours, _) = competing_commits(&alice, &bob, &group_id);
+        let epoch_before = epoch(&alice, &group_id);
+
+        let message = alice.decrypt(group_id.clone(), ours).unwrap();
+        assert!(!message.conflict_resolution.unwrap().conflict);
+        assert_eq!(epoch(&alice, &group_id), epoch_before);
+        assert!(alice.own_commits.lock().is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+//! OpenMLS provider for RelayMlsClient.
+//!
+//! Crypto is held behind a trait object so a Rust host can supply its own
+//! implementation (e.g. hardware-backed keys) or share one instance across
+//! several clients. `OpenMlsCrypto` is not exported through UniFFI, so Swift
+//! hosts always get RustCrypto. Randomness always comes from RustCrypto, and
+//! storage is always a `RelayStorage`, which RelayMlsClient also uses for its
+//! own records; only its backend is the host's, see `StorageBackend`.
+
+use crate::storage::RelayStorage;
+use openmls_rust_crypto::RustCrypto;
+use openmls_traits::crypto::OpenMlsCrypto;
+use openmls_traits::types::{
+    AeadType, Ciphersuite, CryptoError, ExporterSecret, HashType, HpkeCiphertext, HpkeConfig,
+    HpkeKeyPair, KemOutput, SignatureScheme,
+};
+use openmls_traits::OpenMlsProvider;
+use std::sync::Arc;
+use tls_codec::SecretVLBytes;
+
+/// OpenMLS provider used by RelayMlsClient
+#[derive(Default)]
+pub struct RelayProvider {
+    crypto: SharedCrypto,
+    rand: RustCrypto,
+    storage: RelayStorage,
+}
+
+impl RelayProvider {
+    /// Provider with RustCrypto for all cryptographic operations
+    pub fn new(storage: RelayStorage) -> Self {
+        Self::with_crypto(Arc::new(RustCrypto::default()), storage)
+    }
+
+    /// Provider with a host-supplied crypto implementation. The same `Arc`
+    /// may be passed to several providers.
+    pub fn with_crypto(crypto: Arc<dyn OpenMlsCrypto>, storage: RelayStorage) -> Self {
+        Self {
+            crypto: SharedCrypto(crypto),
+            rand: RustCrypto::default(),
+            storage,
+        }
+    }
+}
+
+impl OpenMlsProvider for RelayProvider {
+    type CryptoProvider = SharedCrypto;
+    type RandProvider = RustCrypto;
+    type StorageProvider = RelayStorage;
+
+    fn storage(&self) -> &Self::StorageProvider {
+        &self.storage
+    }
+
+    fn crypto(&self) -> &Self::CryptoProvider {
+        &self.crypto
+    }
+
+    fn rand(&self) -> &Self::RandProvider {
+        &self.rand
+    }
+}
+
+/// Shared handle to a crypto implementation.
+///
+/// OpenMLS needs a concrete `OpenMlsCrypto` type on the provider, so this
+/// forwards every call to the wrapped trait object.
+#[derive(Clone)]
+pub struct SharedCrypto(Arc<dyn OpenMlsCrypto>);
+
+impl Default for SharedCrypto {
+    fn default() -> Self {
+        Self(Arc::new(RustCrypto::default()))
+    }
+}
+
+impl OpenMlsCrypto for SharedCrypto {
+    fn supports(&self, ciphersuite: Ciphersuite) -> Result<(), CryptoError> {
+        self.0.supports(ciphersuite)
+    }
+
+    fn supported_ciphersuites(&self) -> Vec<Ciphersuite> {
+        self.0.supported_ciphersuites()
+    }
+
+    fn hkdf_extract(
+        &self,
+        hash_type: HashType,
+        salt: &[u8],
+        ikm: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hkdf_extract(hash_type, salt, ikm)
+    }
+
+    fn hmac(
+        &self,
+        hash_type: HashType,
+        key: &[u8],
+        message: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hmac(hash_type, key, message)
+    }
+
+    fn hkdf_expand(
+        &self,
+        hash_type: HashType,
+        prk: &[u8],
+        info: &[u8],
+        okm_len: usize,
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hkdf_expand(hash_type, prk, info, okm_len)
+    }
+
+    fn hash(&self, hash_type: HashType, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.hash(hash_type, data)
+    }
+
+    fn aead_encrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        data: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.0.aead_encrypt(alg, key, data, nonce, aad)
+    }
+
+    fn aead_decrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        ct_tag: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.0.aead_decrypt(alg, key, ct_tag, nonce, aad)
+    }
+
+    fn signature_key_gen(&self, alg: SignatureScheme) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        self.0.signature_key_gen(alg)
+    }
+
+    fn verify_signature(
+        &self,
+        alg: SignatureScheme,
+        data: &[u8],
+        pk: &[u8],
+        signature: &[u8],
+    ) -> Result<(), CryptoError> {
+        self.0.verify_signature(alg, data, pk, signature)
+    }
+
+    fn sign(&self, alg: SignatureScheme, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.sign(alg, data, key)
+    }
+
+    fn hpke_seal(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+        ptxt: &[u8],
+    ) -> Result<HpkeCiphertext, CryptoError> {
+        self.0.hpke_seal(config, pk_r, info, aad, ptxt)
+    }
+
+    fn hpke_open(
+        &self,
+        config: HpkeConfig,
+        input: &HpkeCiphertext,
+        sk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.0.hpke_open(config, input, sk_r, info, aad)
+    }
+
+    fn hpke_setup_sender_and_export(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<(KemOutput, ExporterSecret), CryptoError> {
+        self.0
+            .hpke_setup_sender_and_export(config, pk_r, info, exporter_context, exporter_length)
+    }
+
+    fn hpke_setup_receiver_and_export(
+        &self,
+        config: HpkeConfig,
+        enc: &[u8],
+        sk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<ExporterSecret, CryptoError> {
+        self.0.hpke_setup_receiver_and_export(
+            config,
+            enc,
+            sk_r,
+            info,
+            exporter_context,
+            exporter_length,
+        )
+    }
+
+    fn derive_hpke_keypair(
+        &self,
+        config: HpkeConfig,
+        ikm: &[u8],
+    ) -> Result<HpkeKeyPair, CryptoError> {
+        self.0.derive_hpke_keypair(config, ikm)
+    }
+}
@@ -0,0 +1,101 @@
+//! Group roles carried in a GroupContext extension.
+//!
+//! The role map is CBOR-encoded (`{ client_id => role }`) in a private-use
+//! extension. Because it lives in the GroupContext, every change goes through
+//! a signed Commit and all members agree on the same map each epoch.
+
+use openmls::prelude::{
+    Extension, ExtensionType, Extensions, RequiredCapabilitiesExtension, UnknownExtension,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Private-use extension type holding the role map
+pub(crate) const ROLES_EXTENSION_TYPE: u16 = 0xff00;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Role {
+    /// May add and remove members and change roles
+    Admin,
+    Member,
+}
+
+/// Client roles of one group; clients without an entry are members
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct RoleMap(BTreeMap<String, Role>);
+
+impl RoleMap {
+    /// Read the role map from GroupContext extensions, if the group has one
+    pub fn from_extensions(extensions: &Extensions) -> Option<Self> {
+        let extension = extensions.unknown(ROLES_EXTENSION_TYPE)?;
+        ciborium::from_reader(extension.0.as_slice()).ok()
+    }
+
+    /// Replace (or add) the role map in a set of GroupContext extensions.
+    /// The roles extension is also added to the group's required
+    /// capabilities, which OpenMLS needs before it accepts the extension.
+    pub fn into_extensions(self, extensions: &Extensions) -> Extensions {
+        let mut data = Vec::new();
+        // Writing to a Vec cannot fail
+        ciborium::into_writer(&self, &mut data).expect("CBOR encoding into Vec");
+
+        let mut extensions = extensions.clone();
+        let roles_type = ExtensionType::Unknown(ROLES_EXTENSION_TYPE);
+        let required = match extensions.required_capabilities() {
+            Some(required) if required.extension_types().contains(&roles_type) => None,
+            Some(required) => {
+                let mut extension_types = required.extension_types().to_vec();
+                extension_types.push(roles_type);
+                Some(RequiredCapabilitiesExtension::new(
+                    &extension_types,
+                    required.proposal_types(),
+                    required.credential_types(),
+                ))
+            }
+            None => Some(RequiredCapabilitiesExtension::new(&[roles_type], &[], &[])),
+        };
+        if let Some(required) = required {
+            extensions.add_or_replace(Extension::RequiredCapabilities(required));
+        }
+
+        extensions.add_or_replace(Extension::Unknown(
+            ROLES_EXTENSION_TYPE,
+            UnknownExtension(data),
+        ));
+        extensions
+    }
+
+    pub fn role(&self, client_id: &str) -> Role {
+        self.0.get(client_id).copied().unwrap_or(Role::Member)
+    }
+
+    pub fn set(&mut self, client_id: String, role: Role) {
+        match role {
+            Role::Admin => self.0.insert(client_id, role),
+            Role::Member => self.0.remove(&client_id),
+        };
+    }
+
+    /// Roles in this map that differ from those in `previous`
+    pub fn changes_from(&self, previous: &RoleMap) -> Vec<(String, Role)> {
+        let clients: BTreeSet<&String> = self.0.keys().chain(previous.0.keys()).collect();
+        clients
+            .into_iter()
+            .filter(|client_id| self.role(client_id) != previous.role(client_id))
+            .map(|client_id| (client_id.clone(), self.role(client_id)))
+            .collect()
+    }
+
+    pub fn admins(&self) -> impl Iterator<Item = &String> {
+        self.0
+            .iter()
+            .filter(|(_, role)| **role == Role::Admin)
+            .map(|(client_id, _)| client_id)
+    }
+}
+
+/// Extension types every Relay leaf advertises, so role maps can be added
+/// to any group
+pub(crate) fn supported_extensions() -> Vec<ExtensionType> {
+    vec![ExtensionType::Unknown(ROLES_EXTENSION_TYPE)]
+}
@@ -0,0 +1,1255 @@
+//! Storage provider for RelayMlsClient.
+//!
+//! Entries are kept in an in-memory map. When the host app supplies a
+//! `StorageBackend` and a 256-bit key, every entry is additionally sealed with
+//! AES-256-GCM and written through to the backend, so long-lived MLS secrets
+//! only ever leave the process encrypted. The storage key of each entry is
+//! bound to its ciphertext as associated data, and the backend only sees an
+//! HMAC of it, since storage keys contain group ids.
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use openmls_traits::storage::{traits, Entity, StorageProvider, CURRENT_VERSION};
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+
+const NONCE_LEN: usize = 12;
+
+/// HKDF info for the key that turns storage keys into backend keys
+const INDEX_KEY_INFO: &[u8] = b"Relay storage index key";
+
+/// Storage entries as (storage key, plaintext value) pairs
+pub(crate) type RawEntries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// What undoing a change to a group's state needs: the storage keys of the
+/// state before it, and the old values of the entries it rewrote or removed
+pub(crate) struct GroupUndo {
+    keys: HashSet<Vec<u8>>,
+    entries: RawEntries,
+}
+
+impl GroupUndo {
+    /// Undo for the change from `before` to `after`, both from
+    /// `RelayStorage::group_entries`. The group state entry is not kept: it
+    /// holds the pending Commit before that Commit is merged, which an undo
+    /// discards anyway.
+    pub fn new(before: RawEntries, after: &RawEntries) -> Self {
+        let after: HashMap<&[u8], &[u8]> = after
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_slice()))
+            .collect();
+        let keys = before.iter().map(|(key, _)| key.clone()).collect();
+        let entries = before
+            .into_iter()
+            .filter(|(key, value)| {
+                !key.starts_with(GROUP_STATE_LABEL)
+                    && after.get(key.as_slice()) != Some(&value.as_slice())
+            })
+            .collect();
+        Self { keys, entries }
+    }
+}
+
+// ============================================================================
+// Host Backend
+// ============================================================================
+
+/// Persistent key-value store implemented by the host app (e.g. on top of a
+/// file or database). Keys handed to it are HMACs and values are AES-GCM
+/// ciphertexts. A failed write must be reported, as the client otherwise
+/// treats the entry as persisted.
+pub trait StorageBackend: Send + Sync {
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError>;
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), StorageError>;
+    fn remove(&self, key: Vec<u8>) -> Result<(), StorageError>;
+}
+
+/// Errors returned by the storage provider
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("Serialization error: {message}")]
+    Serialization { message: String },
+
+    #[error("Storage key must be 32 bytes")]
+    InvalidKey,
+
+    #[error("Failed to encrypt storage entry")]
+    Encryption,
+
+    #[error("Failed to decrypt storage entry")]
+    Decryption,
+
+    /// Thrown by the host's StorageBackend
+    #[error("Storage backend error: {message}")]
+    Backend { message: String },
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Serialization {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<uniffi::UnexpectedUniFFICallbackError> for StorageError {
+    fn from(e: uniffi::UnexpectedUniFFICallbackError) -> Self {
+        Self::Backend { message: e.reason }
+    }
+}
+
+struct AtRest {
+    cipher: Aes256Gcm,
+    index_key: [u8; 32],
+    backend: Box<dyn StorageBackend>,
+}
+
+impl AtRest {
+    /// The key an entry is stored under in the backend
+    fn backend_key(&self, storage_key: &[u8]) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.index_key)
+            .expect("HMAC accepts keys of any length");
+        mac.update(storage_key);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    fn seal(&self, storage_key: &[u8], value: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: value,
+                    aad: storage_key,
+                },
+            )
+            .map_err(|_| StorageError::Encryption)?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    fn open(&self, storage_key: &[u8], sealed: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(StorageError::Decryption);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| StorageError::Decryption)?;
+        self.cipher
+            .decrypt(
+                &Nonce::from(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: storage_key,
+                },
+            )
+            .map_err(|_| StorageError::Decryption)
+    }
+}
+
+// ============================================================================
+// RelayStorage
+// ============================================================================
+
+#[derive(Default)]
+pub struct RelayStorage {
+    values: RwLock<HashMap<Vec<u8>, Vec<u8>>>,
+    at_rest: Option<AtRest>,
+}
+
+impl RelayStorage {
+    /// Storage that keeps every entry in memory only
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Storage that encrypts every entry with `key` and persists it through
+    /// the host-supplied `backend`
+    pub fn encrypted(key: &[u8], backend: Box<dyn StorageBackend>) -> Result<Self, StorageError> {
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| StorageError::InvalidKey)?;
+        let mut index_key = [0; 32];
+        Hkdf::<Sha256>::new(None, key)
+            .expand(INDEX_KEY_INFO, &mut index_key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Ok(Self {
+            values: RwLock::new(HashMap::new()),
+            at_rest: Some(AtRest {
+                cipher,
+                index_key,
+                backend,
+            }),
+        })
+    }
+
+    /// Read an application-level record (outside the OpenMLS key space)
+    pub(crate) fn read_record<V: DeserializeOwned>(
+        &self,
+        label: &[u8],
+    ) -> Result<Option<V>, StorageError> {
+        match self.get_raw(&record_key(label))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Write an application-level record (outside the OpenMLS key space)
+    pub(crate) fn write_record<V: Serialize>(
+        &self,
+        label: &[u8],
+        value: &V,
+    ) -> Result<(), StorageError> {
+        self.put_raw(record_key(label), serde_json::to_vec(value)?)
+    }
+
+    /// Raw entries holding one group's state for its current epoch: the
+    /// group-scoped values, its queued proposals, the epoch's HPKE key pairs
+    /// for our leaf and the key pair of our leaf's encryption key. Identity
+    /// keys are not included.
+    pub(crate) fn group_entries(
+        &self,
+        group_id: &impl traits::GroupId<CURRENT_VERSION>,
+        epoch: &impl traits::EpochKey<CURRENT_VERSION>,
+        leaf_index: u32,
+        encryption_key: &impl traits::EncryptionKey<CURRENT_VERSION>,
+    ) -> Result<RawEntries, StorageError> {
+        let group_key = serde_json::to_vec(group_id)?;
+        let mut storage_keys: Vec<Vec<u8>> = GROUP_LABELS
+            .iter()
+            .map(|label| build_key(label, &group_key))
+            .collect();
+
+        // Proposals are keyed by the (group_id, proposal_ref) tuple, which
+        // serde_json writes as a two-element array
+        let refs = self.read_raw_list(&build_key(PROPOSAL_QUEUE_REFS_LABEL, &group_key))?;
+        for proposal_ref in refs {
+            let key = [b"[", group_key.as_slice(), b",", &proposal_ref, b"]"].concat();
+            storage_keys.push(build_key(QUEUED_PROPOSAL_LABEL, &key));
+        }
+
+        storage_keys.push(build_key(
+            EPOCH_KEY_PAIRS_LABEL,
+            &epoch_key_pairs_id(group_id, epoch, leaf_index)?,
+        ));
+        storage_keys.push(build_key(
+            ENCRYPTION_KEY_PAIR_LABEL,
+            &serde_json::to_vec(encryption_key)?,
+        ));
+
+        let mut entries = Vec::new();
+        for storage_key in storage_keys {
+            if let Some(value) = self.get_raw(&storage_key)? {
+                entries.push((storage_key, value));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Write entries previously returned by `group_entries`
+    pub(crate) fn put_entries(&self, entries: RawEntries) -> Result<(), StorageError> {
+        for (storage_key, value) in entries {
+            self.put_raw(storage_key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Remove entries by storage key, undoing `put_entries`
+    pub(crate) fn remove_entries(
+        &self,
+        storage_keys: impl IntoIterator<Item = Vec<u8>>,
+    ) -> Result<(), StorageError> {
+        for storage_key in storage_keys {
+            self.remove_raw(storage_key)?;
+        }
+        Ok(())
+    }
+
+    /// Undo a change to a group whose state is now `current`: entries the
+    /// state did not have before are removed and the old values put back
+    pub(crate) fn undo_group_change(
+        &self,
+        current: RawEntries,
+        undo: GroupUndo,
+    ) -> Result<(), StorageError> {
+        self.remove_entries(
+            current
+                .into_iter()
+                .map(|(key, _)| key)
+                .filter(|key| !undo.keys.contains(key)),
+        )?;
+        self.put_entries(undo.entries)
+    }
+
+    fn get_raw(&self, storage_key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        if let Some(value) = self.values.read().get(storage_key) {
+            return Ok(Some(value.clone()));
+        }
+
+        // Cache miss: fall back to the encrypted backend
+        let Some(at_rest) = &self.at_rest else {
+            return Ok(None);
+        };
+        let Some(sealed) = at_rest.backend.get(at_rest.backend_key(storage_key))? else {
+            return Ok(None);
+        };
+        let value = at_rest.open(storage_key, &sealed)?;
+
+        self.values
+            .write()
+            .insert(storage_key.to_vec(), value.clone());
+        Ok(Some(value))
+    }
+
+    fn put_raw(&self, storage_key: Vec<u8>, value: Vec<u8>) -> Result<(), StorageError> {
+        if let Some(at_rest) = &self.at_rest {
+            let sealed = at_rest.seal(&storage_key, &value)?;
+            at_rest
+                .backend
+                .put(at_rest.backend_key(&storage_key), sealed)?;
+        }
+        self.values.write().insert(storage_key, value);
+        Ok(())
+    }
+
+    fn remove_raw(&self, storage_key: Vec<u8>) -> Result<(), StorageError> {
+        if let Some(at_rest) = &self.at_rest {
+            at_rest.backend.remove(at_rest.backend_key(&storage_key))?;
+        }
+        self.values.write().remove(&storage_key);
+        Ok(())
+    }
+
+    fn write(&self, label: &[u8], key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        self.put_raw(build_key(label, key), value)
+    }
+
+    fn read<V: DeserializeOwned>(
+        &self,
+        label: &[u8],
+        key: &[u8],
+    ) -> Result<Option<V>, StorageError> {
+        match self.get_raw(&build_key(label, key))? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&self, label: &[u8], key: &[u8]) -> Result<(), StorageError> {
+        self.remove_raw(build_key(label, key))
+    }
+
+    fn read_raw_list(&self, storage_key: &[u8]) -> Result<Vec<Vec<u8>>, StorageError> {
+        match self.get_raw(storage_key)? {
+            Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn read_list<V: Entity<CURRENT_VERSION>>(
+        &self,
+        label: &[u8],
+        key: &[u8],
+    ) -> Result<Vec<V>, StorageError> {
+        self.read_raw_list(&build_key(label, key))?
+            .iter()
+            .map(|bytes| serde_json::from_slice(bytes).map_err(StorageError::from))
+            .collect()
+    }
+
+    fn append(&self, label: &[u8], key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        let storage_key = build_key(label, key);
+        let mut list = self.read_raw_list(&storage_key)?;
+        list.push(value);
+        self.put_raw(storage_key, serde_json::to_vec(&list)?)
+    }
+
+    fn remove_item(&self, label: &[u8], key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        let storage_key = build_key(label, key);
+        let mut list = self.read_raw_list(&storage_key)?;
+        if let Some(pos) = list.iter().position(|item| item == &value) {
+            list.remove(pos);
+        }
+        self.put_raw(storage_key, serde_json::to_vec(&list)?)
+    }
+}
+
+/// Build a storage key from a label, a serialized key and the storage version
+fn build_key(label: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut storage_key = label.to_vec();
+    storage_key.extend_from_slice(key);
+    storage_key.extend_from_slice(&CURRENT_VERSION.to_be_bytes());
+    storage_key
+}
+
+/// Build a storage key for a record owned by RelayMlsClient itself
+fn record_key(label: &[u8]) -> Vec<u8> {
+    let mut storage_key = b"Relay".to_vec();
+    storage_key.extend_from_slice(label);
+    storage_key
+}
+
+fn epoch_key_pairs_id(
+    group_id: &impl traits::GroupId<CURRENT_VERSION>,
+    epoch: &impl traits::EpochKey<CURRENT_VERSION>,
+    leaf_index: u32,
+) -> Result<Vec<u8>, StorageError> {
+    let mut key = serde_json::to_vec(group_id)?;
+    key.extend_from_slice(&serde_json::to_vec(epoch)?);
+    key.extend_from_slice(&serde_json::to_vec(&leaf_index)?);
+    Ok(key)
+}
+
+// ============================================================================
+// StorageProvider
+// ============================================================================
+
+const KEY_PACKAGE_LABEL: &[u8] = b"KeyPackage";
+const PSK_LABEL: &[u8] = b"Psk";
+const ENCRYPTION_KEY_PAIR_LABEL: &[u8] = b"EncryptionKeyPair";
+const SIGNATURE_KEY_PAIR_LABEL: &[u8] = b"SignatureKeyPair";
+const EPOCH_KEY_PAIRS_LABEL: &[u8] = b"EpochKeyPairs";
+
+// related to PublicGroup
+const TREE_LABEL: &[u8] = b"Tree";
+const GROUP_CONTEXT_LABEL: &[u8] = b"GroupContext";
+const INTERIM_TRANSCRIPT_HASH_LABEL: &[u8] = b"InterimTranscriptHash";
+const CONFIRMATION_TAG_LABEL: &[u8] = b"ConfirmationTag";
+
+// related to MlsGroup
+const JOIN_CONFIG_LABEL: &[u8] = b"MlsGroupJoinConfig";
+const OWN_LEAF_NODES_LABEL: &[u8] = b"OwnLeafNodes";
+const GROUP_STATE_LABEL: &[u8] = b"GroupState";
+const QUEUED_PROPOSAL_LABEL: &[u8] = b"QueuedProposal";
+const PROPOSAL_QUEUE_REFS_LABEL: &[u8] = b"ProposalQueueRefs";
+const OWN_LEAF_NODE_INDEX_LABEL: &[u8] = b"OwnLeafNodeIndex";
+const EPOCH_SECRETS_LABEL: &[u8] = b"EpochSecrets";
+const RESUMPTION_PSK_STORE_LABEL: &[u8] = b"ResumptionPsk";
+const MESSAGE_SECRETS_LABEL: &[u8] = b"MessageSecrets";
+
+/// Labels of entries keyed by the serialized group ID alone
+const GROUP_LABELS: [&[u8]; 12] = [
+    JOIN_CONFIG_LABEL,
+    OWN_LEAF_NODES_LABEL,
+    PROPOSAL_QUEUE_REFS_LABEL,
+    TREE_LABEL,
+    GROUP_CONTEXT_LABEL,
+    INTERIM_TRANSCRIPT_HASH_LABEL,
+    CONFIRMATION_TAG_LABEL,
+    GROUP_STATE_LABEL,
+    MESSAGE_SECRETS_LABEL,
+    RESUMPTION_PSK_STORE_LABEL,
+    OWN_LEAF_NODE_INDEX_LABEL,
+    EPOCH_SECRETS_LABEL,
+];
+
+impl StorageProvider<CURRENT_VERSION> for RelayStorage {
+    type Error = StorageError;
+
+    fn write_mls_join_config<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        MlsGroupJoinConfig: traits::MlsGroupJoinConfig<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        config: &MlsGroupJoinConfig,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            JOIN_CONFIG_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(config)?,
+        )
+    }
+
+    fn append_own_leaf_node<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        LeafNode: traits::LeafNode<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        leaf_node: &LeafNode,
+    ) -> Result<(), Self::Error> {
+        self.append(
+            OWN_LEAF_NODES_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(leaf_node)?,
+        )
+    }
+
+    fn queue_proposal<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ProposalRef: traits::ProposalRef<CURRENT_VERSION>,
+        QueuedProposal: traits::QueuedProposal<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        proposal_ref: &ProposalRef,
+        proposal: &QueuedProposal,
+    ) -> Result<(), Self::Error> {
+        // write proposal to key (group_id, proposal_ref)
+        self.write(
+            QUEUED_PROPOSAL_LABEL,
+            &serde_json::to_vec(&(group_id, proposal_ref))?,
+            serde_json::to_vec(proposal)?,
+        )?;
+
+        // update proposal list for group_id
+        self.append(
+            PROPOSAL_QUEUE_REFS_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(proposal_ref)?,
+        )
+    }
+
+    fn write_tree<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        TreeSync: traits::TreeSync<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        tree: &TreeSync,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            TREE_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(tree)?,
+        )
+    }
+
+    fn write_interim_transcript_hash<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        InterimTranscriptHash: traits::InterimTranscriptHash<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        interim_transcript_hash: &InterimTranscriptHash,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            INTERIM_TRANSCRIPT_HASH_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(interim_transcript_hash)?,
+        )
+    }
+
+    fn write_context<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        GroupContext: traits::GroupContext<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        group_context: &GroupContext,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            GROUP_CONTEXT_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(group_context)?,
+        )
+    }
+
+    fn write_confirmation_tag<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ConfirmationTag: traits::ConfirmationTag<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        confirmation_tag: &ConfirmationTag,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            CONFIRMATION_TAG_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(confirmation_tag)?,
+        )
+    }
+
+    fn write_group_state<
+        GroupState: traits::GroupState<CURRENT_VERSION>,
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        group_state: &GroupState,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            GROUP_STATE_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(group_state)?,
+        )
+    }
+
+    fn write_message_secrets<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        MessageSecrets: traits::MessageSecrets<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        message_secrets: &MessageSecrets,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            MESSAGE_SECRETS_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(message_secrets)?,
+        )
+    }
+
+    fn write_resumption_psk_store<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ResumptionPskStore: traits::ResumptionPskStore<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        resumption_psk_store: &ResumptionPskStore,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            RESUMPTION_PSK_STORE_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(resumption_psk_store)?,
+        )
+    }
+
+    fn write_own_leaf_index<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        LeafNodeIndex: traits::LeafNodeIndex<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        own_leaf_index: &LeafNodeIndex,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            OWN_LEAF_NODE_INDEX_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(own_leaf_index)?,
+        )
+    }
+
+    fn write_group_epoch_secrets<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        GroupEpochSecrets: traits::GroupEpochSecrets<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        group_epoch_secrets: &GroupEpochSecrets,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            EPOCH_SECRETS_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(group_epoch_secrets)?,
+        )
+    }
+
+    fn write_signature_key_pair<
+        SignaturePublicKey: traits::SignaturePublicKey<CURRENT_VERSION>,
+        SignatureKeyPair: traits::SignatureKeyPair<CURRENT_VERSION>,
+    >(
+        &self,
+        public_key: &SignaturePublicKey,
+        signature_key_pair: &SignatureKeyPair,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            SIGNATURE_KEY_PAIR_LABEL,
+            &serde_json::to_vec(public_key)?,
+            serde_json::to_vec(signature_key_pair)?,
+        )
+    }
+
+    fn write_encryption_key_pair<
+        EncryptionKey: traits::EncryptionKey<CURRENT_VERSION>,
+        HpkeKeyPair: traits::HpkeKeyPair<CURRENT_VERSION>,
+    >(
+        &self,
+        public_key: &EncryptionKey,
+        key_pair: &HpkeKeyPair,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            ENCRYPTION_KEY_PAIR_LABEL,
+            &serde_json::to_vec(public_key)?,
+            serde_json::to_vec(key_pair)?,
+        )
+    }
+
+    fn write_encryption_epoch_key_pairs<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        EpochKey: traits::EpochKey<CURRENT_VERSION>,
+        HpkeKeyPair: traits::HpkeKeyPair<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        epoch: &EpochKey,
+        leaf_index: u32,
+        key_pairs: &[HpkeKeyPair],
+    ) -> Result<(), Self::Error> {
+        self.write(
+            EPOCH_KEY_PAIRS_LABEL,
+            &epoch_key_pairs_id(group_id, epoch, leaf_index)?,
+            serde_json::to_vec(key_pairs)?,
+        )
+    }
+
+    fn write_key_package<
+        HashReference: traits::HashReference<CURRENT_VERSION>,
+        KeyPackage: traits::KeyPackage<CURRENT_VERSION>,
+    >(
+        &self,
+        hash_ref: &HashReference,
+        key_package: &KeyPackage,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            KEY_PACKAGE_LABEL,
+            &serde_json::to_vec(hash_ref)?,
+            serde_json::to_vec(key_package)?,
+        )
+    }
+
+    fn write_psk<
+        PskId: traits::PskId<CURRENT_VERSION>,
+        PskBundle: traits::PskBundle<CURRENT_VERSION>,
+    >(
+        &self,
+        psk_id: &PskId,
+        psk: &PskBundle,
+    ) -> Result<(), Self::Error> {
+        self.write(
+            PSK_LABEL,
+            &serde_json::to_vec(psk_id)?,
+            serde_json::to_vec(psk)?,
+        )
+    }
+
+    fn mls_group_join_config<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        MlsGroupJoinConfig: traits::MlsGroupJoinConfig<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<MlsGroupJoinConfig>, Self::Error> {
+        self.read(JOIN_CONFIG_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn own_leaf_nodes<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        LeafNode: traits::LeafNode<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Vec<LeafNode>, Self::Error> {
+        self.read_list(OWN_LEAF_NODES_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn queued_proposal_refs<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ProposalRef: traits::ProposalRef<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Vec<ProposalRef>, Self::Error> {
+        self.read_list(PROPOSAL_QUEUE_REFS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn queued_proposals<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ProposalRef: traits::ProposalRef<CURRENT_VERSION>,
+        QueuedProposal: traits::QueuedProposal<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Vec<(ProposalRef, QueuedProposal)>, Self::Error> {
+        let refs: Vec<ProposalRef> = self.queued_proposal_refs(group_id)?;
+
+        refs.into_iter()
+            .filter_map(|proposal_ref| {
+                let key = match serde_json::to_vec(&(group_id, &proposal_ref)) {
+                    Ok(key) => key,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                self.read(QUEUED_PROPOSAL_LABEL, &key)
+                    .transpose()
+                    .map(|proposal| proposal.map(|p| (proposal_ref, p)))
+            })
+            .collect()
+    }
+
+    fn tree<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        TreeSync: traits::TreeSync<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<TreeSync>, Self::Error> {
+        self.read(TREE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn group_context<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        GroupContext: traits::GroupContext<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<GroupContext>, Self::Error> {
+        self.read(GROUP_CONTEXT_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn interim_transcript_hash<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        InterimTranscriptHash: traits::InterimTranscriptHash<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<InterimTranscriptHash>, Self::Error> {
+        self.read(
+            INTERIM_TRANSCRIPT_HASH_LABEL,
+            &serde_json::to_vec(group_id)?,
+        )
+    }
+
+    fn confirmation_tag<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ConfirmationTag: traits::ConfirmationTag<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<ConfirmationTag>, Self::Error> {
+        self.read(CONFIRMATION_TAG_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn group_state<
+        GroupState: traits::GroupState<CURRENT_VERSION>,
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<GroupState>, Self::Error> {
+        self.read(GROUP_STATE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn message_secrets<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        MessageSecrets: traits::MessageSecrets<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<MessageSecrets>, Self::Error> {
+        self.read(MESSAGE_SECRETS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn resumption_psk_store<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ResumptionPskStore: traits::ResumptionPskStore<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<ResumptionPskStore>, Self::Error> {
+        self.read(RESUMPTION_PSK_STORE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn own_leaf_index<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        LeafNodeIndex: traits::LeafNodeIndex<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<LeafNodeIndex>, Self::Error> {
+        self.read(OWN_LEAF_NODE_INDEX_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn group_epoch_secrets<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        GroupEpochSecrets: traits::GroupEpochSecrets<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<GroupEpochSecrets>, Self::Error> {
+        self.read(EPOCH_SECRETS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn signature_key_pair<
+        SignaturePublicKey: traits::SignaturePublicKey<CURRENT_VERSION>,
+        SignatureKeyPair: traits::SignatureKeyPair<CURRENT_VERSION>,
+    >(
+        &self,
+        public_key: &SignaturePublicKey,
+    ) -> Result<Option<SignatureKeyPair>, Self::Error> {
+        self.read(SIGNATURE_KEY_PAIR_LABEL, &serde_json::to_vec(public_key)?)
+    }
+
+    fn encryption_key_pair<
+        HpkeKeyPair: traits::HpkeKeyPair<CURRENT_VERSION>,
+        EncryptionKey: traits::EncryptionKey<CURRENT_VERSION>,
+    >(
+        &self,
+        public_key: &EncryptionKey,
+    ) -> Result<Option<HpkeKeyPair>, Self::Error> {
+        self.read(ENCRYPTION_KEY_PAIR_LABEL, &serde_json::to_vec(public_key)?)
+    }
+
+    fn encryption_epoch_key_pairs<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        EpochKey: traits::EpochKey<CURRENT_VERSION>,
+        HpkeKeyPair: traits::HpkeKeyPair<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        epoch: &EpochKey,
+        leaf_index: u32,
+    ) -> Result<Vec<HpkeKeyPair>, Self::Error> {
+        let key = epoch_key_pairs_id(group_id, epoch, leaf_index)?;
+        Ok(self.read(EPOCH_KEY_PAIRS_LABEL, &key)?.unwrap_or_default())
+    }
+
+    fn key_package<
+        KeyPackageRef: traits::HashReference<CURRENT_VERSION>,
+        KeyPackage: traits::KeyPackage<CURRENT_VERSION>,
+    >(
+        &self,
+        hash_ref: &KeyPackageRef,
+    ) -> Result<Option<KeyPackage>, Self::Error> {
+        self.read(KEY_PACKAGE_LABEL, &serde_json::to_vec(hash_ref)?)
+    }
+
+    fn psk<PskBundle: traits::PskBundle<CURRENT_VERSION>, PskId: traits::PskId<CURRENT_VERSION>>(
+        &self,
+        psk_id: &PskId,
+    ) -> Result<Option<PskBundle>, Self::Error> {
+        self.read(PSK_LABEL, &serde_json::to_vec(psk_id)?)
+    }
+
+    fn remove_proposal<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ProposalRef: traits::ProposalRef<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        proposal_ref: &ProposalRef,
+    ) -> Result<(), Self::Error> {
+        self.remove_item(
+            PROPOSAL_QUEUE_REFS_LABEL,
+            &serde_json::to_vec(group_id)?,
+            serde_json::to_vec(proposal_ref)?,
+        )?;
+        self.delete(
+            QUEUED_PROPOSAL_LABEL,
+            &serde_json::to_vec(&(group_id, proposal_ref))?,
+        )
+    }
+
+    fn delete_own_leaf_nodes<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(OWN_LEAF_NODES_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_group_config<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(JOIN_CONFIG_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_tree<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(TREE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_confirmation_tag<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(CONFIRMATION_TAG_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_group_state<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(GROUP_STATE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_context<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(GROUP_CONTEXT_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_interim_transcript_hash<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(
+            INTERIM_TRANSCRIPT_HASH_LABEL,
+            &serde_json::to_vec(group_id)?,
+        )
+    }
+
+    fn delete_message_secrets<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(MESSAGE_SECRETS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_all_resumption_psk_secrets<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(RESUMPTION_PSK_STORE_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_own_leaf_index<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(OWN_LEAF_NODE_INDEX_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_group_epoch_secrets<GroupId: traits::GroupId<CURRENT_VERSION>>(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        self.delete(EPOCH_SECRETS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn clear_proposal_queue<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        ProposalRef: traits::ProposalRef<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(), Self::Error> {
+        let proposal_refs: Vec<ProposalRef> = self.queued_proposal_refs(group_id)?;
+        for proposal_ref in proposal_refs {
+            self.delete(
+                QUEUED_PROPOSAL_LABEL,
+                &serde_json::to_vec(&(group_id, proposal_ref))?,
+            )?;
+        }
+        self.delete(PROPOSAL_QUEUE_REFS_LABEL, &serde_json::to_vec(group_id)?)
+    }
+
+    fn delete_signature_key_pair<
+        SignaturePublicKey: traits::SignaturePublicKey<CURRENT_VERSION>,
+    >(
+        &self,
+        public_key: &SignaturePublicKey,
+    ) -> Result<(), Self::Error> {
+        self.delete(SIGNATURE_KEY_PAIR_LABEL, &serde_json::to_vec(public_key)?)
+    }
+
+    fn delete_encryption_key_pair<EncryptionKey: traits::EncryptionKey<CURRENT_VERSION>>(
+        &self,
+        public_key: &EncryptionKey,
+    ) -> Result<(), Self::Error> {
+        self.delete(ENCRYPTION_KEY_PAIR_LABEL, &serde_json::to_vec(public_key)?)
+    }
+
+    fn delete_encryption_epoch_key_pairs<
+        GroupId: traits::GroupId<CURRENT_VERSION>,
+        EpochKey: traits::EpochKey<CURRENT_VERSION>,
+    >(
+        &self,
+        group_id: &GroupId,
+        epoch: &EpochKey,
+        leaf_index: u32,
+    ) -> Result<(), Self::Error> {
+        self.delete(
+            EPOCH_KEY_PAIRS_LABEL,
+            &epoch_key_pairs_id(group_id, epoch, leaf_index)?,
+        )
+    }
+
+    fn delete_key_package<KeyPackageRef: traits::HashReference<CURRENT_VERSION>>(
+        &self,
+        hash_ref: &KeyPackageRef,
+    ) -> Result<(), Self::Error> {
+        self.delete(KEY_PACKAGE_LABEL, &serde_json::to_vec(hash_ref)?)
+    }
+
+    fn delete_psk<PskKey: traits::PskId<CURRENT_VERSION>>(
+        &self,
+        psk_id: &PskKey,
+    ) -> Result<(), Self::Error> {
+        self.delete(PSK_LABEL, &serde_json::to_vec(psk_id)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    /// Backend over a map shared between storages, optionally failing writes
+    #[derive(Clone, Default)]
+    struct MapBackend {
+        entries: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+        fail_writes: bool,
+    }
+
+    impl StorageBackend for MapBackend {
+        fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, StorageError> {
+            Ok(self.entries.read().get(&key).cloned())
+        }
+
+        fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), StorageError> {
+            if self.fail_writes {
+                return Err(StorageError::Backend {
+                    message: "disk full".to_string(),
+                });
+            }
+            self.entries.write().insert(key, value);
+            Ok(())
+        }
+
+        fn remove(&self, key: Vec<u8>) -> Result<(), StorageError> {
+            self.entries.write().remove(&key);
+            Ok(())
+        }
+    }
+
+    fn storage(key: &[u8], backend: &MapBackend) -> RelayStorage {
+        RelayStorage::encrypted(key, Box::new(backend.clone())).unwrap()
+    }
+
+    #[test]
+    fn round_trip_through_backend() {
+        let backend = MapBackend::default();
+        storage(&KEY, &backend)
+            .write_record(b"Test", &"secret value")
+            .unwrap();
+
+        // A fresh storage has nothing cached and reads from the backend
+        let read: Option<String> = storage(&KEY, &backend).read_record(b"Test").unwrap();
+        assert_eq!(read.as_deref(), Some("secret value"));
+    }
+
+    #[test]
+    fn backend_sees_no_plaintext() {
+        let backend = MapBackend::default();
+        storage(&KEY, &backend)
+            .write_record(b"Test", &"secret value")
+            .unwrap();
+
+        let entries = backend.entries.read();
+        let (key, value) = entries.iter().next().unwrap();
+        assert_eq!(key.len(), 32);
+        assert_ne!(key, &record_key(b"Test"));
+        assert!(!value.windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn wrong_key_finds_nothing() {
+        let backend = MapBackend::default();
+        storage(&KEY, &backend)
+            .write_record(b"Test", &"secret value")
+            .unwrap();
+
+        let other = storage(&[8; 32], &backend);
+        let read: Option<String> = other.read_record(b"Test").unwrap();
+        assert_eq!(read, None);
+
+        // Nor does it open an entry it is handed
+        let sealed = backend.entries.read().values().next().unwrap().clone();
+        let opened = other
+            .at_rest
+            .as_ref()
+            .unwrap()
+            .open(&record_key(b"Test"), &sealed);
+        assert!(matches!(opened, Err(StorageError::Decryption)));
+    }
+
+    #[test]
+    fn tampered_entry_is_rejected() {
+        let backend = MapBackend::default();
+        storage(&KEY, &backend)
+            .write_record(b"Test", &"secret value")
+            .unwrap();
+        for value in backend.entries.write().values_mut() {
+            *value.last_mut().unwrap() ^= 1;
+        }
+
+        let read = storage(&KEY, &backend).read_record::<String>(b"Test");
+        assert!(matches!(read, Err(StorageError::Decryption)));
+    }
+
+    #[test]
+    fn entry_moved_to_another_key_is_rejected() {
+        let backend = MapBackend::default();
+        let writer = storage(&KEY, &backend);
+        writer.write_record(b"A", &"value of a").unwrap();
+        writer.write_record(b"B", &"value of b").unwrap();
+
+        // The storage key is the AAD, so a swapped entry doesn't open
+        let at_rest = writer.at_rest.as_ref().unwrap();
+        let (key_a, key_b) = (
+            at_rest.backend_key(&record_key(b"A")),
+            at_rest.backend_key(&record_key(b"B")),
+        );
+        let value_a = backend.entries.read()[&key_a].clone();
+        backend.entries.write().insert(key_b, value_a);
+
+        let read = storage(&KEY, &backend).read_record::<String>(b"B");
+        assert!(matches!(read, Err(StorageError::Decryption)));
+    }
+
+    #[test]
+    fn failed_write_is_reported_and_not_cached() {
+        let backend = MapBackend {
+            fail_writes: true,
+            ..Default::default()
+        };
+        let storage = storage(&KEY, &backend);
+
+        let written = storage.write_record(b"Test", &"secret value");
+        assert!(matches!(written, Err(StorageError::Backend { .. })));
+        assert_eq!(storage.read_record::<String>(b"Test").unwrap(), None);
+    }
+
+    #[test]
+    fn short_key_is_rejected() {
+        let backend = MapBackend::default();
+        let storage = RelayStorage::encrypted(&[7; 16], Box::new(backend));
+        assert!(matches!(storage, Err(StorageError::InvalidKey)));
+    }
+
+    #[test]
+    fn undo_restores_only_changed_entries() {
+        let storage = RelayStorage::in_memory();
+        let entry = |key: &[u8], value: &[u8]| (key.to_vec(), value.to_vec());
+        let before = vec![
+            entry(b"Tree", b"old"),
+            entry(b"Same", b"kept"),
+            entry(b"Gone", b"removed"),
+            entry(GROUP_STATE_LABEL, b"pending"),
+        ];
+        storage.put_entries(before.clone()).unwrap();
+        storage.remove_entries([b"Gone".to_vec()]).unwrap();
+        let after = vec![
+            entry(b"Tree", b"new"),
+            entry(b"Same", b"kept"),
+            entry(b"Added", b"new"),
+            entry(GROUP_STATE_LABEL, b"operational"),
+        ];
+        storage.put_entries(after.clone()).unwrap();
+
+        let undo = GroupUndo::new(before, &after);
+        let mut kept: Vec<_> = undo.entries.iter().map(|(key, _)| key.clone()).collect();
+        kept.sort();
+        assert_eq!(kept, vec![b"Gone".to_vec(), b"Tree".to_vec()]);
+
+        storage.undo_group_change(after, undo).unwrap();
+        assert_eq!(storage.get_raw(b"Tree").unwrap(), Some(b"old".to_vec()));
+        assert_eq!(storage.get_raw(b"Gone").unwrap(), Some(b"removed".to_vec()));
+        assert_eq!(storage.get_raw(b"Added").unwrap(), None);
+        assert_eq!(
+            storage.get_raw(GROUP_STATE_LABEL).unwrap(),
+            Some(b"operational".to_vec())
+        );
+    }
+}
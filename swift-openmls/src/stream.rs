@@ -0,0 +1,276 @@
+//! Chunk framing and reassembly for payloads split across several MLS
+//! application messages.
+//!
+//! Each chunk carries a `ChunkHeader` in the message's authenticated data
+//! (the `chunk` entry of `MessageAad`), so the sequence information is
+//! integrity-protected by MLS without touching the application plaintext:
+//!
+//! ```text
+//! ChunkHeader = stream_id (16 bytes) || index (u32 BE) || total (u32 BE)
+//! ```
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Upper bound on chunks per stream, so a peer cannot make us reserve an
+/// arbitrarily large reassembly buffer
+pub(crate) const MAX_STREAM_CHUNKS: u32 = 65_536;
+
+/// Incomplete streams a group may have at once; chunks of further streams
+/// are rejected until one completes or expires
+const MAX_OPEN_STREAMS: usize = 8;
+
+/// An incomplete stream is dropped when no chunk of it arrived for this long
+const STREAM_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+const HEADER_LEN: usize = 24;
+
+pub(crate) struct ChunkHeader {
+    pub stream_id: [u8; 16],
+    pub index: u32,
+    pub total: u32,
+}
+
+impl ChunkHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.extend_from_slice(&self.stream_id);
+        out.extend_from_slice(&self.index.to_be_bytes());
+        out.extend_from_slice(&self.total.to_be_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != HEADER_LEN {
+            return None;
+        }
+        let header = Self {
+            stream_id: bytes[..16].try_into().ok()?,
+            index: u32::from_be_bytes(bytes[16..20].try_into().ok()?),
+            total: u32::from_be_bytes(bytes[20..24].try_into().ok()?),
+        };
+        if header.total == 0 || header.total > MAX_STREAM_CHUNKS || header.index >= header.total {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+/// Chunks received so far for one stream
+struct PartialStream {
+    sender: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u32,
+    last_chunk: Instant,
+}
+
+impl PartialStream {
+    fn new(sender: u32, total: u32, now: Instant) -> Self {
+        Self {
+            sender,
+            chunks: vec![None; total as usize],
+            received: 0,
+            last_chunk: now,
+        }
+    }
+
+    fn total(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    /// Store a chunk; duplicates of an already received index are ignored
+    fn insert(&mut self, index: u32, data: Vec<u8>, now: Instant) {
+        let slot = &mut self.chunks[index as usize];
+        if slot.is_none() {
+            *slot = Some(data);
+            self.received += 1;
+        }
+        self.last_chunk = now;
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received == self.total()
+    }
+
+    /// Concatenate all chunks in order (only meaningful once complete)
+    fn assemble(self) -> Vec<u8> {
+        self.chunks.into_iter().flatten().flatten().collect()
+    }
+}
+
+/// Where a stream stands after one of its chunks arrived
+pub(crate) struct ChunkProgress {
+    pub received: u32,
+    pub total: u32,
+    /// The whole payload, once every chunk has arrived
+    pub plaintext: Option<Vec<u8>>,
+}
+
+/// Incomplete streams of all groups
+#[derive(Default)]
+pub(crate) struct StreamBuffer {
+    streams: HashMap<(String, String), PartialStream>, // (group_id, stream_id) -> chunks
+}
+
+impl StreamBuffer {
+    /// Buffer a chunk sent by the member at leaf `sender`, returning the
+    /// payload once complete. Streams that went quiet are dropped first.
+    pub fn add_chunk(
+        &mut self,
+        group_id: &str,
+        sender: u32,
+        header: &ChunkHeader,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<ChunkProgress, String> {
+        self.streams
+            .retain(|_, partial| now.duration_since(partial.last_chunk) < STREAM_TIMEOUT);
+
+        let key = (group_id.to_string(), hex::encode(header.stream_id));
+        if !self.streams.contains_key(&key) {
+            let open = self.streams.keys().filter(|(g, _)| g == group_id).count();
+            if open >= MAX_OPEN_STREAMS {
+                return Err(format!(
+                    "Group already has {} incomplete streams",
+                    MAX_OPEN_STREAMS
+                ));
+            }
+        }
+        let partial = self
+            .streams
+            .entry(key.clone())
+            .or_insert_with(|| PartialStream::new(sender, header.total, now));
+        if partial.sender != sender || partial.total() != header.total {
+            return Err("Chunk does not match its stream".to_string());
+        }
+        partial.insert(header.index, data, now);
+
+        let (received, total) = (partial.received, partial.total());
+        let plaintext = if partial.is_complete() {
+            self.streams.remove(&key).map(PartialStream::assemble)
+        } else {
+            None
+        };
+        Ok(ChunkProgress {
+            received,
+            total,
+            plaintext,
+        })
+    }
+
+    /// Drop the buffered chunks of a stream
+    pub fn discard(&mut self, group_id: String, stream_id: String) {
+        self.streams.remove(&(group_id, stream_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(stream: u8, index: u32, total: u32) -> ChunkHeader {
+        ChunkHeader {
+            stream_id: [stream; 16],
+            index,
+            total,
+        }
+    }
+
+    fn add(buffer: &mut StreamBuffer, header: &ChunkHeader, now: Instant) -> ChunkProgress {
+        let data = vec![header.index as u8];
+        buffer.add_chunk("group", 0, header, data, now).unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let decoded = ChunkHeader::decode(&header(1, 2, 3).encode()).unwrap();
+        assert_eq!(
+            (decoded.stream_id, decoded.index, decoded.total),
+            ([1; 16], 2, 3)
+        );
+    }
+
+    #[test]
+    fn out_of_range_headers_are_rejected() {
+        assert!(ChunkHeader::decode(&header(1, 3, 3).encode()).is_none());
+        assert!(ChunkHeader::decode(&header(1, 0, 0).encode()).is_none());
+        assert!(ChunkHeader::decode(&header(1, 0, MAX_STREAM_CHUNKS + 1).encode()).is_none());
+        assert!(ChunkHeader::decode(&header(1, 0, 1).encode()[1..]).is_none());
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        assert!(add(&mut buffer, &header(1, 2, 3), now).plaintext.is_none());
+        assert!(add(&mut buffer, &header(1, 0, 3), now).plaintext.is_none());
+        let done = add(&mut buffer, &header(1, 1, 3), now);
+        assert_eq!((done.received, done.total), (3, 3));
+        assert_eq!(done.plaintext, Some(vec![0, 1, 2]));
+        assert!(buffer.streams.is_empty());
+    }
+
+    #[test]
+    fn missing_chunk_keeps_stream_open() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        add(&mut buffer, &header(1, 0, 3), now);
+        let progress = add(&mut buffer, &header(1, 2, 3), now);
+        assert_eq!((progress.received, progress.plaintext), (2, None));
+    }
+
+    #[test]
+    fn duplicate_chunk_is_counted_once() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        add(&mut buffer, &header(1, 0, 2), now);
+        let progress = add(&mut buffer, &header(1, 0, 2), now);
+        assert_eq!((progress.received, progress.plaintext), (1, None));
+        let done = add(&mut buffer, &header(1, 1, 2), now);
+        assert_eq!(done.plaintext, Some(vec![0, 1]));
+    }
+
+    #[test]
+    fn chunk_from_another_sender_or_total_is_rejected() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        add(&mut buffer, &header(1, 0, 3), now);
+        assert!(buffer
+            .add_chunk("group", 1, &header(1, 1, 3), vec![], now)
+            .is_err());
+        assert!(buffer
+            .add_chunk("group", 0, &header(1, 1, 4), vec![], now)
+            .is_err());
+    }
+
+    #[test]
+    fn open_streams_are_limited_per_group() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        for stream in 0..MAX_OPEN_STREAMS as u8 {
+            add(&mut buffer, &header(stream, 0, 2), now);
+        }
+        let extra = header(MAX_OPEN_STREAMS as u8, 0, 2);
+        assert!(buffer.add_chunk("group", 0, &extra, vec![], now).is_err());
+
+        // Open streams still progress, and other groups are unaffected
+        assert!(add(&mut buffer, &header(0, 1, 2), now).plaintext.is_some());
+        assert!(buffer.add_chunk("other", 0, &extra, vec![], now).is_ok());
+    }
+
+    #[test]
+    fn quiet_streams_expire() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        for stream in 0..MAX_OPEN_STREAMS as u8 {
+            add(&mut buffer, &header(stream, 0, 2), now);
+        }
+
+        let later = now + STREAM_TIMEOUT;
+        let progress = add(&mut buffer, &header(MAX_OPEN_STREAMS as u8, 0, 2), later);
+        assert_eq!(progress.received, 1);
+        assert_eq!(buffer.streams.len(), 1);
+    }
+
+    #[test]
+    fn discarded_stream_starts_over() {
+        let (mut buffer, now) = (StreamBuffer::default(), Instant::now());
+        add(&mut buffer, &header(1, 0, 2), now);
+        buffer.discard("group".to_string(), hex::encode([1u8; 16]));
+        assert_eq!(add(&mut buffer, &header(1, 1, 2), now).received, 1);
+    }
+}